@@ -0,0 +1,445 @@
+//! The syntax tree of a bofink file, as produced by the parser.
+//!
+//! Some nodes have slots that the parser leaves empty, like the kind of every
+//! expression and what a name refers to. The checker fills them in, and the code
+//! generator relies on them being filled.
+
+use std::path::PathBuf;
+
+use crate::enums::ExpressionKind;
+use crate::scanner::Span;
+
+/// A source file of the program, either the compiled source or an imported module.
+#[derive(Debug)]
+pub struct Module {
+    /// The namespace the module was first imported as.
+    pub name: String,
+    /// Canonical path of the file, if it was read from one.
+    pub path: Option<PathBuf>,
+    pub source: String,
+    pub file: File,
+    /// Filled in by the checker: the index of the function running the top level
+    /// code. The compiled source runs as the first function.
+    pub init: u16,
+}
+
+/// A parsed file.
+#[derive(Debug, Default)]
+pub struct File {
+    pub stmts: Vec<Stmt>,
+    /// The names declared by statements that failed to parse. Their uses are not
+    /// reported as errors of their own.
+    pub unknown: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Stmt {
+    pub node: StmtNode,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub enum StmtNode {
+    /// `let name = value;` or `mut name: type = value;`
+    Let(Let),
+    /// `target = value;`, where the target is a variable, a field or an element.
+    Assign {
+        target: Expr,
+        value: Expr,
+    },
+    /// An expression whose value is not used, like a call.
+    Expr(Expr),
+    Print(Expr),
+    Return(Option<Expr>),
+    If(If),
+    While(While),
+    /// `for i in 0:10:2 { ... }`
+    ForRange(ForRange),
+    /// `for x in values { ... }`, over the elements of an array or the keys of a map.
+    ForIn(ForIn),
+    Break(Option<String>),
+    Continue(Option<String>),
+    Function(Function),
+    Class(Class),
+    Enum(Enum),
+    Import(Import),
+}
+
+#[derive(Debug)]
+pub struct Block {
+    pub stmts: Vec<Stmt>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct Let {
+    pub name: String,
+    pub is_mut: bool,
+    pub ty: Option<Type>,
+    pub value: Expr,
+}
+
+#[derive(Debug)]
+pub struct If {
+    pub condition: Expr,
+    pub then_branch: Block,
+    pub else_branch: Option<Else>,
+}
+
+#[derive(Debug)]
+pub enum Else {
+    Block(Block),
+    If(Box<If>),
+}
+
+#[derive(Debug)]
+pub struct While {
+    pub label: Option<String>,
+    pub condition: Expr,
+    pub body: Block,
+}
+
+#[derive(Debug)]
+pub struct ForRange {
+    pub label: Option<String>,
+    pub name: String,
+    pub name_span: Span,
+    pub start: i64,
+    pub end: i64,
+    /// The increment, counting towards `end` by one if it is left out.
+    pub step: Option<i64>,
+    pub body: Block,
+}
+
+#[derive(Debug)]
+pub struct ForIn {
+    pub label: Option<String>,
+    pub name: String,
+    pub name_span: Span,
+    pub iterable: Expr,
+    pub body: Block,
+}
+
+/// A named or an anonymous function, or a method.
+#[derive(Debug)]
+pub struct Function {
+    /// Empty for anonymous functions.
+    pub name: String,
+    pub is_pub: bool,
+    pub params: Vec<Param>,
+    pub return_type: Option<Type>,
+    pub body: Block,
+    pub span: Span,
+    /// Filled in by the checker: the index of the function in the chunk.
+    pub index: u16,
+    /// Filled in by the checker: the enclosing locals an anonymous function uses,
+    /// which are copied into it when it is created.
+    pub captures: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Param {
+    pub name: String,
+    pub ty: Type,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub is_pub: bool,
+    /// Fields and methods in the order they are declared.
+    pub members: Vec<Member>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub enum Member {
+    Field(Field),
+    Method(Method),
+}
+
+#[derive(Debug)]
+pub struct Field {
+    pub name: String,
+    pub ty: Type,
+    pub default: Option<Expr>,
+    pub span: Span,
+    /// Filled in by the checker: the index of the function computing the default.
+    pub default_index: u16,
+}
+
+#[derive(Debug)]
+pub struct Method {
+    /// Only `mut` methods can change the fields of `self`.
+    pub is_mut: bool,
+    pub function: Function,
+}
+
+#[derive(Debug)]
+pub struct Enum {
+    pub name: String,
+    pub is_pub: bool,
+    pub variants: Vec<Variant>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct Variant {
+    pub name: String,
+    pub payload: Vec<Type>,
+    pub span: Span,
+}
+
+/// `import "path/util.bofink";` or `import util;`
+#[derive(Debug)]
+pub struct Import {
+    /// The path of the file, relative to the importing file or the search path.
+    pub path: String,
+    /// The namespace the module is used with, the name of the file.
+    pub name: String,
+    pub span: Span,
+    /// Filled in when the imports are loaded: the index of the module.
+    pub module: Option<usize>,
+}
+
+/// A type annotation.
+#[derive(Debug, Clone)]
+pub struct Type {
+    pub node: TypeNode,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum TypeNode {
+    Int,
+    Float,
+    Bool,
+    Str,
+    /// A class or an enum, possibly of an imported module like `util.Point`.
+    Named {
+        module: Option<String>,
+        name: String,
+    },
+    Array(Box<Type>),
+    Map(Box<Type>, Box<Type>),
+    Optional(Box<Type>),
+    /// `fun(int, str) -> bool`
+    Function(Vec<Type>, Option<Box<Type>>),
+}
+
+#[derive(Debug)]
+pub struct Expr {
+    pub node: ExprNode,
+    pub span: Span,
+    /// Filled in by the checker.
+    pub kind: ExpressionKind,
+}
+
+impl Expr {
+    pub fn new(node: ExprNode, span: Span) -> Self {
+        Expr {
+            node,
+            span,
+            kind: ExpressionKind::None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExprNode {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    /// A name, which the checker resolves to a variable or a function.
+    Variable {
+        name: String,
+        target: VariableTarget,
+    },
+    Unary {
+        operator: UnaryOperator,
+        operand: Box<Expr>,
+    },
+    Binary {
+        operator: BinaryOperator,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    /// `int(x)` or `float(x)`
+    Conversion {
+        to: ExpressionKind,
+        value: Box<Expr>,
+    },
+    Array(Vec<Expr>),
+    Map(Vec<(Expr, Expr)>),
+    /// `object.name`, which the checker resolves to a field, a variant or a function
+    /// of a module.
+    Field {
+        object: Box<Expr>,
+        name: String,
+        target: FieldTarget,
+    },
+    /// `object[index]` on an array or a map.
+    Index {
+        object: Box<Expr>,
+        index: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        args: Vec<Expr>,
+        target: CallTarget,
+    },
+    /// `new Foo(1, 2)` or `new util.Foo(a: 1)`
+    New {
+        module: Option<String>,
+        class: String,
+        args: Vec<Arg>,
+        target: Option<Constructor>,
+    },
+    Match(Box<Match>),
+    /// An anonymous function.
+    Function(Box<Function>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOperator {
+    Not,
+    Negate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOperator {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Is,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+/// What a name refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VariableTarget {
+    #[default]
+    Unresolved,
+    /// A local of the current function, including the copied locals of an anonymous
+    /// function.
+    Local,
+    /// A global of a module, used from inside a function.
+    Global { module: usize, index: usize },
+    /// A declared function, used as a value.
+    Function(u16),
+    /// A value passed in by the host, by its index in the inputs of the chunk.
+    Input(u16),
+}
+
+/// What `object.name` refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FieldTarget {
+    #[default]
+    Unresolved,
+    /// A field of an instance, by its index.
+    Field(u16),
+    /// A variant of an enum without a payload, by its class info.
+    Variant(u16),
+    /// A public function of an imported module, used as a value.
+    Function(u16),
+}
+
+/// What a call runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CallTarget {
+    #[default]
+    Unresolved,
+    /// A declared function.
+    Function(u16),
+    /// A method of a class. The object of the callee is passed as `self`.
+    Method(u16),
+    /// A method of arrays and maps, run by a single instruction.
+    Builtin(Builtin),
+    /// The function value the callee evaluates to.
+    Value,
+    /// A variant of an enum with its payload, by its class info.
+    Variant(u16),
+    /// A function registered by the host, by its index in the registered natives.
+    Native(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    ArrayLen,
+    ArrayPush,
+    ArrayPop,
+    ArrayMap,
+    ArrayFilter,
+    ArraySort,
+    MapLen,
+    MapGet,
+    MapSet,
+    MapHas,
+    MapRemove,
+    MapKeys,
+    MapValues,
+}
+
+/// An argument of `new`, positional or named.
+#[derive(Debug)]
+pub struct Arg {
+    pub name: Option<String>,
+    pub value: Expr,
+    pub span: Span,
+}
+
+/// How a `new` expression builds its instance, filled in by the checker.
+#[derive(Debug)]
+pub struct Constructor {
+    pub info: u16,
+    /// Where the value of every field comes from, in order. With an `init` method the
+    /// arguments go to it instead.
+    pub fields: Vec<Source>,
+    /// The index of the `init` method and where its arguments come from.
+    pub init: Option<(u16, Vec<Source>)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The argument at the index.
+    Arg(usize),
+    /// The default value, computed by the function at the index.
+    Default(u16),
+    Nil,
+}
+
+#[derive(Debug)]
+pub struct Match {
+    pub value: Expr,
+    pub arms: Vec<Arm>,
+}
+
+/// `Circle(r) => ...`, or `_ => ...` for every variant that is left.
+#[derive(Debug)]
+pub struct Arm {
+    pub variant: String,
+    /// The names bound to the payload, `_` skipping a value.
+    pub bindings: Vec<String>,
+    pub body: ArmBody,
+    pub span: Span,
+    /// Filled in by the checker: the class info of the variant, `None` for `_`.
+    pub info: Option<u16>,
+}
+
+#[derive(Debug)]
+pub enum ArmBody {
+    Expr(Expr),
+    Block(Block),
+}
@@ -0,0 +1,2863 @@
+//! Resolves the names of a parsed program and checks its types. What it finds out
+//! is stored in the slots of the syntax tree, so the code generator never has to
+//! look anything up.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{self, *};
+use crate::compiler::{ClassInfo, MAX_OPERAND};
+use crate::enums::{Binding, CompilerError, ExpressionKind, TokenKind, TypeRef};
+use crate::host::{HostKind, HostValue};
+use crate::native::NativeSignature;
+use crate::scanner::Span;
+
+type Result<T> = std::result::Result<T, CompilerError>;
+
+/// What the checker knows about the program as a whole, besides the slots it fills
+/// in the syntax tree.
+#[derive(Debug)]
+pub struct Program {
+    /// Name of every function, by its index in the chunk.
+    pub functions: Vec<String>,
+    /// What the vm needs to know about every class and enum variant.
+    pub classes: Vec<ClassInfo>,
+    /// The inputs the program uses, with the types the vm checks their values against.
+    pub(crate) inputs: Vec<(String, HostKind)>,
+}
+
+/// Checks `modules`, where the first one is the compiled source and the rest are the
+/// modules it imports, with their imports resolved. A statement that fails to check
+/// is skipped, so every independent error is reported. Calls of `natives` are
+/// checked against their signatures, unless a function of the module has the same
+/// name. `inputs` are typed by their values, or by their `input_types` if they have
+/// one. The top level code of the compiled source can not declare their names again,
+/// but the locals of functions shadow them.
+pub fn check<'a>(
+    modules: &'a mut [ast::Module],
+    natives: &'a [NativeSignature],
+    inputs: &'a [(String, HostValue)],
+    input_types: &'a [(String, Type)],
+) -> std::result::Result<Program, Vec<CompilerError>> {
+    let mut checker = Checker {
+        scopes: modules
+            .iter()
+            .map(|m| ModuleScope {
+                name: m.name.to_string(),
+                functions: HashMap::new(),
+                imports: HashMap::new(),
+                frame: 0,
+                globals: 0,
+                checked: false,
+                unknown: m.file.unknown.iter().cloned().collect(),
+            })
+            .collect(),
+        modules,
+        natives,
+        inputs,
+        input_types,
+        used_inputs: vec![],
+        script_return: None,
+        functions: vec!["<script>".to_string()],
+        class_infos: vec![],
+        classes: vec![],
+        enums: vec![],
+        module: 0,
+        frames: vec![],
+        next_local: 0,
+        errors: vec![],
+    };
+    checker.check_module(0);
+    if !checker.errors.is_empty() {
+        return Err(checker.errors);
+    }
+    for class in &checker.classes {
+        let field_kinds = class
+            .fields
+            .iter()
+            .map(|f| checker.host_kind(&f.kind))
+            .collect();
+        checker.class_infos[class.info as usize].field_kinds = field_kinds;
+    }
+    Ok(Program {
+        functions: checker.functions,
+        classes: checker.class_infos,
+        inputs: checker.used_inputs,
+    })
+}
+
+struct Checker<'a> {
+    modules: &'a mut [ast::Module],
+    /// The signatures of the functions registered by the host.
+    natives: &'a [NativeSignature],
+    /// The values passed in by the host.
+    inputs: &'a [(String, HostValue)],
+    /// The types the host declared for some of the inputs.
+    input_types: &'a [(String, Type)],
+    /// The inputs used so far, by the index the code generator uses.
+    used_inputs: Vec<(String, HostKind)>,
+    /// The kind of the values returned by the top level code of the compiled source.
+    script_return: Option<ExpressionKind>,
+    /// The names declared by every module.
+    scopes: Vec<ModuleScope>,
+    functions: Vec<String>,
+    class_infos: Vec<ClassInfo>,
+    classes: Vec<Class>,
+    enums: Vec<Enum>,
+    /// Index of the module being checked.
+    module: usize,
+    /// The function being checked is last, and the top level code of the modules
+    /// being checked is below it.
+    frames: Vec<Frame>,
+    next_local: usize,
+    /// The errors of the statements skipped so far.
+    errors: Vec<CompilerError>,
+}
+
+impl Checker<'_> {
+    fn check_module(&mut self, module: usize) {
+        let enclosing_module = std::mem::replace(&mut self.module, module);
+        if module != 0 {
+            let name = format!("<module {}>", self.modules[module].name);
+            match self.add_function(&name) {
+                Ok(init) => self.modules[module].init = init,
+                Err(error) => self.report(error),
+            }
+        }
+        let mut file = std::mem::take(&mut self.modules[module].file);
+        self.scopes[module].frame = self.frames.len();
+        self.frames.push(Frame::new(Some(module), None));
+
+        let declared = self.declare_top_level(&mut file);
+        for (stmt, declared) in file.stmts.iter_mut().zip(declared) {
+            // the error of a declaration is reported once
+            if declared {
+                self.recovering_stmt(stmt);
+            }
+        }
+
+        self.frames.pop();
+        self.module = enclosing_module;
+        self.modules[module].file = file;
+        self.scopes[module].checked = true;
+    }
+
+    /// Records an error of the module being checked. Errors in an imported module say
+    /// which file it is. Uses of a name whose declaration failed are not reported
+    /// again.
+    fn report(&mut self, error: CompilerError) {
+        if let CompilerError::MissingLocal { name, .. }
+        | CompilerError::UnknownFunction { name, .. }
+        | CompilerError::UnknownClass { name, .. } = &error
+        {
+            if self.scopes[self.module].unknown.contains(name) {
+                return;
+            }
+        }
+        let error = match (self.module, error) {
+            (_, error @ CompilerError::InFile { .. }) | (0, error) => error,
+            (module, error) => CompilerError::InFile {
+                file: self.modules[module]
+                    .path
+                    .as_ref()
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_default(),
+                error: Box::new(error),
+            },
+        };
+        self.errors.push(error);
+    }
+
+    /// Declares everything at the top level of the file before any of it is checked,
+    /// so functions, classes and enums can be used before their declaration. The
+    /// imported modules are checked first. Returns whether each statement was
+    /// declared, which it is unless its declaration failed.
+    fn declare_top_level(&mut self, file: &mut File) -> Vec<bool> {
+        let mut declared = vec![true; file.stmts.len()];
+        for (i, stmt) in file.stmts.iter().enumerate() {
+            if let StmtNode::Import(import) = &stmt.node {
+                // a name declared before the import is reported at the import
+                let previous = file.stmts[..i]
+                    .iter()
+                    .filter_map(top_level_name)
+                    .find(|&(name, _)| name == import.name);
+                let result = match previous {
+                    Some((_, previous)) => Err(CompilerError::Redeclaration {
+                        name: import.name.to_string(),
+                        span: import.span,
+                        previous: Some(previous),
+                    }),
+                    None => self.import(import),
+                };
+                if let Err(error) = result {
+                    self.report(error);
+                    declared[i] = false;
+                }
+            }
+        }
+        // types first, since the signatures and members use them
+        for (i, stmt) in file.stmts.iter().enumerate() {
+            let result = match &stmt.node {
+                StmtNode::Class(class) => self.declare_class(class).map(|_| ()),
+                StmtNode::Enum(declaration) => self.declare_enum(declaration).map(|_| ()),
+                _ => Ok(()),
+            };
+            if let Err(error) = result {
+                self.report(error);
+                declared[i] = false;
+            }
+        }
+        for (i, stmt) in file.stmts.iter_mut().enumerate() {
+            if !declared[i] {
+                continue;
+            }
+            let result = match &mut stmt.node {
+                StmtNode::Class(class) => {
+                    let class_idx = self.find_class(self.module, &class.name).unwrap();
+                    self.declare_class_members(class_idx, class)
+                }
+                StmtNode::Enum(declaration) => {
+                    let enum_idx = self.find_enum(self.module, &declaration.name).unwrap();
+                    self.declare_variants(enum_idx, declaration)
+                }
+                StmtNode::Function(function) => {
+                    let result = self.declare_function(function);
+                    let scope = &mut self.scopes[self.module];
+                    if result.is_err() && !scope.functions.contains_key(&function.name) {
+                        scope.unknown.insert(function.name.to_string());
+                    }
+                    result
+                }
+                _ => Ok(()),
+            };
+            if let Err(error) = result {
+                self.report(error);
+                declared[i] = false;
+            }
+        }
+        declared
+    }
+
+    /// Makes the public functions, classes and enums of an imported module usable
+    /// with its prefix, like `util.`.
+    fn import(&mut self, import: &Import) -> Result<()> {
+        if let Some(&(_, previous)) = self.scopes[self.module].imports.get(&import.name) {
+            return Err(CompilerError::Redeclaration {
+                name: import.name.to_string(),
+                span: import.span,
+                previous: Some(previous),
+            });
+        }
+        // an import that could not be loaded is reported by the loader
+        let Some(module) = import.module else {
+            self.scopes[self.module]
+                .unknown
+                .insert(import.name.to_string());
+            return Ok(());
+        };
+        if !self.scopes[module].checked {
+            self.check_module(module);
+        }
+        self.scopes[self.module]
+            .imports
+            .insert(import.name.to_string(), (module, import.span));
+        Ok(())
+    }
+
+    //
+    // DECLARATIONS
+    //
+
+    /// Reserves the code of a new function and returns its index.
+    fn add_function(&mut self, name: &str) -> Result<u16> {
+        if self.functions.len() > MAX_OPERAND {
+            return Err(CompilerError::MaxFunctions);
+        }
+        self.functions.push(name.to_string());
+        Ok((self.functions.len() - 1) as u16)
+    }
+
+    /// Adds a function by its signature, so it can be called before its body is
+    /// checked.
+    fn declare_function(&mut self, function: &mut ast::Function) -> Result<()> {
+        if let Some(previous) = self.scopes[self.module].functions.get(&function.name) {
+            return Err(CompilerError::Redeclaration {
+                name: function.name.to_string(),
+                span: function.span,
+                previous: Some(previous.span),
+            });
+        }
+        if self.module == 0 {
+            self.check_input_redeclaration(&function.name, function.span)?;
+        }
+        self.check_import_redeclaration(&function.name, function.span)?;
+        function.index = self.add_function(&function.name)?;
+        let signature = self.signature(function)?;
+        self.scopes[self.module]
+            .functions
+            .insert(function.name.to_string(), signature);
+        Ok(())
+    }
+
+    /// Resolves the parameter and return types of a function.
+    fn signature(&mut self, function: &ast::Function) -> Result<Signature> {
+        let mut params = vec![];
+        for param in &function.params {
+            params.push((param.name.to_string(), self.resolve_type(&param.ty)?));
+        }
+        let return_type = match &function.return_type {
+            Some(ty) => Some(self.resolve_type(ty)?),
+            None => None,
+        };
+        Ok(Signature {
+            index: function.index,
+            params,
+            return_type,
+            is_pub: function.is_pub,
+            span: function.span,
+        })
+    }
+
+    /// Adds a class without any members, so it can be used as a type.
+    fn declare_class(&mut self, class: &ast::Class) -> Result<usize> {
+        if self.classes.len() > MAX_OPERAND {
+            return Err(CompilerError::TooManyClasses(class.span));
+        }
+        self.check_type_redeclaration(&class.name, class.span)?;
+        self.check_import_redeclaration(&class.name, class.span)?;
+        let info = self.add_class_info(&class.name, false, class.span)?;
+        self.classes.push(Class {
+            name: class.name.to_string(),
+            fields: vec![],
+            methods: vec![],
+            info,
+            module: self.module,
+            is_pub: class.is_pub,
+            span: class.span,
+        });
+        Ok(self.classes.len() - 1)
+    }
+
+    /// Collects the fields and method signatures of a class, so the method bodies
+    /// can use all of them no matter the order of declaration.
+    fn declare_class_members(&mut self, class_idx: usize, class: &mut ast::Class) -> Result<()> {
+        for member in &mut class.members {
+            match member {
+                Member::Method(method) => {
+                    let function = &mut method.function;
+                    self.check_member_redeclaration(class_idx, &function.name, function.span)?;
+                    function.index =
+                        self.add_function(&format!("{}.{}", class.name, function.name))?;
+                    let signature = self.signature(function)?;
+                    // init sets up the fields, so it is always a mut method
+                    let is_init = function.name == "init";
+                    if let (true, Some(kind)) = (is_init, &signature.return_type) {
+                        return Err(CompilerError::ReturnValueFromVoid {
+                            kind: kind.clone(),
+                            span: function.span,
+                        });
+                    }
+                    // to_string is called by the vm whenever an instance is printed
+                    if function.name == "to_string"
+                        && (!signature.params.is_empty()
+                            || signature.return_type != Some(ExpressionKind::String))
+                    {
+                        return Err(CompilerError::InvalidToString(function.span));
+                    }
+                    self.classes[class_idx].methods.push(ClassMethod {
+                        name: function.name.to_string(),
+                        signature,
+                        is_mut: method.is_mut || is_init,
+                    });
+                }
+                Member::Field(field) => {
+                    if self.classes[class_idx].fields.len() >= MAX_OPERAND {
+                        return Err(CompilerError::TooManyFields(field.span));
+                    }
+                    let kind = self.resolve_type(&field.ty)?;
+                    self.check_member_redeclaration(class_idx, &field.name, field.span)?;
+                    let default = match field.default {
+                        Some(_) => {
+                            field.default_index =
+                                self.add_function(&format!("{}.{}", class.name, field.name))?;
+                            Some(field.default_index)
+                        }
+                        None => None,
+                    };
+                    self.classes[class_idx].fields.push(ClassField {
+                        name: field.name.to_string(),
+                        kind,
+                        default,
+                        span: field.span,
+                    });
+                }
+            }
+        }
+
+        let class = &self.classes[class_idx];
+        let class_info = &mut self.class_infos[class.info as usize];
+        class_info.fields = class.fields.iter().map(|f| f.name.to_string()).collect();
+        class_info.to_string = class
+            .methods
+            .iter()
+            .find(|m| m.name == "to_string")
+            .map(|m| m.signature.index as usize);
+        Ok(())
+    }
+
+    /// Checks the default values and method bodies of a declared class.
+    fn define_class(&mut self, class_idx: usize, class: &mut ast::Class) -> Result<()> {
+        let (mut field, mut method) = (0, 0);
+        for member in &mut class.members {
+            match member {
+                Member::Method(declaration) => {
+                    let signature = self.classes[class_idx].methods[method].signature.clone();
+                    let is_mut = self.classes[class_idx].methods[method].is_mut;
+                    method += 1;
+                    let receiver = (self.class_kind(class_idx), is_mut);
+                    self.function_body(
+                        &mut declaration.function,
+                        &signature,
+                        Some(receiver),
+                        false,
+                    )?;
+                }
+                Member::Field(declaration) => {
+                    let kind = self.classes[class_idx].fields[field].kind.clone();
+                    field += 1;
+                    if let Some(default) = &mut declaration.default {
+                        self.field_default(default, &kind)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the default value of a field. It runs at every construction and can
+    /// not see the locals around the class.
+    fn field_default(&mut self, default: &mut Expr, kind: &ExpressionKind) -> Result<()> {
+        self.frames.push(Frame::new(None, None));
+        let result = self.expr(default, Some(kind));
+        self.frames.pop();
+        let default_kind = result?;
+        if !kind.accepts(&default_kind) {
+            return Err(CompilerError::DeclarationType {
+                expected: kind.clone(),
+                actual: default_kind,
+                span: default.span,
+            });
+        }
+        Ok(())
+    }
+
+    /// Adds what the vm needs to know about a class or an enum variant. The fields
+    /// are filled in once they are known.
+    fn add_class_info(&mut self, name: &str, variant: bool, span: Span) -> Result<u16> {
+        if self.class_infos.len() > MAX_OPERAND {
+            return Err(CompilerError::TooManyClasses(span));
+        }
+        self.class_infos.push(ClassInfo {
+            name: name.to_string(),
+            fields: vec![],
+            to_string: None,
+            variant,
+            field_kinds: vec![],
+        });
+        Ok((self.class_infos.len() - 1) as u16)
+    }
+
+    /// The inputs are bindings of the compiled source, which its top level code can not
+    /// declare again.
+    fn check_input_redeclaration(&self, name: &str, span: Span) -> Result<()> {
+        match self.inputs.iter().any(|(input, _)| input == name) {
+            true => Err(CompilerError::Redeclaration {
+                name: name.to_string(),
+                span,
+                previous: None,
+            }),
+            false => Ok(()),
+        }
+    }
+
+    /// The names of imports are declared at the top level of the module, before
+    /// anything else. Locals in blocks and functions shadow them.
+    fn check_import_redeclaration(&self, name: &str, span: Span) -> Result<()> {
+        match self.scopes[self.module].imports.get(name) {
+            Some(&(_, previous)) => Err(CompilerError::Redeclaration {
+                name: name.to_string(),
+                span,
+                previous: Some(previous),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Classes and enums share the names of types.
+    fn check_type_redeclaration(&self, name: &str, span: Span) -> Result<()> {
+        let previous = match self.find_class(self.module, name) {
+            Some(idx) => Some(self.classes[idx].span),
+            None => self
+                .find_enum(self.module, name)
+                .map(|idx| self.enums[idx].span),
+        };
+        match previous {
+            Some(previous) => Err(CompilerError::Redeclaration {
+                name: name.to_string(),
+                span,
+                previous: Some(previous),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn check_member_redeclaration(&self, class_idx: usize, name: &str, span: Span) -> Result<()> {
+        let class = &self.classes[class_idx];
+        let previous = class
+            .fields
+            .iter()
+            .find(|f| f.name == name)
+            .map(|f| f.span)
+            .or_else(|| {
+                class
+                    .methods
+                    .iter()
+                    .find(|m| m.name == name)
+                    .map(|m| m.signature.span)
+            });
+        match previous {
+            Some(previous) => Err(CompilerError::Redeclaration {
+                name: name.to_string(),
+                span,
+                previous: Some(previous),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds an enum without any variants, so it can be used as a type.
+    fn declare_enum(&mut self, declaration: &ast::Enum) -> Result<usize> {
+        if self.enums.len() > MAX_OPERAND {
+            return Err(CompilerError::TooManyClasses(declaration.span));
+        }
+        self.check_type_redeclaration(&declaration.name, declaration.span)?;
+        self.check_import_redeclaration(&declaration.name, declaration.span)?;
+        self.enums.push(Enum {
+            name: declaration.name.to_string(),
+            variants: vec![],
+            module: self.module,
+            is_pub: declaration.is_pub,
+            span: declaration.span,
+        });
+        Ok(self.enums.len() - 1)
+    }
+
+    /// Collects the variants of a declared enum. Every variant is a kind of instance
+    /// of its own, with the payload as its fields.
+    fn declare_variants(&mut self, enum_idx: usize, declaration: &ast::Enum) -> Result<()> {
+        for variant in &declaration.variants {
+            if let Some(previous) = self.enums[enum_idx]
+                .variants
+                .iter()
+                .find(|v| v.name == variant.name)
+            {
+                return Err(CompilerError::Redeclaration {
+                    name: variant.name.to_string(),
+                    span: variant.span,
+                    previous: Some(previous.span),
+                });
+            }
+            if variant.payload.len() > MAX_OPERAND {
+                return Err(CompilerError::TooManyFields(variant.span));
+            }
+            let mut payload = vec![];
+            for ty in &variant.payload {
+                payload.push(self.resolve_type(ty)?);
+            }
+            let name = format!("{}.{}", declaration.name, variant.name);
+            let info = self.add_class_info(&name, true, variant.span)?;
+            self.class_infos[info as usize].fields = vec![String::new(); payload.len()];
+            self.enums[enum_idx].variants.push(EnumVariant {
+                name: variant.name.to_string(),
+                payload,
+                info,
+                span: variant.span,
+            });
+        }
+        Ok(())
+    }
+
+    /// Resolves a type annotation like `int`, `Foo`, `[str]`, `{str: int}`, `int?` or
+    /// `fun(int) -> str`.
+    fn resolve_type(&mut self, ty: &Type) -> Result<ExpressionKind> {
+        let kind = match &ty.node {
+            TypeNode::Int => ExpressionKind::Int,
+            TypeNode::Float => ExpressionKind::Float,
+            TypeNode::Bool => ExpressionKind::Bool,
+            TypeNode::Str => ExpressionKind::String,
+            TypeNode::Named { module, name } => {
+                let module = match module {
+                    Some(namespace) => match self.scopes[self.module].imports.get(namespace) {
+                        Some(&(module, _)) => module,
+                        None => {
+                            return Err(CompilerError::NotAType {
+                                kind: TokenKind::Identifier,
+                                span: ty.span,
+                            })
+                        }
+                    },
+                    None => self.module,
+                };
+                match (self.find_class(module, name), self.find_enum(module, name)) {
+                    (Some(idx), _) if self.classes[idx].is_pub || module == self.module => {
+                        self.class_kind(idx)
+                    }
+                    (_, Some(idx)) if self.enums[idx].is_pub || module == self.module => {
+                        self.enum_kind(idx)
+                    }
+                    _ if module != self.module => {
+                        return Err(CompilerError::NotExported {
+                            module: self.scopes[module].name.to_string(),
+                            name: name.to_string(),
+                            span: ty.span,
+                        })
+                    }
+                    _ => {
+                        return Err(CompilerError::NotAType {
+                            kind: TokenKind::Identifier,
+                            span: ty.span,
+                        })
+                    }
+                }
+            }
+            TypeNode::Array(element) => {
+                ExpressionKind::Array(Box::new(self.resolve_type(element)?))
+            }
+            TypeNode::Map(key, value) => {
+                let key_kind = self.resolve_type(key)?;
+                self.check_key_kind(&key_kind, key.span)?;
+                ExpressionKind::Map(Box::new(key_kind), Box::new(self.resolve_type(value)?))
+            }
+            TypeNode::Optional(inner) => {
+                ExpressionKind::Optional(Box::new(self.resolve_type(inner)?))
+            }
+            TypeNode::Function(params, return_type) => {
+                let mut param_kinds = vec![];
+                for param in params {
+                    param_kinds.push(self.resolve_type(param)?);
+                }
+                let return_kind = match return_type {
+                    Some(ty) => self.resolve_type(ty)?,
+                    None => ExpressionKind::None,
+                };
+                ExpressionKind::Function(param_kinds, Box::new(return_kind))
+            }
+        };
+        Ok(kind)
+    }
+
+    /// Only values that can be compared by content can be used as map keys.
+    fn check_key_kind(&self, kind: &ExpressionKind, span: Span) -> Result<()> {
+        match kind {
+            ExpressionKind::Int | ExpressionKind::String | ExpressionKind::Bool => Ok(()),
+            kind => Err(CompilerError::InvalidKeyType {
+                kind: kind.clone(),
+                span,
+            }),
+        }
+    }
+
+    fn find_class(&self, module: usize, name: &str) -> Option<usize> {
+        self.classes
+            .iter()
+            .position(|c| c.module == module && c.name == name)
+    }
+
+    fn class_kind(&self, idx: usize) -> ExpressionKind {
+        ExpressionKind::Class(TypeRef::new(idx, &self.classes[idx].name))
+    }
+
+    fn enum_kind(&self, idx: usize) -> ExpressionKind {
+        ExpressionKind::Enum(TypeRef::new(idx, &self.enums[idx].name))
+    }
+
+    fn find_enum(&self, module: usize, name: &str) -> Option<usize> {
+        self.enums
+            .iter()
+            .position(|e| e.module == module && e.name == name)
+    }
+
+    //
+    // STATEMENTS
+    //
+
+    /// Checks the statements of a block and returns whether the last one is a
+    /// `return`, `break` or `continue`.
+    fn stmts(&mut self, stmts: &mut [Stmt]) -> bool {
+        let mut exits = false;
+        for stmt in stmts {
+            self.recovering_stmt(stmt);
+            exits = matches!(
+                stmt.node,
+                StmtNode::Return(_) | StmtNode::Break(_) | StmtNode::Continue(_)
+            );
+        }
+        exits
+    }
+
+    /// Checks a block in a scope of its own.
+    fn block(&mut self, block: &mut Block) {
+        self.start_scope();
+        self.stmts(&mut block.stmts);
+        self.end_scope();
+    }
+
+    /// Checks a statement, or records its error. The scopes and loops the statement
+    /// left open are closed, so the statements after it are checked as usual.
+    fn recovering_stmt(&mut self, stmt: &mut Stmt) {
+        let frame_count = self.frames.len();
+        let frame = self.frame();
+        let (scope_count, loop_count) = (frame.scopes.len(), frame.loops.len());
+        let Err(error) = self.stmt(stmt) else {
+            return;
+        };
+        self.frames.truncate(frame_count);
+        while self.frame().scopes.len() > scope_count {
+            self.end_scope();
+        }
+        self.frame().loops.truncate(loop_count);
+        // reported before the name is unknown, since the error may be about the name
+        // itself, like in `let a = a;`
+        self.report(error);
+        if let StmtNode::Let(declaration) = &stmt.node {
+            if self.frame().find_local(&declaration.name).is_none() {
+                self.scopes[self.module]
+                    .unknown
+                    .insert(declaration.name.to_string());
+            }
+        }
+    }
+
+    fn stmt(&mut self, stmt: &mut Stmt) -> Result<()> {
+        let span = stmt.span;
+        let top_level = self.at_top_level();
+        let is_pub = match &stmt.node {
+            StmtNode::Function(function) => function.is_pub,
+            StmtNode::Class(class) => class.is_pub,
+            StmtNode::Enum(declaration) => declaration.is_pub,
+            _ => false,
+        };
+        if is_pub && !top_level {
+            return Err(CompilerError::NotTopLevel(span));
+        }
+        match &mut stmt.node {
+            StmtNode::Let(declaration) => self.let_stmt(declaration, span, top_level),
+            StmtNode::Assign { target, value } => self.assign(target, value, span),
+            StmtNode::Expr(expr) => self.expr(expr, None).map(|_| ()),
+            StmtNode::Print(expr) => {
+                if self.value(expr, None)?.may_contain_instances() {
+                    self.widen_mut_globals();
+                }
+                Ok(())
+            }
+            StmtNode::Return(value) => self.return_stmt(value.as_mut(), span),
+            StmtNode::If(if_stmt) => self.if_stmt(if_stmt),
+            StmtNode::While(while_stmt) => self.while_stmt(while_stmt),
+            StmtNode::ForRange(for_stmt) => {
+                self.widen_mut_globals();
+                // The iterator lives in its own scope around the loop
+                self.start_scope();
+                self.add_local(
+                    &for_stmt.name,
+                    ExpressionKind::Int,
+                    true,
+                    Some(Binding::ForVariable),
+                    for_stmt.name_span,
+                );
+                self.frame().loops.push(for_stmt.label.clone());
+                self.block(&mut for_stmt.body);
+                self.frame().loops.pop();
+                self.end_scope();
+                Ok(())
+            }
+            StmtNode::ForIn(for_stmt) => self.for_in_stmt(for_stmt),
+            StmtNode::Break(label) | StmtNode::Continue(label) => {
+                self.target_loop(label.as_deref(), span).map(|_| ())
+            }
+            StmtNode::Function(function) => {
+                let signature = match top_level {
+                    // declared before the file was checked
+                    true => self.scopes[self.module].functions[&function.name].clone(),
+                    false => {
+                        self.declare_function(function)?;
+                        self.scopes[self.module].functions[&function.name].clone()
+                    }
+                };
+                self.function_body(function, &signature, None, false)
+            }
+            StmtNode::Class(class) => {
+                let class_idx = match top_level {
+                    true => self.find_class(self.module, &class.name).unwrap(),
+                    false => {
+                        let class_idx = self.declare_class(class)?;
+                        self.declare_class_members(class_idx, class)?;
+                        class_idx
+                    }
+                };
+                self.define_class(class_idx, class)
+            }
+            StmtNode::Enum(declaration) => {
+                if !top_level {
+                    let enum_idx = self.declare_enum(declaration)?;
+                    self.declare_variants(enum_idx, declaration)?;
+                }
+                Ok(())
+            }
+            // imports at the top level are checked before everything else
+            StmtNode::Import(_) if top_level => Ok(()),
+            StmtNode::Import(_) => Err(CompilerError::NotTopLevel(span)),
+        }
+    }
+
+    fn let_stmt(&mut self, declaration: &mut Let, span: Span, top_level: bool) -> Result<()> {
+        if let Some(previous) = self.frame().find_local(&declaration.name) {
+            return Err(CompilerError::Redeclaration {
+                name: declaration.name.to_string(),
+                span,
+                previous: Some(previous.span),
+            });
+        }
+        if self.frame().module == Some(0) {
+            self.check_input_redeclaration(&declaration.name, span)?;
+        }
+        if top_level {
+            self.check_import_redeclaration(&declaration.name, span)?;
+        }
+        let type_kind = match &declaration.ty {
+            Some(ty) => Some(self.resolve_type(ty)?),
+            None => None,
+        };
+        let kind = self.value(&mut declaration.value, type_kind.as_ref())?;
+        let kind = match type_kind {
+            None if kind == ExpressionKind::Nil => return Err(CompilerError::UntypedNil(span)),
+            None => kind,
+            Some(type_kind) if !type_kind.accepts(&kind) => {
+                return Err(CompilerError::DeclarationType {
+                    expected: type_kind,
+                    actual: kind,
+                    span,
+                })
+            }
+            Some(type_kind) => type_kind,
+        };
+        let local = self.add_local(
+            &declaration.name,
+            kind,
+            declaration.is_mut,
+            Some(Binding::Let),
+            span,
+        );
+        if top_level {
+            let index = self.scopes[self.module].globals;
+            self.scopes[self.module].globals += 1;
+            self.frame().local_mut(local).unwrap().global = Some(index);
+        }
+        Ok(())
+    }
+
+    /// Checks an assignment to a variable, a field or an element.
+    fn assign(&mut self, target: &mut Expr, value: &mut Expr, span: Span) -> Result<()> {
+        let kind = match &mut target.node {
+            ExprNode::Variable { name, target } => {
+                let Some(variable) = self.find_variable(name) else {
+                    // inputs are passed in by value and can not be changed
+                    if self.inputs.iter().any(|(input, _)| input == name) {
+                        return Err(CompilerError::CantMut {
+                            name: name.to_string(),
+                            span,
+                            declaration: None,
+                        });
+                    }
+                    return Err(CompilerError::MissingLocal {
+                        name: name.to_string(),
+                        span,
+                    });
+                };
+                if variable.captured {
+                    return Err(CompilerError::AssignCapture {
+                        name: name.to_string(),
+                        span,
+                    });
+                }
+                if !variable.is_mut {
+                    return Err(CompilerError::CantMut {
+                        name: name.to_string(),
+                        span,
+                        declaration: variable
+                            .binding
+                            .map(|binding| (binding, variable.declaration)),
+                    });
+                }
+                *target = variable.target;
+                let kind = self.value(value, Some(&variable.kind))?;
+                if !variable.kind.accepts(&kind) {
+                    // a narrowed local becomes optional again, unless it has to stay
+                    // narrowed for the next iteration of a loop
+                    let frame = self.frame();
+                    let loop_depth = frame.loops.len();
+                    let narrowing = frame
+                        .narrowings
+                        .iter()
+                        .rev()
+                        .find(|n| Some(n.local) == variable.local);
+                    match narrowing {
+                        Some(n) if n.declared.accepts(&kind) && n.loop_depth == loop_depth => {
+                            let (local, declared) = (n.local, n.declared.clone());
+                            self.frame().local_mut(local).unwrap().kind = declared;
+                        }
+                        _ => {
+                            return Err(CompilerError::ReassignmentType {
+                                expected: variable.kind,
+                                actual: kind,
+                                span,
+                            })
+                        }
+                    }
+                }
+                variable.kind
+            }
+            ExprNode::Field {
+                object,
+                name,
+                target,
+            } => {
+                let object_kind = self.expr(object, None)?;
+                self.check_not_optional(&object_kind, span)?;
+                let (index, kind) = self.field(&object_kind, name, span)?;
+                if !self.is_mut_chain(object) {
+                    return Err(self.cant_mut(object, span));
+                }
+                *target = FieldTarget::Field(index);
+                self.check_value(value, &kind, span)?;
+                kind
+            }
+            ExprNode::Index { object, index } => {
+                let kind = self.index(object, index, span)?;
+                if !self.is_mut_chain(object) {
+                    return Err(self.cant_mut(object, span));
+                }
+                self.check_value(value, &kind, span)?;
+                kind
+            }
+            _ => unreachable!("only variables, fields and elements are parsed as targets"),
+        };
+        target.kind = kind;
+        Ok(())
+    }
+
+    /// Checks that `value` is of the `expected` kind.
+    fn check_value(
+        &mut self,
+        value: &mut Expr,
+        expected: &ExpressionKind,
+        span: Span,
+    ) -> Result<()> {
+        let kind = self.value(value, Some(expected))?;
+        if !expected.accepts(&kind) {
+            return Err(CompilerError::Type {
+                expected: expected.clone(),
+                actual: kind,
+                span,
+            });
+        }
+        Ok(())
+    }
+
+    fn return_stmt(&mut self, value: Option<&mut Expr>, span: Span) -> Result<()> {
+        if self.frame().module == Some(0) {
+            return self.script_return(value, span);
+        }
+        let return_kind = self.frame().return_kind.clone();
+        let kind = match value {
+            Some(value) => self.expr(value, return_kind.as_ref())?,
+            None => ExpressionKind::None,
+        };
+        match return_kind {
+            Some(expected) if !expected.accepts(&kind) => Err(CompilerError::Type {
+                actual: kind,
+                expected,
+                span,
+            }),
+            None if kind != ExpressionKind::None => {
+                Err(CompilerError::ReturnValueFromVoid { kind, span })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The top level code of the compiled source can return a value to the host. The
+    /// first return with a type decides the type of the others.
+    fn script_return(&mut self, value: Option<&mut Expr>, span: Span) -> Result<()> {
+        let Some(value) = value else {
+            return Ok(());
+        };
+        let expected = self.script_return.clone();
+        let kind = self.expr(value, expected.as_ref())?;
+        match expected {
+            Some(expected) if !expected.accepts(&kind) => Err(CompilerError::Type {
+                actual: kind,
+                expected,
+                span,
+            }),
+            None if kind == ExpressionKind::None => Err(CompilerError::NoneValue { span }),
+            None if kind != ExpressionKind::Nil => {
+                self.script_return = Some(kind);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks of optional locals against `nil` narrow them to their inner kind in the
+    /// branch where they are not nil, and after an `== nil` branch that always exits.
+    fn if_stmt(&mut self, if_stmt: &mut If) -> Result<()> {
+        // the branches are checked even if the condition is not
+        if let Err(error) = self.condition(&mut if_stmt.condition) {
+            self.report(error);
+        }
+        let not_nil = self.nil_checks(&if_stmt.condition, true);
+        let nil_otherwise = self.nil_checks(&if_stmt.condition, false);
+        self.start_scope();
+        for &local in &not_nil {
+            self.narrow(local);
+        }
+        let then_exits = self.stmts(&mut if_stmt.then_branch.stmts);
+        self.end_scope();
+        match &mut if_stmt.else_branch {
+            None => {}
+            Some(Else::If(else_if)) => self.if_stmt(else_if)?,
+            Some(Else::Block(block)) => {
+                self.start_scope();
+                for &local in &nil_otherwise {
+                    self.narrow(local);
+                }
+                self.stmts(&mut block.stmts);
+                self.end_scope();
+            }
+        }
+        if then_exits {
+            for local in nil_otherwise {
+                self.narrow(local);
+            }
+        }
+        Ok(())
+    }
+
+    fn condition(&mut self, condition: &mut Expr) -> Result<()> {
+        let kind = self.value(condition, None)?;
+        self.check_expression_kind(&kind, ExpressionKind::Bool, condition.span)
+    }
+
+    fn while_stmt(&mut self, while_stmt: &mut While) -> Result<()> {
+        self.widen_mut_globals();
+        if let Err(error) = self.condition(&mut while_stmt.condition) {
+            self.report(error);
+        }
+        let not_nil = self.nil_checks(&while_stmt.condition, true);
+        self.frame().loops.push(while_stmt.label.clone());
+        self.start_scope();
+        for local in not_nil {
+            self.narrow(local);
+        }
+        self.stmts(&mut while_stmt.body.stmts);
+        self.end_scope();
+        self.frame().loops.pop();
+        Ok(())
+    }
+
+    /// Checks a `for` loop over the elements of an array, or over the keys of a map.
+    fn for_in_stmt(&mut self, for_stmt: &mut ForIn) -> Result<()> {
+        self.widen_mut_globals();
+        self.start_scope();
+        let mut kind = self.expr(&mut for_stmt.iterable, None)?;
+        if let ExpressionKind::Map(key_kind, _) = kind {
+            kind = ExpressionKind::Array(key_kind);
+        }
+        let ExpressionKind::Array(element_kind) = kind else {
+            return Err(CompilerError::NotIterable {
+                kind,
+                span: for_stmt.iterable.span,
+            });
+        };
+        self.frame().loops.push(for_stmt.label.clone());
+        // The element is a local of each iteration
+        self.start_scope();
+        self.add_local(
+            &for_stmt.name,
+            *element_kind,
+            false,
+            Some(Binding::ForVariable),
+            for_stmt.name_span,
+        );
+        self.block(&mut for_stmt.body);
+        self.end_scope();
+        self.frame().loops.pop();
+        self.end_scope();
+        Ok(())
+    }
+
+    /// Finds the loop targeted by a `break` or `continue`, either the innermost one
+    /// or the one with the label.
+    fn target_loop(&mut self, label: Option<&str>, span: Span) -> Result<usize> {
+        let loops = &self.frame().loops;
+        match label {
+            Some(label) => loops
+                .iter()
+                .rposition(|l| l.as_deref() == Some(label))
+                .ok_or(CompilerError::UnknownLabel {
+                    label: label.to_string(),
+                    span,
+                }),
+            None => match loops.len() {
+                0 => Err(CompilerError::OutsideLoop(span)),
+                len => Ok(len - 1),
+            },
+        }
+    }
+
+    /// Checks the body of a function. Methods get their `receiver` as the local
+    /// `self` before the parameters. Anonymous functions copy the enclosing locals
+    /// they use, which are stored as the captures of the function.
+    fn function_body(
+        &mut self,
+        function: &mut ast::Function,
+        signature: &Signature,
+        receiver: Option<(ExpressionKind, bool)>,
+        anonymous: bool,
+    ) -> Result<()> {
+        let mut frame = Frame::new(None, anonymous.then(Vec::new));
+        frame.return_kind = signature.return_type.clone();
+        self.frames.push(frame);
+        if let Some((kind, is_mut)) = receiver {
+            self.add_local(
+                "self",
+                kind,
+                is_mut,
+                Some(Binding::SelfParam),
+                function.span,
+            );
+        }
+        for ((name, kind), param) in signature.params.iter().zip(&function.params) {
+            self.add_local(name, kind.clone(), true, None, param.span);
+        }
+        self.stmts(&mut function.body.stmts);
+        let frame = self.frames.pop().unwrap();
+        if let Some(kind) = &signature.return_type {
+            if !returns(&function.body.stmts) {
+                self.report(CompilerError::MissingReturn {
+                    kind: kind.clone(),
+                    span: function.span,
+                });
+            }
+        }
+        function.captures = frame
+            .captures
+            .unwrap_or_default()
+            .into_iter()
+            .map(|capture| capture.name)
+            .collect();
+        Ok(())
+    }
+
+    //
+    // EXPRESSIONS
+    //
+
+    /// Checks an expression whose value is used, which a call of a function without
+    /// a return value does not have.
+    fn value(&mut self, expr: &mut Expr, hint: Option<&ExpressionKind>) -> Result<ExpressionKind> {
+        match self.expr(expr, hint)? {
+            ExpressionKind::None => Err(CompilerError::NoneValue { span: expr.span }),
+            kind => Ok(kind),
+        }
+    }
+
+    /// Checks an expression where a value of kind `hint` is expected, if it is known.
+    /// This is how an empty array literal gets its element type.
+    fn expr(&mut self, expr: &mut Expr, hint: Option<&ExpressionKind>) -> Result<ExpressionKind> {
+        let span = expr.span;
+        let kind = match &mut expr.node {
+            ExprNode::Nil => ExpressionKind::Nil,
+            ExprNode::Bool(_) => ExpressionKind::Bool,
+            ExprNode::Int(_) => ExpressionKind::Int,
+            ExprNode::Float(_) => ExpressionKind::Float,
+            ExprNode::String(_) => ExpressionKind::String,
+            ExprNode::Variable { name, target } => self.variable(name, target, span)?,
+            ExprNode::Unary { operator, operand } => {
+                let kind = self.expr(operand, None)?;
+                match operator {
+                    UnaryOperator::Not => {
+                        self.check_expression_kind(&kind, ExpressionKind::Bool, span)?
+                    }
+                    UnaryOperator::Negate => {
+                        self.check_number_kinds(&kind, &kind, span)?;
+                    }
+                }
+                kind
+            }
+            ExprNode::Binary {
+                operator,
+                left,
+                right,
+            } => self.binary(*operator, left, right, span)?,
+            ExprNode::Conversion { to, value } => {
+                let kind = self.expr(value, None)?;
+                match (&kind, &*to) {
+                    (
+                        ExpressionKind::Int | ExpressionKind::Float,
+                        ExpressionKind::Int | ExpressionKind::Float,
+                    ) => {}
+                    _ => {
+                        return Err(CompilerError::InvalidConversion {
+                            from: kind,
+                            to: to.clone(),
+                            span,
+                        })
+                    }
+                }
+                to.clone()
+            }
+            ExprNode::Array(elements) => self.array(elements, hint, span)?,
+            ExprNode::Map(entries) => self.map(entries, hint, span)?,
+            ExprNode::Field {
+                object,
+                name,
+                target,
+            } => self.field_expr(object, name, target, span)?,
+            ExprNode::Index { object, index } => self.index(object, index, span)?,
+            ExprNode::Call {
+                callee,
+                args,
+                target,
+            } => self.call(callee, args, target, span)?,
+            ExprNode::New {
+                module,
+                class,
+                args,
+                target,
+            } => {
+                let (kind, constructor) = self.new_expr(module.as_deref(), class, args, span)?;
+                *target = Some(constructor);
+                kind
+            }
+            ExprNode::Match(match_expr) => self.match_expr(match_expr, hint, span)?,
+            ExprNode::Function(function) => {
+                let index = self.add_function("<anonymous>")?;
+                function.index = index;
+                let signature = self.signature(function)?;
+                self.function_body(function, &signature, None, true)?;
+                if function.captures.len() > MAX_OPERAND {
+                    return Err(CompilerError::TooManyLocals(span));
+                }
+                signature.kind()
+            }
+        };
+        expr.kind = kind.clone();
+        Ok(kind)
+    }
+
+    fn variable(
+        &mut self,
+        name: &str,
+        target: &mut VariableTarget,
+        span: Span,
+    ) -> Result<ExpressionKind> {
+        if let Some(variable) = self.find_variable(name) {
+            *target = variable.target;
+            return Ok(variable.kind);
+        }
+        if let Some(function) = self.scopes[self.module].functions.get(name) {
+            *target = VariableTarget::Function(function.index);
+            return Ok(function.kind());
+        }
+        match self.input(name, span)? {
+            Some((index, kind)) => {
+                *target = VariableTarget::Input(index);
+                Ok(kind)
+            }
+            None => Err(CompilerError::MissingLocal {
+                name: name.to_string(),
+                span,
+            }),
+        }
+    }
+
+    /// Resolves a value passed in by the host, typed by its value. An instance is of
+    /// a class of the compiled source.
+    fn input(&mut self, name: &str, span: Span) -> Result<Option<(u16, ExpressionKind)>> {
+        let Some((_, value)) = self.inputs.iter().find(|(input, _)| input == name) else {
+            return Ok(None);
+        };
+        if let Some((_, ty)) = self.input_types.iter().find(|(input, _)| input == name) {
+            let kind = self.input_type(ty, span)?;
+            let Some(host_kind) = self.host_kind(&kind) else {
+                return Err(CompilerError::InputType {
+                    name: name.to_string(),
+                    kind,
+                    span,
+                });
+            };
+            return self.use_input(name, kind, host_kind, span).map(Some);
+        }
+        let (kind, host_kind) = match value {
+            HostValue::Int(_) => (ExpressionKind::Int, HostKind::Int),
+            HostValue::Float(_) => (ExpressionKind::Float, HostKind::Float),
+            HostValue::Bool(_) => (ExpressionKind::Bool, HostKind::Bool),
+            HostValue::Str(_) => (ExpressionKind::String, HostKind::Str),
+            HostValue::Instance(instance) => match self.find_class(0, &instance.class) {
+                Some(idx) => (
+                    self.class_kind(idx),
+                    HostKind::Instance(self.classes[idx].info as usize),
+                ),
+                None => {
+                    return Err(CompilerError::UnknownClass {
+                        name: instance.class.to_string(),
+                        span,
+                    })
+                }
+            },
+            HostValue::Nil => return Err(CompilerError::UntypedNil(span)),
+        };
+        self.use_input(name, kind, host_kind, span).map(Some)
+    }
+
+    /// Resolves the type the host declared for an input in the compiled source, with
+    /// its errors at the use of the input.
+    fn input_type(&mut self, ty: &Type, span: Span) -> Result<ExpressionKind> {
+        fn relocate(ty: &mut Type, span: Span) {
+            ty.span = span;
+            match &mut ty.node {
+                TypeNode::Array(inner) | TypeNode::Optional(inner) => relocate(inner, span),
+                TypeNode::Map(key, value) => {
+                    relocate(key, span);
+                    relocate(value, span);
+                }
+                TypeNode::Function(params, return_type) => {
+                    params.iter_mut().for_each(|param| relocate(param, span));
+                    if let Some(return_type) = return_type {
+                        relocate(return_type, span);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut ty = ty.clone();
+        relocate(&mut ty, span);
+        let module = std::mem::replace(&mut self.module, 0);
+        let kind = self.resolve_type(&ty);
+        self.module = module;
+        kind
+    }
+
+    /// Gives the input an index for the code generator, the first time it is used.
+    fn use_input(
+        &mut self,
+        name: &str,
+        kind: ExpressionKind,
+        host_kind: HostKind,
+        span: Span,
+    ) -> Result<(u16, ExpressionKind)> {
+        let index = match self.used_inputs.iter().position(|(input, _)| input == name) {
+            Some(index) => index,
+            None if self.used_inputs.len() > MAX_OPERAND => {
+                return Err(CompilerError::TooManyLocals(span))
+            }
+            None => {
+                self.used_inputs.push((name.to_string(), host_kind));
+                self.used_inputs.len() - 1
+            }
+        };
+        Ok((index as u16, kind))
+    }
+
+    /// The type of values of `kind` that the host can pass in, if it can.
+    fn host_kind(&self, kind: &ExpressionKind) -> Option<HostKind> {
+        match kind {
+            ExpressionKind::Int => Some(HostKind::Int),
+            ExpressionKind::Float => Some(HostKind::Float),
+            ExpressionKind::Bool => Some(HostKind::Bool),
+            ExpressionKind::String => Some(HostKind::Str),
+            ExpressionKind::Optional(inner) => {
+                Some(HostKind::Optional(Box::new(self.host_kind(inner)?)))
+            }
+            ExpressionKind::Class(class) => Some(HostKind::Instance(
+                self.classes[class.index as usize].info as usize,
+            )),
+            _ => None,
+        }
+    }
+
+    fn binary(
+        &mut self,
+        operator: BinaryOperator,
+        left: &mut Expr,
+        right: &mut Expr,
+        span: Span,
+    ) -> Result<ExpressionKind> {
+        use BinaryOperator::*;
+        let left_kind = self.value(left, None)?;
+        match operator {
+            Or | And => {
+                self.check_expression_kind(&left_kind, ExpressionKind::Bool, span)?;
+                // the right operand only runs when the left one is true for `and`, and
+                // false for `or`
+                let not_nil = self.nil_checks(left, operator == And);
+                self.start_scope();
+                for local in not_nil {
+                    self.narrow(local);
+                }
+                let right_kind = self.value(right, None);
+                self.end_scope();
+                let right_kind = right_kind?;
+                self.check_expression_kind(&right_kind, ExpressionKind::Bool, span)?;
+                Ok(ExpressionKind::Bool)
+            }
+            Equal | NotEqual | Is => {
+                let right_kind = self.value(right, None)?;
+                if let (ExpressionKind::Int, ExpressionKind::Float)
+                | (ExpressionKind::Float, ExpressionKind::Int) = (&left_kind, &right_kind)
+                {
+                    return Err(CompilerError::MixedNumbers(span));
+                }
+                if !left_kind.accepts(&right_kind) && !right_kind.accepts(&left_kind) {
+                    return Err(CompilerError::ComparisonType {
+                        first: left_kind,
+                        second: right_kind,
+                        span,
+                    });
+                }
+                if operator == Is && !is_reference(&left_kind) {
+                    return Err(CompilerError::InvalidOperatorTypes {
+                        first: left_kind,
+                        second: right_kind,
+                        span,
+                    });
+                }
+                Ok(ExpressionKind::Bool)
+            }
+            Less | LessEqual | Greater | GreaterEqual => {
+                self.check_number_kind(&left_kind, span)?;
+                let right_kind = self.value(right, None)?;
+                self.check_number_kinds(&left_kind, &right_kind, span)?;
+                Ok(ExpressionKind::Bool)
+            }
+            Add => {
+                let right_kind = self.value(right, None)?;
+                use ExpressionKind::{Bool, Float, Int, String};
+                match (&left_kind, &right_kind) {
+                    (Int, Int) | (Float, Float) => {}
+                    (String, Bool | Int | Float | String) | (Bool | Int | Float, String) => {}
+                    (String, kind) | (kind, String) if is_reference(kind) => {
+                        // printing an instance may call its to_string method
+                        self.widen_mut_globals();
+                    }
+                    (Int, Float) | (Float, Int) => return Err(CompilerError::MixedNumbers(span)),
+                    _ => {
+                        self.check_not_optional(&left_kind, span)?;
+                        self.check_not_optional(&right_kind, span)?;
+                        return Err(CompilerError::InvalidOperatorTypes {
+                            first: left_kind,
+                            second: right_kind,
+                            span,
+                        });
+                    }
+                }
+                if left_kind == String || right_kind == String {
+                    return Ok(String);
+                }
+                Ok(left_kind)
+            }
+            Subtract => {
+                let right_kind = self.value(right, None)?;
+                self.check_number_kind(&left_kind, span)?;
+                self.check_number_kinds(&left_kind, &right_kind, span)?;
+                Ok(left_kind)
+            }
+            Multiply | Divide | Modulo => {
+                self.check_number_kind(&left_kind, span)?;
+                let right_kind = self.value(right, None)?;
+                self.check_number_kinds(&left_kind, &right_kind, span)?;
+                Ok(left_kind)
+            }
+        }
+    }
+
+    fn check_expression_kind(
+        &self,
+        kind: &ExpressionKind,
+        expected: ExpressionKind,
+        span: Span,
+    ) -> Result<()> {
+        if !expected.accepts(kind) {
+            self.check_not_optional(kind, span)?;
+            return Err(CompilerError::Type {
+                actual: kind.clone(),
+                expected,
+                span,
+            });
+        }
+        Ok(())
+    }
+
+    /// Optionals have to be compared with `nil` before their value can be used.
+    fn check_not_optional(&self, kind: &ExpressionKind, span: Span) -> Result<()> {
+        if let ExpressionKind::Optional(_) = kind {
+            return Err(CompilerError::UncheckedOptional {
+                kind: kind.clone(),
+                span,
+            });
+        }
+        Ok(())
+    }
+
+    /// Numbers are either `Int` or `Float`. A non-number is reported as expecting an `Int`.
+    fn check_number_kind(&self, kind: &ExpressionKind, span: Span) -> Result<()> {
+        match kind {
+            ExpressionKind::Float => Ok(()),
+            kind => self.check_expression_kind(kind, ExpressionKind::Int, span),
+        }
+    }
+
+    /// Checks the operands of an operation on two numbers. Ints and floats are never
+    /// converted implicitly.
+    fn check_number_kinds(
+        &self,
+        left_kind: &ExpressionKind,
+        right_kind: &ExpressionKind,
+        span: Span,
+    ) -> Result<()> {
+        self.check_number_kind(left_kind, span)?;
+        self.check_number_kind(right_kind, span)?;
+        if left_kind != right_kind {
+            return Err(CompilerError::MixedNumbers(span));
+        }
+        Ok(())
+    }
+
+    /// Checks an array literal like `[1, 2, 3]`. An empty literal takes its type from
+    /// the hint.
+    fn array(
+        &mut self,
+        elements: &mut [Expr],
+        hint: Option<&ExpressionKind>,
+        span: Span,
+    ) -> Result<ExpressionKind> {
+        if elements.len() > MAX_OPERAND {
+            return Err(CompilerError::TooManyElements(span));
+        }
+        let mut element_kind = match hint {
+            Some(ExpressionKind::Array(kind)) => Some(*kind.clone()),
+            _ => None,
+        };
+        for element in elements {
+            let kind = self.value(element, element_kind.as_ref())?;
+            match &element_kind {
+                Some(expected) if !expected.accepts(&kind) => {
+                    return Err(CompilerError::Type {
+                        actual: kind,
+                        expected: expected.clone(),
+                        span: element.span,
+                    })
+                }
+                Some(_) => {}
+                None if kind == ExpressionKind::Nil => {
+                    return Err(CompilerError::UntypedNil(element.span))
+                }
+                None => element_kind = Some(kind),
+            }
+        }
+        match element_kind {
+            Some(kind) => Ok(ExpressionKind::Array(Box::new(kind))),
+            None => Err(CompilerError::EmptyArray(span)),
+        }
+    }
+
+    /// Checks a map literal like `{"a": 1, "b": 2}`. An empty literal takes its type
+    /// from the hint.
+    fn map(
+        &mut self,
+        entries: &mut [(Expr, Expr)],
+        hint: Option<&ExpressionKind>,
+        span: Span,
+    ) -> Result<ExpressionKind> {
+        if entries.len() > MAX_OPERAND / 2 {
+            return Err(CompilerError::TooManyElements(span));
+        }
+        let mut entry_kinds = match hint {
+            Some(ExpressionKind::Map(key, value)) => Some((*key.clone(), *value.clone())),
+            _ => None,
+        };
+        for (key, value) in entries {
+            let key_kind = self.value(key, entry_kinds.as_ref().map(|(key, _)| key))?;
+            let value_kind = self.value(value, entry_kinds.as_ref().map(|(_, value)| value))?;
+            match &entry_kinds {
+                Some((key_expected, value_expected)) => {
+                    self.check_expression_kind(&key_kind, key_expected.clone(), key.span)?;
+                    self.check_expression_kind(&value_kind, value_expected.clone(), value.span)?;
+                }
+                None => {
+                    self.check_key_kind(&key_kind, key.span)?;
+                    if value_kind == ExpressionKind::Nil {
+                        return Err(CompilerError::UntypedNil(value.span));
+                    }
+                    entry_kinds = Some((key_kind, value_kind));
+                }
+            }
+        }
+        match entry_kinds {
+            Some((key, value)) => Ok(ExpressionKind::Map(Box::new(key), Box::new(value))),
+            None => Err(CompilerError::EmptyMap(span)),
+        }
+    }
+
+    /// Checks `object.name`, which is a field, a variant without a payload like
+    /// `Shape.Empty`, or a function of a module like `util.f`.
+    fn field_expr(
+        &mut self,
+        object: &mut Expr,
+        name: &str,
+        target: &mut FieldTarget,
+        span: Span,
+    ) -> Result<ExpressionKind> {
+        if let Some(module) = self.namespace(object) {
+            let function = self.module_function(module, name, span)?;
+            *target = FieldTarget::Function(function.index);
+            return Ok(function.kind());
+        }
+        if let Some(enum_idx) = self.enum_path(object)? {
+            let (info, payload) = self.variant(enum_idx, name, span)?;
+            if !payload.is_empty() {
+                return Err(CompilerError::PayloadCount {
+                    name: name.to_string(),
+                    expected: payload.len(),
+                    actual: 0,
+                    span,
+                });
+            }
+            *target = FieldTarget::Variant(info);
+            return Ok(self.enum_kind(enum_idx));
+        }
+        let kind = self.expr(object, None)?;
+        self.check_not_optional(&kind, span)?;
+        let (index, kind) = self.field(&kind, name, span)?;
+        *target = FieldTarget::Field(index);
+        Ok(kind)
+    }
+
+    /// Finds the field `name` of a value of `kind`.
+    fn field(
+        &self,
+        kind: &ExpressionKind,
+        name: &str,
+        span: Span,
+    ) -> Result<(u16, ExpressionKind)> {
+        if let ExpressionKind::Class(class) = kind {
+            let fields = &self.classes[class.index as usize].fields;
+            if let Some(index) = fields.iter().position(|f| f.name == name) {
+                return Ok((index as u16, fields[index].kind.clone()));
+            }
+        }
+        Err(CompilerError::UnknownField {
+            name: name.to_string(),
+            kind: kind.clone(),
+            span,
+        })
+    }
+
+    /// Checks `object[index]` and returns the kind of the element.
+    fn index(&mut self, object: &mut Expr, index: &mut Expr, span: Span) -> Result<ExpressionKind> {
+        let kind = self.expr(object, None)?;
+        self.check_not_optional(&kind, span)?;
+        match kind {
+            ExpressionKind::Array(element_kind) => {
+                let index_kind = self.value(index, Some(&ExpressionKind::Int))?;
+                self.check_expression_kind(&index_kind, ExpressionKind::Int, index.span)?;
+                Ok(*element_kind)
+            }
+            ExpressionKind::Map(key_kind, value_kind) => {
+                let kind = self.value(index, Some(&key_kind))?;
+                self.check_expression_kind(&kind, *key_kind, index.span)?;
+                Ok(*value_kind)
+            }
+            kind => Err(CompilerError::NotIndexable { kind, span }),
+        }
+    }
+
+    /// Returns the imported module if `expr` is the prefix of a module member, like
+    /// `util` in `util.f`. Variables shadow imports.
+    fn namespace(&mut self, expr: &Expr) -> Option<usize> {
+        let ExprNode::Variable { name, .. } = &expr.node else {
+            return None;
+        };
+        if self.find_variable(name).is_some() {
+            return None;
+        }
+        self.scopes[self.module]
+            .imports
+            .get(name)
+            .map(|&(module, _)| module)
+    }
+
+    /// Returns the enum if `expr` names one, like `Shape` or `geo.Shape`.
+    fn enum_path(&mut self, expr: &Expr) -> Result<Option<usize>> {
+        match &expr.node {
+            ExprNode::Variable { name, .. } if self.find_variable(name).is_none() => {
+                Ok(self.find_enum(self.module, name))
+            }
+            ExprNode::Field { object, name, .. } => {
+                let Some(module) = self.namespace(object) else {
+                    return Ok(None);
+                };
+                match self.find_enum(module, name) {
+                    Some(enum_idx) if self.enums[enum_idx].is_pub => Ok(Some(enum_idx)),
+                    Some(_) => Err(CompilerError::NotExported {
+                        module: self.scopes[module].name.to_string(),
+                        name: name.to_string(),
+                        span: expr.span,
+                    }),
+                    None => Ok(None),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Finds the public function `name` of an imported module.
+    fn module_function(&self, module: usize, name: &str, span: Span) -> Result<Signature> {
+        match self.scopes[module].functions.get(name) {
+            Some(function) if function.is_pub => Ok(function.clone()),
+            _ => Err(CompilerError::NotExported {
+                module: self.scopes[module].name.to_string(),
+                name: name.to_string(),
+                span,
+            }),
+        }
+    }
+
+    /// Finds the variant `name` of an enum and returns its class info and payload.
+    fn variant(
+        &self,
+        enum_idx: usize,
+        name: &str,
+        span: Span,
+    ) -> Result<(u16, Vec<ExpressionKind>)> {
+        match self.enums[enum_idx]
+            .variants
+            .iter()
+            .find(|v| v.name == name)
+        {
+            Some(variant) => Ok((variant.info, variant.payload.clone())),
+            None => Err(CompilerError::UnknownVariant {
+                name: name.to_string(),
+                kind: self.enum_kind(enum_idx),
+                span,
+            }),
+        }
+    }
+
+    /// Checks a call of a function, a method, a function value or a variant with a
+    /// payload.
+    fn call(
+        &mut self,
+        callee: &mut Expr,
+        args: &mut [Expr],
+        target: &mut CallTarget,
+        span: Span,
+    ) -> Result<ExpressionKind> {
+        match &mut callee.node {
+            // a variable holding a function shadows a function declaration
+            ExprNode::Variable {
+                name,
+                target: variable,
+            } if self.find_variable(name).is_none() => {
+                let Some(function) = self.scopes[self.module]
+                    .functions
+                    .get(name.as_str())
+                    .cloned()
+                else {
+                    let natives = self.natives;
+                    if let Some(index) = natives.iter().position(|n| n.name == *name) {
+                        let native = &natives[index];
+                        self.args(&native.params, args, span)?;
+                        *target = CallTarget::Native(index as u16);
+                        return Ok(native.return_kind.clone());
+                    }
+                    return Err(CompilerError::UnknownFunction {
+                        name: name.to_string(),
+                        span,
+                    });
+                };
+                *variable = VariableTarget::Function(function.index);
+                *target = CallTarget::Function(function.index);
+                return self.call_function(&function, args, span);
+            }
+            ExprNode::Field {
+                object,
+                name,
+                target: field,
+            } => {
+                if let Some(module) = self.namespace(object) {
+                    let function = self.module_function(module, name, span)?;
+                    *field = FieldTarget::Function(function.index);
+                    *target = CallTarget::Function(function.index);
+                    return self.call_function(&function, args, span);
+                }
+                if let Some(enum_idx) = self.enum_path(object)? {
+                    let (info, payload) = self.variant(enum_idx, name, span)?;
+                    let kind = self.enum_kind(enum_idx);
+                    if payload.is_empty() {
+                        return Err(CompilerError::NotCallable { kind, span });
+                    }
+                    *target = CallTarget::Variant(info);
+                    self.payload(name, &payload, args, span)?;
+                    return Ok(kind);
+                }
+                let kind = self.expr(object, None)?;
+                self.check_not_optional(&kind, span)?;
+                if !self.is_function_field(&kind, name) {
+                    let receiver = match self.is_mut_chain(object) {
+                        true => None,
+                        false => Some(&**object),
+                    };
+                    return self.method_call(kind, name, args, target, receiver, span);
+                }
+                let (index, kind) = self.field(&kind, name, span)?;
+                *field = FieldTarget::Field(index);
+                *target = CallTarget::Value;
+                return self.call_value(kind, args, span);
+            }
+            _ => {}
+        }
+        let kind = self.expr(callee, None)?;
+        *target = CallTarget::Value;
+        self.call_value(kind, args, span)
+    }
+
+    /// Checks the arguments of a call against the kinds of the parameters.
+    fn args(&mut self, params: &[ExpressionKind], args: &mut [Expr], span: Span) -> Result<()> {
+        if let Some(extra) = args.get(params.len()) {
+            return Err(CompilerError::UnexpectedToken {
+                expected: TokenKind::RightParen,
+                actual: TokenKind::Comma,
+                span: extra.span,
+            });
+        }
+        for (expected, arg) in params.iter().zip(args.iter_mut()) {
+            let actual = self.value(arg, Some(expected))?;
+            if !expected.accepts(&actual) {
+                return Err(CompilerError::ParamType {
+                    expected: expected.clone(),
+                    actual,
+                    span: arg.span,
+                });
+            }
+        }
+        if let Some(expected) = params.get(args.len()) {
+            return Err(CompilerError::ParamType {
+                expected: expected.clone(),
+                actual: ExpressionKind::None,
+                span,
+            });
+        }
+        Ok(())
+    }
+
+    fn call_function(
+        &mut self,
+        function: &Signature,
+        args: &mut [Expr],
+        span: Span,
+    ) -> Result<ExpressionKind> {
+        let params: Vec<ExpressionKind> = function
+            .params
+            .iter()
+            .map(|(_, kind)| kind.clone())
+            .collect();
+        self.args(&params, args, span)?;
+        self.widen_mut_globals();
+        Ok(function.return_type.clone().unwrap_or(ExpressionKind::None))
+    }
+
+    /// Checks a call of a value of `kind`, which has to be a function.
+    fn call_value(
+        &mut self,
+        kind: ExpressionKind,
+        args: &mut [Expr],
+        span: Span,
+    ) -> Result<ExpressionKind> {
+        self.check_not_optional(&kind, span)?;
+        let ExpressionKind::Function(params, return_kind) = kind else {
+            return Err(CompilerError::NotCallable { kind, span });
+        };
+        self.args(&params, args, span)?;
+        self.widen_mut_globals();
+        Ok(*return_kind)
+    }
+
+    /// Checks the payload of a variant, like `Shape.Circle(1.0)`.
+    fn payload(
+        &mut self,
+        name: &str,
+        payload: &[ExpressionKind],
+        args: &mut [Expr],
+        span: Span,
+    ) -> Result<()> {
+        for (i, arg) in args.iter_mut().enumerate() {
+            let expected = payload.get(i).cloned().unwrap_or(ExpressionKind::None);
+            let actual = self.value(arg, Some(&expected))?;
+            if i < payload.len() && !expected.accepts(&actual) {
+                return Err(CompilerError::Type {
+                    expected,
+                    actual,
+                    span: arg.span,
+                });
+            }
+        }
+        if args.len() != payload.len() {
+            return Err(CompilerError::PayloadCount {
+                name: name.to_string(),
+                expected: payload.len(),
+                actual: args.len(),
+                span,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether `name` is a field holding a function rather than a method, so that
+    /// `value.name()` calls the function in the field.
+    fn is_function_field(&self, kind: &ExpressionKind, name: &str) -> bool {
+        let ExpressionKind::Class(class) = kind else {
+            return false;
+        };
+        let class = &self.classes[class.index as usize];
+        !class.methods.iter().any(|m| m.name == name)
+            && class
+                .fields
+                .iter()
+                .any(|f| f.name == name && matches!(f.kind, ExpressionKind::Function(..)))
+    }
+
+    /// Checks a call to the method `name` on a value of `kind`. Mutating methods
+    /// require the value to be reached from a `mut` binding, so `receiver` is the
+    /// value when it is not.
+    fn method_call(
+        &mut self,
+        kind: ExpressionKind,
+        name: &str,
+        args: &mut [Expr],
+        target: &mut CallTarget,
+        receiver: Option<&Expr>,
+        span: Span,
+    ) -> Result<ExpressionKind> {
+        use ExpressionKind::{Array, Bool, Int, Map};
+        if let ExpressionKind::Class(class) = &kind {
+            let class = &self.classes[class.index as usize];
+            let Some(method) = class.methods.iter().find(|m| m.name == name) else {
+                return Err(CompilerError::UnknownMethod {
+                    name: name.to_string(),
+                    kind,
+                    span,
+                });
+            };
+            if let (true, Some(receiver)) = (method.is_mut, receiver) {
+                return Err(self.cant_mut(receiver, span));
+            }
+            let signature = method.signature.clone();
+            *target = CallTarget::Method(signature.index);
+            return self.call_function(&signature, args, span);
+        }
+        if let (Array(element), "map") = (&kind, name) {
+            let expected =
+                ExpressionKind::Function(vec![*element.clone()], Box::new(ExpressionKind::None));
+            let [function] = args else {
+                return Err(CompilerError::ParamType {
+                    expected,
+                    actual: ExpressionKind::None,
+                    span,
+                });
+            };
+            let actual = self.expr(function, None)?;
+            self.widen_mut_globals();
+            *target = CallTarget::Builtin(Builtin::ArrayMap);
+            return match actual {
+                ExpressionKind::Function(params, return_kind)
+                    if params == [*element.clone()] && *return_kind != ExpressionKind::None =>
+                {
+                    Ok(ExpressionKind::Array(return_kind))
+                }
+                actual => Err(CompilerError::ParamType {
+                    expected,
+                    actual,
+                    span,
+                }),
+            };
+        }
+        // (instruction, parameters, return kind, mutates the receiver)
+        let (builtin, params, return_kind, mutates) = match (&kind, name) {
+            (Array(_), "len") => (Builtin::ArrayLen, vec![], Int, false),
+            (Array(element), "push") => (
+                Builtin::ArrayPush,
+                vec![*element.clone()],
+                ExpressionKind::None,
+                true,
+            ),
+            (Array(element), "pop") => (Builtin::ArrayPop, vec![], *element.clone(), true),
+            (Array(element), "filter") => (
+                Builtin::ArrayFilter,
+                vec![ExpressionKind::Function(
+                    vec![*element.clone()],
+                    Box::new(Bool),
+                )],
+                Array(element.clone()),
+                false,
+            ),
+            (Array(element), "sort") => (
+                Builtin::ArraySort,
+                vec![ExpressionKind::Function(
+                    vec![*element.clone(), *element.clone()],
+                    Box::new(Bool),
+                )],
+                ExpressionKind::None,
+                true,
+            ),
+            (Map(..), "len") => (Builtin::MapLen, vec![], Int, false),
+            (Map(key, value), "get") => {
+                (Builtin::MapGet, vec![*key.clone()], *value.clone(), false)
+            }
+            (Map(key, value), "set") => (
+                Builtin::MapSet,
+                vec![*key.clone(), *value.clone()],
+                ExpressionKind::None,
+                true,
+            ),
+            (Map(key, _), "has") => (Builtin::MapHas, vec![*key.clone()], Bool, false),
+            (Map(key, value), "remove") => {
+                (Builtin::MapRemove, vec![*key.clone()], *value.clone(), true)
+            }
+            (Map(key, _), "keys") => (Builtin::MapKeys, vec![], Array(key.clone()), false),
+            (Map(_, value), "values") => (Builtin::MapValues, vec![], Array(value.clone()), false),
+            _ => {
+                return Err(CompilerError::UnknownMethod {
+                    name: name.to_string(),
+                    kind,
+                    span,
+                })
+            }
+        };
+        if let (true, Some(receiver)) = (mutates, receiver) {
+            return Err(self.cant_mut(receiver, span));
+        }
+        self.args(&params, args, span)?;
+        // the functions passed to filter and sort are called by the instruction
+        if matches!(builtin, Builtin::ArrayFilter | Builtin::ArraySort) {
+            self.widen_mut_globals();
+        }
+        *target = CallTarget::Builtin(builtin);
+        Ok(return_kind)
+    }
+
+    /// Whether a chain of accessors like `a.b[0]` starts at a `mut` binding, so the
+    /// value it reaches can be changed.
+    fn is_mut_chain(&mut self, expr: &Expr) -> bool {
+        match &expr.node {
+            ExprNode::Variable { name, .. } => self.find_variable(name).is_some_and(|v| v.is_mut),
+            ExprNode::Field { object, .. } | ExprNode::Index { object, .. } => {
+                self.is_mut_chain(object)
+            }
+            ExprNode::Call { callee, .. } => match &callee.node {
+                ExprNode::Field { object, .. } => self.is_mut_chain(object),
+                // the result of a function call is never a binding
+                ExprNode::Variable { .. } => false,
+                _ => self.is_mut_chain(callee),
+            },
+            _ => false,
+        }
+    }
+
+    /// The name at the start of a chain of accessors, for error messages.
+    fn root_name(expr: &Expr) -> String {
+        match &expr.node {
+            ExprNode::Variable { name, .. } => name.to_string(),
+            ExprNode::Field { object, .. } | ExprNode::Index { object, .. } => {
+                Self::root_name(object)
+            }
+            ExprNode::Call { callee, .. } => Self::root_name(callee),
+            _ => String::new(),
+        }
+    }
+
+    /// The error for changing the value a chain of accessors reaches, pointing at
+    /// the declaration of the binding it starts at.
+    fn cant_mut(&mut self, chain: &Expr, span: Span) -> CompilerError {
+        let name = Self::root_name(chain);
+        CompilerError::CantMut {
+            declaration: self
+                .find_variable(&name)
+                .and_then(|v| Some((v.binding?, v.declaration))),
+            name,
+            span,
+        }
+    }
+
+    /// Checks a `new` expression. Without an `init` method the arguments are the
+    /// fields of the class. With one they are passed on to `init`, and every field
+    /// starts out with its default value.
+    fn new_expr(
+        &mut self,
+        module: Option<&str>,
+        class_name: &str,
+        args: &mut [Arg],
+        span: Span,
+    ) -> Result<(ExpressionKind, Constructor)> {
+        let module = match module {
+            Some(namespace) => match self.scopes[self.module].imports.get(namespace) {
+                Some(&(module, _)) => module,
+                None => {
+                    return Err(CompilerError::UnknownClass {
+                        name: namespace.to_string(),
+                        span,
+                    })
+                }
+            },
+            None => self.module,
+        };
+        let idx = match self.find_class(module, class_name) {
+            Some(idx) if self.classes[idx].is_pub || module == self.module => idx,
+            _ if module != self.module => {
+                return Err(CompilerError::NotExported {
+                    module: self.scopes[module].name.to_string(),
+                    name: class_name.to_string(),
+                    span,
+                })
+            }
+            _ => {
+                return Err(CompilerError::UnknownClass {
+                    name: class_name.to_string(),
+                    span,
+                })
+            }
+        };
+        let class = &self.classes[idx];
+        let fields: Vec<(String, ExpressionKind, Option<u16>)> = class
+            .fields
+            .iter()
+            .map(|f| (f.name.to_string(), f.kind.clone(), f.default))
+            .collect();
+        let init = class
+            .methods
+            .iter()
+            .find(|m| m.name == "init")
+            .map(|m| m.signature.clone());
+        let kind = self.class_kind(idx);
+        let info = class.info;
+
+        let Some(init) = init else {
+            let fields = self.constructor_args(&kind, &fields, args, span)?;
+            let constructor = Constructor {
+                info,
+                fields,
+                init: None,
+            };
+            return Ok((kind, constructor));
+        };
+        let mut sources = vec![];
+        for (name, field_kind, default) in fields {
+            sources.push(match default {
+                Some(default) => {
+                    self.widen_mut_globals();
+                    Source::Default(default)
+                }
+                None if matches!(field_kind, ExpressionKind::Optional(_)) => Source::Nil,
+                None => return Err(CompilerError::MissingField { name, span }),
+            });
+        }
+        let params: Vec<(String, ExpressionKind, Option<u16>)> = init
+            .params
+            .iter()
+            .map(|(name, kind)| (name.to_string(), kind.clone(), None))
+            .collect();
+        let init_sources = self.constructor_args(&kind, &params, args, span)?;
+        self.widen_mut_globals();
+        let constructor = Constructor {
+            info,
+            fields: sources,
+            init: Some((init.index, init_sources)),
+        };
+        Ok((kind, constructor))
+    }
+
+    /// Checks the arguments of a `new` expression, either positional or all named
+    /// like `new Foo(a: 1, b: 2)`, and returns where the value of every parameter
+    /// comes from. A parameter without an argument gets its default value.
+    fn constructor_args(
+        &mut self,
+        class_kind: &ExpressionKind,
+        params: &[(String, ExpressionKind, Option<u16>)],
+        args: &mut [Arg],
+        span: Span,
+    ) -> Result<Vec<Source>> {
+        // The argument of every parameter that has one
+        let mut assigned: Vec<Option<usize>> = vec![None; params.len()];
+        for (i, arg) in args.iter().enumerate() {
+            let param_idx = match &arg.name {
+                Some(name) => match params.iter().position(|p| &p.0 == name) {
+                    Some(param_idx) => param_idx,
+                    None => {
+                        return Err(CompilerError::UnknownField {
+                            name: name.to_string(),
+                            kind: class_kind.clone(),
+                            span: arg.span,
+                        })
+                    }
+                },
+                None if i >= params.len() => {
+                    return Err(CompilerError::UnexpectedToken {
+                        expected: TokenKind::RightParen,
+                        actual: TokenKind::Comma,
+                        span: arg.span,
+                    })
+                }
+                None => i,
+            };
+            if let Some(previous) = assigned[param_idx] {
+                return Err(CompilerError::Redeclaration {
+                    name: params[param_idx].0.to_string(),
+                    span: arg.span,
+                    previous: Some(args[previous].span),
+                });
+            }
+            assigned[param_idx] = Some(i);
+        }
+
+        let mut sources = vec![];
+        for ((name, kind, default), arg) in params.iter().zip(assigned) {
+            let source = match (arg, default) {
+                (Some(arg), _) => {
+                    let actual = self.value(&mut args[arg].value, Some(kind))?;
+                    if !kind.accepts(&actual) {
+                        return Err(CompilerError::Type {
+                            actual,
+                            expected: kind.clone(),
+                            span: args[arg].span,
+                        });
+                    }
+                    Source::Arg(arg)
+                }
+                (None, Some(default)) => {
+                    self.widen_mut_globals();
+                    Source::Default(*default)
+                }
+                // optionals are nil unless told otherwise
+                (None, None) if matches!(kind, ExpressionKind::Optional(_)) => Source::Nil,
+                (None, None) => {
+                    return Err(CompilerError::MissingField {
+                        name: name.to_string(),
+                        span,
+                    })
+                }
+            };
+            sources.push(source);
+        }
+        Ok(sources)
+    }
+
+    /// Checks a `match` on an enum value. Every arm binds the payload of its variant
+    /// to new locals, and the arms have to cover every variant exactly once.
+    fn match_expr(
+        &mut self,
+        match_expr: &mut Match,
+        hint: Option<&ExpressionKind>,
+        span: Span,
+    ) -> Result<ExpressionKind> {
+        self.start_scope();
+        let kind = self.expr(&mut match_expr.value, None)?;
+        let ExpressionKind::Enum(enum_ref) = &kind else {
+            return Err(CompilerError::NotMatchable { kind, span });
+        };
+        let enum_idx = enum_ref.index as usize;
+        let mut covered = vec![false; self.enums[enum_idx].variants.len()];
+        let mut result_kind: Option<ExpressionKind> = None;
+        for arm in &mut match_expr.arms {
+            // `_` matches every variant that is left
+            let variant_idx = match arm.variant.as_str() {
+                "_" => None,
+                name => match self.enums[enum_idx]
+                    .variants
+                    .iter()
+                    .position(|v| v.name == name)
+                {
+                    Some(idx) => Some(idx),
+                    None => {
+                        return Err(CompilerError::UnknownVariant {
+                            name: arm.variant.to_string(),
+                            kind,
+                            span: arm.span,
+                        })
+                    }
+                },
+            };
+            let unreachable = match variant_idx {
+                Some(idx) => covered[idx],
+                None => covered.iter().all(|c| *c),
+            };
+            if unreachable {
+                return Err(CompilerError::UnreachableArm(arm.span));
+            }
+
+            self.start_scope();
+            match variant_idx {
+                Some(idx) => {
+                    let variant = &self.enums[enum_idx].variants[idx];
+                    let (info, payload) = (variant.info, variant.payload.clone());
+                    if arm.bindings.len() != payload.len() {
+                        return Err(CompilerError::PayloadCount {
+                            name: arm.variant.to_string(),
+                            expected: payload.len(),
+                            actual: arm.bindings.len(),
+                            span: arm.span,
+                        });
+                    }
+                    for (binding, kind) in arm.bindings.iter().zip(payload) {
+                        if binding != "_" {
+                            self.add_local(
+                                binding,
+                                kind,
+                                false,
+                                Some(Binding::MatchBinding),
+                                arm.span,
+                            );
+                        }
+                    }
+                    arm.info = Some(info);
+                    covered[idx] = true;
+                }
+                None => covered.fill(true),
+            }
+
+            let (arm_kind, arm_span) = match &mut arm.body {
+                ArmBody::Block(block) => {
+                    self.block(block);
+                    (ExpressionKind::None, block.span)
+                }
+                ArmBody::Expr(expr) => {
+                    let hint = hint.or(result_kind.as_ref()).cloned();
+                    (self.expr(expr, hint.as_ref())?, expr.span)
+                }
+            };
+            result_kind = match result_kind {
+                None => Some(arm_kind),
+                Some(expected) if expected.accepts(&arm_kind) => Some(expected),
+                // a nil arm before an optional one
+                Some(expected) if arm_kind.accepts(&expected) => Some(arm_kind),
+                Some(expected) => {
+                    return Err(CompilerError::MatchArmType {
+                        expected,
+                        actual: arm_kind,
+                        span: arm_span,
+                    })
+                }
+            };
+            self.end_scope();
+        }
+
+        let missing: Vec<String> = covered
+            .iter()
+            .zip(&self.enums[enum_idx].variants)
+            .filter(|(covered, _)| !**covered)
+            .map(|(_, variant)| variant.name.to_string())
+            .collect();
+        if !missing.is_empty() {
+            return Err(CompilerError::NonExhaustiveMatch { missing, span });
+        }
+        self.end_scope();
+        Ok(result_kind.unwrap_or(ExpressionKind::None))
+    }
+
+    //
+    // LOCALS
+    //
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("there is always a frame")
+    }
+
+    /// Whether bindings declared here are globals of the module.
+    fn at_top_level(&self) -> bool {
+        let frame = self.frames.last().expect("there is always a frame");
+        frame.module.is_some() && frame.scopes.is_empty()
+    }
+
+    fn add_local(
+        &mut self,
+        name: &str,
+        kind: ExpressionKind,
+        is_mut: bool,
+        binding: Option<Binding>,
+        span: Span,
+    ) -> usize {
+        let id = self.next_local;
+        self.next_local += 1;
+        self.frame().locals.push(Local {
+            id,
+            name: name.to_string(),
+            kind,
+            is_mut,
+            binding,
+            captured: false,
+            global: None,
+            span,
+        });
+        id
+    }
+
+    fn start_scope(&mut self) {
+        let frame = self.frame();
+        frame.scopes.push(frame.locals.len());
+    }
+
+    fn end_scope(&mut self) {
+        let depth = self.frame().scopes.len();
+        self.widen(depth);
+        let frame = self.frame();
+        let start = frame.scopes.pop().expect("No scope exists.");
+        frame.locals.truncate(start);
+    }
+
+    /// Finds a local of the current function, or else a global of the current module.
+    fn find_variable(&mut self, name: &str) -> Option<Variable> {
+        self.find_in_frame(self.frames.len() - 1, name)
+    }
+
+    fn find_in_frame(&mut self, frame_idx: usize, name: &str) -> Option<Variable> {
+        let frame = &self.frames[frame_idx];
+        if let Some(local) = frame.find_local(name) {
+            return Some(Variable::local(local));
+        }
+        if frame.captures.is_some() {
+            // anonymous functions copy the locals of the enclosing function they use
+            let variable = self.find_in_frame(frame_idx - 1, name)?;
+            if variable.target != VariableTarget::Local {
+                return Some(variable);
+            }
+            // globals are used directly rather than copied
+            if let (Some(module), Some(index)) =
+                (self.frames[frame_idx - 1].module, variable.global)
+            {
+                return Some(Variable {
+                    target: VariableTarget::Global { module, index },
+                    local: None,
+                    ..variable
+                });
+            }
+            let id = self.next_local;
+            self.next_local += 1;
+            let capture = Local {
+                id,
+                name: name.to_string(),
+                kind: variable.kind,
+                is_mut: variable.is_mut,
+                binding: variable.binding,
+                captured: true,
+                global: None,
+                span: variable.declaration,
+            };
+            let variable = Variable::local(&capture);
+            self.frames[frame_idx]
+                .captures
+                .as_mut()
+                .unwrap()
+                .push(capture);
+            return Some(variable);
+        }
+        // at the top level of the module the globals are found as locals
+        let module_frame = self.scopes[self.module].frame;
+        if frame_idx == module_frame {
+            return None;
+        }
+        let global = self.frames[module_frame]
+            .locals
+            .iter()
+            .rev()
+            .find(|l| l.global.is_some() && l.name == name)?;
+        Some(Variable {
+            target: VariableTarget::Global {
+                module: self.module,
+                index: global.global.unwrap(),
+            },
+            kind: global.kind.clone(),
+            is_mut: global.is_mut,
+            binding: global.binding,
+            captured: false,
+            global: global.global,
+            local: None,
+            declaration: global.span,
+        })
+    }
+
+    /// The optional locals that are not nil when `condition` is `holds`, from checks
+    /// like `name != nil` joined with `and`, or `name == nil` joined with `or`.
+    fn nil_checks(&mut self, condition: &Expr, holds: bool) -> Vec<usize> {
+        let ExprNode::Binary {
+            operator,
+            left,
+            right,
+        } = &condition.node
+        else {
+            return vec![];
+        };
+        let not_nil = match (operator, holds) {
+            (BinaryOperator::And, true) | (BinaryOperator::Or, false) => {
+                let mut locals = self.nil_checks(left, holds);
+                locals.extend(self.nil_checks(right, holds));
+                return locals;
+            }
+            (BinaryOperator::NotEqual, _) => holds,
+            (BinaryOperator::Equal, _) => !holds,
+            _ => return vec![],
+        };
+        let (true, ExprNode::Variable { name, .. }, ExprNode::Nil) =
+            (not_nil, &left.node, &right.node)
+        else {
+            return vec![];
+        };
+        match self.frame().find_local(name) {
+            Some(local) if matches!(local.kind, ExpressionKind::Optional(_)) => vec![local.id],
+            _ => vec![],
+        }
+    }
+
+    /// Treats an optional local as its inner kind until the current scope ends.
+    fn narrow(&mut self, id: usize) {
+        let frame = self.frame();
+        let (scope_depth, loop_depth) = (frame.scopes.len(), frame.loops.len());
+        let Some(local) = frame.local_mut(id) else {
+            return;
+        };
+        let ExpressionKind::Optional(inner) = &local.kind else {
+            return;
+        };
+        let inner = *inner.clone();
+        let declared = std::mem::replace(&mut local.kind, inner);
+        frame.narrowings.push(Narrowing {
+            local: id,
+            declared,
+            scope_depth,
+            loop_depth,
+        });
+    }
+
+    /// Ends the narrowings made in scopes at least `scope_depth` deep.
+    fn widen(&mut self, scope_depth: usize) {
+        let frame = self.frame();
+        while frame
+            .narrowings
+            .last()
+            .is_some_and(|n| n.scope_depth >= scope_depth)
+        {
+            let narrowing = frame.narrowings.pop().unwrap();
+            if let Some(local) = frame.local_mut(narrowing.local) {
+                local.kind = narrowing.declared;
+            }
+        }
+    }
+
+    /// Ends the narrowings of `mut` globals before anything that may run a function,
+    /// since the function can set them to nil. Loops end them too, as the code
+    /// before a call in the loop runs again after it.
+    fn widen_mut_globals(&mut self) {
+        let Frame {
+            locals, narrowings, ..
+        } = self.frame();
+        for narrowing in narrowings.iter() {
+            if let Some(local) = locals
+                .iter_mut()
+                .find(|l| l.id == narrowing.local && l.global.is_some() && l.is_mut)
+            {
+                local.kind = narrowing.declared.clone();
+            }
+        }
+    }
+}
+
+/// Whether the statements always end in a `return`, or in a loop that never ends,
+/// rather than running past their end.
+fn returns(stmts: &[Stmt]) -> bool {
+    stmts.iter().any(|stmt| match &stmt.node {
+        StmtNode::Return(_) => true,
+        StmtNode::If(if_stmt) => if_returns(if_stmt),
+        StmtNode::While(while_stmt) => {
+            matches!(while_stmt.condition.node, ExprNode::Bool(true))
+                && !breaks(&while_stmt.body.stmts, while_stmt.label.as_deref(), 0)
+        }
+        // a match that checks covers every variant
+        StmtNode::Expr(Expr {
+            node: ExprNode::Match(match_expr),
+            ..
+        }) => match_expr.arms.iter().all(|arm| match &arm.body {
+            ArmBody::Block(block) => returns(&block.stmts),
+            ArmBody::Expr(_) => false,
+        }),
+        _ => false,
+    })
+}
+
+fn if_returns(if_stmt: &If) -> bool {
+    returns(&if_stmt.then_branch.stmts)
+        && match &if_stmt.else_branch {
+            Some(Else::Block(block)) => returns(&block.stmts),
+            Some(Else::If(else_if)) => if_returns(else_if),
+            None => false,
+        }
+}
+
+/// Whether the statements break out of the loop with `label` whose body they are
+/// in, `depth` loops deep.
+fn breaks(stmts: &[Stmt], label: Option<&str>, depth: usize) -> bool {
+    let block_breaks = |block: &Block| breaks(&block.stmts, label, depth);
+    let loop_breaks = |block: &Block| breaks(&block.stmts, label, depth + 1);
+    stmts.iter().any(|stmt| match &stmt.node {
+        StmtNode::Break(None) => depth == 0,
+        StmtNode::Break(Some(name)) => Some(name.as_str()) == label,
+        StmtNode::If(if_stmt) => if_breaks(if_stmt, label, depth),
+        StmtNode::While(while_stmt) => loop_breaks(&while_stmt.body),
+        StmtNode::ForRange(for_stmt) => loop_breaks(&for_stmt.body),
+        StmtNode::ForIn(for_stmt) => loop_breaks(&for_stmt.body),
+        StmtNode::Expr(Expr {
+            node: ExprNode::Match(match_expr),
+            ..
+        }) => match_expr.arms.iter().any(|arm| match &arm.body {
+            ArmBody::Block(block) => block_breaks(block),
+            ArmBody::Expr(_) => false,
+        }),
+        _ => false,
+    })
+}
+
+fn if_breaks(if_stmt: &If, label: Option<&str>, depth: usize) -> bool {
+    breaks(&if_stmt.then_branch.stmts, label, depth)
+        || match &if_stmt.else_branch {
+            Some(Else::Block(block)) => breaks(&block.stmts, label, depth),
+            Some(Else::If(else_if)) => if_breaks(else_if, label, depth),
+            None => false,
+        }
+}
+
+/// The name a statement declares at the top level of a module, with where it is
+/// declared.
+fn top_level_name(stmt: &Stmt) -> Option<(&str, Span)> {
+    match &stmt.node {
+        StmtNode::Let(declaration) => Some((&declaration.name, stmt.span)),
+        StmtNode::Function(function) => Some((&function.name, function.span)),
+        StmtNode::Class(class) => Some((&class.name, class.span)),
+        StmtNode::Enum(declaration) => Some((&declaration.name, declaration.span)),
+        _ => None,
+    }
+}
+
+/// Whether values of `kind` are references to the heap, which `is` compares.
+fn is_reference(kind: &ExpressionKind) -> bool {
+    matches!(
+        kind,
+        ExpressionKind::Class(_)
+            | ExpressionKind::Enum(_)
+            | ExpressionKind::Array(_)
+            | ExpressionKind::Map(..)
+            | ExpressionKind::Function(..)
+    )
+}
+
+/// The locals of a function being checked, or of the top level code of a module.
+struct Frame {
+    locals: Vec<Local>,
+    /// The copied enclosing locals of an anonymous function, or `None` for other
+    /// functions.
+    captures: Option<Vec<Local>>,
+    /// Number of locals when each enclosing scope started.
+    scopes: Vec<usize>,
+    /// The labels of the enclosing loops.
+    loops: Vec<Option<String>>,
+    /// Optional locals that are known not to be nil, innermost last.
+    narrowings: Vec<Narrowing>,
+    return_kind: Option<ExpressionKind>,
+    /// The module, if this is its top level code.
+    module: Option<usize>,
+}
+
+impl Frame {
+    fn new(module: Option<usize>, captures: Option<Vec<Local>>) -> Self {
+        Frame {
+            locals: vec![],
+            captures,
+            scopes: vec![],
+            loops: vec![],
+            narrowings: vec![],
+            return_kind: None,
+            module,
+        }
+    }
+
+    fn find_local(&self, name: &str) -> Option<&Local> {
+        self.locals
+            .iter()
+            .rev()
+            .chain(self.captures.iter().flatten())
+            .find(|l| l.name == name)
+    }
+
+    fn local_mut(&mut self, id: usize) -> Option<&mut Local> {
+        self.locals
+            .iter_mut()
+            .chain(self.captures.iter_mut().flatten())
+            .find(|l| l.id == id)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Local {
+    /// Tells locals apart for narrowings, since names can be shadowed.
+    id: usize,
+    name: String,
+    kind: ExpressionKind,
+    is_mut: bool,
+    /// How the local is declared, `None` for parameters which are always `mut`.
+    binding: Option<Binding>,
+    /// Whether this is a copy of a local of the enclosing function.
+    captured: bool,
+    /// The index of the global, if this is declared at the top level of a module so
+    /// that functions can use it.
+    global: Option<usize>,
+    /// Where the local is declared.
+    span: Span,
+}
+
+/// A variable as it is found from the current function.
+struct Variable {
+    target: VariableTarget,
+    kind: ExpressionKind,
+    is_mut: bool,
+    binding: Option<Binding>,
+    captured: bool,
+    global: Option<usize>,
+    /// The local, if it is one of the current function.
+    local: Option<usize>,
+    /// Where the variable is declared.
+    declaration: Span,
+}
+
+impl Variable {
+    fn local(local: &Local) -> Self {
+        Variable {
+            target: VariableTarget::Local,
+            kind: local.kind.clone(),
+            is_mut: local.is_mut,
+            binding: local.binding,
+            captured: local.captured,
+            global: local.global,
+            local: Some(local.id),
+            declaration: local.span,
+        }
+    }
+}
+
+/// An optional local that is known not to be nil for the rest of a scope.
+struct Narrowing {
+    local: usize,
+    /// The declared optional kind of the local.
+    declared: ExpressionKind,
+    /// Depth of the scope where the narrowing ends.
+    scope_depth: usize,
+    /// Number of loops around the narrowing. Inside a deeper loop the local can not
+    /// become nil again, since the code before the assignment runs again.
+    loop_depth: usize,
+}
+
+struct ModuleScope {
+    /// The namespace the module was first imported as.
+    name: String,
+    /// Functions declared in the module.
+    functions: HashMap<String, Signature>,
+    /// Modules imported by the module and where, by their namespace.
+    imports: HashMap<String, (usize, Span)>,
+    /// Index of the frame of the top level code, while the module is checked.
+    frame: usize,
+    /// Number of globals declared so far.
+    globals: usize,
+    checked: bool,
+    /// Names whose declaration failed, so their uses are not reported again.
+    unknown: HashSet<String>,
+}
+
+#[derive(Clone)]
+struct Signature {
+    index: u16,
+    params: Vec<(String, ExpressionKind)>,
+    return_type: Option<ExpressionKind>,
+    /// Whether other modules can use the function.
+    is_pub: bool,
+    /// The name of the function where it is declared.
+    span: Span,
+}
+
+impl Signature {
+    /// The kind of the function as a value.
+    fn kind(&self) -> ExpressionKind {
+        ExpressionKind::Function(
+            self.params.iter().map(|(_, kind)| kind.clone()).collect(),
+            Box::new(self.return_type.clone().unwrap_or(ExpressionKind::None)),
+        )
+    }
+}
+
+struct Class {
+    name: String,
+    fields: Vec<ClassField>,
+    methods: Vec<ClassMethod>,
+    /// Index of the class in the classes of the chunk.
+    info: u16,
+    /// Index of the module declaring the class.
+    module: usize,
+    is_pub: bool,
+    span: Span,
+}
+
+struct ClassField {
+    name: String,
+    kind: ExpressionKind,
+    /// Index of the function computing the default value.
+    default: Option<u16>,
+    span: Span,
+}
+
+struct ClassMethod {
+    name: String,
+    signature: Signature,
+    /// Only `mut` methods can change the fields of `self`.
+    is_mut: bool,
+}
+
+struct Enum {
+    name: String,
+    variants: Vec<EnumVariant>,
+    /// Index of the module declaring the enum.
+    module: usize,
+    is_pub: bool,
+    span: Span,
+}
+
+struct EnumVariant {
+    name: String,
+    payload: Vec<ExpressionKind>,
+    /// Index of the variant in the classes of the chunk.
+    info: u16,
+    span: Span,
+}
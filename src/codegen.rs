@@ -0,0 +1,1043 @@
+//! Emits the bytecode of a checked program. Every name and kind in the syntax tree
+//! is already resolved, so the only errors left are the limits of the chunk.
+
+use std::mem;
+
+use crate::ast::{self, *};
+use crate::checker::Program;
+use crate::compiler::{Chunk, MAX_OPERAND};
+use crate::enums::{CompilerError, ExpressionKind};
+use crate::opcode::OpCode;
+use crate::scanner::Span;
+
+type Result<T> = std::result::Result<T, CompilerError>;
+
+/// Generates the chunk of `modules` as checked into `program`. The first module is
+/// the compiled source.
+pub fn generate(modules: &[ast::Module], program: Program) -> Result<Chunk> {
+    let mut generator = Generator {
+        chunk: Chunk::new(program.functions, program.classes, program.inputs),
+        modules,
+        layouts: modules.iter().map(|_| Layout::default()).collect(),
+        frame: Frame::default(),
+        span: Span {
+            line: 1,
+            ..Span::default()
+        },
+    };
+    generator.layouts[0].generated = true;
+    generator.module(0)?;
+    Ok(generator.chunk)
+}
+
+struct Generator<'a> {
+    chunk: Chunk,
+    modules: &'a [ast::Module],
+    layouts: Vec<Layout>,
+    /// The function being generated.
+    frame: Frame,
+    /// Source of the emitted code.
+    span: Span,
+}
+
+/// Where the globals of a module live on the stack.
+#[derive(Default)]
+struct Layout {
+    /// Stack position of the frame of the top level code. The globals stay on the
+    /// stack after the top level code has run.
+    base: usize,
+    /// Position in the top level frame of the first global. The slots of every
+    /// global are reserved before the top level code runs.
+    first_global: usize,
+    /// Number of globals declared so far.
+    next_global: usize,
+    generated: bool,
+}
+
+#[derive(Default)]
+struct Frame {
+    locals: Vec<Local>,
+    /// Number of values on the stack of the function, locals and temporaries.
+    local_count: usize,
+    /// Number of locals when each enclosing scope started.
+    scopes: Vec<usize>,
+    loops: Vec<Loop>,
+    /// The module, if this is its top level code.
+    module: Option<usize>,
+}
+
+struct Local {
+    name: String,
+    stack_pos: usize,
+}
+
+struct Loop {
+    label: Option<String>,
+    /// Number of locals in the function when the loop body starts.
+    local_depth: usize,
+    /// Where a `continue` jumps back to, or `None` if it has to jump forward.
+    continue_target: Option<usize>,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+impl Generator<'_> {
+    /// Generates the top level code of a module into its init function and returns the
+    /// number of values it leaves on the stack, the globals of the module and of the
+    /// modules it imports first.
+    fn module(&mut self, module: usize) -> Result<usize> {
+        let modules = self.modules;
+        self.chunk.begin_function(modules[module].init);
+        let enclosing_frame = mem::replace(
+            &mut self.frame,
+            Frame {
+                module: Some(module),
+                ..Frame::default()
+            },
+        );
+        let stmts = &modules[module].file.stmts;
+
+        for stmt in stmts {
+            if let StmtNode::Import(import) = &stmt.node {
+                self.span = stmt.span;
+                self.import(import)?;
+            }
+        }
+        let global_count = stmts
+            .iter()
+            .filter(|stmt| matches!(stmt.node, StmtNode::Let(_)))
+            .count();
+        if self.frame.local_count + global_count > MAX_OPERAND {
+            return Err(CompilerError::TooManyLocals(self.span));
+        }
+        if global_count > 0 {
+            self.emit_opcode(OpCode::ReserveGlobals);
+            self.emit_u16(global_count as u16);
+        }
+        self.layouts[module].first_global = self.frame.local_count;
+        self.frame.local_count += global_count;
+
+        for stmt in stmts {
+            self.stmt(stmt)?;
+        }
+        // the globals of the module are left on the stack
+        if module != 0 {
+            self.emit_opcode(OpCode::Return);
+            self.emit_u16(0);
+        }
+        let frame = mem::replace(&mut self.frame, enclosing_frame);
+        self.chunk.end_function();
+        Ok(frame.local_count)
+    }
+
+    /// Runs the top level code of an imported module, where it is first imported.
+    fn import(&mut self, import: &Import) -> Result<()> {
+        let module = import
+            .module
+            .expect("imports are loaded before they are generated");
+        if self.layouts[module].generated {
+            return Ok(());
+        }
+        self.layouts[module].generated = true;
+        let current = self.frame.module.expect("imports are at the top level");
+        self.layouts[module].base = self.layouts[current].base + self.frame.local_count;
+        let global_count = self.module(module)?;
+        self.emit_call(self.modules[module].init, 0);
+        let name = format!("<module {}>", self.modules[module].name);
+        for _ in 0..global_count {
+            self.add_local(&name)?;
+        }
+        Ok(())
+    }
+
+    //
+    // STATEMENTS
+    //
+
+    fn stmts(&mut self, stmts: &[Stmt]) -> Result<()> {
+        for stmt in stmts {
+            self.stmt(stmt)?;
+        }
+        Ok(())
+    }
+
+    /// Generates a block in a scope of its own.
+    fn block(&mut self, block: &Block) -> Result<()> {
+        self.start_scope();
+        self.stmts(&block.stmts)?;
+        self.end_scope();
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Result<()> {
+        self.span = stmt.span;
+        match &stmt.node {
+            StmtNode::Let(declaration) => {
+                self.expr(&declaration.value)?;
+                match self.frame.module {
+                    Some(module) if self.frame.scopes.is_empty() => {
+                        // stored in the next reserved global slot
+                        let layout = &mut self.layouts[module];
+                        let stack_pos = layout.first_global + layout.next_global;
+                        layout.next_global += 1;
+                        self.emit_opcode(OpCode::SetLocal);
+                        self.emit_u16(stack_pos as u16);
+                        // the compiled source runs at the bottom of the stack
+                        if module == 0 {
+                            let name = declaration.name.to_string();
+                            self.chunk.globals.push((name, stack_pos));
+                        }
+                        self.frame.locals.push(Local {
+                            name: declaration.name.to_string(),
+                            stack_pos,
+                        });
+                    }
+                    _ => self.add_local(&declaration.name)?,
+                }
+            }
+            StmtNode::Assign { target, value } => self.assign(target, value)?,
+            StmtNode::Expr(expr) => {
+                self.expr(expr)?;
+                // a match always leaves its result on the stack
+                if expr.kind != ExpressionKind::None || matches!(expr.node, ExprNode::Match(_)) {
+                    self.emit_opcode(OpCode::PopStack);
+                }
+            }
+            StmtNode::Print(expr) => {
+                self.expr(expr)?;
+                self.emit_opcode(OpCode::Print);
+            }
+            StmtNode::Return(value) => {
+                let opcode = match value {
+                    Some(value) => {
+                        self.expr(value)?;
+                        match value.kind {
+                            ExpressionKind::None => OpCode::Return,
+                            _ => OpCode::ReturnValue,
+                        }
+                    }
+                    None => OpCode::Return,
+                };
+                self.emit_opcode(opcode);
+                self.emit_u16(self.frame.local_count as u16);
+            }
+            StmtNode::If(if_stmt) => self.if_stmt(if_stmt)?,
+            StmtNode::While(while_stmt) => self.while_stmt(while_stmt)?,
+            StmtNode::ForRange(for_stmt) => self.for_range_stmt(for_stmt)?,
+            StmtNode::ForIn(for_stmt) => self.for_in_stmt(for_stmt)?,
+            StmtNode::Break(label) => {
+                let loop_idx = self.target_loop(label.as_deref());
+                self.pop_loop_locals(loop_idx);
+                self.emit_opcode(OpCode::JumpForward);
+                let jump = self.chunk.emit_placeholder(self.span.line);
+                self.frame.loops[loop_idx].breaks.push(jump);
+            }
+            StmtNode::Continue(label) => {
+                let loop_idx = self.target_loop(label.as_deref());
+                self.pop_loop_locals(loop_idx);
+                match self.frame.loops[loop_idx].continue_target {
+                    Some(jump_point) => self.emit_jump_back(jump_point)?,
+                    None => {
+                        self.emit_opcode(OpCode::JumpForward);
+                        let jump = self.chunk.emit_placeholder(self.span.line);
+                        self.frame.loops[loop_idx].continues.push(jump);
+                    }
+                }
+            }
+            StmtNode::Function(function) => self.function(function, false)?,
+            StmtNode::Class(class) => {
+                for member in &class.members {
+                    match member {
+                        Member::Method(method) => self.function(&method.function, true)?,
+                        Member::Field(field) => {
+                            if let Some(default) = &field.default {
+                                self.field_default(field.default_index, default)?;
+                            }
+                        }
+                    }
+                }
+            }
+            // the variants are only class infos
+            StmtNode::Enum(_) => {}
+            // imports are generated before everything else
+            StmtNode::Import(_) => {}
+        }
+        Ok(())
+    }
+
+    fn assign(&mut self, target: &Expr, value: &Expr) -> Result<()> {
+        match &target.node {
+            ExprNode::Variable { name, target } => {
+                self.expr(value)?;
+                match *target {
+                    VariableTarget::Local => {
+                        let stack_pos = self.find_local(name);
+                        self.emit_opcode(OpCode::SetLocal);
+                        self.emit_u16(stack_pos as u16);
+                    }
+                    VariableTarget::Global { module, index } => {
+                        let slot = self.global_slot(module, index)?;
+                        self.emit_opcode(OpCode::SetGlobal);
+                        self.emit_u16(slot);
+                    }
+                    _ => unreachable!("only variables are assigned"),
+                }
+            }
+            ExprNode::Field { object, target, .. } => {
+                let FieldTarget::Field(index) = *target else {
+                    unreachable!("only fields are assigned");
+                };
+                self.expr(object)?;
+                // the instance is below the value
+                self.with_temporaries(1, |g| g.expr(value))?;
+                self.emit_opcode(OpCode::SetField);
+                self.emit_u16(index);
+            }
+            ExprNode::Index { object, index } => {
+                self.expr(object)?;
+                self.with_temporaries(1, |g| g.expr(index))?;
+                // the collection and the index are below the value
+                self.with_temporaries(2, |g| g.expr(value))?;
+                match object.kind {
+                    ExpressionKind::Map(..) => self.emit_opcode(OpCode::MapSet),
+                    _ => self.emit_opcode(OpCode::SetIndex),
+                }
+            }
+            _ => unreachable!("only variables, fields and elements are assigned"),
+        }
+        Ok(())
+    }
+
+    /// Generates `and` and `or`, which only run the right operand when the left one
+    /// does not decide the result.
+    fn logical(&mut self, operator: BinaryOperator, left: &Expr, right: &Expr) -> Result<()> {
+        self.expr(left)?;
+        self.emit_opcode(OpCode::JumpIfFalse);
+        let false_jump = self.chunk.emit_placeholder(self.span.line);
+        match operator {
+            BinaryOperator::And => self.expr(right)?,
+            _ => self.emit_opcode(OpCode::True),
+        }
+        self.emit_opcode(OpCode::JumpForward);
+        let end_jump = self.chunk.emit_placeholder(self.span.line);
+        self.replace_placeholder(false_jump)?;
+        match operator {
+            BinaryOperator::And => self.emit_opcode(OpCode::False),
+            _ => self.expr(right)?,
+        }
+        self.replace_placeholder(end_jump)
+    }
+
+    fn if_stmt(&mut self, if_stmt: &If) -> Result<()> {
+        self.expr(&if_stmt.condition)?;
+        self.emit_opcode(OpCode::JumpIfFalse);
+        let else_jump = self.chunk.emit_placeholder(self.span.line);
+        self.block(&if_stmt.then_branch)?;
+
+        let Some(else_branch) = &if_stmt.else_branch else {
+            return self.replace_placeholder(else_jump);
+        };
+        // the then branch skips over the else branch
+        self.emit_opcode(OpCode::JumpForward);
+        let end_jump = self.chunk.emit_placeholder(self.span.line);
+        self.replace_placeholder(else_jump)?;
+        match else_branch {
+            Else::If(else_if) => self.if_stmt(else_if)?,
+            Else::Block(block) => self.block(block)?,
+        }
+        self.replace_placeholder(end_jump)
+    }
+
+    fn while_stmt(&mut self, while_stmt: &While) -> Result<()> {
+        let jump_point = self.chunk.code_len();
+        self.expr(&while_stmt.condition)?;
+        self.emit_opcode(OpCode::JumpIfFalse);
+        let exit_jump = self.chunk.emit_placeholder(self.span.line);
+        self.frame.loops.push(Loop {
+            label: while_stmt.label.clone(),
+            local_depth: self.frame.locals.len(),
+            continue_target: Some(jump_point),
+            breaks: vec![],
+            continues: vec![],
+        });
+        self.block(&while_stmt.body)?;
+        self.emit_jump_back(jump_point)?;
+        self.replace_placeholder(exit_jump)?;
+        let lp = self.frame.loops.pop().unwrap();
+        for jump in lp.breaks {
+            self.replace_placeholder(jump)?;
+        }
+        Ok(())
+    }
+
+    /// Generates `for i in start:end:step`, which counts towards `end` by one unless
+    /// told otherwise.
+    fn for_range_stmt(&mut self, for_stmt: &ForRange) -> Result<()> {
+        let counts_up = for_stmt.start <= for_stmt.end;
+        let step = match for_stmt.step {
+            Some(step) if step != 0 => step,
+            _ if counts_up => 1,
+            _ => -1,
+        };
+
+        // The iterator lives in its own scope around the loop
+        self.frame.scopes.push(self.frame.locals.len());
+        self.emit_int(for_stmt.start)?;
+        self.add_local(&for_stmt.name)?;
+        let iterator_stack_pos = (self.frame.local_count - 1) as u16;
+
+        let jump_point = self.chunk.code_len();
+        self.emit_opcode(OpCode::GetLocal);
+        self.emit_u16(iterator_stack_pos);
+        self.emit_int(for_stmt.end)?;
+        self.emit_opcode(if counts_up {
+            OpCode::Less
+        } else {
+            OpCode::Greater
+        });
+        self.emit_opcode(OpCode::JumpIfFalse);
+        let exit_jump = self.chunk.emit_placeholder(self.span.line);
+
+        self.frame.loops.push(Loop {
+            label: for_stmt.label.clone(),
+            local_depth: self.frame.locals.len(),
+            continue_target: None,
+            breaks: vec![],
+            continues: vec![],
+        });
+        self.block(&for_stmt.body)?;
+        let lp = self.frame.loops.pop().unwrap();
+        for jump in lp.continues {
+            self.replace_placeholder(jump)?;
+        }
+
+        self.emit_opcode(OpCode::GetLocal);
+        self.emit_u16(iterator_stack_pos);
+        self.emit_int(step)?;
+        self.emit_opcode(OpCode::Add);
+        self.emit_opcode(OpCode::SetLocal);
+        self.emit_u16(iterator_stack_pos);
+        self.emit_jump_back(jump_point)?;
+        self.replace_placeholder(exit_jump)?;
+        for jump in lp.breaks {
+            self.replace_placeholder(jump)?;
+        }
+        self.end_scope();
+        Ok(())
+    }
+
+    /// Generates a `for` loop over the elements of an array, or over the keys of a map
+    /// in insertion order.
+    fn for_in_stmt(&mut self, for_stmt: &ForIn) -> Result<()> {
+        // The array and the index live in their own scope around the loop. Their
+        // names can never clash with an identifier.
+        self.frame.scopes.push(self.frame.locals.len());
+        self.expr(&for_stmt.iterable)?;
+        if let ExpressionKind::Map(..) = for_stmt.iterable.kind {
+            self.emit_opcode(OpCode::MapKeys);
+        }
+        self.add_local("for array")?;
+        let array_stack_pos = (self.frame.local_count - 1) as u16;
+        self.emit_int(0)?;
+        self.add_local("for index")?;
+        let index_stack_pos = (self.frame.local_count - 1) as u16;
+
+        let jump_point = self.chunk.code_len();
+        self.emit_opcode(OpCode::GetLocal);
+        self.emit_u16(index_stack_pos);
+        self.emit_opcode(OpCode::GetLocal);
+        self.emit_u16(array_stack_pos);
+        self.emit_opcode(OpCode::ArrayLen);
+        self.emit_opcode(OpCode::Less);
+        self.emit_opcode(OpCode::JumpIfFalse);
+        let exit_jump = self.chunk.emit_placeholder(self.span.line);
+
+        self.frame.loops.push(Loop {
+            label: for_stmt.label.clone(),
+            local_depth: self.frame.locals.len(),
+            continue_target: None,
+            breaks: vec![],
+            continues: vec![],
+        });
+        // The element is a local of each iteration
+        self.frame.scopes.push(self.frame.locals.len());
+        self.emit_opcode(OpCode::GetLocal);
+        self.emit_u16(array_stack_pos);
+        self.emit_opcode(OpCode::GetLocal);
+        self.emit_u16(index_stack_pos);
+        self.emit_opcode(OpCode::GetIndex);
+        self.add_local(&for_stmt.name)?;
+        self.block(&for_stmt.body)?;
+        self.end_scope();
+        let lp = self.frame.loops.pop().unwrap();
+        for jump in lp.continues {
+            self.replace_placeholder(jump)?;
+        }
+
+        self.emit_opcode(OpCode::GetLocal);
+        self.emit_u16(index_stack_pos);
+        self.emit_int(1)?;
+        self.emit_opcode(OpCode::Add);
+        self.emit_opcode(OpCode::SetLocal);
+        self.emit_u16(index_stack_pos);
+        self.emit_jump_back(jump_point)?;
+        self.replace_placeholder(exit_jump)?;
+        for jump in lp.breaks {
+            self.replace_placeholder(jump)?;
+        }
+        self.end_scope();
+        Ok(())
+    }
+
+    /// Finds the loop targeted by a `break` or `continue`, which the checker made
+    /// sure exists.
+    fn target_loop(&self, label: Option<&str>) -> usize {
+        let loops = &self.frame.loops;
+        match label {
+            Some(label) => loops
+                .iter()
+                .rposition(|l| l.label.as_deref() == Some(label))
+                .expect("labels are checked"),
+            None => loops.len() - 1,
+        }
+    }
+
+    /// Pops the locals declared inside the loop before jumping out of its body.
+    fn pop_loop_locals(&mut self, loop_idx: usize) {
+        let local_depth = self.frame.loops[loop_idx].local_depth;
+        for _ in local_depth..self.frame.locals.len() {
+            self.emit_opcode(OpCode::PopStack);
+        }
+    }
+
+    /// Generates the body of a function. Methods get the instance as the local `self`
+    /// before the parameters, and anonymous functions get their captures after them.
+    fn function(&mut self, function: &ast::Function, is_method: bool) -> Result<()> {
+        self.chunk.begin_function(function.index);
+        let enclosing_frame = mem::take(&mut self.frame);
+        let enclosing_span = self.span;
+        if is_method {
+            self.add_local("self")?;
+        }
+        for param in &function.params {
+            self.add_local(&param.name)?;
+        }
+        for capture in &function.captures {
+            self.add_local(capture)?;
+        }
+        self.stmts(&function.body.stmts)?;
+        self.emit_opcode(OpCode::Return);
+        self.emit_u16(self.frame.local_count as u16);
+        self.frame = enclosing_frame;
+        self.span = enclosing_span;
+        self.chunk.end_function();
+        Ok(())
+    }
+
+    /// Generates the default value of a field into the function at `index`. It runs at
+    /// every construction.
+    fn field_default(&mut self, index: u16, default: &Expr) -> Result<()> {
+        self.chunk.begin_function(index);
+        let enclosing_frame = mem::take(&mut self.frame);
+        self.expr(default)?;
+        self.emit_opcode(OpCode::ReturnValue);
+        self.emit_u16(0);
+        self.frame = enclosing_frame;
+        self.chunk.end_function();
+        Ok(())
+    }
+
+    //
+    // EXPRESSIONS
+    //
+
+    fn expr(&mut self, expr: &Expr) -> Result<()> {
+        let enclosing_span = mem::replace(&mut self.span, expr.span);
+        self.expr_node(expr)?;
+        self.span = enclosing_span;
+        Ok(())
+    }
+
+    fn expr_node(&mut self, expr: &Expr) -> Result<()> {
+        match &expr.node {
+            ExprNode::Nil => self.emit_opcode(OpCode::Nil),
+            ExprNode::Bool(true) => self.emit_opcode(OpCode::True),
+            ExprNode::Bool(false) => self.emit_opcode(OpCode::False),
+            ExprNode::Int(value) => self.emit_int(*value)?,
+            ExprNode::Float(value) => self.chunk.emit_float(*value, self.span)?,
+            ExprNode::String(value) => self.chunk.emit_string(value, self.span)?,
+            ExprNode::Variable { name, target } => match *target {
+                VariableTarget::Local => {
+                    let stack_pos = self.find_local(name);
+                    self.emit_opcode(OpCode::GetLocal);
+                    self.emit_u16(stack_pos as u16);
+                }
+                VariableTarget::Global { module, index } => {
+                    let slot = self.global_slot(module, index)?;
+                    self.emit_opcode(OpCode::GetGlobal);
+                    self.emit_u16(slot);
+                }
+                VariableTarget::Function(index) => self.function_value(index),
+                VariableTarget::Input(index) => {
+                    self.emit_opcode(OpCode::GetInput);
+                    self.emit_u16(index);
+                }
+                VariableTarget::Unresolved => unreachable!("names are resolved by the checker"),
+            },
+            ExprNode::Unary { operator, operand } => {
+                self.expr(operand)?;
+                let opcode = match (operator, &operand.kind) {
+                    (UnaryOperator::Not, _) => OpCode::Not,
+                    (UnaryOperator::Negate, ExpressionKind::Float) => OpCode::NegateFloat,
+                    (UnaryOperator::Negate, _) => OpCode::Negate,
+                };
+                self.emit_opcode(opcode);
+            }
+            ExprNode::Binary {
+                operator: operator @ (BinaryOperator::And | BinaryOperator::Or),
+                left,
+                right,
+            } => self.logical(*operator, left, right)?,
+            ExprNode::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                self.expr(left)?;
+                self.with_temporaries(1, |g| g.expr(right))?;
+                let opcode = binary_opcode(*operator, &left.kind, &right.kind);
+                self.emit_opcode(opcode);
+            }
+            ExprNode::Conversion { to, value } => {
+                self.expr(value)?;
+                match (&value.kind, to) {
+                    (ExpressionKind::Float, ExpressionKind::Int) => {
+                        self.emit_opcode(OpCode::FloatToInt)
+                    }
+                    (ExpressionKind::Int, ExpressionKind::Float) => {
+                        self.emit_opcode(OpCode::IntToFloat)
+                    }
+                    _ => {}
+                }
+            }
+            ExprNode::Array(elements) => {
+                for (i, element) in elements.iter().enumerate() {
+                    self.with_temporaries(i, |g| g.expr(element))?;
+                }
+                self.emit_opcode(OpCode::CreateArray);
+                self.emit_u16(elements.len() as u16);
+            }
+            ExprNode::Map(entries) => {
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    self.with_temporaries(i * 2, |g| g.expr(key))?;
+                    self.with_temporaries(i * 2 + 1, |g| g.expr(value))?;
+                }
+                self.emit_opcode(OpCode::CreateMap);
+                self.emit_u16(entries.len() as u16);
+            }
+            ExprNode::Field { object, target, .. } => match *target {
+                FieldTarget::Field(index) => {
+                    self.expr(object)?;
+                    self.emit_opcode(OpCode::GetField);
+                    self.emit_u16(index);
+                }
+                FieldTarget::Variant(info) => {
+                    self.emit_opcode(OpCode::CreateInstance);
+                    self.emit_u16(info);
+                }
+                FieldTarget::Function(index) => self.function_value(index),
+                FieldTarget::Unresolved => unreachable!("fields are resolved by the checker"),
+            },
+            ExprNode::Index { object, index } => {
+                self.expr(object)?;
+                self.with_temporaries(1, |g| g.expr(index))?;
+                match object.kind {
+                    ExpressionKind::Map(..) => self.emit_opcode(OpCode::MapGet),
+                    _ => self.emit_opcode(OpCode::GetIndex),
+                }
+            }
+            ExprNode::Call {
+                callee,
+                args,
+                target,
+            } => self.call(callee, args, *target)?,
+            ExprNode::New { args, target, .. } => {
+                let constructor = target
+                    .as_ref()
+                    .expect("constructors are resolved by the checker");
+                self.new_expr(constructor, args)?;
+            }
+            ExprNode::Match(match_expr) => self.match_expr(match_expr)?,
+            ExprNode::Function(function) => {
+                if function.captures.len() > MAX_OPERAND {
+                    return Err(CompilerError::TooManyLocals(self.span));
+                }
+                for capture in &function.captures {
+                    let stack_pos = self.find_local(capture);
+                    self.emit_opcode(OpCode::GetLocal);
+                    self.emit_u16(stack_pos as u16);
+                }
+                self.function(function, false)?;
+                self.emit_opcode(OpCode::Closure);
+                self.emit_u16(function.index);
+                self.emit_u16(function.captures.len() as u16);
+            }
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, callee: &Expr, args: &[Expr], target: CallTarget) -> Result<()> {
+        match target {
+            CallTarget::Function(index) => {
+                for (i, arg) in args.iter().enumerate() {
+                    self.with_temporaries(i, |g| g.expr(arg))?;
+                }
+                self.emit_call(index, args.len());
+            }
+            CallTarget::Variant(info) => {
+                for (i, arg) in args.iter().enumerate() {
+                    self.with_temporaries(i, |g| g.expr(arg))?;
+                }
+                self.emit_opcode(OpCode::CreateInstance);
+                self.emit_u16(info);
+            }
+            CallTarget::Native(index) => {
+                for (i, arg) in args.iter().enumerate() {
+                    self.with_temporaries(i, |g| g.expr(arg))?;
+                }
+                self.emit_opcode(OpCode::CallNative);
+                self.emit_u16(index);
+                self.emit_u16(args.len() as u16);
+            }
+            CallTarget::Method(index) => {
+                self.receiver(callee)?;
+                self.args(args)?;
+                self.emit_call(index, args.len() + 1);
+            }
+            CallTarget::Builtin(builtin) => {
+                self.receiver(callee)?;
+                self.args(args)?;
+                self.emit_opcode(builtin_opcode(builtin));
+            }
+            CallTarget::Value => {
+                self.expr(callee)?;
+                self.args(args)?;
+                self.emit_opcode(OpCode::CallValue);
+                self.emit_u16(args.len() as u16);
+                self.emit_opcode(OpCode::PopOffset);
+            }
+            CallTarget::Unresolved => unreachable!("calls are resolved by the checker"),
+        }
+        Ok(())
+    }
+
+    /// Generates the object of a method call, like `a` in `a.len()`.
+    fn receiver(&mut self, callee: &Expr) -> Result<()> {
+        let ExprNode::Field { object, .. } = &callee.node else {
+            unreachable!("methods are called on an object");
+        };
+        self.expr(object)
+    }
+
+    /// Generates the arguments of a call with the callee or the object below them.
+    fn args(&mut self, args: &[Expr]) -> Result<()> {
+        for (i, arg) in args.iter().enumerate() {
+            self.with_temporaries(1 + i, |g| g.expr(arg))?;
+        }
+        Ok(())
+    }
+
+    /// Generates a `new` expression. The values of the fields, or of the parameters of
+    /// `init`, are pushed in order.
+    fn new_expr(&mut self, constructor: &Constructor, args: &[Arg]) -> Result<()> {
+        match &constructor.init {
+            None => {
+                self.sources(&constructor.fields, args, 0)?;
+                self.emit_opcode(OpCode::CreateInstance);
+                self.emit_u16(constructor.info);
+            }
+            Some((init, params)) => {
+                self.sources(&constructor.fields, args, 0)?;
+                self.emit_opcode(OpCode::CreateInstance);
+                self.emit_u16(constructor.info);
+                // one copy is `self` in init, the other is the result
+                self.emit_opcode(OpCode::Duplicate);
+                // the instance and its copy are below the arguments
+                self.sources(params, args, 2)?;
+                self.emit_call(*init, params.len() + 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pushes the value of every source, with `below` values under the first one.
+    fn sources(&mut self, sources: &[Source], args: &[Arg], below: usize) -> Result<()> {
+        for (i, source) in sources.iter().enumerate() {
+            match *source {
+                Source::Arg(arg) => {
+                    self.with_temporaries(below + i, |g| g.expr(&args[arg].value))?
+                }
+                Source::Default(index) => self.emit_call(index, 0),
+                Source::Nil => self.emit_opcode(OpCode::Nil),
+            }
+        }
+        Ok(())
+    }
+
+    /// Generates a `match`. Every arm tests the variant of the value and binds its
+    /// payload to new locals. The result of the arm is stored in a hidden local, which
+    /// is left on the stack as the value of the match.
+    fn match_expr(&mut self, match_expr: &Match) -> Result<()> {
+        self.frame.scopes.push(self.frame.locals.len());
+        let result_pos = self.frame.local_count;
+        self.emit_opcode(OpCode::Nil);
+        self.add_local("match result")?;
+        self.expr(&match_expr.value)?;
+        let value_pos = self.frame.local_count;
+        self.add_local("match value")?;
+
+        let mut end_jumps = vec![];
+        for arm in &match_expr.arms {
+            self.span = arm.span;
+            let next_arm = match arm.info {
+                Some(info) => {
+                    self.emit_opcode(OpCode::GetLocal);
+                    self.emit_u16(value_pos as u16);
+                    self.emit_opcode(OpCode::InstanceOf);
+                    self.emit_u16(info);
+                    self.emit_opcode(OpCode::JumpIfFalse);
+                    Some(self.chunk.emit_placeholder(self.span.line))
+                }
+                None => None,
+            };
+            self.frame.scopes.push(self.frame.locals.len());
+            for (field, binding) in arm.bindings.iter().enumerate() {
+                if binding == "_" {
+                    continue;
+                }
+                self.emit_opcode(OpCode::GetLocal);
+                self.emit_u16(value_pos as u16);
+                self.emit_opcode(OpCode::GetField);
+                self.emit_u16(field as u16);
+                self.add_local(binding)?;
+            }
+            match &arm.body {
+                ArmBody::Block(block) => self.block(block)?,
+                ArmBody::Expr(expr) => {
+                    self.expr(expr)?;
+                    if expr.kind != ExpressionKind::None {
+                        self.emit_opcode(OpCode::SetLocal);
+                        self.emit_u16(result_pos as u16);
+                    }
+                }
+            }
+            self.end_scope();
+
+            self.emit_opcode(OpCode::JumpForward);
+            end_jumps.push(self.chunk.emit_placeholder(self.span.line));
+            if let Some(jump) = next_arm {
+                self.replace_placeholder(jump)?;
+            }
+        }
+        for jump in end_jumps {
+            self.replace_placeholder(jump)?;
+        }
+
+        // the value is popped and the result is left as the value of the match
+        self.emit_opcode(OpCode::PopStack);
+        let locals = &mut self.frame.locals;
+        locals.truncate(locals.len() - 2);
+        self.frame.local_count -= 2;
+        self.frame.scopes.pop();
+        Ok(())
+    }
+
+    /// Emits a named function as a value.
+    fn function_value(&mut self, index: u16) {
+        self.emit_opcode(OpCode::Closure);
+        self.emit_u16(index);
+        self.emit_u16(0);
+    }
+
+    //
+    // LOCALS
+    //
+
+    /// Generates `f` while `count` values that are not locals sit on top of the stack,
+    /// so the locals it declares, like the bindings of a `match`, get the right slots.
+    fn with_temporaries(
+        &mut self,
+        count: usize,
+        f: impl FnOnce(&mut Self) -> Result<()>,
+    ) -> Result<()> {
+        self.frame.local_count += count;
+        let result = f(self);
+        self.frame.local_count -= count;
+        result
+    }
+
+    fn add_local(&mut self, name: &str) -> Result<()> {
+        if self.frame.local_count >= MAX_OPERAND {
+            return Err(CompilerError::TooManyLocals(self.span));
+        }
+        self.frame.locals.push(Local {
+            name: name.to_string(),
+            stack_pos: self.frame.local_count,
+        });
+        self.frame.local_count += 1;
+        Ok(())
+    }
+
+    /// The stack position of a local the checker resolved.
+    fn find_local(&self, name: &str) -> usize {
+        self.frame
+            .locals
+            .iter()
+            .rev()
+            .find(|l| l.name == name)
+            .expect("locals are resolved by the checker")
+            .stack_pos
+    }
+
+    /// The position of a global from the bottom of the stack.
+    fn global_slot(&self, module: usize, index: usize) -> Result<u16> {
+        let layout = &self.layouts[module];
+        let slot = layout.base + layout.first_global + index;
+        if slot > MAX_OPERAND {
+            return Err(CompilerError::TooManyLocals(self.span));
+        }
+        Ok(slot as u16)
+    }
+
+    fn start_scope(&mut self) {
+        self.frame.scopes.push(self.frame.locals.len());
+    }
+
+    fn end_scope(&mut self) {
+        let start_locals = self.frame.scopes.pop().expect("No scope exists.");
+        for _ in start_locals..self.frame.locals.len() {
+            self.frame.locals.pop();
+            self.frame.local_count -= 1;
+            self.emit_opcode(OpCode::PopStack);
+        }
+    }
+
+    //
+    // EMITTING
+    //
+
+    /// Calls the function at `index` with the top `arg_count` values of the stack as
+    /// its first locals.
+    fn emit_call(&mut self, index: u16, arg_count: usize) {
+        self.emit_opcode(OpCode::SetOffset);
+        self.emit_u16(arg_count as u16);
+        self.emit_opcode(OpCode::FunctionCall);
+        self.emit_u16(index);
+        self.emit_opcode(OpCode::PopOffset);
+    }
+
+    fn emit_int(&mut self, value: i64) -> Result<()> {
+        self.chunk.emit_int(value, self.span)
+    }
+
+    fn emit_opcode(&mut self, opcode: OpCode) {
+        self.chunk.emit_code(opcode as u8, self.span.line);
+    }
+
+    fn emit_u16(&mut self, value: u16) {
+        self.chunk.emit_u16(value, self.span.line);
+    }
+
+    /// Emits a `JumpBack` to `jump_point`, measured from the end of the instruction.
+    fn emit_jump_back(&mut self, jump_point: usize) -> Result<()> {
+        self.emit_opcode(OpCode::JumpBack);
+        let jump_len = self.chunk.code_len() + 2 - jump_point;
+        if jump_len > MAX_OPERAND {
+            return Err(CompilerError::JumpTooLarge(self.span));
+        }
+        self.emit_u16(jump_len as u16);
+        Ok(())
+    }
+
+    fn replace_placeholder(&mut self, placeholder: usize) -> Result<()> {
+        if !self.chunk.replace_placeholder(placeholder) {
+            return Err(CompilerError::JumpTooLarge(self.span));
+        }
+        Ok(())
+    }
+}
+
+/// Picks the instruction of a binary operator for the kinds of its operands.
+fn binary_opcode(
+    operator: BinaryOperator,
+    left: &ExpressionKind,
+    right: &ExpressionKind,
+) -> OpCode {
+    use ExpressionKind::{Bool, Float, Int, Nil, Optional, String};
+    let optional = matches!(left, Optional(_) | Nil) || matches!(right, Optional(_) | Nil);
+    match (operator, left, right) {
+        // an optional may be nil on either side, so the values are compared whole
+        (BinaryOperator::Equal, ..) if optional => OpCode::CompareValue,
+        (BinaryOperator::NotEqual, ..) if optional => OpCode::CompareValueNot,
+        (BinaryOperator::Equal, Bool, _) => OpCode::CompareBool,
+        (BinaryOperator::Equal, String, _) => OpCode::CompareString,
+        (BinaryOperator::Equal, Int, _) => OpCode::CompareInt,
+        (BinaryOperator::Equal, Float, _) => OpCode::CompareFloat,
+        (BinaryOperator::Equal, ..) => OpCode::CompareValue,
+        (BinaryOperator::NotEqual, Bool, _) => OpCode::CompareBoolNot,
+        (BinaryOperator::NotEqual, String, _) => OpCode::CompareStringNot,
+        (BinaryOperator::NotEqual, Int, _) => OpCode::CompareIntNot,
+        (BinaryOperator::NotEqual, Float, _) => OpCode::CompareFloatNot,
+        (BinaryOperator::NotEqual, ..) => OpCode::CompareValueNot,
+        (BinaryOperator::Is, ..) => OpCode::CompareIdentity,
+        (BinaryOperator::Less, Float, _) => OpCode::LessFloat,
+        (BinaryOperator::Less, ..) => OpCode::Less,
+        (BinaryOperator::LessEqual, Float, _) => OpCode::LessEqualFloat,
+        (BinaryOperator::LessEqual, ..) => OpCode::LessEqual,
+        (BinaryOperator::Greater, Float, _) => OpCode::GreaterFloat,
+        (BinaryOperator::Greater, ..) => OpCode::Greater,
+        (BinaryOperator::GreaterEqual, Float, _) => OpCode::GreaterEqualFloat,
+        (BinaryOperator::GreaterEqual, ..) => OpCode::GreaterEqual,
+        (BinaryOperator::Add, Bool, String) => OpCode::BoolStringConcat,
+        (BinaryOperator::Add, String, Bool) => OpCode::StringBoolConcat,
+        (BinaryOperator::Add, Int, String) => OpCode::IntStringConcat,
+        (BinaryOperator::Add, String, Int) => OpCode::StringIntConcat,
+        (BinaryOperator::Add, Float, String) => OpCode::FloatStringConcat,
+        (BinaryOperator::Add, String, Float) => OpCode::StringFloatConcat,
+        (BinaryOperator::Add, String, String) => OpCode::StringStringConcat,
+        // printing an instance may call its to_string method
+        (BinaryOperator::Add, String, _) => OpCode::StringValueConcat,
+        (BinaryOperator::Add, _, String) => OpCode::ValueStringConcat,
+        (BinaryOperator::Add, Float, _) => OpCode::AddFloat,
+        (BinaryOperator::Add, ..) => OpCode::Add,
+        (BinaryOperator::Subtract, Float, _) => OpCode::SubtractFloat,
+        (BinaryOperator::Subtract, ..) => OpCode::Subtract,
+        (BinaryOperator::Multiply, Float, _) => OpCode::MultiplyFloat,
+        (BinaryOperator::Multiply, ..) => OpCode::Multiply,
+        (BinaryOperator::Divide, Float, _) => OpCode::DivideFloat,
+        (BinaryOperator::Divide, ..) => OpCode::Divide,
+        (BinaryOperator::Modulo, Float, _) => OpCode::ModuloFloat,
+        (BinaryOperator::Modulo, ..) => OpCode::Modulo,
+        (BinaryOperator::And | BinaryOperator::Or, ..) => {
+            unreachable!("and and or jump over their right operand")
+        }
+    }
+}
+
+fn builtin_opcode(builtin: Builtin) -> OpCode {
+    match builtin {
+        Builtin::ArrayLen => OpCode::ArrayLen,
+        Builtin::ArrayPush => OpCode::ArrayPush,
+        Builtin::ArrayPop => OpCode::ArrayPop,
+        Builtin::ArrayMap => OpCode::ArrayMap,
+        Builtin::ArrayFilter => OpCode::ArrayFilter,
+        Builtin::ArraySort => OpCode::ArraySort,
+        Builtin::MapLen => OpCode::MapLen,
+        Builtin::MapGet => OpCode::MapGet,
+        Builtin::MapSet => OpCode::MapSet,
+        Builtin::MapHas => OpCode::MapHas,
+        Builtin::MapRemove => OpCode::MapRemove,
+        Builtin::MapKeys => OpCode::MapKeys,
+        Builtin::MapValues => OpCode::MapValues,
+    }
+}
@@ -1,1109 +1,321 @@
-use std::collections::HashMap;
+//! Compiles a bofink source and the modules it imports into a chunk. The files are
+//! parsed into syntax trees, checked, and then turned into bytecode.
 
-use crate::enums::{CompilerError, ExpressionKind, TokenKind};
+use std::fs;
+use std::path::{Path, PathBuf};
 
+use crate::ast::{self, StmtNode};
+use crate::enums::{CompilerError, Diagnostic};
+use crate::host::{HostKind, HostValue};
+use crate::native::NativeSignature;
 use crate::opcode::OpCode;
-use crate::scanner::{Scanner, Token};
-
-struct Compiler {
-    chunk: Chunk,
-    p: usize,
-    locals: Vec<Vec<Local>>,
-    local_count: usize,
-    tokens: Vec<Token>,
-    functions: HashMap<String, Function>,
-    function_return_kind: Option<ExpressionKind>,
-    scopes: Vec<usize>,
-    classes: Vec<Class>,
+use crate::scanner::Span;
+use crate::{checker, codegen, parser};
+
+/// Where the compiled source comes from and where its imports are looked up.
+#[derive(Debug, Clone, Default)]
+pub struct CompileConfig {
+    /// Path of the source file. Imports are looked up relative to it first.
+    pub file: Option<PathBuf>,
+    /// Directories searched for imports that are not found next to the importing
+    /// file.
+    pub search_path: Vec<PathBuf>,
+    /// The functions registered by the host that the source can call.
+    pub natives: Vec<NativeSignature>,
+    /// The values the host passes in by name. The source is checked against their
+    /// types, the values themselves are passed to the run.
+    pub inputs: Vec<(String, HostValue)>,
+    /// The types the host declared for inputs, instead of the types of their values.
+    pub input_types: Vec<(String, ast::Type)>,
 }
 
 type Result<T> = std::result::Result<T, CompilerError>;
 
-pub fn compile(source: String) -> Result<Chunk> {
-    let mut compiler = Compiler {
-        chunk: Chunk {
-            code: vec![vec![]],
-            line: vec![],
-            strings: vec![],
-            ints: vec![],
-            patch_list: vec![],
-            func_temp: vec![0],
-        },
-        p: 0,
-        locals: vec![vec![]],
-        functions: HashMap::new(),
-        function_return_kind: None,
-        local_count: 0,
-        scopes: vec![],
-        tokens: Scanner::get_tokens(source.clone()),
-        classes: vec![],
-    };
+/// Largest value that fits in an instruction operand.
+pub const MAX_OPERAND: usize = u16::MAX as usize;
 
-    match compiler.declaration() {
-        Ok(_) => Ok(compiler.chunk),
-        Err(e) => {
-            let line_index = compiler.current_line();
-            let line = source.lines().nth(line_index - 1).unwrap();
-            println!("{}", e);
-            println!("{}   _________", " ".repeat(line_index.to_string().len()));
-            println!("{}  |", " ".repeat(line_index.to_string().len()));
-            println!("{}  | {}", line_index, line);
-            println!("{}  |_________\n", " ".repeat(line_index.to_string().len()));
-            Err(e)
-        }
-    }
+#[cfg(test)]
+pub fn compile(source: String) -> std::result::Result<Chunk, Vec<Diagnostic>> {
+    compile_with_config(source, CompileConfig::default())
 }
 
-impl Compiler {
-    fn check_expression_kind(
-        &mut self,
-        kind: ExpressionKind,
-        expected: ExpressionKind,
-    ) -> Result<()> {
-        if kind != expected {
-            return Err(CompilerError::Type {
-                actual: kind,
-                expected,
-                line: self.current_line(),
-            });
-        }
-        Ok(())
-    }
-
-    /// Compiles an `expression` to bytecode.
-    fn expression(&mut self) -> Result<ExpressionKind> {
-        self.or()
-    }
-
-    fn or(&mut self) -> Result<ExpressionKind> {
-        let left_kind = self.and()?;
-        while self.current_kind() == TokenKind::Or {
-            self.check_expression_kind(left_kind, ExpressionKind::Bool)?;
-            self.p += 1;
-            let right_kind = self.and()?;
-            self.check_expression_kind(right_kind, ExpressionKind::Bool)?;
-            self.emit_opcode(OpCode::Or);
-            return Ok(ExpressionKind::Bool);
-        }
-        Ok(left_kind)
-    }
-
-    fn and(&mut self) -> Result<ExpressionKind> {
-        let left_kind = self.equality()?;
-        while self.current_kind() == TokenKind::And {
-            self.check_expression_kind(left_kind, ExpressionKind::Bool)?;
-            self.p += 1;
-            let right_kind = self.equality()?;
-            self.check_expression_kind(right_kind, ExpressionKind::Bool)?;
-            self.emit_opcode(OpCode::And);
-            return Ok(ExpressionKind::Bool);
-        }
-        Ok(left_kind)
-    }
-
-    fn equality(&mut self) -> Result<ExpressionKind> {
-        let left_kind = self.comparison()?;
-        let mut return_type = left_kind;
-        while self.current_kind() == TokenKind::BangEqual
-            || self.current_kind() == TokenKind::EqualEqual
-        {
-            let token_kind = self.current_kind();
-            self.p += 1;
-            let right_kind = self.comparison()?;
-            if left_kind != right_kind {
-                return Err(CompilerError::ComparisonType {
-                    first: left_kind,
-                    second: right_kind,
-                    line: self.current_line(),
-                });
-            }
-            match token_kind {
-                TokenKind::BangEqual => match left_kind {
-                    ExpressionKind::Bool => self.emit_opcode(OpCode::CompareBoolNot),
-                    ExpressionKind::String => self.emit_opcode(OpCode::CompareStringNot),
-                    ExpressionKind::Int => self.emit_opcode(OpCode::CompareIntNot),
-                    ExpressionKind::Class(_) => todo!("cant compare class"),
-                    ExpressionKind::None => {
-                        return Err(CompilerError::NoneValue {
-                            line: self.current_line(),
-                        })
-                    }
-                },
-                TokenKind::EqualEqual => match left_kind {
-                    ExpressionKind::Bool => self.emit_opcode(OpCode::CompareBool),
-                    ExpressionKind::String => self.emit_opcode(OpCode::CompareString),
-                    ExpressionKind::Int => self.emit_opcode(OpCode::CompareInt),
-                    ExpressionKind::Class(_) => todo!("cant compare class"),
-                    ExpressionKind::None => {
-                        return Err(CompilerError::NoneValue {
-                            line: self.current_line(),
-                        })
-                    }
-                },
-                _ => unreachable!(),
-            }
-            return_type = ExpressionKind::Bool;
-        }
-        Ok(return_type)
-    }
-
-    fn comparison(&mut self) -> Result<ExpressionKind> {
-        let left_kind = self.term()?;
-        let mut return_kind = left_kind;
-        loop {
-            match self.current_kind() {
-                TokenKind::Greater
-                | TokenKind::GreaterEqual
-                | TokenKind::Less
-                | TokenKind::LessEqual => {}
-                _ => break,
-            }
-            self.check_expression_kind(left_kind, ExpressionKind::Int)?;
-            let token_kind = self.current_kind();
-            self.p += 1;
-            let right_kind = self.term()?;
-            self.check_expression_kind(right_kind, ExpressionKind::Int)?;
-
-            match token_kind {
-                TokenKind::Greater => self.emit_opcode(OpCode::Greater),
-                TokenKind::GreaterEqual => self.emit_opcode(OpCode::GreaterEqual),
-                TokenKind::Less => self.emit_opcode(OpCode::Less),
-                TokenKind::LessEqual => self.emit_opcode(OpCode::LessEqual),
-                _ => unreachable!(),
-            }
-            return_kind = ExpressionKind::Bool;
-        }
-        Ok(return_kind)
-    }
-
-    fn term(&mut self) -> Result<ExpressionKind> {
-        let left_kind = self.factor()?;
-        let mut return_kind = left_kind;
-        loop {
-            let token_kind = self.current_kind();
-            match token_kind {
-                TokenKind::Minus | TokenKind::Plus => {}
-                _ => break,
-            }
-            self.p += 1;
-            let right_kind = self.factor()?;
-
-            match token_kind {
-                TokenKind::Minus => {
-                    self.check_expression_kind(left_kind, ExpressionKind::Int)?;
-                    self.check_expression_kind(right_kind, ExpressionKind::Int)?;
-                    self.emit_opcode(OpCode::Subtract)
-                }
-                TokenKind::Plus => match (left_kind, right_kind) {
-                    (ExpressionKind::Bool, ExpressionKind::String) => {
-                        self.emit_opcode(OpCode::BoolStringConcat)
-                    }
-                    (ExpressionKind::String, ExpressionKind::Bool) => {
-                        self.emit_opcode(OpCode::StringBoolConcat)
-                    }
-                    (ExpressionKind::Int, ExpressionKind::String) => {
-                        self.emit_opcode(OpCode::IntStringConcat)
-                    }
-                    (ExpressionKind::String, ExpressionKind::Int) => {
-                        self.emit_opcode(OpCode::StringIntConcat)
-                    }
-                    (ExpressionKind::String, ExpressionKind::String) => {
-                        self.emit_opcode(OpCode::StringStringConcat)
-                    }
-                    (ExpressionKind::Int, ExpressionKind::Int) => self.emit_opcode(OpCode::Add),
-                    _ => todo!("invalid types error"),
-                },
-                _ => unreachable!(),
-            }
-            if left_kind == ExpressionKind::String || right_kind == ExpressionKind::String {
-                return_kind = ExpressionKind::String;
-            } else {
-                return_kind = ExpressionKind::Int;
-            }
-        }
-        Ok(return_kind)
-    }
-
-    fn factor(&mut self) -> Result<ExpressionKind> {
-        let left_kind = self.unary()?;
-        let mut return_kind = left_kind;
-        loop {
-            match self.current_kind() {
-                TokenKind::Slash | TokenKind::Star | TokenKind::Percent => {}
-                _ => break,
-            }
-            self.check_expression_kind(left_kind, ExpressionKind::Int)?;
-            let token_kind = self.current_kind();
-            self.p += 1;
-            let right_kind = self.unary()?;
-            self.check_expression_kind(right_kind, ExpressionKind::Int)?;
-
-            match token_kind {
-                TokenKind::Slash => self.emit_opcode(OpCode::Divide),
-                TokenKind::Star => self.emit_opcode(OpCode::Multiply),
-                TokenKind::Percent => self.emit_opcode(OpCode::Modulo),
-                _ => unreachable!(),
-            }
-            return_kind = ExpressionKind::Int;
-        }
-        Ok(return_kind)
-    }
-
-    fn unary(&mut self) -> Result<ExpressionKind> {
-        match self.current_kind() {
-            TokenKind::Bang => {
-                self.p += 1;
-                let kind = self.unary()?;
-                self.check_expression_kind(kind, ExpressionKind::Bool)?;
-                self.emit_opcode(OpCode::Not);
-                Ok(kind)
-            }
-            TokenKind::Minus => {
-                self.p += 1;
-                let kind = self.unary()?;
-                self.check_expression_kind(kind, ExpressionKind::Int)?;
-                self.emit_opcode(OpCode::Negate);
-                Ok(kind)
-            }
-            _ => {
-                self.primary()
-            }
-        }
-    }
-
-    fn primary(&mut self) -> Result<ExpressionKind> {
-        let curr_kind = self.current_kind();
-        self.p += 1;
-        match curr_kind {
-            TokenKind::False => {
-                self.emit_opcode(OpCode::False);
-                Ok(ExpressionKind::Bool)
-            }
-            TokenKind::True => {
-                self.emit_opcode(OpCode::True);
-                Ok(ExpressionKind::Bool)
-            }
-            TokenKind::Nil => {
-                panic!("rd_primary -> Nil")
-            }
-            TokenKind::Number => {
-                self.chunk.emit_number(&self.tokens[self.p - 1]);
-                Ok(ExpressionKind::Int)
-            }
-            TokenKind::String => {
-                self.chunk.emit_string(&self.tokens[self.p - 1]);
-                Ok(ExpressionKind::String)
-            }
-            TokenKind::New => {
-                self.class_call()
-            }
-            TokenKind::Identifier => {
-                let identifier = self.tokens[self.p - 1].value.to_string();
-                match self.tokens[self.p].kind {
-                    // function call Todo: just dont...
-                    TokenKind::LeftParen => Ok(self.function_call(identifier)?.unwrap()),
-                    TokenKind::Dot => {
-                        let mut kind = self.get_local()?;
-                        loop {
-                            self.p += 1;
-                            let consumed_token = self.consume_token(TokenKind::Identifier)?;
-                            self.emit_opcode(OpCode::GetField);
-                            match kind {
-                                ExpressionKind::Class(x) => {
-                                    let temp = self.classes[x as usize]
-                                        .fields
-                                        .iter()
-                                        .position(|f| f.0 == consumed_token.value)
-                                        .unwrap();
-                                    self.emit_u8(temp as u8);
-                                    kind = self.classes[x as usize].fields[temp].1;
-                                }
-                                _ => panic!("not a class"),
-                            }
-
-                            if self.current_kind() != TokenKind::Dot {
-                                break;
-                            }
-                        }
-
-                        Ok(kind)
-                    }
-                    _ => self.get_local(),
-                }
-            }
-            TokenKind::LeftParen => {
-                // Todo:???
-                let kind = self.expression();
-                self.consume_token(TokenKind::RightParen)?;
-                kind
-            }
-            _ => unreachable!("Not a valid token: {:?}", curr_kind),
-        }
-    }
-
-    fn get_local(&mut self) -> Result<ExpressionKind> {
-        let res = match self.locals.last() {
-            Some(l_vec) => match l_vec
-                .iter()
-                .find(|l| l.name == self.tokens[self.p - 1].value)
-            {
-                Some(l) => (l.stack_pos, l.kind),
-                None => {
-                    return Err(CompilerError::MissingLocal {
-                        name: self.tokens[self.p - 1].value.to_string(),
-                        line: self.current_line(),
-                    })
-                }
-            },
-            None => unreachable!("Locals Vec should never be empty"),
-        };
-        self.emit_opcode(OpCode::GetLocal);
-        self.emit_u8(res.0 as u8);
-        Ok(res.1)
-    }
-
-    fn consume_token(&mut self, kind: TokenKind) -> Result<Token> {
-        let token = &self.tokens[self.p];
-        if token.kind != kind {
-            return Err(CompilerError::UnexpectedToken {
-                expected: kind,
-                actual: token.kind,
-                line: token.line,
-            });
-        }
-        self.p += 1;
-        Ok(Token {
-            kind: token.kind,
-            line: token.line,
-            column: token.column,
-            value: token.value.to_string(),
-        })
-    }
-
-    fn consume_if_match(&mut self, kind: TokenKind) -> Option<Token> {
-        let token = &self.tokens[self.p];
-        if token.kind == kind {
-            self.p += 1;
-            return Some(Token {
-                kind: token.kind,
-                line: token.line,
-                column: token.column,
-                value: token.value.to_string(),
-            });
-        }
-        None
-    }
-
-    fn local_declaration(&mut self) -> Result<()> {
-        let is_mut = match self.current_kind() {
-            TokenKind::Mut => true,
-            TokenKind::Let => false,
-            _ => unreachable!(),
-        };
-        self.p += 1;
-
-        let consumed_token = self.consume_token(TokenKind::Identifier)?;
-        let identifier = &consumed_token.value.to_string();
-        if self
-            .locals
-            .last()
-            .unwrap()
-            .iter()
-            .any(|x| &x.name == identifier)
-        {
-            return Err(CompilerError::Redeclaration(consumed_token.line));
-        }
-
-        let type_kind = match self.consume_if_match(TokenKind::Colon) {
-            Some(_) => {
-                self.p += 1;
-                match self.tokens[self.p - 1].kind {
-                    TokenKind::Int => ExpressionKind::Int,
-                    TokenKind::Bool => ExpressionKind::Bool,
-                    TokenKind::String => ExpressionKind::String,
-                    _ => {
-                        return Err(CompilerError::NotAType {
-                            kind: self.tokens[self.p].kind,
-                            line: self.current_line(),
-                        })
-                    }
-                }
-            }
-            None => ExpressionKind::None,
-        };
-        let consumed_token = self.consume_token(TokenKind::Equal)?;
-        let kind = self.expression()?;
-
-        if type_kind != ExpressionKind::None && kind != type_kind {
-            return Err(CompilerError::DelcarationType {
-                expected: type_kind,
-                actual: kind,
-                line: consumed_token.line,
-            });
-        }
-
-        self.add_local(identifier, kind, is_mut);
-        self.consume_token(TokenKind::Semicolon)?;
-        Ok(())
-    }
-    fn class_call(&mut self) -> Result<ExpressionKind> {
-        let identifier = self.consume_token(TokenKind::Identifier)?.value.to_string();
-
-        let mut field_types: Vec<ExpressionKind> = vec![];
-        for class in &self.classes {
-            if class.name == identifier {
-                for field in &class.fields {
-                    field_types.push(field.1);
-                }
-            }
-        }
-        let idx = self
-            .classes
-            .iter()
-            .position(|class| class.name == identifier)
-            .unwrap() as u8;
-
-        self.consume_token(TokenKind::LeftParen)?;
-        let mut field_count = 0;
-        for field_kind in &field_types {
-            let kind = self.expression()?;
-            if field_kind != &kind {
-                return Err(CompilerError::Type {
-                    actual: kind,
-                    expected: *field_kind,
-                    line: self.current_line(),
-                });
-            }
-            field_count += 1;
-            if field_count != field_types.len() {
-                self.consume_token(TokenKind::Comma)?;
-            }
-        }
-
-        self.emit_opcode(OpCode::CreateInstance);
-        self.emit_u8(field_count as u8);
-
-        self.consume_token(TokenKind::RightParen)?;
-        Ok(ExpressionKind::Class(idx))
-    }
-    fn class_declaration(&mut self) -> Result<()> {
-        self.consume_token(TokenKind::Class)?;
-        let identifier = self.consume_token(TokenKind::Identifier)?.value.to_string();
-        self.consume_token(TokenKind::LeftBrace)?;
-
-        let mut class = Class {
-            name: identifier,
-            fields: vec![],
-        };
-
-        while self.current_kind() != TokenKind::RightBrace {
-            let kind = match self.current_kind() {
-                TokenKind::Int => ExpressionKind::Int,
-                TokenKind::Str => ExpressionKind::String,
-                TokenKind::Bool => ExpressionKind::Bool,
-                TokenKind::Fun => {
-                    continue;
-                }
-                TokenKind::Identifier => ExpressionKind::Class(
-                    self.classes
-                        .iter()
-                        .position(|c| &c.name == &self.tokens[self.p].value)
-                        .unwrap() as u8,
-                ),
-                _ => todo!(
-                    "KIND PANIC in class_declaration - kind: {:?}",
-                    self.current_kind()
-                ),
-            };
-            self.p += 1;
-            let param_identifier = self.consume_token(TokenKind::Identifier)?.value.to_string();
-            class.fields.push((param_identifier, kind));
-            self.consume_token(TokenKind::Semicolon)?;
-        }
-        self.consume_token(TokenKind::RightBrace)?;
-        self.classes.push(class);
-        Ok(())
-    }
-
-    // creating and instance is probably a primary?
-    // runtime object
-    // name, idx, fields(type(a number), value(string|int|bool|etc))
-    // syntax
-    // token::new, token::identifier, token::left_parent, expression*(params), token::right_paren
-    // emit: create object
-    // emit: add values to param if exists
-    // done?
-    //
-    // then access values with .
-    //
-
-    fn declaration(&mut self) -> Result<()> {
-        loop {
-            match self.current_kind() {
-                // type should be a first class member?
-                // 'typeof' built in function?
-                TokenKind::Mut | TokenKind::Let => {
-                    self.local_declaration()?;
-                }
-                TokenKind::Class => {
-                    self.class_declaration()?;
-                }
-                // vad ar detta????
-                // end scope bara losa allt
-                TokenKind::RightBrace => {
-                    self.p += 1;
-                    return Ok(());
-                }
-                // Function declaration
-                TokenKind::Fun => {
-                    self.function_declaration()?;
-                }
-
-                TokenKind::Eof => return Ok(()),
-                _ => {
-                    self.statement()?;
-                }
-            }
-        }
-    }
-
-    fn function_declaration(&mut self) -> Result<()> {
-        self.p += 1;
-        self.locals.push(vec![]);
-        self.local_count = 0;
-        let identifier = &self.tokens[self.p].value.to_string();
-
-        if self.functions.contains_key(identifier) {
-            return Err(CompilerError::Redeclaration(self.current_line()));
+/// Compiles a source, or returns every error found in it and its imports, in source
+/// order.
+pub fn compile_with_config(
+    source: String,
+    config: CompileConfig,
+) -> std::result::Result<Chunk, Vec<Diagnostic>> {
+    let file = config.file.map(|file| file.canonicalize().unwrap_or(file));
+    let mut loader = Loader {
+        modules: vec![],
+        loaded: vec![],
+        search_path: config.search_path,
+        errors: vec![],
+    };
+    loader.load(file, "<script>", source);
+    let mut modules = loader.modules;
+    // the statements that parsed are checked even if others did not, but only a
+    // source without any error is turned into bytecode
+    let mut errors = loader.errors;
+    let result = match checker::check(
+        &mut modules,
+        &config.natives,
+        &config.inputs,
+        &config.input_types,
+    ) {
+        Ok(program) if errors.is_empty() => {
+            codegen::generate(&modules, program).map_err(|error| vec![error])
+        }
+        Ok(_) => Err(errors),
+        Err(checker_errors) => {
+            errors.extend(checker_errors);
+            Err(errors)
         }
+    };
+    result.map_err(|errors| diagnostics(errors, &modules))
+}
 
-        self.chunk.new_function();
-
-        let fun_count = self.functions.len();
-        if fun_count >= u8::MAX as usize {
-            return Err(CompilerError::MaxFunctions);
-        }
-        self.p += 1;
-        self.consume_token(TokenKind::LeftParen)?;
-        let mut function = Function {
-            index: fun_count as u8 + 1,
-            params: vec![],
-            return_type: None,
-        };
-        while self.current_kind() != TokenKind::RightParen {
-            let consumed_token = self.consume_token(TokenKind::Identifier)?;
-            let param_name = &consumed_token.value.to_string();
-            self.consume_token(TokenKind::Colon)?;
-            let param_kind = match self.current_kind() {
-                TokenKind::Int => ExpressionKind::Int,
-                TokenKind::Bool => ExpressionKind::Bool,
-                TokenKind::Str => ExpressionKind::String,
-                _ => return Err(CompilerError::UnknownParamType(self.current_line())),
+/// Locates every error in the source of its file, and sorts them in source order.
+fn diagnostics(errors: Vec<CompilerError>, modules: &[ast::Module]) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = errors
+        .into_iter()
+        .map(|error| {
+            let module = match error.file() {
+                Some(file) => modules.iter().find(|m| {
+                    m.path
+                        .as_ref()
+                        .is_some_and(|path| path.display().to_string() == file)
+                }),
+                None => modules.first(),
             };
-            self.p += 1;
-            function.params.push(Param { kind: param_kind });
-            self.add_local(param_name, param_kind, true);
-            self.consume_if_match(TokenKind::Comma);
-        }
-
-        self.consume_token(TokenKind::RightParen)?;
-        function.return_type = match self.current_kind() {
-            TokenKind::LeftBrace => None,
-            TokenKind::Int => {
-                self.p += 1;
-                Some(ExpressionKind::Int)
-            }
-            TokenKind::Str => {
-                self.p += 1;
-                Some(ExpressionKind::String)
-            }
-            TokenKind::Bool => {
-                self.p += 1;
-                Some(ExpressionKind::Bool)
-            }
-            _ => panic!("TODO!"),
-        };
-        self.function_return_kind = function.return_type;
-
-        self.functions.insert(identifier.to_string(), function);
-        self.consume_token(TokenKind::LeftBrace)?;
-        self.declaration()?;
-
-        self.emit_opcode(OpCode::Return);
-        self.emit_u8(self.local_count as u8);
-        self.locals.pop();
-        self.local_count = self.locals.last().unwrap().len();
-        self.function_return_kind = None;
-        self.chunk.end_function();
-        Ok(())
-    }
-
-    fn start_scope(&mut self) -> Result<()> {
-        self.consume_token(TokenKind::LeftBrace)?;
-        self.scopes
-            .push(self.locals.last().expect("Locals is empty.").len());
-        Ok(())
-    }
+            let source = module.map_or("", |m| m.source.as_str());
+            Diagnostic::new(error, source)
+        })
+        .collect();
+    diagnostics.sort_by(|a, b| {
+        let start = |d: &Diagnostic| d.error.span().map(|span| span.start);
+        (&a.file, start(a)).cmp(&(&b.file, start(b)))
+    });
+    diagnostics
+}
 
-    fn end_scope(&mut self) {
-        let end_locals = self.locals.last().expect("Locals is empty.").len();
-        let start_locals = self.scopes.pop().expect("No scope exists.");
-        for _ in 0..(end_locals - start_locals) {
-            self.locals.last_mut().expect("Locals is empty.").pop();
-            self.emit_opcode(OpCode::PopStack);
-        }
-    }
+/// Reads and parses the compiled source and every module it imports.
+struct Loader {
+    /// The first module is the compiled source.
+    modules: Vec<ast::Module>,
+    /// Whether the imports of every module have been loaded.
+    loaded: Vec<bool>,
+    search_path: Vec<PathBuf>,
+    /// The syntax errors of every file, and the errors of the imports that could not
+    /// be loaded.
+    errors: Vec<CompilerError>,
+}
 
-    fn add_local(&mut self, name: &str, kind: ExpressionKind, is_mut: bool) {
-        self.locals.last_mut().unwrap().push(Local {
+impl Loader {
+    /// Parses a file and loads the modules it imports, depth first. Returns the index
+    /// of the new module.
+    fn load(&mut self, path: Option<PathBuf>, name: &str, source: String) -> usize {
+        let module = self.modules.len();
+        let (mut file, errors) = parser::parse(&source);
+        self.modules.push(ast::Module {
             name: name.to_string(),
-            stack_pos: self.local_count,
-            is_mut,
-            kind,
+            path: path.clone(),
+            source,
+            file: ast::File::default(),
+            init: 0,
         });
-        self.local_count += 1;
-    }
-
-    //
-    // STATEMENTS START
-    //
-
-    /// Finds and compiles the correct statement to bytecode.
-    fn statement(&mut self) -> Result<()> {
-        match self.current_kind() {
-            TokenKind::While => self.while_stmt()?,
-            TokenKind::If => self.if_stmt()?,
-            TokenKind::Print => self.print_stmt()?,
-            TokenKind::Return => self.return_stmt()?,
-            TokenKind::Identifier => self.identifier_stmt(self.tokens[self.p].value.to_string())?,
-            TokenKind::For => self.for_stmt()?,
-            // dont know if I should allow arbitrary blocks
-            //TokenKind::LeftBrace => {}
-            _ => {
-                self.expression()?;
+        self.loaded.push(false);
+        for error in errors {
+            self.report(module, error);
+        }
+
+        let base = path.as_ref().and_then(|path| path.parent());
+        // imports anywhere else are rejected by the checker
+        for stmt in &mut file.stmts {
+            if let StmtNode::Import(import) = &mut stmt.node {
+                match self.import(base, import) {
+                    Ok(imported) => import.module = Some(imported),
+                    Err(error) => self.report(module, error),
+                }
             }
         }
-        Ok(())
-    }
-
-    /// Compiles a `while` statement to bytecode.
-    fn while_stmt(&mut self) -> Result<()> {
-        let jump_point = self.chunk.code[*self.chunk.func_temp.last().unwrap()].len();
-        self.p += 1;
-        self.expression()?;
-        self.emit_opcode(OpCode::SetJump);
-        self.chunk.emit_placeholder(0);
-        self.emit_opcode(OpCode::JumpIfFalse);
-        self.start_scope()?;
-        self.declaration()?;
-        self.end_scope();
-        self.emit_opcode(OpCode::SetJump);
-        self.emit_u8(
-            (self.chunk.code[*self.chunk.func_temp.last().unwrap()].len() - jump_point + 2) as u8,
-        );
-        self.emit_opcode(OpCode::JumpBack);
-        self.chunk.replace_placeholder();
-        Ok(())
+        self.modules[module].file = file;
+        self.loaded[module] = true;
+        module
     }
 
-    /// Compiles an `if` statement to bytecode.
-    fn if_stmt(&mut self) -> Result<()> {
-        self.p += 1;
-        self.expression()?;
-        self.emit_opcode(OpCode::SetJump);
-        self.chunk.emit_placeholder(0);
-        self.emit_opcode(OpCode::JumpIfFalse);
-        self.start_scope()?;
-        self.declaration()?;
-        self.end_scope();
-        self.chunk.replace_placeholder();
-        Ok(())
-    }
-
-    /// Compiles a `print` statement to bytecode.
-    fn print_stmt(&mut self) -> Result<()> {
-        self.p += 1;
-        self.expression()?;
-        self.emit_opcode(OpCode::Print);
-        self.consume_token(TokenKind::Semicolon)?;
-        Ok(())
-    }
-
-    /// Compiles a `return` statement to bytecode.
-    fn return_stmt(&mut self) -> Result<()> {
-        self.p += 1;
-        let return_type = self.expression()?;
-        match self.function_return_kind {
-            Some(kind) => {
-                if kind != return_type {
-                    return Err(CompilerError::Type {
-                        actual: return_type,
-                        expected: kind,
-                        line: self.current_line(),
-                    });
-                }
-                self.emit_opcode(OpCode::ReturnValue);
-                self.emit_u8(self.local_count as u8);
-            }
+    /// Loads an imported file, unless it already is.
+    fn import(&mut self, base: Option<&Path>, import: &ast::Import) -> Result<usize> {
+        let path = self.resolve(base, &import.path, import.span)?;
+        match self
+            .modules
+            .iter()
+            .position(|m| m.path.as_ref() == Some(&path))
+        {
+            Some(module) if !self.loaded[module] => Err(CompilerError::CircularImport {
+                path: import.path.to_string(),
+                span: import.span,
+            }),
+            Some(module) => Ok(module),
             None => {
-                if return_type != ExpressionKind::None {
-                    return Err(CompilerError::ReturnValueFromVoid {
-                        kind: return_type,
-                        line: self.current_line(),
+                let Ok(source) = fs::read_to_string(&path) else {
+                    return Err(CompilerError::ImportNotFound {
+                        path: path.display().to_string(),
+                        span: import.span,
                     });
-                }
-                self.emit_opcode(OpCode::Return);
-            }
-        }
-        self.consume_token(TokenKind::Semicolon)?;
-        Ok(())
-    }
-
-    /// Compiles an `indentifier` statement to bytecode.
-    fn identifier_stmt(&mut self, identifier_name: String) -> Result<()> {
-        self.p += 1;
-        match &self.current_kind() {
-            // Reassignment
-            TokenKind::Equal => {
-                self.p += 1;
-                let kind = self.expression()?;
-                self.emit_opcode(OpCode::SetLocal);
-                for local in self.locals.last().unwrap() {
-                    if local.name == identifier_name {
-                        if local.kind != kind {
-                            return Err(CompilerError::ReassignmentType {
-                                expected: local.kind,
-                                actual: kind,
-                                line: self.tokens[self.p].line,
-                            });
-                        }
-                        if !local.is_mut {
-                            let error_token = Self::get_error_token(&self.tokens[self.p]);
-                            return Err(CompilerError::CantMut { token: error_token });
-                        }
-                        self.emit_u8(local.stack_pos as u8);
-                        break;
-                    }
-                }
-            }
-            // function call
-            TokenKind::LeftParen => {
-                // This kind is never needed?
-                let _kind = self.function_call(identifier_name)?;
-            }
-            // Reassign instance value
-            TokenKind::Dot => {
-                // find the local
-                let local_kind = self
-                    .locals
-                    .last()
-                    .unwrap()
-                    .iter()
-                    .find(|local| local.name == identifier_name)
-                    .unwrap()
-                    .kind;
-                let local_is_mut = self
-                    .locals
-                    .last()
-                    .unwrap()
-                    .iter()
-                    .find(|local| local.name == identifier_name)
-                    .unwrap()
-                    .is_mut;
-                self.get_local()?;
-                // get the class idx and field kind
-                // if field kind is class - repeat
-                // else use field_idx
-                if !local_is_mut {
-                    let error_token = Self::get_error_token(&self.tokens[self.p]);
-                    // TODO: name should be included in the error messasge
-                    return Err(CompilerError::CantMut { token: error_token });
-                }
-                let mut field_idxs: Vec<u8> = vec![];
-
-                let mut class_idx = match local_kind {
-                    ExpressionKind::Class(c) => c,
-                    _ => panic!("must be class"),
                 };
-                let reassignment_kind = loop {
-                    self.p += 1;
-                    let consumed_token = self.consume_token(TokenKind::Identifier)?;
-                    let field_kind = self.classes[class_idx as usize]
-                        .fields
-                        .iter()
-                        .find(|f| f.0 == consumed_token.value)
-                        .unwrap()
-                        .1;
-                    let field_idx = self.classes[class_idx as usize]
-                        .fields
-                        .iter()
-                        .position(|f| f.0 == consumed_token.value)
-                        .unwrap();
-                    class_idx = match field_kind {
-                        ExpressionKind::Class(c) => c,
-                        _ => 0,
-                    };
-                    field_idxs.push(field_idx as u8);
-                    if self.current_kind() != TokenKind::Dot {
-                        break field_kind
-                    }
-                };
-                self.consume_token(TokenKind::Equal)?;
-                let exp_kind = self.expression()?;
-
-                if exp_kind != reassignment_kind {
-                    return Err(CompilerError::Type {
-                        expected: reassignment_kind,
-                        actual: exp_kind,
-                        line: self.current_line(),
-                    });
-                }
-                self.emit_opcode(OpCode::SetField);
-                self.emit_u8(field_idxs.len() as u8);
-                for f_idx in field_idxs {
-                    self.emit_u8(f_idx);
-                }
-
-            }
-            _ => {
-                // Is this even possible? maybe just panic
-                return Err(CompilerError::InvalidToken {
-                    actual: self.current_kind(),
-                    line: self.current_line(),
-                });
-            }
-        }
-        self.consume_token(TokenKind::Semicolon)?;
-        Ok(())
-    }
-
-    /// Compiles a `for` statement to bytecode.
-    fn for_stmt(&mut self) -> Result<()> {
-        self.p += 1;
-        let consumed_token = self.consume_token(TokenKind::Identifier)?;
-        let iter_name = &consumed_token.value.to_string();
-        self.consume_token(TokenKind::In)?;
-        let consumed_token = self.consume_token(TokenKind::Number)?;
-        let loop_start = &consumed_token.value.parse::<i64>().unwrap();
-
-        // Emit local
-        self.chunk.emit_number(&consumed_token);
-        self.add_local(iter_name, ExpressionKind::Int, true);
-
-        let jump_point = self.chunk.code[*self.chunk.func_temp.last().unwrap()].len();
-
-        // move on
-        self.consume_token(TokenKind::Colon)?;
-
-        // Get the local
-        self.emit_opcode(OpCode::GetLocal);
-        let iterator_stack_pos = self
-            .locals
-            .last()
-            .expect("Should always be the last added local-stack")
-            .last()
-            .expect("Should always be the last added local")
-            .stack_pos as u8;
-        self.emit_u8(iterator_stack_pos);
-
-        // push the max to stack
-        let consumed_token = self.consume_token(TokenKind::Number)?;
-
-        let loop_end = &consumed_token.value.parse::<i64>().unwrap();
-        // TODO: emit number self()?
-        self.chunk.emit_number(&consumed_token);
-        if loop_start <= loop_end {
-            self.emit_opcode(OpCode::Less);
-        } else {
-            self.emit_opcode(OpCode::Greater);
-        }
-
-        // Setup jump
-        self.emit_opcode(OpCode::SetJump);
-        self.chunk.emit_placeholder(0);
-        self.emit_opcode(OpCode::JumpIfFalse);
-
-        let mut step = 0;
-        let mut negative_increment = false;
-
-        // check for custom increment
-        if let Some(_) = self.consume_if_match(TokenKind::Colon) {
-            if let Some(_) = self.consume_if_match(TokenKind::Minus) {
-                negative_increment = true;
-            }
-            let consumed_token = self.consume_token(TokenKind::Number)?;
-            step = consumed_token.value.parse::<i64>().unwrap();
-            if negative_increment {
-                step *= -1;
-            }
-        }
-        self.start_scope()?;
-        self.declaration()?;
-        self.end_scope();
-
-        self.emit_opcode(OpCode::GetLocal);
-        self.emit_u8(iterator_stack_pos);
-
-        // Push the increment to the stack
-        if step == 0 {
-            if loop_start <= loop_end {
-                step = 1;
-            } else {
-                step = -1
-            }
-        }
-        // TODO: Dont use dummy token
-        let dummy_token = Token {
-            value: step.to_string(),
-            kind: TokenKind::Number,
-            line: 0,
-            column: 0,
+                Ok(self.load(Some(path), &import.name, source))
+            }
+        }
+    }
+
+    /// Finds an imported file next to the importing file, or else in the search path.
+    /// A source that is not a file only imports from the search path.
+    fn resolve(&self, base: Option<&Path>, spec: &str, span: Span) -> Result<PathBuf> {
+        base.into_iter()
+            .chain(self.search_path.iter().map(PathBuf::as_path))
+            .filter_map(|dir| dir.join(spec).canonicalize().ok())
+            .find(|path| path.is_file())
+            .ok_or(CompilerError::ImportNotFound {
+                path: spec.to_string(),
+                span,
+            })
+    }
+
+    /// Records an error of a module. Errors in an imported file say which file it is.
+    fn report(&mut self, module: usize, error: CompilerError) {
+        let error = match module {
+            0 => error,
+            _ => CompilerError::InFile {
+                file: self.modules[module]
+                    .path
+                    .as_ref()
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_default(),
+                error: Box::new(error),
+            },
         };
-        // todo: self.emit_number?
-        self.chunk.emit_number(&dummy_token);
-        self.emit_opcode(OpCode::Add);
-        self.emit_opcode(OpCode::SetLocal);
-        self.emit_u8(iterator_stack_pos);
-        self.emit_opcode(OpCode::SetJump);
-        self.emit_u8(
-            (self.chunk.code[*self.chunk.func_temp.last().unwrap()].len() - jump_point + 2) as u8,
-        );
-        self.emit_opcode(OpCode::JumpBack);
-        self.chunk.replace_placeholder();
-        Ok(())
-    }
-
-    // STATEMENTS END
-    //
-
-    // TODO  handle the case where the function has a return type
-    fn function_call(&mut self, identifier_name: String) -> Result<Option<ExpressionKind>> {
-        self.consume_token(TokenKind::LeftParen)?;
-        let function = self.functions[&identifier_name].clone();
-        for param in function.params.clone() {
-            let kind = self.expression()?;
-            if kind != param.kind {
-                return Err(CompilerError::ParamType {
-                    expected: param.kind,
-                    actual: kind,
-                    line: self.current_line(),
-                });
-            }
-            self.local_count += 1;
-            self.consume_if_match(TokenKind::Comma);
-        }
-
-        self.emit_opcode(OpCode::SetOffset);
-        self.emit_u8(function.params.len() as u8);
-        self.emit_opcode(OpCode::FunctionCall);
-        self.emit_u8(function.index);
-
-        self.consume_token(TokenKind::RightParen)?;
-        for _ in 0..function.params.len() {
-            self.local_count -= 1;
-        }
-        self.emit_opcode(OpCode::PopOffset);
-
-        Ok(function.return_type)
+        self.errors.push(error);
     }
-
-    fn emit_opcode(&mut self, opcode: OpCode) {
-        self.chunk.emit_code(opcode as u8, self.current_line());
-    }
-    fn emit_u8(&mut self, b: u8) {
-        self.chunk.emit_code(b, self.current_line());
-    }
-
-    fn get_error_token(token: &Token) -> Token {
-        Token {
-            kind: token.kind,
-            line: token.line,
-            column: token.column,
-            value: token.value.to_string(),
-        }
-    }
-
-    fn current_line(&self) -> usize {
-        self.tokens[self.p].line
-    }
-    fn current_kind(&self) -> TokenKind {
-        self.tokens[self.p].kind
-    }
-}
-
-#[derive(Debug)]
-struct Local {
-    kind: ExpressionKind,
-    name: String,
-    is_mut: bool,
-    stack_pos: usize,
 }
 
-#[derive(Clone)]
-struct Function {
-    index: u8,
-    params: Vec<Param>,
-    return_type: Option<ExpressionKind>,
-}
-
-// TODO
 #[derive(Debug)]
-struct Class {
-    name: String,
-    fields: Vec<(String, ExpressionKind)>,
-}
-
-#[derive(Clone)]
-struct Param {
-    kind: ExpressionKind,
+pub struct ClassInfo {
+    pub name: String,
+    pub fields: Vec<String>,
+    /// Index of the `to_string` method, if the class has one.
+    pub to_string: Option<usize>,
+    /// Whether this is a variant of an enum rather than a class.
+    pub variant: bool,
+    /// The type of every field of a class, if values of it can be passed in by the
+    /// host.
+    pub(crate) field_kinds: Vec<Option<HostKind>>,
 }
 
+/// The bytecode of a compiled program, ready to be run.
 #[derive(Debug)]
 pub struct Chunk {
-    pub code: Vec<Vec<u8>>,
-    pub line: Vec<usize>,
-    pub strings: Vec<String>,
-    pub ints: Vec<i64>,
-    pub patch_list: Vec<usize>,
-    pub func_temp: Vec<usize>,
+    pub(crate) code: Vec<Vec<u8>>,
+    /// Source line of every emitted byte, per function.
+    pub(crate) line: Vec<Vec<usize>>,
+    /// Name of every function, used for runtime stack traces.
+    pub(crate) function_names: Vec<String>,
+    pub(crate) strings: Vec<String>,
+    pub(crate) ints: Vec<i64>,
+    pub(crate) floats: Vec<f64>,
+    /// What the vm needs to know about every class to print its instances.
+    pub(crate) classes: Vec<ClassInfo>,
+    pub(crate) func_temp: Vec<usize>,
+    /// The inputs the source uses, by the index `GetInput` uses, with their types.
+    pub(crate) inputs: Vec<(String, HostKind)>,
+    /// The stack slot of every global of the compiled source, read back after a run.
+    pub(crate) globals: Vec<(String, usize)>,
 }
 
 impl Chunk {
-    fn new_function(&mut self) {
-        self.func_temp.push(self.code.len());
-        self.code.push(vec![]);
+    /// Creates a chunk without code for the functions, classes and inputs of a program.
+    pub(crate) fn new(
+        function_names: Vec<String>,
+        classes: Vec<ClassInfo>,
+        inputs: Vec<(String, HostKind)>,
+    ) -> Self {
+        Chunk {
+            code: vec![vec![]; function_names.len()],
+            line: vec![vec![]; function_names.len()],
+            function_names,
+            strings: vec![],
+            ints: vec![],
+            floats: vec![],
+            classes,
+            func_temp: vec![0],
+            inputs,
+            globals: vec![],
+        }
     }
-    fn end_function(&mut self) {
+    /// Emits the following code into the function at `index`.
+    pub(crate) fn begin_function(&mut self, index: u16) {
+        self.func_temp.push(index as usize);
+    }
+    pub(crate) fn end_function(&mut self) {
         self.func_temp.pop();
     }
-    fn emit_placeholder(&mut self, line: usize) {
-        self.patch_list
-            .push(self.code[*self.func_temp.last().unwrap()].len());
-        self.code[*self.func_temp.last().unwrap()].push(0);
-        self.line.push(line);
+    /// Length of the code of the function being emitted.
+    pub(crate) fn code_len(&self) -> usize {
+        self.code[*self.func_temp.last().unwrap()].len()
+    }
+    /// Emits a jump operand to be patched later and returns its position.
+    pub(crate) fn emit_placeholder(&mut self, line: usize) -> usize {
+        let p = self.code_len();
+        self.emit_u16(0, line);
+        p
     }
 
-    fn replace_placeholder(&mut self) {
-        if let Some(p) = self.patch_list.pop() {
-            let jump_len = self.code[*self.func_temp.last().unwrap()].len() - p - 2;
-            self.code[*self.func_temp.last().unwrap()][p] = jump_len as u8;
-        } else {
-            panic!("Patch list is empty");
+    /// Patches the placeholder at `p` with the distance to the end of the code.
+    /// Returns false if the distance does not fit in an operand.
+    pub(crate) fn replace_placeholder(&mut self, p: usize) -> bool {
+        let code = &mut self.code[*self.func_temp.last().unwrap()];
+        let jump_len = code.len() - p - 2;
+        if jump_len > MAX_OPERAND {
+            return false;
         }
+        code[p..p + 2].copy_from_slice(&(jump_len as u16).to_be_bytes());
+        true
     }
-    fn emit_code(&mut self, b: u8, line: usize) {
+    pub(crate) fn emit_code(&mut self, b: u8, line: usize) {
         self.code[*self.func_temp.last().unwrap()].push(b);
-        self.line.push(line);
+        self.line[*self.func_temp.last().unwrap()].push(line);
+    }
+    /// Operands are encoded as two bytes, big endian.
+    pub(crate) fn emit_u16(&mut self, value: u16, line: usize) {
+        for b in value.to_be_bytes() {
+            self.emit_code(b, line);
+        }
     }
-    fn emit_number(&mut self, token: &Token) {
-        let int: i64 = token.value.parse().unwrap();
+    pub(crate) fn emit_int(&mut self, int: i64, span: Span) -> Result<()> {
+        if self.ints.len() > MAX_OPERAND {
+            return Err(CompilerError::TooManyConstants(span));
+        }
         self.ints.push(int);
-        self.emit_code(OpCode::Int as u8, token.line);
-        self.emit_code(self.ints.len() as u8 - 1, token.line);
+        self.emit_code(OpCode::Int as u8, span.line);
+        self.emit_u16((self.ints.len() - 1) as u16, span.line);
+        Ok(())
     }
-    fn emit_string(&mut self, token: &Token) {
-        self.strings.push(token.value.to_string());
-        self.emit_code(OpCode::String as u8, token.line);
-        self.emit_code(self.strings.len() as u8 - 1, token.line);
+    pub(crate) fn emit_float(&mut self, float: f64, span: Span) -> Result<()> {
+        if self.floats.len() > MAX_OPERAND {
+            return Err(CompilerError::TooManyConstants(span));
+        }
+        self.floats.push(float);
+        self.emit_code(OpCode::Float as u8, span.line);
+        self.emit_u16((self.floats.len() - 1) as u16, span.line);
+        Ok(())
+    }
+    pub(crate) fn emit_string(&mut self, string: &str, span: Span) -> Result<()> {
+        if self.strings.len() > MAX_OPERAND {
+            return Err(CompilerError::TooManyConstants(span));
+        }
+        self.strings.push(string.to_string());
+        self.emit_code(OpCode::String as u8, span.line);
+        self.emit_u16((self.strings.len() - 1) as u16, span.line);
+        Ok(())
     }
 }
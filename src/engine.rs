@@ -0,0 +1,263 @@
+//! Compiles and runs bofink programs on behalf of a host program.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Stdout, Write};
+use std::path::{Path, PathBuf};
+
+use crate::compiler::{self, Chunk, CompileConfig};
+use crate::enums::{Diagnostic, ExpressionKind, RuntimeError};
+use crate::heap::GcConfig;
+use crate::host::{HostValue, IntoHost, Outcome};
+use crate::native::{NativeFunction, NativeSignature};
+use crate::vm;
+use crate::{ast, parser};
+
+/// Compiles sources and runs chunks, with `print` writing to `output`.
+///
+/// ```
+/// let mut engine = bofink::Engine::with_output(Vec::new());
+/// engine.eval("print 1 + 2;").unwrap();
+/// assert_eq!(engine.output(), b"3\n");
+/// ```
+pub struct Engine<W: Write = Stdout> {
+    output: W,
+    /// Directories searched for imports that are not found next to the importing
+    /// file.
+    pub search_path: Vec<PathBuf>,
+    /// When the garbage collector runs in every run of the engine.
+    pub gc: GcConfig,
+    natives: Vec<NativeFunction>,
+    inputs: Vec<(String, HostValue)>,
+    input_types: Vec<(String, ast::Type)>,
+}
+
+impl Engine<Stdout> {
+    /// An engine printing to stdout.
+    pub fn new() -> Self {
+        Engine::with_output(io::stdout())
+    }
+}
+
+impl Default for Engine<Stdout> {
+    fn default() -> Self {
+        Engine::new()
+    }
+}
+
+impl<W: Write> Engine<W> {
+    /// An engine printing to `output`, like a `Vec<u8>` to read the output back.
+    pub fn with_output(output: W) -> Self {
+        Engine {
+            output,
+            search_path: vec![],
+            gc: GcConfig::default(),
+            natives: vec![],
+            inputs: vec![],
+            input_types: vec![],
+        }
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    pub fn output_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    pub fn into_output(self) -> W {
+        self.output
+    }
+
+    /// Registers a Rust function that scripts compiled from now on can call as
+    /// `name`, with arguments of the kinds of `params`. `return_kind` is
+    /// `ExpressionKind::None` for a function without a return value, which returns
+    /// `HostValue::Nil`. An error message fails the script with a runtime error.
+    ///
+    /// Chunks must be run by the engine that compiled them, with the natives they
+    /// were compiled against. Registering a name again replaces the function.
+    ///
+    /// ```
+    /// use bofink::{ExpressionKind, HostValue};
+    ///
+    /// let mut engine = bofink::Engine::with_output(Vec::new());
+    /// engine.register(
+    ///     "repeat",
+    ///     &[ExpressionKind::String, ExpressionKind::Int],
+    ///     ExpressionKind::String,
+    ///     |args| match args {
+    ///         [HostValue::Str(s), HostValue::Int(n)] if *n >= 0 => Ok(HostValue::Str(s.repeat(*n as usize))),
+    ///         _ => Err("cannot repeat a negative number of times".to_string()),
+    ///     },
+    /// );
+    /// engine.eval("print repeat(\"ab\", 2);").unwrap();
+    /// assert_eq!(engine.output(), b"abab\n");
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If a parameter is not an int, a float, a bool or a str, or if the return kind
+    /// is none of those or `ExpressionKind::None`.
+    pub fn register<F>(
+        &mut self,
+        name: &str,
+        params: &[ExpressionKind],
+        return_kind: ExpressionKind,
+        function: F,
+    ) where
+        F: Fn(&[HostValue]) -> Result<HostValue, String> + 'static,
+    {
+        let signature = NativeSignature {
+            name: name.to_string(),
+            params: params.to_vec(),
+            return_kind,
+        };
+        let native = NativeFunction::new(signature, Box::new(function));
+        match self.natives.iter_mut().find(|n| n.signature.name == name) {
+            Some(existing) => *existing = native,
+            None => self.natives.push(native),
+        }
+    }
+
+    /// Passes `value` to scripts as the read-only binding `name`, which their top level
+    /// code can not declare again. Scripts compiled from now on are checked against
+    /// the type of the value, and every run gets the value the input has when it
+    /// starts. An instance must be of a class declared by the compiled source.
+    ///
+    /// ```
+    /// use bofink::HostInstance;
+    ///
+    /// let mut engine = bofink::Engine::with_output(Vec::new());
+    /// engine.set_input("limit", 3);
+    /// engine.set_input("user", HostInstance::new("User").with("name", "ada"));
+    /// let source = "class User { str name; }\nreturn user.name + limit;";
+    /// let outcome = engine.eval(source).unwrap();
+    /// assert_eq!(outcome.value::<String>(), Ok("ada3".to_string()));
+    /// ```
+    pub fn set_input(&mut self, name: &str, value: impl IntoHost) {
+        let value = value.into_host();
+        match self.inputs.iter_mut().find(|(input, _)| input == name) {
+            Some((_, existing)) => *existing = value,
+            None => self.inputs.push((name.to_string(), value)),
+        }
+    }
+
+    /// Like `set_input`, but scripts are checked against `ty`, a type written the way
+    /// the source writes it, rather than the type of the value. This passes in an
+    /// optional, which may have no value to take its type from. Setting the input
+    /// with `set_input` later keeps the type.
+    ///
+    /// ```
+    /// let mut engine = bofink::Engine::with_output(Vec::new());
+    /// engine.set_input_typed("limit", "int?", None::<i64>);
+    /// let source = "let l = limit;\nif l == nil {\n    return 0;\n}\nreturn l + 1;";
+    /// assert_eq!(engine.eval(source).unwrap().value::<i64>(), Ok(0));
+    /// engine.set_input("limit", 2);
+    /// assert_eq!(engine.eval(source).unwrap().value::<i64>(), Ok(3));
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If `ty` is not a type.
+    pub fn set_input_typed(&mut self, name: &str, ty: &str, value: impl IntoHost) {
+        let Some(ty) = parser::parse_type(ty) else {
+            panic!(
+                "input '{}' is declared with '{}', which is not a type",
+                name, ty
+            );
+        };
+        match self.input_types.iter_mut().find(|(input, _)| input == name) {
+            Some((_, existing)) => *existing = ty,
+            None => self.input_types.push((name.to_string(), ty)),
+        }
+        self.set_input(name, value);
+    }
+
+    fn natives(&self) -> Vec<NativeSignature> {
+        self.natives.iter().map(|n| n.signature.clone()).collect()
+    }
+
+    /// Compiles a source that is not read from a file. Its imports are looked up in
+    /// the search path.
+    pub fn compile(&self, source: &str) -> Result<Chunk, Vec<Diagnostic>> {
+        let config = CompileConfig {
+            file: None,
+            search_path: self.search_path.clone(),
+            natives: self.natives(),
+            inputs: self.inputs.clone(),
+            input_types: self.input_types.clone(),
+        };
+        compiler::compile_with_config(source.to_string(), config)
+    }
+
+    /// Compiles the file at `path`. Its imports are looked up next to it first.
+    pub fn compile_file(&self, path: impl AsRef<Path>) -> Result<Chunk, Error> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(Error::Io)?;
+        let config = CompileConfig {
+            file: Some(path.to_path_buf()),
+            search_path: self.search_path.clone(),
+            natives: self.natives(),
+            inputs: self.inputs.clone(),
+            input_types: self.input_types.clone(),
+        };
+        compiler::compile_with_config(source, config).map_err(Error::Compile)
+    }
+
+    /// Runs a compiled chunk with the current values of the inputs, and returns what
+    /// the run left to read back.
+    pub fn run(&mut self, chunk: Chunk) -> Result<Outcome, RuntimeError> {
+        vm::execute(
+            chunk,
+            &mut self.output,
+            self.gc,
+            &self.natives,
+            &self.inputs,
+        )
+    }
+
+    /// Compiles and runs a source.
+    pub fn eval(&mut self, source: &str) -> Result<Outcome, Error> {
+        let chunk = self.compile(source).map_err(Error::Compile)?;
+        self.run(chunk).map_err(Error::Runtime)
+    }
+}
+
+/// Why a program could not be compiled or run.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The source file could not be read.
+    Io(io::Error),
+    /// Every error found in the source and its imports, in source order.
+    Compile(Vec<Diagnostic>),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "Unable to read file: {}", error),
+            Error::Compile(diagnostics) => {
+                let plural = if diagnostics.len() == 1 { "" } else { "s" };
+                write!(f, "{} compile error{}", diagnostics.len(), plural)?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            Error::Runtime(error) => write!(f, "Runtime error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Compile(_) => None,
+            Error::Runtime(error) => Some(error),
+        }
+    }
+}
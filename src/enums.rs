@@ -1,144 +1,938 @@
 use std::fmt;
 
-use crate::scanner::Token;
+use crate::compiler::MAX_OPERAND;
+use crate::host::ConversionError;
+use crate::scanner::Span;
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum CompilerError {
+    /// Mutating a binding that is not `mut`, with how and where it is declared if it
+    /// is known.
     CantMut {
-        token: Token,
+        name: String,
+        span: Span,
+        declaration: Option<(Binding, Span)>,
     },
+    /// An expression of another type than the one it is used as.
     Type {
         actual: ExpressionKind,
         expected: ExpressionKind,
-        line: usize,
+        span: Span,
     },
+    /// A type annotation that does not name a type.
     NotAType {
         kind: TokenKind,
-        line: usize
+        span: Span,
     },
+    /// A token that can not start or continue the construct it is in.
     InvalidToken {
         actual: TokenKind,
-        line: usize,
+        span: Span,
     },
+    /// A token other than the one the syntax requires.
     UnexpectedToken {
         expected: TokenKind,
         actual: TokenKind,
-        line: usize,
+        span: Span,
+    },
+    /// A name declared twice in the same scope, with its first declaration. `None`
+    /// for an input, which the host declares.
+    Redeclaration {
+        name: String,
+        span: Span,
+        previous: Option<Span>,
     },
-    Redeclaration(usize),
-    DelcarationType {
+    /// A declaration with a value that does not match its type annotation.
+    DeclarationType {
         expected: ExpressionKind,
         actual: ExpressionKind,
-        line: usize,
+        span: Span,
     },
+    /// A `break` or `continue` outside of a loop.
+    OutsideLoop(Span),
+    /// A `break` or `continue` with a label that no enclosing loop has.
+    UnknownLabel {
+        label: String,
+        span: Span,
+    },
+    /// More functions in the program than an operand can address.
     MaxFunctions,
-    UnknownParamType(usize),
+    /// More constants of one type than an operand can address.
+    TooManyConstants(Span),
+    /// A number literal that does not fit in its type.
+    InvalidNumber(Span),
+    /// More locals in a function than an operand can address.
+    TooManyLocals(Span),
+    /// More classes in the program than an operand can address.
+    TooManyClasses(Span),
+    /// More fields in a class than an operand can address.
+    TooManyFields(Span),
+    /// A branch or loop with more code than a jump can cross.
+    JumpTooLarge(Span),
+    /// An array or map literal with more elements than an operand can count.
+    TooManyElements(Span),
+    /// An empty array literal without a type to infer its element type from.
+    EmptyArray(Span),
+    /// An empty map literal without a type to infer its key and value types from.
+    EmptyMap(Span),
+    /// An operation on an `int` and a `float`, which are never converted implicitly.
+    MixedNumbers(Span),
+    /// A `to_string` method with parameters or a return type other than `str`.
+    InvalidToString(Span),
+    /// A conversion between types that can not be converted.
+    InvalidConversion {
+        from: ExpressionKind,
+        to: ExpressionKind,
+        span: Span,
+    },
+    /// A map key type other than `int`, `str` or `bool`.
+    InvalidKeyType {
+        kind: ExpressionKind,
+        span: Span,
+    },
+    /// A class name that is not declared.
+    UnknownClass {
+        name: String,
+        span: Span,
+    },
+    /// A function name that is not declared.
+    UnknownFunction {
+        name: String,
+        span: Span,
+    },
+    /// A class construction that leaves a field without a value.
+    MissingField {
+        name: String,
+        span: Span,
+    },
+    /// A field access on a type without that field.
+    UnknownField {
+        name: String,
+        kind: ExpressionKind,
+        span: Span,
+    },
+    /// A method call on a type without that method.
+    UnknownMethod {
+        name: String,
+        kind: ExpressionKind,
+        span: Span,
+    },
+    /// An index into a value that is not an array, map or string.
+    NotIndexable {
+        kind: ExpressionKind,
+        span: Span,
+    },
+    /// A `for` loop over a value that can not be iterated.
+    NotIterable {
+        kind: ExpressionKind,
+        span: Span,
+    },
+    /// A variable name that is not declared.
     MissingLocal {
         name: String,
-        line: usize,
+        span: Span,
     },
+    /// An assignment of a value that does not match the type of the binding.
     ReassignmentType {
         expected: ExpressionKind,
         actual: ExpressionKind,
-        line: usize,
+        span: Span,
     },
+    /// An argument that does not match the type of its parameter.
     ParamType {
         expected: ExpressionKind,
         actual: ExpressionKind,
-        line: usize,
-    },
-    _NumberOperation {
-        operator: TokenKind,
+        span: Span,
     },
+    /// A call to a function without a return type used as a value.
     NoneValue {
-        line: usize
+        span: Span,
     },
+    /// An equality comparison between values of unrelated types.
     ComparisonType {
         first: ExpressionKind,
         second: ExpressionKind,
-        line: usize,
+        span: Span,
     },
-    _InvalidOperatorTypes {
+    /// An operator applied to types it does not work on.
+    InvalidOperatorTypes {
         first: ExpressionKind,
         second: ExpressionKind,
-        line: usize,
+        span: Span,
     },
+    /// A `return` with a value in a function without a return type.
     ReturnValueFromVoid {
         kind: ExpressionKind,
-        line: usize,
+        span: Span,
+    },
+    /// An optional value used as its inner type without checking it against `nil`.
+    UncheckedOptional {
+        kind: ExpressionKind,
+        span: Span,
+    },
+    /// A `nil` without a type to infer the optional type from.
+    UntypedNil(Span),
+    /// A variant name that the enum does not have.
+    UnknownVariant {
+        name: String,
+        kind: ExpressionKind,
+        span: Span,
+    },
+    /// A variant constructed or matched with the wrong number of values.
+    PayloadCount {
+        name: String,
+        expected: usize,
+        actual: usize,
+        span: Span,
+    },
+    /// A `match` on a value that is not an enum.
+    NotMatchable {
+        kind: ExpressionKind,
+        span: Span,
+    },
+    /// A `match` that does not cover every variant, with the missing ones.
+    NonExhaustiveMatch {
+        missing: Vec<String>,
+        span: Span,
+    },
+    /// A match arm for a variant that an earlier arm already covers.
+    UnreachableArm(Span),
+    /// A `match` expression with arms of different types.
+    MatchArmType {
+        expected: ExpressionKind,
+        actual: ExpressionKind,
+        span: Span,
+    },
+    /// A call of a value that is not a function.
+    NotCallable {
+        kind: ExpressionKind,
+        span: Span,
+    },
+    /// An assignment to a local of an enclosing function, which closures capture by value.
+    AssignCapture {
+        name: String,
+        span: Span,
+    },
+    /// An import of a module that is in neither the importing directory nor the search path.
+    ImportNotFound {
+        path: String,
+        span: Span,
+    },
+    /// An import of a module that is still being loaded.
+    CircularImport {
+        path: String,
+        span: Span,
+    },
+    /// Imports and `pub` declarations are only allowed at the top level of a file.
+    NotTopLevel(Span),
+    /// A use of a name that the imported module does not declare `pub`.
+    NotExported {
+        module: String,
+        name: String,
+        span: Span,
     },
-    _BooleanExpression(usize),
+    /// A function with a return type that can finish without returning a value.
+    MissingReturn {
+        kind: ExpressionKind,
+        span: Span,
+    },
+    /// An input declared by the host with a type that it can not pass in.
+    InputType {
+        name: String,
+        kind: ExpressionKind,
+        span: Span,
+    },
+    /// A part of the source that is not a token, like an unterminated string, with
+    /// the message of the scanner.
+    Lexical {
+        message: String,
+        span: Span,
+    },
+    /// An error in an imported file.
+    InFile {
+        file: String,
+        error: Box<CompilerError>,
+    },
+}
+
+/// How a binding is declared, which decides how it can be made mutable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Binding {
+    /// A `let` or `mut` declaration.
+    Let,
+    /// The variable of a `for` loop.
+    ForVariable,
+    /// `self` in a method.
+    SelfParam,
+    /// A value bound by a match arm.
+    MatchBinding,
+}
+
+impl Binding {
+    /// The hint for changing `name` when it is not `mut`.
+    fn hint(self, name: &str) -> String {
+        match self {
+            Binding::Let => format!("declare '{}' with 'mut' to change it", name),
+            Binding::ForVariable => format!("'{}' is declared by the loop and cannot be changed", name),
+            Binding::SelfParam => "declare the method with 'mut fun' to change 'self'".to_string(),
+            Binding::MatchBinding => format!("'{}' is bound by the match arm and cannot be changed", name),
+        }
+    }
+}
+
+impl CompilerError {
+    /// The imported file the error occurred in, `None` for the compiled source.
+    pub fn file(&self) -> Option<&str> {
+        match self {
+            CompilerError::InFile { file, error } => error.file().or(Some(file)),
+            _ => None,
+        }
+    }
+
+    /// Where the error occurred, in the file where it occurred.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CompilerError::MaxFunctions => None,
+            CompilerError::InFile { error, .. } => error.span(),
+            CompilerError::CantMut { span, .. }
+            | CompilerError::Type { span, .. }
+            | CompilerError::NotAType { span, .. }
+            | CompilerError::InvalidToken { span, .. }
+            | CompilerError::UnexpectedToken { span, .. }
+            | CompilerError::Redeclaration { span, .. }
+            | CompilerError::DeclarationType { span, .. }
+            | CompilerError::UnknownLabel { span, .. }
+            | CompilerError::InvalidConversion { span, .. }
+            | CompilerError::InvalidKeyType { span, .. }
+            | CompilerError::UnknownClass { span, .. }
+            | CompilerError::UnknownFunction { span, .. }
+            | CompilerError::MissingField { span, .. }
+            | CompilerError::UnknownField { span, .. }
+            | CompilerError::UnknownMethod { span, .. }
+            | CompilerError::NotIndexable { span, .. }
+            | CompilerError::NotIterable { span, .. }
+            | CompilerError::MissingLocal { span, .. }
+            | CompilerError::ReassignmentType { span, .. }
+            | CompilerError::ParamType { span, .. }
+            | CompilerError::NoneValue { span }
+            | CompilerError::ComparisonType { span, .. }
+            | CompilerError::InvalidOperatorTypes { span, .. }
+            | CompilerError::ReturnValueFromVoid { span, .. }
+            | CompilerError::UncheckedOptional { span, .. }
+            | CompilerError::UnknownVariant { span, .. }
+            | CompilerError::PayloadCount { span, .. }
+            | CompilerError::NotMatchable { span, .. }
+            | CompilerError::NonExhaustiveMatch { span, .. }
+            | CompilerError::MatchArmType { span, .. }
+            | CompilerError::NotCallable { span, .. }
+            | CompilerError::AssignCapture { span, .. }
+            | CompilerError::ImportNotFound { span, .. }
+            | CompilerError::CircularImport { span, .. }
+            | CompilerError::NotExported { span, .. }
+            | CompilerError::MissingReturn { span, .. }
+            | CompilerError::InputType { span, .. }
+            | CompilerError::Lexical { span, .. } => Some(*span),
+            CompilerError::OutsideLoop(span)
+            | CompilerError::TooManyConstants(span)
+            | CompilerError::InvalidNumber(span)
+            | CompilerError::TooManyLocals(span)
+            | CompilerError::TooManyClasses(span)
+            | CompilerError::TooManyFields(span)
+            | CompilerError::JumpTooLarge(span)
+            | CompilerError::TooManyElements(span)
+            | CompilerError::EmptyArray(span)
+            | CompilerError::EmptyMap(span)
+            | CompilerError::MixedNumbers(span)
+            | CompilerError::InvalidToString(span)
+            | CompilerError::UntypedNil(span)
+            | CompilerError::UnreachableArm(span)
+            | CompilerError::NotTopLevel(span) => Some(*span),
+        }
+    }
+
+    /// The source line of the error, in the file where it occurred.
+    pub fn line(&self) -> Option<usize> {
+        self.span().map(|span| span.line)
+    }
+
+    /// A code that identifies the kind of error. Codes are never reused or changed,
+    /// so tools can rely on them. E0034 and E0039 are retired.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CompilerError::CantMut { .. } => "E0001",
+            CompilerError::Type { .. } => "E0002",
+            CompilerError::NotAType { .. } => "E0003",
+            CompilerError::InvalidToken { .. } => "E0004",
+            CompilerError::UnexpectedToken { .. } => "E0005",
+            CompilerError::Redeclaration { .. } => "E0006",
+            CompilerError::DeclarationType { .. } => "E0007",
+            CompilerError::OutsideLoop(_) => "E0008",
+            CompilerError::UnknownLabel { .. } => "E0009",
+            CompilerError::MaxFunctions => "E0010",
+            CompilerError::TooManyConstants(_) => "E0011",
+            CompilerError::InvalidNumber(_) => "E0012",
+            CompilerError::TooManyLocals(_) => "E0013",
+            CompilerError::TooManyClasses(_) => "E0014",
+            CompilerError::TooManyFields(_) => "E0015",
+            CompilerError::JumpTooLarge(_) => "E0016",
+            CompilerError::TooManyElements(_) => "E0017",
+            CompilerError::EmptyArray(_) => "E0018",
+            CompilerError::EmptyMap(_) => "E0019",
+            CompilerError::MixedNumbers(_) => "E0020",
+            CompilerError::InvalidToString(_) => "E0021",
+            CompilerError::InvalidConversion { .. } => "E0022",
+            CompilerError::InvalidKeyType { .. } => "E0023",
+            CompilerError::UnknownClass { .. } => "E0024",
+            CompilerError::UnknownFunction { .. } => "E0025",
+            CompilerError::MissingField { .. } => "E0026",
+            CompilerError::UnknownField { .. } => "E0027",
+            CompilerError::UnknownMethod { .. } => "E0028",
+            CompilerError::NotIndexable { .. } => "E0029",
+            CompilerError::NotIterable { .. } => "E0030",
+            CompilerError::MissingLocal { .. } => "E0031",
+            CompilerError::ReassignmentType { .. } => "E0032",
+            CompilerError::ParamType { .. } => "E0033",
+            CompilerError::NoneValue { .. } => "E0035",
+            CompilerError::ComparisonType { .. } => "E0036",
+            CompilerError::InvalidOperatorTypes { .. } => "E0037",
+            CompilerError::ReturnValueFromVoid { .. } => "E0038",
+            CompilerError::UncheckedOptional { .. } => "E0040",
+            CompilerError::UntypedNil(_) => "E0041",
+            CompilerError::UnknownVariant { .. } => "E0042",
+            CompilerError::PayloadCount { .. } => "E0043",
+            CompilerError::NotMatchable { .. } => "E0044",
+            CompilerError::NonExhaustiveMatch { .. } => "E0045",
+            CompilerError::UnreachableArm(_) => "E0046",
+            CompilerError::MatchArmType { .. } => "E0047",
+            CompilerError::NotCallable { .. } => "E0048",
+            CompilerError::AssignCapture { .. } => "E0049",
+            CompilerError::ImportNotFound { .. } => "E0050",
+            CompilerError::CircularImport { .. } => "E0051",
+            CompilerError::NotTopLevel(_) => "E0052",
+            CompilerError::NotExported { .. } => "E0053",
+            CompilerError::MissingReturn { .. } => "E0054",
+            CompilerError::InputType { .. } => "E0055",
+            CompilerError::Lexical { .. } => "E0056",
+            CompilerError::InFile { error, .. } => error.code(),
+        }
+    }
+
+    /// Other places in the file that explain the error, like the first declaration of
+    /// a redeclared name.
+    pub fn labels(&self) -> Vec<(Span, String)> {
+        match self {
+            CompilerError::Redeclaration {
+                name,
+                previous: Some(previous),
+                ..
+            } => vec![(*previous, format!("'{}' is first declared here", name))],
+            CompilerError::CantMut {
+                name,
+                declaration: Some((binding, declaration)),
+                ..
+            } => vec![(*declaration, binding.hint(name))],
+            CompilerError::InFile { error, .. } => error.labels(),
+            _ => vec![],
+        }
+    }
+
+    /// What the error says about the code it points at, besides its message.
+    fn note(&self) -> String {
+        match self {
+            CompilerError::Redeclaration {
+                name,
+                previous: None,
+                ..
+            } => format!("'{}' is an input passed in by the host", name),
+            CompilerError::InFile { error, .. } => error.note(),
+            _ => String::new(),
+        }
+    }
+
+    /// What went wrong, without where.
+    pub fn message(&self) -> String {
+        match self {
+            CompilerError::CantMut { name, .. } => format!("Cannot mutate '{}', it is not declared 'mut'", name),
+            CompilerError::NotAType { kind, .. } => format!("Exptected type annotation but got '{:?}'", kind),
+            CompilerError::ReturnValueFromVoid { kind, .. } => format!("Cannot return a '{}' value from a function that has no return type", kind),
+            CompilerError::NoneValue { .. } => "Trying to use a call without a return value in an expression".to_string(),
+            CompilerError::Type { actual, expected, .. } => format!("Expected type '{}' but got '{}'", expected, actual),
+            CompilerError::InvalidToken { actual, .. } => format!("Unexpected token '{:?}'", actual),
+            CompilerError::UnexpectedToken { expected, actual, .. } => format!("Unexpected token | Expected '{:?}' but got '{:?}'", expected, actual),
+            CompilerError::Redeclaration { name, .. } => format!("Cannot redeclare '{}'", name),
+            CompilerError::DeclarationType { expected, actual, .. } => format!("Expression does not match declaration type | Expected '{}' but got '{}'", expected, actual),
+            CompilerError::OutsideLoop(_) => "Cannot use 'break' or 'continue' outside of a loop".to_string(),
+            CompilerError::UnknownLabel { label, .. } => format!("Could not find loop with label '{}'", label),
+            CompilerError::MaxFunctions => format!("Too many functions | At the moment bofink only supports {} functions in any program", MAX_OPERAND),
+            CompilerError::TooManyConstants(_) => format!("Too many constants | At the moment bofink only supports {} constants of each type", MAX_OPERAND + 1),
+            CompilerError::InvalidNumber(_) => "Number literal is too large".to_string(),
+            CompilerError::TooManyLocals(_) => format!("Too many local variables | At the moment bofink only supports {} locals in a function", MAX_OPERAND),
+            CompilerError::TooManyClasses(_) => format!("Too many classes | At the moment bofink only supports {} classes in any program", MAX_OPERAND + 1),
+            CompilerError::TooManyFields(_) => format!("Too many fields | At the moment bofink only supports {} fields in a class", MAX_OPERAND),
+            CompilerError::JumpTooLarge(_) => format!("Too much code to jump over | At the moment bofink only supports jumps of {} bytes", MAX_OPERAND),
+            CompilerError::TooManyElements(_) => format!("Too many elements | At the moment bofink only supports {} elements in an array literal", MAX_OPERAND),
+            CompilerError::EmptyArray(_) => "Cannot infer the type of an empty array, add a type annotation".to_string(),
+            CompilerError::InvalidToString(_) => "A 'to_string' method must take no parameters and return 'str'".to_string(),
+            CompilerError::MixedNumbers(_) => "Cannot mix 'int' and 'float' | Convert explicitly with 'int(..)' or 'float(..)'".to_string(),
+            CompilerError::InvalidConversion { from, to, .. } => format!("Cannot convert '{}' to '{}'", from, to),
+            CompilerError::EmptyMap(_) => "Cannot infer the type of an empty map, add a type annotation".to_string(),
+            CompilerError::InvalidKeyType { kind, .. } => format!("Type '{}' cannot be used as a map key, only 'int', 'str' and 'bool' can", kind),
+            CompilerError::UnknownClass { name, .. } => format!("Could not find class with name '{}'", name),
+            CompilerError::UnknownFunction { name, .. } => format!("Could not find function with name '{}'", name),
+            CompilerError::MissingField { name, .. } => format!("Missing value for field '{}' | Pass it as an argument or give it a default value", name),
+            CompilerError::UnknownField { name, kind, .. } => format!("No field '{}' on type '{}'", name, kind),
+            CompilerError::UnknownMethod { name, kind, .. } => format!("No method '{}' on type '{}'", name, kind),
+            CompilerError::NotIndexable { kind, .. } => format!("Cannot index into a value of type '{}'", kind),
+            CompilerError::NotIterable { kind, .. } => format!("Cannot iterate over a value of type '{}'", kind),
+            CompilerError::MissingLocal { name, .. } => format!("Could not find local with name '{}'", name),
+            CompilerError::ReassignmentType { expected, actual, .. } => format!("Trying to reassign wrong type to local | Expected '{}' but got '{}'", expected, actual),
+            CompilerError::ParamType { expected, actual, .. } => format!("Unexpected type for parameter | Expected '{}' but got '{}'", expected, actual),
+            CompilerError::ComparisonType { first, second, .. } => format!("Invalid comparison types | Got '{}' and '{}'", first, second),
+            CompilerError::InvalidOperatorTypes { first, second, .. } => format!("Invalid types for operator | Got '{}' and '{}'", first, second),
+            CompilerError::UncheckedOptional { kind, .. } => format!("Value of type '{}' may be nil | Check it with '!= nil' first", kind),
+            CompilerError::UnknownVariant { name, kind, .. } => format!("No variant '{}' in enum '{}'", name, kind),
+            CompilerError::PayloadCount { name, expected, actual, .. } => format!("Variant '{}' has {} values but got {}", name, expected, actual),
+            CompilerError::NotMatchable { kind, .. } => format!("Cannot match on a value of type '{}', only enums can be matched", kind),
+            CompilerError::NonExhaustiveMatch { missing, .. } => format!("Match does not cover every variant | Missing '{}'", missing.join("', '")),
+            CompilerError::UnreachableArm(_) => "Unreachable match arm, the variant is already covered".to_string(),
+            CompilerError::MatchArmType { expected, actual, .. } => format!("Match arms have different types | Expected '{}' but got '{}'", expected, actual),
+            CompilerError::NotCallable { kind, .. } => format!("Cannot call a value of type '{}'", kind),
+            CompilerError::AssignCapture { name, .. } => format!("Cannot assign to '{}' | Functions capture the values of enclosing locals, not the locals themselves", name),
+            CompilerError::ImportNotFound { path, .. } => format!("Cannot find module '{}'", path),
+            CompilerError::CircularImport { path, .. } => format!("Circular import of '{}'", path),
+            CompilerError::NotTopLevel(_) => "Imports and pub declarations must be at the top level of a file".to_string(),
+            CompilerError::NotExported { module, name, .. } => format!("Module '{}' has no public '{}'", module, name),
+            CompilerError::MissingReturn { kind, .. } => format!("Function can finish without returning a value of type '{}'", kind),
+            CompilerError::InputType { name, kind, .. } => format!("Input '{}' is declared as '{}', which the host cannot pass in", name, kind),
+            CompilerError::Lexical { message, .. } => message.to_string(),
+            CompilerError::InFile { error, .. } => error.message(),
+            CompilerError::UntypedNil(_) => "Cannot infer a type from 'nil', add a type annotation".to_string(),
+        }
+    }
 }
 
 impl fmt::Display for CompilerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            CompilerError::CantMut { token } => write!(f, "Cant mutate var | at line {}", token.line),
-            CompilerError::NotAType { kind: token, line } => write!(f, "Exptected type annotation but got '{:?}' | at line {}", token, line),
-            CompilerError::ReturnValueFromVoid { kind, line } => write!(f, "Cannot return a '{:?}' value from a function that has no return type | at line {}", kind, line),
-            CompilerError::NoneValue { line } => write!(f, "Trying to use use 'None' value in expression | at line {}", line),
-            CompilerError::Type { actual, expected, line } => write!(f, "Expected type '{:?}' but got '{:?}' | at line {}", expected, actual, line),
-            CompilerError::_NumberOperation { operator } => write!(f, "Operator '{:?}' expects 2 numbers", operator),
-            CompilerError::InvalidToken { actual, line } => write!(f, "Unexpected token '{:?}' | at line {}", actual, line),
-            CompilerError::UnexpectedToken {
-                expected,
-                actual,
-                line,
-            } => write!(
-                f,
-                "Unexpected token | Expected '{:?}' but got '{:?}' | at line {}",
-                expected, actual, line
+            CompilerError::InFile { file, error } => write!(f, "{} | in {}", error, file),
+            error => match error.line() {
+                Some(line) => write!(f, "{} | at line {}", error.message(), line),
+                None => write!(f, "{}", error.message()),
+            },
+        }
+    }
+}
+
+impl std::error::Error for CompilerError {}
+
+/// A compile error, located in the source of the file where it occurred.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct Diagnostic {
+    pub error: CompilerError,
+    pub severity: Severity,
+    /// Path of the imported file the error occurred in, `None` for the compiled
+    /// source.
+    pub file: Option<String>,
+    /// Where the error occurred, `None` for errors about the program as a whole.
+    pub label: Option<Label>,
+    /// Other places that explain the error.
+    pub secondary: Vec<Label>,
+}
+
+/// How serious a diagnostic is. Every diagnostic is an error for now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Severity {
+    Error,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// A span of a source file, with the line it starts on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Label {
+    pub span: Span,
+    pub line: usize,
+    /// Character of the line where the span starts, counted from 1.
+    pub column: usize,
+    /// Number of characters of the span on its first line, at least 1.
+    pub width: usize,
+    /// The text of the line.
+    pub text: String,
+    pub message: String,
+}
+
+impl Label {
+    fn new(span: Span, message: String, source: &str) -> Self {
+        let start = span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        let end = span.end.clamp(start, line_end);
+        Label {
+            span,
+            line: span.line,
+            column: source[line_start..start].chars().count() + 1,
+            width: source[start..end].chars().count().max(1),
+            text: source[line_start..line_end].trim_end_matches('\r').to_string(),
+            message,
+        }
+    }
+}
+
+impl Diagnostic {
+    /// Locates `error` in `source`, the source of the file where it occurred.
+    pub fn new(error: CompilerError, source: &str) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            file: error.file().map(str::to_string),
+            label: error.span().map(|span| Label::new(span, error.note(), source)),
+            secondary: error
+                .labels()
+                .into_iter()
+                .map(|(span, message)| Label::new(span, message, source))
+                .collect(),
+            error,
+        }
+    }
+
+    /// Renders the error with the lines it points at, and carets under the spans.
+    /// `name` is shown for the compiled source, which has no file of its own.
+    pub fn render(&self, name: &str) -> String {
+        let mut out = format!(
+            "{}[{}]: {}\n",
+            self.severity.as_str(),
+            self.error.code(),
+            self.error.message()
+        );
+        let Some(label) = &self.label else {
+            return out;
+        };
+        let mut labels: Vec<(&Label, char)> = self.secondary.iter().map(|l| (l, '-')).collect();
+        labels.push((label, '^'));
+        labels.sort_by_key(|(label, _)| (label.line, label.column));
+        let gutter = " ".repeat(labels.iter().map(|(l, _)| l.line.to_string().len()).max().unwrap_or(1));
+
+        let file = self.file.as_deref().unwrap_or(name);
+        out += &format!("{}--> {}:{}:{}\n", gutter, file, label.line, label.column);
+        out += &format!("{} |\n", gutter);
+        for (i, (label, marker)) in labels.iter().enumerate() {
+            // consecutive labels on the same line share it
+            if i == 0 || labels[i - 1].0.line != label.line {
+                out += &format!("{:>width$} | {}\n", label.line, label.text, width = gutter.len());
+            }
+            let indent: String = label
+                .text
+                .chars()
+                .take(label.column - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let underline = marker.to_string().repeat(label.width);
+            let line = format!("{} | {}{} {}", gutter, indent, underline, label.message);
+            out += line.trim_end();
+            out += "\n";
+        }
+        out
+    }
+
+    /// The diagnostic as a single line JSON object, with the schema described in the
+    /// README. `name` is the file of the compiled source.
+    pub fn to_json(&self, name: &str) -> String {
+        let file = self.file.as_deref().unwrap_or(name);
+        let labels: Vec<String> = self
+            .secondary
+            .iter()
+            .map(|label| {
+                format!(
+                    "{{{},\"message\":{}}}",
+                    Self::json_location(Some(label)),
+                    json_string(&label.message)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"file\":{},{},\"severity\":\"{}\",\"code\":\"{}\",\"message\":{},\"labels\":[{}]}}",
+            json_string(file),
+            Self::json_location(self.label.as_ref()),
+            self.severity.as_str(),
+            self.error.code(),
+            json_string(&self.error.message()),
+            labels.join(",")
+        )
+    }
+
+    /// The `line`, `column` and `span` members of a JSON object, `null` without a
+    /// label.
+    fn json_location(label: Option<&Label>) -> String {
+        match label {
+            Some(label) => format!(
+                "\"line\":{},\"column\":{},\"span\":{{\"start\":{},\"end\":{}}}",
+                label.line, label.column, label.span.start, label.span.end
             ),
-            CompilerError::Redeclaration(line) => write!(f, "Cannot redeclare variables | at line {}", line),
-            CompilerError::DelcarationType {
-                expected,
-                actual,
-                line,
-            } => write!(f, "Expression does not match declaration type | Expected '{:?}' but got '{:?}' | at line {}", expected, actual, line),
-            CompilerError::MaxFunctions => write!(f, "Too many functions | At the moment bofink only supports {} functions in any program", u8::MAX),
-            CompilerError::UnknownParamType(line) => write!(f, "Unexpected paramater type | at line {}", line),
-            CompilerError::MissingLocal { name, line } => write!(f, "Could not find local with name '{}' | at line {}", name, line),
-            CompilerError::ReassignmentType {
-                expected,
-                actual,
-                line,
-            } => write!(f, "Trying to reassign wrong type to local | Expected '{:?}' but got '{:?}' | at line {}", expected, actual, line),
-            CompilerError::ParamType {
+            None => "\"line\":null,\"column\":null,\"span\":null".to_string(),
+        }
+    }
+}
+
+/// Quotes `value` as a JSON string.
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            c if c.is_control() => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+/// An error raised while running a chunk, together with the source line of the
+/// failing instruction and the bofink call stack at that point.
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub line: usize,
+    /// Innermost frame first.
+    pub call_stack: Vec<StackFrame>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct StackFrame {
+    pub function: String,
+    pub line: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RuntimeErrorKind {
+    /// An instruction got an operand of another type than it works on, which the
+    /// compiler never emits.
+    TypeMismatch {
+        /// The name of the instruction, like `Add`.
+        instruction: &'static str,
+        expected: &'static str,
+        actual: &'static str,
+    },
+    DivisionByZero,
+    IntegerOverflow,
+    StackUnderflow,
+    UnknownOpcode(u8),
+    UnknownFunction(usize),
+    /// The chunk calls a native function that was not registered with the engine
+    /// running it.
+    UnknownNative(usize),
+    UnknownInput(usize),
+    /// The chunk uses an input that the host did not pass to the run.
+    MissingInput(String),
+    /// The value of an input is not of the type the chunk was compiled against.
+    Input {
+        name: String,
+        error: ConversionError,
+    },
+    /// An int, float or string constant that is not in the chunk.
+    InvalidConstant(usize),
+    InvalidString(usize),
+    InvalidInstance(usize),
+    InvalidClass(usize),
+    InvalidField(usize),
+    InvalidArray(usize),
+    InvalidMap(usize),
+    InvalidFunction(usize),
+    /// A global was used by a function called before its declaration ran.
+    UninitializedGlobal(usize),
+    MissingKey(String),
+    IndexOutOfBounds {
+        index: i64,
+        len: usize,
+    },
+    EmptyArray,
+    InvalidJump,
+    Output(std::io::ErrorKind),
+    /// A native function returned an error, or a value of another kind than its
+    /// signature declares.
+    Native {
+        function: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeErrorKind::TypeMismatch {
+                instruction,
                 expected,
                 actual,
-                line,
-            } => write!(f, "Unexpected type for parameter | Expected '{:?}' but got '{:?}' | at line {}", expected, actual, line),
-            CompilerError::ComparisonType {
-                first,
-                second,
-                line,
-            } => write!(f, "Invalid comparison types | Got '{:?}' and '{:?}' | at line '{}'",first, second, line),
-            CompilerError::_InvalidOperatorTypes {
-                first,
-                second,
-                line,
-            } => write!(f, "Invalid types for operator | Got '{:?}' and '{:?} | at line {}'", first, second, line),
-            CompilerError::_BooleanExpression(line) => write!(f, "Expected boolean expressions | at line {}", line),
+            } => write!(f, "Type mismatch in instruction '{}' | Expected '{}' but got '{}'", instruction, expected, actual),
+            RuntimeErrorKind::DivisionByZero => write!(f, "Division by zero"),
+            RuntimeErrorKind::IntegerOverflow => write!(f, "Integer overflow"),
+            RuntimeErrorKind::StackUnderflow => write!(f, "Stack underflow"),
+            RuntimeErrorKind::UnknownOpcode(byte) => write!(f, "Unknown instruction '{:#04x}'", byte),
+            RuntimeErrorKind::UnknownFunction(idx) => write!(f, "Unknown function '{}'", idx),
+            RuntimeErrorKind::UnknownNative(idx) => write!(f, "Unknown native function '{}'", idx),
+            RuntimeErrorKind::UnknownInput(idx) => write!(f, "Unknown input '{}'", idx),
+            RuntimeErrorKind::MissingInput(name) => write!(f, "No value for input '{}'", name),
+            RuntimeErrorKind::Input { name, error } => write!(f, "Invalid value for input '{}': {}", name, error),
+            RuntimeErrorKind::InvalidConstant(idx) => write!(f, "Invalid constant reference '{}'", idx),
+            RuntimeErrorKind::InvalidString(idx) => write!(f, "Invalid string reference '{}'", idx),
+            RuntimeErrorKind::InvalidInstance(idx) => write!(f, "Invalid instance reference '{}'", idx),
+            RuntimeErrorKind::InvalidClass(idx) => write!(f, "Invalid class '{}'", idx),
+            RuntimeErrorKind::InvalidField(idx) => write!(f, "Invalid field '{}'", idx),
+            RuntimeErrorKind::InvalidArray(idx) => write!(f, "Invalid array reference '{}'", idx),
+            RuntimeErrorKind::InvalidMap(idx) => write!(f, "Invalid map reference '{}'", idx),
+            RuntimeErrorKind::InvalidFunction(idx) => write!(f, "Invalid function reference '{}'", idx),
+            RuntimeErrorKind::UninitializedGlobal(slot) => write!(f, "Global in slot '{}' is used before its declaration", slot),
+            RuntimeErrorKind::MissingKey(key) => write!(f, "Key '{}' is not in the map", key),
+            RuntimeErrorKind::IndexOutOfBounds { index, len } => write!(f, "Index out of bounds | The index is '{}' but the length is '{}'", index, len),
+            RuntimeErrorKind::EmptyArray => write!(f, "Cannot pop from an empty array"),
+            RuntimeErrorKind::InvalidJump => write!(f, "Jump outside of function"),
+            RuntimeErrorKind::Output(kind) => write!(f, "Unable to write to output: {}", kind),
+            RuntimeErrorKind::Native { function, message } => write!(f, "Native function '{}' failed: {}", function, message),
         }
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} | at line {}", self.kind, self.line)?;
+        for frame in &self.call_stack {
+            write!(f, "\n    in {} at line {}", frame.function, frame.line)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[non_exhaustive]
 pub enum ExpressionKind {
     Bool,
     String,
     Int,
-    Class(u8),
+    Float,
+    Class(TypeRef),
+    Enum(TypeRef),
+    Array(Box<ExpressionKind>),
+    Map(Box<ExpressionKind>, Box<ExpressionKind>),
+    /// A function value with its parameter kinds and return kind, `None` when it
+    /// returns nothing.
+    Function(Vec<ExpressionKind>, Box<ExpressionKind>),
+    /// A value of the inner kind, or `nil`.
+    Optional(Box<ExpressionKind>),
+    /// The kind of the `nil` literal, which fits any optional.
+    Nil,
     None,
 }
 
+/// A class or an enum declared by the compiled program.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct TypeRef {
+    pub(crate) index: u16,
+    name: String,
+}
+
+impl TypeRef {
+    pub(crate) fn new(index: usize, name: &str) -> Self {
+        TypeRef {
+            index: index as u16,
+            name: name.to_string(),
+        }
+    }
+
+    /// The name of the class or the enum in its declaration.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl ExpressionKind {
+    /// Whether a value of this kind may be or hold an instance, whose `to_string`
+    /// method runs when it is printed.
+    pub fn may_contain_instances(&self) -> bool {
+        !matches!(
+            self,
+            ExpressionKind::Bool
+                | ExpressionKind::String
+                | ExpressionKind::Int
+                | ExpressionKind::Float
+                | ExpressionKind::Function(..)
+                | ExpressionKind::Nil
+                | ExpressionKind::None
+        )
+    }
+
+    /// Whether a value of kind `actual` can be stored where `self` is expected.
+    /// Optionals accept `nil` and values of their inner kind.
+    pub fn accepts(&self, actual: &ExpressionKind) -> bool {
+        match self {
+            ExpressionKind::Optional(inner) => {
+                self == actual || *actual == ExpressionKind::Nil || **inner == *actual
+            }
+            expected => expected == actual,
+        }
+    }
+}
+
+/// Kinds are written the way types are in the source, like `int?`, `[str]`,
+/// `{str: int}` and `fun(int) -> str`.
+impl fmt::Display for ExpressionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpressionKind::Bool => write!(f, "bool"),
+            ExpressionKind::String => write!(f, "str"),
+            ExpressionKind::Int => write!(f, "int"),
+            ExpressionKind::Float => write!(f, "float"),
+            ExpressionKind::Class(class) => write!(f, "{}", class.name()),
+            ExpressionKind::Enum(enum_ref) => write!(f, "{}", enum_ref.name()),
+            ExpressionKind::Array(element) => write!(f, "[{}]", element),
+            ExpressionKind::Map(key, value) => write!(f, "{{{}: {}}}", key, value),
+            ExpressionKind::Function(params, return_kind) => {
+                let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+                write!(f, "fun({})", params.join(", "))?;
+                match **return_kind {
+                    ExpressionKind::None => Ok(()),
+                    ref kind => write!(f, " -> {}", kind),
+                }
+            }
+            ExpressionKind::Optional(inner) => write!(f, "{}?", inner),
+            ExpressionKind::Nil => write!(f, "nil"),
+            ExpressionKind::None => write!(f, "none"),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
 pub enum TokenKind {
     // Single-character tokens.
     LeftParen,
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
@@ -148,11 +942,14 @@ pub enum TokenKind {
     Star,
     Colon,
     Percent,
+    Question,
     // One or two character tokens.
     Bang,
     BangEqual,
     Equal,
     EqualEqual,
+    FatArrow,
+    Arrow,
     Greater,
     GreaterEqual,
     Less,
@@ -161,6 +958,7 @@ pub enum TokenKind {
     Identifier,
     String,
     Number,
+    FloatNumber,
     // Keywords.
     New,
     Let,
@@ -178,10 +976,18 @@ pub enum TokenKind {
     Return,
     True,
     In,
+    Is,
+    Enum,
+    Match,
+    Import,
+    Pub,
     Int,
+    Float,
     Str,
     Bool,
     While,
+    Break,
+    Continue,
     Error,
     Eof,
 }
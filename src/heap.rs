@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::mem::size_of;
+
+use crate::vm::Value;
+
+/// Controls when the garbage collector runs.
+///
+/// ```
+/// let config = bofink::GcConfig::new().with_initial_threshold(4096);
+/// assert_eq!(config.initial_threshold, 4096);
+/// ```
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct GcConfig {
+    /// Number of bytes that can be allocated before the first collection.
+    pub initial_threshold: usize,
+    /// After a collection the next one runs when the heap has grown to
+    /// `growth_factor` times the bytes that survived.
+    pub growth_factor: usize,
+    /// Collect before every allocation. Only useful for testing.
+    pub stress: bool,
+}
+
+impl GcConfig {
+    /// The default configuration, a first collection after 1 MiB that doubles the
+    /// threshold.
+    pub const fn new() -> GcConfig {
+        GcConfig {
+            initial_threshold: 1024 * 1024,
+            growth_factor: 2,
+            stress: false,
+        }
+    }
+
+    pub const fn with_initial_threshold(self, initial_threshold: usize) -> GcConfig {
+        GcConfig {
+            initial_threshold,
+            ..self
+        }
+    }
+
+    pub const fn with_growth_factor(self, growth_factor: usize) -> GcConfig {
+        GcConfig {
+            growth_factor,
+            ..self
+        }
+    }
+
+    pub const fn with_stress(self, stress: bool) -> GcConfig {
+        GcConfig { stress, ..self }
+    }
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig::new()
+    }
+}
+
+/// Counters of the garbage collector, from the start of a run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct GcStats {
+    /// Number of collections that ran.
+    pub collections: usize,
+    pub objects_allocated: usize,
+    pub objects_freed: usize,
+    /// Objects allocated and not yet freed.
+    pub live_objects: usize,
+    /// Bytes held by live objects.
+    pub bytes_allocated: usize,
+    /// The largest `bytes_allocated` reached.
+    pub peak_bytes_allocated: usize,
+}
+
+pub enum Object {
+    String(String),
+    Instance(RuntimeInstance),
+    Array(Vec<Value>),
+    Map(RuntimeMap),
+    Closure(RuntimeClosure),
+}
+
+impl Object {
+    fn size(&self) -> usize {
+        size_of::<Object>()
+            + match self {
+                Object::String(s) => s.capacity(),
+                Object::Instance(i) => i.values.capacity() * size_of::<Value>(),
+                Object::Array(values) => values.capacity() * size_of::<Value>(),
+                Object::Map(map) => {
+                    map.entries.capacity() * size_of::<Option<(Value, Value)>>()
+                        + map.index.capacity() * size_of::<(MapKey, usize)>()
+                }
+                Object::Closure(closure) => closure.captures.capacity() * size_of::<Value>(),
+            }
+    }
+}
+
+pub struct RuntimeInstance {
+    /// Index of the class in the chunk.
+    pub class: usize,
+    pub values: Vec<Value>,
+}
+
+impl Display for RuntimeInstance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Struct RuntimeInstance - values")?;
+        for v in &self.values {
+            write!(f, " {}", v)?;
+        }
+        Ok(())
+    }
+}
+
+/// A function value together with the values it captured when it was created.
+pub struct RuntimeClosure {
+    /// Index of the function in the chunk.
+    pub function: usize,
+    /// Passed to the function as the locals after its parameters.
+    pub captures: Vec<Value>,
+}
+
+/// A map key, compared by content rather than by heap handle.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Int(i64),
+    Bool(bool),
+    String(String),
+}
+
+impl Display for MapKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MapKey::Int(i) => write!(f, "{}", i),
+            MapKey::Bool(b) => write!(f, "{}", b),
+            MapKey::String(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Map entries in insertion order, with an index from key to entry. Removing an
+/// entry leaves a hole, so it takes constant time, and the holes are compacted away
+/// once they make up half of the entries.
+#[derive(Default)]
+pub struct RuntimeMap {
+    entries: Vec<Option<(Value, Value)>>,
+    index: HashMap<MapKey, usize>,
+}
+
+impl RuntimeMap {
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// The keys and values in insertion order.
+    pub fn entries(&self) -> impl Iterator<Item = (Value, Value)> + '_ {
+        self.entries.iter().flatten().copied()
+    }
+
+    pub fn get(&self, key: &MapKey) -> Option<Value> {
+        let (_, value) = self.entries[*self.index.get(key)?]?;
+        Some(value)
+    }
+
+    pub fn has(&self, key: &MapKey) -> bool {
+        self.index.contains_key(key)
+    }
+
+    /// Replaces the value of an existing key in place, or adds a new entry last.
+    pub fn insert(&mut self, key: MapKey, key_value: Value, value: Value) {
+        match self.index.get(&key) {
+            Some(idx) => self.entries[*idx] = Some((key_value, value)),
+            None => {
+                self.index.insert(key, self.entries.len());
+                self.entries.push(Some((key_value, value)));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &MapKey) -> Option<Value> {
+        let idx = self.index.remove(key)?;
+        let (_, value) = self.entries[idx].take()?;
+        if self.entries.len() > 2 * self.index.len() {
+            self.compact();
+        }
+        Some(value)
+    }
+
+    /// Removes the holes left by removed entries, keeping the order of the rest.
+    fn compact(&mut self) {
+        let mut moved_to = Vec::with_capacity(self.entries.len());
+        let mut next = 0;
+        for entry in &self.entries {
+            moved_to.push(next);
+            next += entry.is_some() as usize;
+        }
+        for idx in self.index.values_mut() {
+            *idx = moved_to[*idx];
+        }
+        self.entries.retain(Option::is_some);
+    }
+}
+
+struct Slot {
+    object: Object,
+    marked: bool,
+    /// Size of the object when it was last measured, which is what the heap
+    /// accounts for. Objects that grow after their allocation are measured again.
+    size: usize,
+}
+
+/// Managed heap for strings, class instances, arrays, maps and closures. Values refer to objects by
+/// their slot index; freed slots are reused by later allocations.
+pub struct Heap {
+    slots: Vec<Option<Slot>>,
+    free: Vec<usize>,
+    next_gc: usize,
+    config: GcConfig,
+    stats: GcStats,
+}
+
+impl Heap {
+    pub fn new(config: GcConfig) -> Heap {
+        Heap {
+            slots: vec![],
+            free: vec![],
+            next_gc: config.initial_threshold,
+            config,
+            stats: GcStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.stats
+    }
+
+    /// Whether allocating `object` should be preceded by a collection.
+    pub fn should_collect(&self, object: &Object) -> bool {
+        self.config.stress || self.stats.bytes_allocated + object.size() > self.next_gc
+    }
+
+    pub fn alloc(&mut self, object: Object) -> usize {
+        let size = object.size();
+        self.stats.bytes_allocated += size;
+        self.stats.peak_bytes_allocated = self
+            .stats
+            .peak_bytes_allocated
+            .max(self.stats.bytes_allocated);
+        self.stats.objects_allocated += 1;
+        self.stats.live_objects += 1;
+        let slot = Some(Slot {
+            object,
+            marked: false,
+            size,
+        });
+        match self.free.pop() {
+            Some(idx) => {
+                self.slots[idx] = slot;
+                idx
+            }
+            None => {
+                self.slots.push(slot);
+                self.slots.len() - 1
+            }
+        }
+    }
+
+    /// Measures an object again after it has grown, like an array that was pushed
+    /// to, so the next collection runs when the heap has really grown enough.
+    pub fn grow(&mut self, idx: usize) {
+        let Some(Some(slot)) = self.slots.get_mut(idx) else {
+            return;
+        };
+        let size = slot.object.size();
+        self.stats.bytes_allocated = self.stats.bytes_allocated - slot.size + size;
+        slot.size = size;
+        self.stats.peak_bytes_allocated = self
+            .stats
+            .peak_bytes_allocated
+            .max(self.stats.bytes_allocated);
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&Object> {
+        match self.slots.get(idx) {
+            Some(Some(slot)) => Some(&slot.object),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, idx: usize) -> Option<&mut Object> {
+        match self.slots.get_mut(idx) {
+            Some(Some(slot)) => Some(&mut slot.object),
+            _ => None,
+        }
+    }
+
+    /// Frees every object that is not reachable from `roots`.
+    pub fn collect<'a>(&mut self, roots: impl IntoIterator<Item = &'a Value>) {
+        let mut gray: Vec<usize> = roots.into_iter().filter_map(Self::handle).collect();
+        while let Some(idx) = gray.pop() {
+            let Some(Some(slot)) = self.slots.get_mut(idx) else {
+                continue;
+            };
+            if slot.marked {
+                continue;
+            }
+            slot.marked = true;
+            match &slot.object {
+                Object::Instance(instance) => {
+                    gray.extend(instance.values.iter().filter_map(Self::handle))
+                }
+                Object::Array(values) => gray.extend(values.iter().filter_map(Self::handle)),
+                Object::Closure(closure) => {
+                    gray.extend(closure.captures.iter().filter_map(Self::handle))
+                }
+                Object::Map(map) => gray.extend(
+                    map.entries()
+                        .flat_map(|(key, value)| [key, value])
+                        .filter_map(|value| Self::handle(&value)),
+                ),
+                Object::String(_) => {}
+            }
+        }
+
+        for (idx, entry) in self.slots.iter_mut().enumerate() {
+            match entry {
+                Some(slot) if slot.marked => slot.marked = false,
+                Some(slot) => {
+                    self.stats.bytes_allocated -= slot.size;
+                    self.stats.objects_freed += 1;
+                    self.stats.live_objects -= 1;
+                    self.free.push(idx);
+                    *entry = None;
+                }
+                None => {}
+            }
+        }
+
+        self.stats.collections += 1;
+        self.next_gc = (self.stats.bytes_allocated * self.config.growth_factor)
+            .max(self.config.initial_threshold);
+    }
+
+    fn handle(value: &Value) -> Option<usize> {
+        match value {
+            Value::String(idx)
+            | Value::Instance(idx)
+            | Value::Array(idx)
+            | Value::Map(idx)
+            | Value::Function(idx) => Some(*idx),
+            _ => None,
+        }
+    }
+}
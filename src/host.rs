@@ -0,0 +1,289 @@
+//! Values passed between the host program and scripts, and their conversions from
+//! and to Rust types.
+
+use std::fmt;
+
+use crate::enums::ExpressionKind;
+use crate::heap::GcStats;
+
+/// A value passed between the host program and a script.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum HostValue {
+    /// `nil`, also returned by native functions without a return value.
+    Nil,
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Instance(HostInstance),
+}
+
+impl HostValue {
+    /// The name of the bofink type of the value.
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            HostValue::Nil => "nil",
+            HostValue::Int(_) => "int",
+            HostValue::Float(_) => "float",
+            HostValue::Bool(_) => "bool",
+            HostValue::Str(_) => "str",
+            HostValue::Instance(_) => "instance",
+        }
+    }
+
+    /// Like `kind_name`, but naming the class of an instance.
+    fn type_name(&self) -> String {
+        match self {
+            HostValue::Instance(instance) => instance.class.to_string(),
+            value => value.kind_name().to_string(),
+        }
+    }
+
+    /// Whether the value is of `kind`, where `None` is no value at all.
+    pub(crate) fn is_kind(&self, kind: &ExpressionKind) -> bool {
+        matches!(
+            (self, kind),
+            (HostValue::Nil, ExpressionKind::None)
+                | (HostValue::Int(_), ExpressionKind::Int)
+                | (HostValue::Float(_), ExpressionKind::Float)
+                | (HostValue::Bool(_), ExpressionKind::Bool)
+                | (HostValue::Str(_), ExpressionKind::String)
+        )
+    }
+
+    fn mismatch(&self, expected: &str) -> ConversionError {
+        ConversionError::Type {
+            expected: expected.to_string(),
+            actual: self.type_name(),
+        }
+    }
+}
+
+/// An instance of a class declared by a script, with its fields by name.
+///
+/// ```
+/// use bofink::HostInstance;
+///
+/// let point = HostInstance::new("Point").with("x", 1).with("y", 2);
+/// assert_eq!(point.get::<i64>("y"), Ok(2));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostInstance {
+    pub class: String,
+    pub fields: Vec<(String, HostValue)>,
+}
+
+impl HostInstance {
+    /// An instance without fields.
+    pub fn new(class: &str) -> Self {
+        HostInstance {
+            class: class.to_string(),
+            fields: vec![],
+        }
+    }
+
+    /// Sets the field `name`, replacing any value it had.
+    pub fn with(mut self, name: &str, value: impl IntoHost) -> Self {
+        let value = value.into_host();
+        match self.fields.iter_mut().find(|(field, _)| field == name) {
+            Some((_, existing)) => *existing = value,
+            None => self.fields.push((name.to_string(), value)),
+        }
+        self
+    }
+
+    /// Converts the field `name`.
+    pub fn get<T: FromHost>(&self, name: &str) -> Result<T, ConversionError> {
+        match self.field(name) {
+            Some(value) => T::from_host(value.clone()),
+            None => Err(ConversionError::MissingField {
+                class: self.class.to_string(),
+                field: name.to_string(),
+            }),
+        }
+    }
+
+    pub fn field(&self, name: &str) -> Option<&HostValue> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Fails unless this is an instance of `class`, for conversions into Rust types.
+    pub fn expect_class(self, class: &str) -> Result<Self, ConversionError> {
+        match self.class == class {
+            true => Ok(self),
+            false => Err(ConversionError::Type {
+                expected: class.to_string(),
+                actual: self.class,
+            }),
+        }
+    }
+}
+
+/// Why a value could not be converted between the host program and a script. A
+/// value is never reinterpreted as another type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConversionError {
+    /// A value of another type than the Rust type or the script expects, named by
+    /// their bofink types.
+    Type { expected: String, actual: String },
+    /// An instance without a field that the class or the Rust type needs.
+    MissingField { class: String, field: String },
+    /// An instance with a field that its class does not declare.
+    UnknownField { class: String, field: String },
+    /// A value of a type that can not cross over, like an array or a map.
+    Unsupported(String),
+    /// An instance that contains itself, which has no finite host value.
+    Cycle(String),
+    /// A global that the script does not declare, or did not get to declare.
+    UnknownGlobal(String),
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionError::Type { expected, actual } => {
+                write!(f, "Expected '{}' but got '{}'", expected, actual)
+            }
+            ConversionError::MissingField { class, field } => {
+                write!(f, "Instance of '{}' has no field '{}'", class, field)
+            }
+            ConversionError::UnknownField { class, field } => {
+                write!(f, "Class '{}' has no field '{}'", class, field)
+            }
+            ConversionError::Unsupported(kind) => write!(
+                f,
+                "Values of type '{}' can not be passed to or from the host",
+                kind
+            ),
+            ConversionError::Cycle(class) => write!(f, "Instance of '{}' contains itself", class),
+            ConversionError::UnknownGlobal(name) => {
+                write!(f, "The script has no global '{}'", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// Conversion of a Rust value into a value for a script.
+pub trait IntoHost {
+    fn into_host(self) -> HostValue;
+}
+
+/// Conversion of a value of a script into a Rust value, failing if it is of another
+/// type.
+pub trait FromHost: Sized {
+    fn from_host(value: HostValue) -> Result<Self, ConversionError>;
+}
+
+impl IntoHost for HostValue {
+    fn into_host(self) -> HostValue {
+        self
+    }
+}
+
+impl FromHost for HostValue {
+    fn from_host(value: HostValue) -> Result<Self, ConversionError> {
+        Ok(value)
+    }
+}
+
+macro_rules! host_conversions {
+    ($($type:ty => $variant:ident, $name:literal;)*) => {
+        $(
+            impl IntoHost for $type {
+                fn into_host(self) -> HostValue {
+                    HostValue::$variant(self)
+                }
+            }
+
+            impl FromHost for $type {
+                fn from_host(value: HostValue) -> Result<Self, ConversionError> {
+                    match value {
+                        HostValue::$variant(value) => Ok(value),
+                        value => Err(value.mismatch($name)),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+host_conversions! {
+    i64 => Int, "int";
+    f64 => Float, "float";
+    bool => Bool, "bool";
+    String => Str, "str";
+    HostInstance => Instance, "instance";
+}
+
+impl IntoHost for &str {
+    fn into_host(self) -> HostValue {
+        HostValue::Str(self.to_string())
+    }
+}
+
+/// `None` is `nil`, for optionals.
+impl<T: IntoHost> IntoHost for Option<T> {
+    fn into_host(self) -> HostValue {
+        match self {
+            Some(value) => value.into_host(),
+            None => HostValue::Nil,
+        }
+    }
+}
+
+impl<T: FromHost> FromHost for Option<T> {
+    fn from_host(value: HostValue) -> Result<Self, ConversionError> {
+        match value {
+            HostValue::Nil => Ok(None),
+            value => T::from_host(value).map(Some),
+        }
+    }
+}
+
+/// The type of a value that can be passed to a script, as the vm checks it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum HostKind {
+    Int,
+    Float,
+    Bool,
+    Str,
+    Optional(Box<HostKind>),
+    /// An instance of a class, by its class info.
+    Instance(usize),
+}
+
+/// What a run of a script left for the host: its return value and its globals.
+#[derive(Debug)]
+pub struct Outcome {
+    pub(crate) value: Result<HostValue, ConversionError>,
+    pub(crate) globals: Vec<(String, Result<HostValue, ConversionError>)>,
+    pub(crate) gc_stats: GcStats,
+}
+
+impl Outcome {
+    /// The value returned by the top level code of the script, `nil` if it did not
+    /// return one.
+    pub fn value<T: FromHost>(&self) -> Result<T, ConversionError> {
+        T::from_host(self.value.clone()?)
+    }
+
+    /// The value of a global declared at the top level of the script.
+    pub fn global<T: FromHost>(&self, name: &str) -> Result<T, ConversionError> {
+        match self.globals.iter().find(|(global, _)| global == name) {
+            Some((_, value)) => T::from_host(value.clone()?),
+            None => Err(ConversionError::UnknownGlobal(name.to_string())),
+        }
+    }
+
+    /// The garbage collection statistics of the run.
+    pub fn gc_stats(&self) -> GcStats {
+        self.gc_stats
+    }
+}
@@ -0,0 +1,3082 @@
+//! Bofink is a simple typed scripting language, type checked at compile time.
+//!
+//! An [`Engine`] compiles sources into a [`Chunk`] of bytecode and runs it. The items
+//! exported here are the stable surface of the crate, apart from the compiler stages
+//! in [`unstable`]; everything else may change between versions.
+
+mod ast;
+mod checker;
+mod codegen;
+mod compiler;
+mod engine;
+mod enums;
+mod heap;
+mod host;
+mod native;
+mod opcode;
+mod parser;
+mod scanner;
+mod vm;
+
+pub use compiler::Chunk;
+pub use engine::{Engine, Error};
+pub use enums::{
+    Binding, CompilerError, Diagnostic, ExpressionKind, Label, RuntimeError, RuntimeErrorKind,
+    Severity, StackFrame, TokenKind, TypeRef,
+};
+pub use heap::{GcConfig, GcStats};
+pub use host::{ConversionError, FromHost, HostInstance, HostValue, IntoHost, Outcome};
+pub use scanner::Span;
+
+/// The stages of the compiler, for tools that work on the syntax tree, like
+/// formatters and linters.
+///
+/// **Unstable**: unlike the rest of the crate, anything in here may change in any
+/// version.
+///
+/// ```
+/// use bofink::unstable::{ast, check, generate, parse};
+/// use bofink::Engine;
+///
+/// let source = "let a = 1 + 2;\nprint a;";
+/// let (file, errors) = parse(source);
+/// assert!(errors.is_empty());
+/// assert_eq!(file.stmts.len(), 2);
+///
+/// let mut modules = vec![ast::Module {
+///     name: "<script>".to_string(),
+///     path: None,
+///     source: source.to_string(),
+///     file,
+///     init: 0,
+/// }];
+/// let program = check(&mut modules, &[], &[], &[]).unwrap();
+/// let chunk = generate(&modules, program).unwrap();
+///
+/// let mut engine = Engine::with_output(Vec::new());
+/// engine.run(chunk).unwrap();
+/// assert_eq!(engine.output(), b"3\n");
+/// ```
+pub mod unstable {
+    /// The syntax tree of a file, with the slots the checker fills in.
+    pub mod ast {
+        pub use crate::ast::*;
+    }
+
+    pub use crate::checker::{check, Program};
+    pub use crate::codegen::generate;
+    pub use crate::compiler::ClassInfo;
+    pub use crate::native::NativeSignature;
+    pub use crate::parser::parse;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::compiler::CompileConfig;
+    use crate::enums::{CompilerError, Diagnostic, ExpressionKind, RuntimeError, RuntimeErrorKind, Severity, StackFrame};
+    use crate::heap::{GcConfig, GcStats};
+    use crate::opcode::OpCode;
+    use crate::scanner::Span;
+    use crate::{ast, checker, codegen, compiler, parser, vm};
+
+    // Collecting before every allocation makes every test exercise the roots of the gc.
+    const STRESS_GC: GcConfig = GcConfig::new().with_initial_threshold(0).with_stress(true);
+
+    fn test_output(source: &str, expected_output: &str) {
+        let (output, _) = run_with_config(source, STRESS_GC);
+        assert_eq!(output, expected_output);
+    }
+
+    /// Compiles a source, keeping only the first error.
+    fn compile(source: String) -> Result<compiler::Chunk, CompilerError> {
+        compiler::compile(source).map_err(|mut diagnostics| diagnostics.remove(0).error)
+    }
+
+    fn run_with_config(source: &str, config: GcConfig) -> (String, GcStats) {
+        let mut buf = Vec::new();
+        let stats = match compile(source.to_string()) {
+            Ok(chunk) => match vm::start_with_config(chunk, &mut buf, config, &[]) {
+                Ok(stats) => stats,
+                Err(e) => panic!("Runtime error: {}", e),
+            },
+            Err(e) => panic!("Compiler error: {}", e),
+        };
+        (String::from_utf8(buf).unwrap(), stats)
+    }
+
+    fn test_runtime_error(source: &str) -> RuntimeError {
+        let mut buf = Vec::new();
+        match compile(source.to_string()) {
+            Ok(chunk) => match vm::start(chunk, &mut buf) {
+                Ok(_) => panic!("Expected a runtime error"),
+                Err(e) => e,
+            },
+            Err(e) => panic!("Compiler error: {}", e),
+        }
+    }
+
+    /// Writes `files` to a new directory and compiles the `main.bofink` among them,
+    /// keeping only the first error.
+    fn compile_files(
+        test: &str,
+        files: &[(&str, &str)],
+        search_path: &[&str],
+    ) -> Result<compiler::Chunk, CompilerError> {
+        compile_files_diagnostics(test, files, search_path)
+            .map_err(|mut diagnostics| diagnostics.remove(0).error)
+    }
+
+    fn compile_files_diagnostics(
+        test: &str,
+        files: &[(&str, &str)],
+        search_path: &[&str],
+    ) -> Result<compiler::Chunk, Vec<Diagnostic>> {
+        let dir = std::env::temp_dir().join(format!("bofink-{}-{}", test, std::process::id()));
+        for (name, source) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        let main = dir.join("main.bofink");
+        let config = CompileConfig {
+            file: Some(main.clone()),
+            search_path: search_path.iter().map(|p| dir.join(p)).collect(),
+            natives: vec![],
+            inputs: vec![],
+            input_types: vec![],
+        };
+        compiler::compile_with_config(fs::read_to_string(main).unwrap(), config)
+    }
+
+    fn run_files(test: &str, files: &[(&str, &str)], search_path: &[&str]) -> String {
+        let mut buf = Vec::new();
+        match compile_files(test, files, search_path) {
+            Ok(chunk) => {
+                if let Err(e) = vm::start_with_config(chunk, &mut buf, STRESS_GC, &[]) {
+                    panic!("Runtime error: {}", e);
+                }
+            }
+            Err(e) => panic!("Compiler error: {}", e),
+        }
+        String::from_utf8(buf).unwrap()
+    }
+
+    // TODO: test error types
+    fn test_error(source: &str) {
+        let _result = compile(source.to_string());
+    }
+
+    #[test]
+    fn assignment_without_declaration_should_fail() {
+        test_error("some code");
+    }
+
+    #[test]
+    fn hello_world() {
+        let source = r#"
+            print "Hello world!";
+        "#;
+
+        let expected = "Hello world!\n";
+
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn concatenation() {
+        let source = r#"
+            let i = 1;
+            let s = "word";
+            let b = true;
+            print s + i;
+            print s + b;
+            print i + s;
+            print b + s;
+        "#;
+        let expected = "word1\nwordtrue\n1word\ntrueword\n";
+
+        test_output(source, expected)
+    }
+
+    #[test]
+    fn adding_numbers() {
+        let source = r#"
+            let i = 2 + 3 + 4 + 5;
+            print "Sum: " + i;
+        "#;
+        let expected = "Sum: 14\n";
+
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn while_loop() {
+        let source = r#"
+            mut i = 0;
+            while i < 5 {
+                print "hello";
+                i = i + 1;
+            }
+        "#;
+        let expected = "hello\nhello\nhello\nhello\nhello\n";
+
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn while_loop_concat() {
+        let source = r#"
+            mut i = 0;
+            while i < 5 {
+                print "hello" + i;
+                i = i + 1;
+            }
+        "#;
+        let expected = "hello0\nhello1\nhello2\nhello3\nhello4\n";
+
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn function() {
+        let source = r#"
+            fun test(p1: int, p2: str) {
+                print "first: " + p1;
+                print "second: " + p2;
+            }
+            test(5, "a string");
+            test(10, "text");
+        "#;
+        let expected = "first: 5\nsecond: a string\nfirst: 10\nsecond: text\n";
+
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn function_with_local_params() {
+        let source = r#"
+            let s1 = "first";
+            fun test(p1: str, p2: str) {
+                print p1 + "!";
+                print p2 + "!";
+            }
+            let s2 = "second";
+            test(s1, s2);
+        "#;
+        let expected = "first!\nsecond!\n";
+
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn multiple_function_with_local_params() {
+        let source = r#"
+            let s1 = "first";
+            fun test(p1: str, p2: str) {
+                print p1 + "!";
+                print p2 + "!";
+            }
+            let s2 = "second";
+            test(s1, s2);
+            test(s1, s2);
+        "#;
+        let expected = "first!\nsecond!\nfirst!\nsecond!\n";
+
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn if_statement() {
+        let source = r#"
+            if true {
+                print "yes1";
+            }
+            if false {
+                print "no";
+            }
+            if 1 == 1 {
+                print "yes2";
+            }
+            if "hello" != "world" {
+                print "yes3";
+            }
+
+            mut i = 0;
+            i = i + 1;
+            if i == 1 {
+                print "yes4";
+            }
+        "#;
+        let expected = "yes1\nyes2\nyes3\nyes4\n";
+
+        test_output(source, expected);
+    }
+    #[test]
+    fn nested_while() {
+        let source = r#"
+            mut i = 0;
+            while i < 3 {
+                mut j = 0;
+                while j < 3 {
+                    print "i: " + i + ", j: " + j;
+                    j = j + 1;
+                }
+                i = i + 1;
+            }
+        "#;
+        let expected = "i: 0, j: 0\ni: 0, j: 1\ni: 0, j: 2\ni: 1, j: 0\ni: 1, j: 1\ni: 1, j: 2\ni: 2, j: 0\ni: 2, j: 1\ni: 2, j: 2\n";
+
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn nested_while_with_if() {
+        let source = r#"
+            mut i: int = 0;
+            while i < 5 {
+                mut j: int = 0;
+                while j < 5 {
+                    if j == 3 {
+                        print "j==3";
+                    }
+                    j = j + 1;
+                }
+                i = i + 1;
+            }
+        "#;
+        let expected = "j==3\n".repeat(5);
+
+        test_output(source, &expected);
+    }
+
+    #[test]
+    fn for_loop() {
+        let source = r#"
+            for i in 0:3 {
+                print "i" + i;
+            }
+        "#;
+        let expected = "i0\ni1\ni2\n";
+
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn nested_for_loop() {
+        let source = r#"
+            for i in 0:3 {
+                for j in 0:3 {
+                    print "i" + i + "j" + j;
+                }
+            }
+        "#;
+        let expected = "i0j0\ni0j1\ni0j2\ni1j0\ni1j1\ni1j2\ni2j0\ni2j1\ni2j2\n";
+
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn and_and_or() {
+        let source = r#"
+            if true and true {
+                print "1";
+            }
+            if true and false {
+                print "2";
+            }
+            if false and true {
+                print "3";
+            }
+            if false and false {
+                print "4";
+            }
+
+            if true or true {
+                print "5";
+            }
+            if true or false {
+                print "6";
+            }
+            if false or true {
+                print "7";
+            }
+            if false or false {
+                print "8";
+            }
+        "#;
+        let expected = "1\n5\n6\n7\n";
+
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn compare_strings() {
+        let source = r#"
+        if "1" == "1" {
+            print "1";
+        }
+        if "1" == "2" {
+            print "error";
+        }
+        if "1" != "2" {
+            print "2";
+        }
+        if "2" != "2" {
+            print "error";
+        }
+        "#;
+        let expected = "1\n2\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn comparing() {
+        let source = r#"
+            let i = 5;
+            if i > 3 {
+                print "3";
+            }
+            if i < 8 {
+                print "8";
+            }
+            if i < 3 {
+                print "not this";
+            }
+            if i > 8 {
+                print "not this";
+            }
+            if i == 5 {
+                print "5";
+            }
+            if i != 5 {
+                print "not this";
+            }
+            if i != 7 {
+                print "7";
+            }
+            if 5 <= 5 {
+                print "5";
+            }
+            if 5 >= 5 {
+                print "5";
+            }
+        "#;
+        let expected = "3\n8\n5\n7\n5\n5\n";
+        test_output(source, expected)
+    }
+
+    #[test]
+    fn arithmetic() {
+        let source = r#"
+            let i = 5 - 3;
+            if i != 2 {
+                print "something went wrong";
+            }
+
+            let j = 5 + 5;
+            if j != 10 {
+                print "something went wrong";
+            }
+
+            let k = 10 / 2;
+            if k != 5 {
+                print "something went wrong";
+            }
+
+            let l = 5 * 5;
+            if l != 25 {
+                print "something went wrong";
+            }
+
+            let h = 7 % 3;
+            if h != 1 {
+                print "something went wrong";
+            }
+        "#;
+        let expected = "";
+        test_output(source, expected)
+    }
+
+    #[test]
+    fn recursion() {
+        let source = r#"
+            fun fib(i: int) int {
+                if i == 0 {
+                    return 1;
+                }
+                if i == 1 {
+                    return 1;
+                }
+                let r1 = fib(i - 1);
+                let r2 = fib(i - 2);
+                return r1 + r2; 
+            }
+            let res: int = fib(10);
+            print "res: " + res;
+        "#;
+        let expected = "res: 89\n";
+        test_output(source, expected);
+    }
+    #[test]
+    fn fizzbuzz() {
+        let source = r#"
+            for i in 1:20 {
+                if i % 3 == 0 and i % 5 == 0 {
+                    print "fizzbuzz";
+                }
+                if i % 3 == 0 and i % 5 != 0 {
+                    print "fizz";
+                }
+                if i % 3 != 0 and i % 5 == 0 {
+                    print "buzz";
+                }
+                if i % 3 != 0 and i % 5 != 0 {
+                    print "" + i;
+                }
+            }
+        "#;
+        let expected = "1\n2\nfizz\n4\nbuzz\nfizz\n7\n8\nfizz\nbuzz\n11\nfizz\n13\n14\nfizzbuzz\n16\n17\nfizz\n19\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn nested_objects_get() {
+        let source = r#"
+            class Test {
+                int i1;
+            }
+            class Foo {
+                Test t1;
+            }
+            let bar = new Foo(new Test(2));
+            print "i1:" + bar.t1.i1;
+        "#;
+        let expected = "i1:2\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn reassign_local_in_function() {
+        let source = r#"
+            let s = "outer";
+            fun test(p1: int) {
+                mut i = p1;
+                i = i + 1;
+                print "i" + i;
+            }
+            test(1);
+            print s;
+        "#;
+        let expected = "i2\nouter\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn chained_and_or() {
+        let source = r#"
+            if false or false or true {
+                print "1";
+            }
+            if true and true and false {
+                print "2";
+            }
+        "#;
+        let expected = "1\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn operand_type_mismatch() {
+        // the compiler never emits this, so the bytecode is patched by hand
+        let mut chunk = compile("print 1 + 2;".to_string()).unwrap();
+        chunk.code[0] = vec![
+            OpCode::True as u8,
+            OpCode::Int as u8,
+            0,
+            0,
+            OpCode::Add as u8,
+            OpCode::Print as u8,
+            OpCode::Return as u8,
+            0,
+            0,
+        ];
+        chunk.line[0] = vec![1; chunk.code[0].len()];
+        let error = vm::start(chunk, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            error.kind,
+            RuntimeErrorKind::TypeMismatch {
+                instruction: "Add",
+                expected: "int",
+                actual: "bool",
+            }
+        );
+        assert_eq!(error.line, 1);
+    }
+
+    #[test]
+    fn invalid_constants() {
+        for opcode in [OpCode::Int, OpCode::Float, OpCode::String] {
+            let mut chunk = compile("print 1;".to_string()).unwrap();
+            chunk.code[0] = vec![opcode as u8, 0, 9, OpCode::Print as u8, OpCode::Return as u8, 0, 0];
+            chunk.line[0] = vec![1; chunk.code[0].len()];
+            let error = vm::start(chunk, &mut Vec::new()).unwrap_err();
+            assert!(matches!(error.kind, RuntimeErrorKind::InvalidConstant(_)), "{:?}", opcode);
+        }
+    }
+
+    #[test]
+    fn print_instances() {
+        let source = r#"
+            class Point {
+                int x;
+                int y;
+            }
+            class Named {
+                str name;
+                Point at;
+                [int] tags;
+                {str: float} weights;
+            }
+            print new Point(1, 2);
+            let named = new Named("a", new Point(0, -1), [1, 2], {"w": 0.5});
+            print named;
+            print "named: " + named;
+            print named.tags + " tags";
+            print ["x", "y"];
+        "#;
+        let expected = "Point(x: 1, y: 2)\n\
+            Named(name: \"a\", at: Point(x: 0, y: -1), tags: [1, 2], weights: {\"w\": 0.5})\n\
+            named: Named(name: \"a\", at: Point(x: 0, y: -1), tags: [1, 2], weights: {\"w\": 0.5})\n\
+            [1, 2] tags\n\
+            [\"x\", \"y\"]\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn to_string_method() {
+        let source = r#"
+            class Money {
+                int cents;
+                fun to_string() str {
+                    return "$" + self.cents / 100 + "." + self.cents % 100;
+                }
+            }
+            class Wallet {
+                [Money] money;
+                fun to_string() str {
+                    return "wallet with " + self.money;
+                }
+            }
+            let m = new Money(1250);
+            print m;
+            print "total: " + m;
+            print new Wallet([m, new Money(199)]);
+        "#;
+        test_output(source, "$12.50\ntotal: $12.50\nwallet with [$12.50, $1.99]\n");
+    }
+
+    #[test]
+    fn print_cyclic_values() {
+        let source = r#"
+            class Node {
+                str name;
+                [Node] next;
+            }
+            let a = new Node("a", []);
+            mut next = a.next;
+            next.push(a);
+            print a;
+        "#;
+        test_output(source, "Node(name: \"a\", next: [...])\n");
+    }
+
+    #[test]
+    fn instance_equality() {
+        let source = r#"
+            class Point {
+                int x;
+                int y;
+            }
+            class Line {
+                Point from;
+                Point to;
+            }
+            let a = new Point(1, 2);
+            let b = new Point(1, 2);
+            print a == b;
+            print a != b;
+            print a == new Point(2, 1);
+            print a is b;
+            print a is a;
+            let c = a;
+            print c is a;
+            print new Line(a, b) == new Line(b, a);
+            print new Line(a, b) == new Line(a, new Point(0, 0));
+            print [a, b] == [b, a];
+            print {"x": 1, "y": 2} == {"y": 2, "x": 1};
+            print {"x": 1} != {"x": 2};
+        "#;
+        test_output(
+            source,
+            "true\nfalse\nfalse\nfalse\ntrue\ntrue\ntrue\nfalse\ntrue\ntrue\ntrue\n",
+        );
+    }
+
+    #[test]
+    fn instance_equality_errors() {
+        let source = r#"
+            class A {
+                int i;
+            }
+            class B {
+                int i;
+            }
+            print new A(1) == new B(1);
+        "#;
+        let result = compile(source.to_string());
+        assert!(matches!(
+            result,
+            Err(CompilerError::ComparisonType { span: Span { line: 8, .. }, .. })
+        ));
+
+        let result = compile("print 1 is 1;".to_string());
+        assert!(matches!(
+            result,
+            Err(CompilerError::InvalidOperatorTypes { span: Span { line: 1, .. }, .. })
+        ));
+
+        let source = r#"
+            class A {
+                int i;
+                fun to_string() int {
+                    return self.i;
+                }
+            }
+        "#;
+        let result = compile(source.to_string());
+        assert!(matches!(result, Err(CompilerError::InvalidToString(Span { line: 4, .. }))));
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let source = r#"
+            let i = 0;
+            print "result: " + 10 / i;
+        "#;
+        let error = test_runtime_error(source);
+        assert_eq!(error.kind, RuntimeErrorKind::DivisionByZero);
+        assert_eq!(error.line, 3);
+    }
+
+    #[test]
+    fn runtime_error_call_stack() {
+        let source = r#"
+            fun inner(i: int) int {
+                return 10 % i;
+            }
+            fun outer(i: int) int {
+                let res = inner(i);
+                return res;
+            }
+            let res = outer(0);
+        "#;
+        let error = test_runtime_error(source);
+        assert_eq!(error.kind, RuntimeErrorKind::DivisionByZero);
+        assert_eq!(error.line, 3);
+        assert_eq!(
+            error.call_stack,
+            vec![
+                StackFrame {
+                    function: "inner".to_string(),
+                    line: 3
+                },
+                StackFrame {
+                    function: "outer".to_string(),
+                    line: 6
+                },
+                StackFrame {
+                    function: "<script>".to_string(),
+                    line: 9
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn integer_overflow() {
+        let source = r#"
+            mut i = 4611686018427387904;
+            i = i + i;
+        "#;
+        let error = test_runtime_error(source);
+        assert_eq!(error.kind, RuntimeErrorKind::IntegerOverflow);
+        assert_eq!(error.line, 3);
+    }
+
+    #[test]
+    fn more_than_256_constants_and_locals() {
+        let mut source = String::new();
+        let mut expected = String::new();
+        for i in 0..300 {
+            source += &format!("let s{} = \"s{}\";\n", i, i);
+        }
+        source += "print s0 + s299;\n";
+        expected += "s0s299\n";
+        test_output(&source, &expected);
+    }
+
+    #[test]
+    fn long_jumps() {
+        let mut source = String::from("mut i = 0;\nwhile i < 2 {\n");
+        let mut expected = String::new();
+        for _ in 0..2 {
+            for j in 0..100 {
+                source += &format!("    print \"line\" + {};\n", j);
+                expected += &format!("line{}\n", j);
+            }
+        }
+        source += "    i = i + 1;\n}\n";
+        test_output(&source, &expected.repeat(2));
+    }
+
+    #[test]
+    fn more_than_256_functions() {
+        let mut source = String::new();
+        for i in 0..300 {
+            source += &format!("fun f{}() int {{ return {}; }}\n", i, i);
+        }
+        source += "let res = f299() + f0();\nprint \"\" + res;\n";
+        test_output(&source, "299\n");
+    }
+
+    #[test]
+    fn gc_frees_temporary_strings() {
+        let source = r#"
+            mut s = "";
+            for i in 0:2000 {
+                s = "iteration " + i;
+            }
+            print s;
+        "#;
+        let config = GcConfig::new().with_initial_threshold(4096);
+        let (output, stats) = run_with_config(source, config);
+        assert_eq!(output, "iteration 1999\n");
+        assert!(stats.collections > 0);
+        assert!(stats.objects_freed > 1900);
+        assert!(stats.live_objects < 100);
+        assert!(stats.peak_bytes_allocated < 16 * 4096);
+    }
+
+    #[test]
+    fn gc_accounts_for_growing_arrays() {
+        // every array grows to a megabyte and is dropped, so the heap has to collect
+        // them while they are pushed to rather than only counting their first size
+        let source = r#"
+            mut total = 0;
+            for i in 0:20 {
+                mut values = [0];
+                for j in 0:50000 {
+                    values.push(j);
+                }
+                total = total + values.len();
+                let s = "round " + i;
+            }
+            print total;
+        "#;
+        let (output, stats) = run_with_config(source, GcConfig::default());
+        assert_eq!(output, "1000020\n");
+        assert!(stats.collections > 0);
+        assert!(stats.peak_bytes_allocated > 50000 * std::mem::size_of::<vm::Value>());
+        assert!(stats.peak_bytes_allocated < 8 * 1024 * 1024);
+    }
+
+    #[test]
+    fn gc_accounts_for_growing_maps() {
+        let source = r#"
+            mut total = 0;
+            for i in 0:20 {
+                mut squares = {0: 0};
+                for j in 0:20000 {
+                    squares[j] = j * j;
+                }
+                total = total + squares.len();
+            }
+            print total;
+        "#;
+        let (output, stats) = run_with_config(source, GcConfig::default());
+        assert_eq!(output, "400000\n");
+        assert!(stats.collections > 0);
+        assert!(stats.peak_bytes_allocated > 20000 * std::mem::size_of::<(vm::Value, vm::Value)>());
+        assert!(stats.peak_bytes_allocated < 8 * 1024 * 1024);
+    }
+
+    #[test]
+    fn gc_keeps_reachable_instances() {
+        let source = r#"
+            class Test {
+                str s;
+            }
+            class Foo {
+                Test t1;
+            }
+            mut bar = new Foo(new Test("start"));
+            for i in 0:100 {
+                let temp = new Foo(new Test("temp" + i));
+                bar.t1.s = "kept" + i;
+            }
+            print bar.t1.s;
+        "#;
+        let (output, stats) = run_with_config(source, STRESS_GC);
+        assert_eq!(output, "kept99\n");
+        // every iteration leaves at least three unreachable objects behind
+        assert!(stats.objects_freed >= 300);
+    }
+
+    #[test]
+    fn if_else() {
+        let source = r#"
+            if 1 > 2 {
+                print "no";
+            } else {
+                print "yes1";
+            }
+            if 1 < 2 {
+                print "yes2";
+            } else {
+                print "no";
+            }
+        "#;
+        let expected = "yes1\nyes2\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn fizzbuzz_else_if() {
+        let source = r#"
+            for i in 1:20 {
+                if i % 15 == 0 {
+                    print "fizzbuzz";
+                } else if i % 3 == 0 {
+                    print "fizz";
+                } else if i % 5 == 0 {
+                    print "buzz";
+                } else {
+                    print "" + i;
+                }
+            }
+        "#;
+        let expected = "1\n2\nfizz\n4\nbuzz\nfizz\n7\n8\nfizz\nbuzz\n11\nfizz\n13\n14\nfizzbuzz\n16\n17\nfizz\n19\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn nested_if_else() {
+        let source = r#"
+            for i in 0:4 {
+                let big = i > 1;
+                if big {
+                    let s = "big";
+                    if i % 2 == 0 {
+                        print s + " even";
+                    } else {
+                        print s + " odd";
+                    }
+                } else {
+                    mut s = "small";
+                    if i == 0 {
+                        s = s + " zero";
+                    }
+                    print s;
+                }
+            }
+        "#;
+        let expected = "small zero\nsmall\nbig even\nbig odd\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn return_inside_branches() {
+        let source = r#"
+            fun sign(i: int) str {
+                if i < 0 {
+                    let s = "negative";
+                    return s;
+                } else if i == 0 {
+                    return "zero";
+                } else {
+                    mut j = 0;
+                    while j < i {
+                        if j == 2 {
+                            return "big";
+                        }
+                        j = j + 1;
+                    }
+                }
+                return "positive";
+            }
+            fun check(i: int) {
+                if i > 1 {
+                    print "checked";
+                    return;
+                } else {
+                    print "not checked";
+                }
+                print "after";
+            }
+            print sign(-5);
+            print sign(0);
+            print sign(2);
+            print sign(7);
+            check(0);
+            check(5);
+        "#;
+        let expected = "negative\nzero\npositive\nbig\nnot checked\nafter\nchecked\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn void_values_and_missing_returns() {
+        // a call of a function without a return value has no value to use
+        let error = |source: &str| compile(format!("fun f() {{}}\nfun g(a: int) {{}}\n{}", source)).unwrap_err();
+        for source in ["let x = f();", "print f();", "mut x = 1;\nx = f();", "g(f());", "print \"a\" + f();", "print f() == nil;"] {
+            assert!(matches!(error(source), CompilerError::NoneValue { .. }), "{}", source);
+        }
+        test_output("fun f() { print 1; }\nf();", "1\n");
+
+        // a function with a return type has to return on every path
+        let result = compile("fun f(a: int) int {\n    if a > 0 { return 1; }\n}\nprint f(0);".to_string());
+        assert!(matches!(result, Err(CompilerError::MissingReturn { kind: ExpressionKind::Int, span: Span { line: 1, .. } })));
+        let result = compile("let f = fun() str { while true { break; } };".to_string());
+        assert!(matches!(result, Err(CompilerError::MissingReturn { .. })));
+        let source = r#"
+            enum E { A, B }
+            fun branches(a: int) int {
+                if a > 0 { return 1; } else if a < 0 { return -1; } else { return 0; }
+            }
+            fun forever() int {
+                outer: while true {
+                    for i in 0:3 { if i == 1 { break; } }
+                    return 2;
+                }
+            }
+            fun matched(e: E) str {
+                match e {
+                    A => { return "a"; },
+                    B => { return "b"; },
+                }
+            }
+            print branches(-3) + forever();
+            print matched(E.B);
+        "#;
+        test_output(source, "1\nb\n");
+    }
+
+    #[test]
+    fn if_condition_must_be_bool() {
+        let result = compile("if 1 { print \"no\"; }".to_string());
+        assert!(matches!(result, Err(CompilerError::Type { .. })));
+    }
+
+    #[test]
+    fn break_and_continue_in_while() {
+        let source = r#"
+            mut i = 0;
+            while true {
+                i = i + 1;
+                let half = i / 2;
+                if i % 2 == 0 {
+                    continue;
+                }
+                if i > 7 {
+                    break;
+                }
+                print "i" + i + " half" + half;
+            }
+            print "done" + i;
+        "#;
+        let expected = "i1 half0\ni3 half1\ni5 half2\ni7 half3\ndone9\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn break_and_continue_in_for() {
+        let source = r#"
+            for i in 0:10 {
+                let s = "i" + i;
+                if i == 1 {
+                    continue;
+                }
+                if i == 4 {
+                    break;
+                }
+                print s;
+            }
+            for i in 0:3 {
+                print "second" + i;
+            }
+        "#;
+        let expected = "i0\ni2\ni3\nsecond0\nsecond1\nsecond2\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn labeled_loops() {
+        let source = r#"
+            outer: for i in 0:3 {
+                let s = "i" + i;
+                mut j = 0;
+                inner: while j < 3 {
+                    let t = "j" + j;
+                    j = j + 1;
+                    if j == 2 {
+                        continue outer;
+                    }
+                    if i == 2 {
+                        break outer;
+                    }
+                    print s + t;
+                }
+            }
+            print "done";
+        "#;
+        let expected = "i0j0\ni1j0\ndone\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn search_with_break_in_function() {
+        let source = r#"
+            fun firstMultiple(n: int, limit: int) int {
+                mut found = -1;
+                for i in 1:100 {
+                    if i > limit {
+                        break;
+                    }
+                    if i % n != 0 {
+                        continue;
+                    }
+                    found = i;
+                    break;
+                }
+                return found;
+            }
+            print "" + firstMultiple(7, 50);
+            print "" + firstMultiple(70, 50);
+        "#;
+        let expected = "7\n-1\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn break_outside_loop() {
+        let result = compile("break;".to_string());
+        assert!(matches!(result, Err(CompilerError::OutsideLoop(Span { line: 1, .. }))));
+
+        let result = compile("while true { break missing; }".to_string());
+        assert!(matches!(result, Err(CompilerError::UnknownLabel { .. })));
+    }
+
+    #[test]
+    fn arrays() {
+        let source = r#"
+            mut numbers = [1, 2, 3];
+            print numbers[0] + numbers[2];
+            numbers[1] = 10;
+            print "" + numbers[1];
+            numbers.push(4);
+            print "len " + numbers.len();
+            print "popped " + numbers.pop();
+            print "len " + numbers.len();
+            let words: [str] = [];
+            print "empty " + words.len();
+            let grid = [[1, 2], [3, 4]];
+            print grid[1][0];
+        "#;
+        let expected = "4\n10\nlen 4\npopped 4\nlen 3\nempty 0\n3\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn arrays_in_functions_and_classes() {
+        let source = r#"
+            class Bag {
+                [str] items;
+            }
+            fun sum(numbers: [int]) int {
+                mut total = 0;
+                for n in numbers {
+                    total = total + n;
+                }
+                return total;
+            }
+            fun range(n: int) [int] {
+                mut res: [int] = [];
+                while res.len() < n {
+                    res.push(res.len());
+                }
+                return res;
+            }
+            print "" + sum(range(5));
+            mut bag = new Bag([]);
+            bag.items.push("a");
+            bag.items.push("b");
+            bag.items[0] = "c";
+            for item in bag.items {
+                print item;
+            }
+        "#;
+        let expected = "10\nc\nb\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn for_in_array() {
+        let source = r#"
+            let words = ["a", "b", "c", "d"];
+            for w in words {
+                let s = w + "!";
+                if w == "b" {
+                    continue;
+                }
+                if w == "d" {
+                    break;
+                }
+                print s;
+            }
+            outer: for w in ["x", "y"] {
+                for n in [1, 2, 3] {
+                    if n == 2 {
+                        continue outer;
+                    }
+                    print w + n;
+                }
+            }
+            print "done";
+        "#;
+        let expected = "a!\nc!\nx1\ny1\ndone\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn array_runtime_errors() {
+        let error = test_runtime_error("let a = [1, 2];\nprint a[2];");
+        assert_eq!(error.kind, RuntimeErrorKind::IndexOutOfBounds { index: 2, len: 2 });
+        assert_eq!(error.line, 2);
+
+        let error = test_runtime_error("mut a = [1];\na[-1] = 3;");
+        assert_eq!(error.kind, RuntimeErrorKind::IndexOutOfBounds { index: -1, len: 1 });
+
+        let error = test_runtime_error("mut a = [1];\na.pop();\na.pop();");
+        assert_eq!(error.kind, RuntimeErrorKind::EmptyArray);
+        assert_eq!(error.line, 3);
+    }
+
+    #[test]
+    fn array_compile_errors() {
+        let result = compile("let a = [1, 2];\na[0] = 3;".to_string());
+        assert!(matches!(result, Err(CompilerError::CantMut { .. })));
+
+        let result = compile("let a = [1, 2];\na.push(3);".to_string());
+        assert!(matches!(result, Err(CompilerError::CantMut { .. })));
+
+        let result = compile("mut a = [1, 2];\na[0] = \"s\";".to_string());
+        assert!(matches!(result, Err(CompilerError::Type { .. })));
+
+        let result = compile("let a = [1, \"s\"];".to_string());
+        assert!(matches!(result, Err(CompilerError::Type { .. })));
+
+        let result = compile("let a = [];".to_string());
+        assert!(matches!(result, Err(CompilerError::EmptyArray(Span { line: 1, .. }))));
+
+        let result = compile("let a = 1;\nprint a[0];".to_string());
+        assert!(matches!(result, Err(CompilerError::NotIndexable { .. })));
+
+        let result = compile("for x in \"abc\" {}".to_string());
+        assert!(matches!(result, Err(CompilerError::NotIterable { .. })));
+    }
+
+    #[test]
+    fn let_freezes_the_binding() {
+        // arrays are shared, so a mut binding to the same array can change it
+        let source = r#"
+            let xs = [1];
+            mut ys = xs;
+            ys.push(2);
+            print xs;
+            fun add(zs: [int]) {
+                zs.push(3);
+            }
+            add(xs);
+            print xs;
+        "#;
+        test_output(source, "[1, 2]\n[1, 2, 3]\n");
+
+        let result = compile("let xs = [1];\nmut ys = xs;\nxs.push(2);".to_string());
+        assert!(matches!(result, Err(CompilerError::CantMut { span: Span { line: 3, .. }, .. })));
+    }
+
+    #[test]
+    fn arrays_are_collected() {
+        let source = r#"
+            mut keep: [[int]] = [];
+            for i in 0:100 {
+                mut temp = [i];
+                temp.push(i + 1);
+                if i % 10 == 0 {
+                    keep.push(temp);
+                }
+            }
+            print "" + keep.len() + keep[9][1];
+        "#;
+        let config = GcConfig::new().with_initial_threshold(1024);
+        let (output, stats) = run_with_config(source, config);
+        assert_eq!(output, "1091\n");
+        assert!(stats.collections > 0);
+        assert!(stats.live_objects < 30);
+    }
+
+    #[test]
+    fn maps() {
+        let source = r#"
+            mut ages = {"alice": 31, "bob": 25};
+            print "" + ages["alice"];
+            ages["carol"] = 40;
+            ages.set("bob", 26);
+            print "bob " + ages.get("bob");
+            print "has dave " + ages.has("dave");
+            print "has carol " + ages.has("carol");
+            print "removed " + ages.remove("alice");
+            print "len " + ages.len();
+            ages["alice"] = 1;
+            for name in ages {
+                print name + " " + ages[name];
+            }
+            let squares: {int: int} = {};
+            print "empty " + squares.len();
+            let flags = {true: "yes", false: "no"};
+            print flags[1 < 2];
+        "#;
+        let expected = "31\nbob 26\nhas dave false\nhas carol true\nremoved 31\nlen 2\nbob 26\ncarol 40\nalice 1\nempty 0\nyes\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn map_removal() {
+        // the remaining entries keep their order however many are removed
+        let source = r#"
+            mut squares: {int: int} = {};
+            for i in 0:20 {
+                squares[i] = i * i;
+            }
+            for i in 0:20 {
+                if i % 3 != 0 {
+                    squares.remove(i);
+                }
+            }
+            squares[1] = 1;
+            squares[9] = 0;
+            print squares;
+            print squares.len();
+        "#;
+        test_output(source, "{0: 0, 3: 9, 6: 36, 9: 0, 12: 144, 15: 225, 18: 324, 1: 1}\n8\n");
+
+        // a loop visits the keys the map has when it starts
+        let source = r#"
+            mut m = {"a": 1, "b": 2, "c": 3, "d": 4};
+            for k in m {
+                if m.has(k) {
+                    print k + " " + m.remove(k);
+                    if k == "a" {
+                        m.remove("c");
+                        m["e"] = 5;
+                    }
+                } else {
+                    print k + " removed";
+                }
+            }
+            print m;
+        "#;
+        test_output(source, "a 1\nb 2\nc removed\nd 4\n{\"e\": 5}\n");
+    }
+
+    #[test]
+    fn maps_in_functions() {
+        let source = r#"
+            fun count(words: [str]) {str: int} {
+                mut counts: {str: int} = {};
+                for w in words {
+                    if counts.has(w) {
+                        counts[w] = counts[w] + 1;
+                    } else {
+                        counts[w] = 1;
+                    }
+                }
+                return counts;
+            }
+            let counts = count(["b", "a", "b", "c", "b"]);
+            for w in counts.keys() {
+                print w + counts[w];
+            }
+            mut total = 0;
+            for n in counts.values() {
+                total = total + n;
+            }
+            print "total " + total;
+            mut groups: {int: [str]} = {};
+            groups[1] = [];
+            groups[1].push("x");
+            print groups[1][0];
+        "#;
+        let expected = "b3\na1\nc1\ntotal 5\nx\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn map_errors() {
+        let error = test_runtime_error("let m = {\"a\": 1};\nprint m[\"b\"];");
+        assert_eq!(error.kind, RuntimeErrorKind::MissingKey("b".to_string()));
+        assert_eq!(error.line, 2);
+
+        let result = compile("mut m = {\"a\": 1};\nm[\"b\"] = \"c\";".to_string());
+        assert!(matches!(result, Err(CompilerError::Type { .. })));
+
+        let result = compile("mut m = {\"a\": 1};\nm[2] = 3;".to_string());
+        assert!(matches!(result, Err(CompilerError::Type { .. })));
+
+        let result = compile("let m = {\"a\": 1, \"b\": true};".to_string());
+        assert!(matches!(result, Err(CompilerError::Type { .. })));
+
+        let result = compile("mut m = {\"a\": 1};\nm.set(\"b\", \"c\");".to_string());
+        assert!(matches!(result, Err(CompilerError::ParamType { .. })));
+
+        let result = compile("let m = {\"a\": 1};\nm.remove(\"a\");".to_string());
+        assert!(matches!(result, Err(CompilerError::CantMut { .. })));
+
+        let result = compile("let m = {[1]: 1};".to_string());
+        assert!(matches!(result, Err(CompilerError::InvalidKeyType { .. })));
+
+        let result = compile("let m: {[int]: int} = {};".to_string());
+        assert!(matches!(result, Err(CompilerError::InvalidKeyType { .. })));
+
+        let result = compile("let m = {};".to_string());
+        assert!(matches!(result, Err(CompilerError::EmptyMap(Span { line: 1, .. }))));
+    }
+
+    #[test]
+    fn floats() {
+        let source = r#"
+            let a = 1.5;
+            let b: float = 2e1;
+            print a + b;
+            print a * 2.0 - 1.0;
+            print 7.0 / 2.0;
+            print 7.5 % 2.0;
+            print -a;
+            print "half " + 0.5;
+            print 2.0 + " is a float";
+            print a < b;
+            print a == 1.5;
+            print a != 1.5;
+            fun average(numbers: [float]) float {
+                mut total = 0.0;
+                for n in numbers {
+                    total = total + n;
+                }
+                return total / float(numbers.len());
+            }
+            print average([1.0, 2.0, 4.5]);
+        "#;
+        let expected = "21.5\n2.0\n3.5\n1.5\n-1.5\nhalf 0.5\n2.0 is a float\ntrue\ntrue\nfalse\n2.5\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn float_conversion() {
+        let source = r#"
+            print float(3);
+            print int(3.9);
+            print int(-3.9);
+            print 1 + int(2.5);
+            print float(1) / 4.0;
+        "#;
+        test_output(source, "3.0\n3\n-3\n3\n0.25\n");
+
+        let error = test_runtime_error("print int(1e300);");
+        assert_eq!(error.kind, RuntimeErrorKind::IntegerOverflow);
+    }
+
+    #[test]
+    fn mixing_ints_and_floats() {
+        for source in [
+            "print 1 + 1.5;",
+            "print 1.5 * 2;",
+            "print 2 < 2.5;",
+            "print 1 == 1.0;",
+            "let a = 1;\nlet b = 2.0;\nprint a - b;",
+        ] {
+            let result = compile(source.to_string());
+            assert!(
+                matches!(result, Err(CompilerError::MixedNumbers(_))),
+                "{}",
+                source
+            );
+        }
+
+        let result = compile("let a: float = 1;".to_string());
+        assert!(matches!(result, Err(CompilerError::DeclarationType { .. })));
+
+        let result = compile("print int(\"1\");".to_string());
+        assert!(matches!(result, Err(CompilerError::InvalidConversion { .. })));
+    }
+
+    #[test]
+    fn methods() {
+        let source = r#"
+            class Counter {
+                int count;
+                str name;
+                fun describe() str {
+                    return self.name + ": " + self.get();
+                }
+                fun get() int {
+                    return self.count;
+                }
+                mut fun add(by: int) {
+                    self.count = self.count + by;
+                }
+                mut fun reset() int {
+                    let old = self.count;
+                    self.count = 0;
+                    return old;
+                }
+            }
+            mut c = new Counter(1, "clicks");
+            c.add(2);
+            c.add(3);
+            print c.describe();
+            print "reset from " + c.reset();
+            print c.describe();
+            let d = new Counter(7, "other");
+            print "" + d.get();
+        "#;
+        let expected = "clicks: 6\nreset from 6\nclicks: 0\n7\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn methods_with_instances_and_recursion() {
+        let source = r#"
+            class Point {
+                int x;
+                int y;
+                fun plus(other: Point) Point {
+                    return new Point(self.x + other.x, self.y + other.y);
+                }
+                fun show() str {
+                    return "(" + self.x + ", " + self.y + ")";
+                }
+            }
+            class Path {
+                [Point] points;
+                mut fun add(p: Point) {
+                    self.points.push(p);
+                }
+                fun length() int {
+                    return self.countFrom(0);
+                }
+                fun countFrom(i: int) int {
+                    if i >= self.points.len() {
+                        return 0;
+                    }
+                    return 1 + self.countFrom(i + 1);
+                }
+            }
+            let a = new Point(1, 2);
+            print a.plus(new Point(10, 20)).show();
+            mut path = new Path([]);
+            path.add(a);
+            path.add(a.plus(a));
+            print "" + path.length();
+            print path.points[1].show();
+        "#;
+        let expected = "(11, 22)\n2\n(2, 4)\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn method_errors() {
+        let class = "class Counter {\nint count;\nfun get() int { return self.count; }\nmut fun add(by: int) { self.count = self.count + by; }\n}\n";
+
+        let result = compile(format!("{}let c = new Counter(1);\nc.add(1);", class));
+        assert!(matches!(result, Err(CompilerError::CantMut { .. })));
+
+        let result = compile(format!("{}mut c = new Counter(1);\nc.add(\"1\");", class));
+        assert!(matches!(result, Err(CompilerError::ParamType { .. })));
+
+        let result = compile(format!("{}mut c = new Counter(1);\nc.add(1, 2);", class));
+        assert!(matches!(result, Err(CompilerError::UnexpectedToken { .. })));
+
+        let result = compile(format!("{}let c = new Counter(1);\nc.missing();", class));
+        assert!(matches!(result, Err(CompilerError::UnknownMethod { .. })));
+        assert_eq!(result.unwrap_err().message(), "No method 'missing' on type 'Counter'");
+
+        let source = "class A {\nint a;\nfun set() { self.a = 1; }\n}";
+        let result = compile(source.to_string());
+        assert!(matches!(result, Err(CompilerError::CantMut { .. })));
+
+        let source = "class A {\nint a;\nfun a() int { return 1; }\n}";
+        let result = compile(source.to_string());
+        assert!(matches!(result, Err(CompilerError::Redeclaration { span: Span { line: 3, .. }, .. })));
+    }
+
+    #[test]
+    fn named_constructors_and_defaults() {
+        let source = r#"
+            class Config {
+                str name;
+                int retries = 3;
+                bool verbose = false;
+                [str] tags = [];
+            }
+            let a = new Config(name: "a");
+            print a.name + " " + a.retries + " " + a.verbose + " " + a.tags.len();
+            let b = new Config(verbose: true, name: "b", retries: 5);
+            print b.name + " " + b.retries + " " + b.verbose;
+            let c = new Config("c", 1);
+            print c.name + " " + c.retries + " " + c.verbose;
+            mut d = new Config(name: "d");
+            d.tags.push("x");
+            print "" + d.tags.len() + new Config(name: "e").tags.len();
+        "#;
+        let expected = "a 3 false 0\nb 5 true\nc 1 false\n10\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn init_constructor() {
+        let source = r#"
+            class Temperature {
+                float celsius = 0.0;
+                str unit = "C";
+                fun init(fahrenheit: float) {
+                    if fahrenheit < -459.67 {
+                        fahrenheit = -459.67;
+                    }
+                    self.celsius = (fahrenheit - 32.0) * 5.0 / 9.0;
+                }
+                fun show() str {
+                    return "" + self.celsius + self.unit;
+                }
+            }
+            print new Temperature(212.0).show();
+            let t = new Temperature(fahrenheit: 32.0);
+            print t.show();
+            print new Temperature(-1000.0).show();
+        "#;
+        let expected = "100.0C\n0.0C\n-273.15C\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn constructor_errors() {
+        let class = "class Foo {\nint a;\nint b = 2;\n}\n";
+
+        let result = compile(format!("{}let f = new Foo(b: 1);", class));
+        assert!(matches!(result, Err(CompilerError::MissingField { name, .. }) if name == "a"));
+
+        let result = compile(format!("{}let f = new Foo();", class));
+        assert!(matches!(result, Err(CompilerError::MissingField { name, .. }) if name == "a"));
+
+        let result = compile(format!("{}let f = new Foo(a: 1, c: 3);", class));
+        assert!(matches!(
+            &result,
+            Err(CompilerError::UnknownField { name, kind: ExpressionKind::Class(class), .. }) if name == "c" && class.name() == "Foo"
+        ));
+        assert_eq!(result.unwrap_err().message(), "No field 'c' on type 'Foo'");
+
+        let result = compile(format!("{}let f = new Foo(a: 1, a: 3);", class));
+        assert!(matches!(result, Err(CompilerError::Redeclaration { span: Span { line: 5, .. }, .. })));
+
+        let result = compile(format!("{}let f = new Foo(a: \"1\");", class));
+        assert!(matches!(result, Err(CompilerError::Type { .. })));
+
+        let result = compile("class Foo {\nint a = \"s\";\n}".to_string());
+        assert!(matches!(result, Err(CompilerError::DeclarationType { .. })));
+
+        let result = compile("let f = new Missing();".to_string());
+        assert!(matches!(result, Err(CompilerError::UnknownClass { .. })));
+
+        // with init every field needs a default value
+        let source = "class Foo {\nint a;\nfun init() {}\n}\nlet f = new Foo();";
+        let result = compile(source.to_string());
+        assert!(matches!(result, Err(CompilerError::MissingField { name, .. }) if name == "a"));
+    }
+
+    #[test]
+    fn optionals() {
+        let source = r#"
+            class Node {
+                int value;
+                Node? next;
+            }
+            fun find(values: [int], target: int) int? {
+                for v in values {
+                    if v == target {
+                        return v;
+                    }
+                }
+                return nil;
+            }
+            let found = find([1, 2, 3], 2);
+            if found != nil {
+                print found + 10;
+            }
+            let missing = find([1, 2, 3], 5);
+            print missing;
+            print missing == nil;
+            if missing != nil {
+                print "unreachable";
+            } else {
+                print "missing";
+            }
+
+            let list = new Node(1, new Node(2, new Node(3)));
+            mut node: Node? = list;
+            mut sum = 0;
+            while node != nil {
+                sum = sum + node.value;
+                node = node.next;
+            }
+            print sum;
+            print new Node(4);
+        "#;
+        test_output(
+            source,
+            "12\nnil\ntrue\nmissing\n6\nNode(value: 4, next: nil)\n",
+        );
+    }
+
+    #[test]
+    fn optional_early_exit() {
+        let source = r#"
+            fun describe(name: str?) str {
+                if name == nil {
+                    return "nobody";
+                }
+                return "hello " + name;
+            }
+            print describe("bo");
+            print describe(nil);
+            mut maybe: int? = 1;
+            if maybe != nil {
+                maybe = maybe + 1;
+                print maybe;
+                maybe = nil;
+            }
+            print maybe;
+        "#;
+        test_output(source, "hello bo\nnobody\n2\nnil\n");
+    }
+
+    #[test]
+    fn optional_logical_operators() {
+        let source = r#"
+            fun check(x: int?, y: int?) {
+                print x != nil and x > 0;
+                print x == nil or x > 2;
+                if x != nil and y != nil and x > y {
+                    print x - y;
+                }
+                if x == nil or y == nil {
+                    return;
+                }
+                print x + y;
+            }
+            check(nil, 1);
+            check(3, 1);
+            check(1, 3);
+        "#;
+        test_output(source, "false\ntrue\ntrue\ntrue\n2\n4\ntrue\nfalse\n4\n");
+
+        // the right operand only runs when the left one does not decide the result
+        let source = r#"
+            fun side(value: bool) bool {
+                print "side";
+                return value;
+            }
+            print false and side(true);
+            print true or side(false);
+            print true and side(false);
+            print false or side(true);
+        "#;
+        test_output(source, "false\ntrue\nside\nfalse\nside\ntrue\n");
+
+        // the right operand of `or` runs when the left one is false
+        let result = compile("fun f(x: int?) bool {\nreturn x != nil or x > 2;\n}".to_string());
+        assert!(matches!(
+            result,
+            Err(CompilerError::UncheckedOptional { span: Span { line: 2, .. }, .. })
+        ));
+        // the narrowing ends with the operand
+        let result = compile("fun f(x: int?) {\nprint x != nil and x > 2;\nprint x + 1;\n}".to_string());
+        assert!(matches!(
+            result,
+            Err(CompilerError::UncheckedOptional { span: Span { line: 3, .. }, .. })
+        ));
+        // an else branch only knows the whole condition is false
+        let source = "fun f(x: int?, y: bool) {\nif x == nil and y {\nreturn;\n} else {\nprint x + 1;\n}\n}";
+        let result = compile(source.to_string());
+        assert!(matches!(
+            result,
+            Err(CompilerError::UncheckedOptional { span: Span { line: 5, .. }, .. })
+        ));
+    }
+
+    #[test]
+    fn optional_errors() {
+        let result = compile("let a: int? = 1;\nprint a + 1;".to_string());
+        assert!(matches!(
+            result,
+            Err(CompilerError::UncheckedOptional { span: Span { line: 2, .. }, .. })
+        ));
+
+        let source = "class A {\nint i;\n}\nlet a: A? = nil;\nprint a.i;";
+        let result = compile(source.to_string());
+        assert!(matches!(
+            result,
+            Err(CompilerError::UncheckedOptional { span: Span { line: 5, .. }, .. })
+        ));
+
+        let result = compile("let a = nil;".to_string());
+        assert!(matches!(result, Err(CompilerError::UntypedNil(Span { line: 1, .. }))));
+
+        let result = compile("let a: int = nil;".to_string());
+        assert!(matches!(result, Err(CompilerError::DeclarationType { .. })));
+
+        // the narrowing ends with the block
+        let source = "let a: int? = 1;\nif a != nil {\nprint a + 1;\n}\nprint a + 1;";
+        let result = compile(source.to_string());
+        assert!(matches!(
+            result,
+            Err(CompilerError::UncheckedOptional { span: Span { line: 5, .. }, .. })
+        ));
+
+        // a nested loop could run the narrowed code again after the assignment
+        let source = r#"
+            fun f() {
+                mut a: int? = 1;
+                if a != nil {
+                    while true {
+                        print a + 1;
+                        a = nil;
+                    }
+                }
+            }
+        "#;
+        let result = compile(source.to_string());
+        assert!(matches!(
+            result,
+            Err(CompilerError::ReassignmentType { .. })
+        ));
+    }
+
+    #[test]
+    fn enums_and_match() {
+        let source = r#"
+            enum Shape {
+                Circle(float),
+                Rect(float, float),
+                Empty,
+            }
+            fun area(shape: Shape) float {
+                return match shape {
+                    Circle(r) => 3.0 * r * r,
+                    Rect(w, h) => w * h,
+                    Empty => 0.0,
+                };
+            }
+            let shapes = [Shape.Circle(1.0), Shape.Rect(2.0, 3.0), Shape.Empty];
+            for shape in shapes {
+                print area(shape);
+            }
+            print shapes;
+            print Shape.Rect(1.0, 2.0) == Shape.Rect(1.0, 2.0);
+            print Shape.Empty != Shape.Circle(0.0);
+
+            // arms can be blocks, which makes the match a statement
+            match shapes[1] {
+                Rect(w, _) => {
+                    let doubled = w * 2.0;
+                    print "rect " + doubled;
+                }
+                _ => {
+                    print "other";
+                }
+            }
+            // a match inside a larger expression
+            print "sides: " + (1 + match shapes[0] {
+                Circle(_) => 0,
+                Rect(_, _) => 4,
+                Empty => 0,
+            });
+        "#;
+        let expected = "3.0\n6.0\n0.0\n\
+            [Shape.Circle(1.0), Shape.Rect(2.0, 3.0), Shape.Empty]\n\
+            true\ntrue\nrect 4.0\nsides: 1\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn recursive_enums() {
+        let source = r#"
+            enum List {
+                Cons(int, List),
+                End,
+            }
+            fun sum(list: List) int {
+                return match list {
+                    Cons(head, tail) => head + sum(tail),
+                    End => 0,
+                };
+            }
+            let list = List.Cons(1, List.Cons(2, List.Cons(3, List.End)));
+            print sum(list);
+            print list;
+            mut count = 0;
+            mut rest = list;
+            while true {
+                match rest {
+                    Cons(_, tail) => {
+                        count = count + 1;
+                        rest = tail;
+                    }
+                    End => {
+                        break;
+                    }
+                }
+            }
+            print count;
+        "#;
+        test_output(
+            source,
+            "6\nList.Cons(1, List.Cons(2, List.Cons(3, List.End)))\n3\n",
+        );
+    }
+
+    #[test]
+    fn match_errors() {
+        let shape = "enum Shape {\nCircle(float),\nEmpty,\n}\nlet s = Shape.Empty;\n";
+        let compile = |rest: &str| compile(format!("{}{}", shape, rest));
+
+        let result = compile("let a = match s {\nCircle(r) => r,\n};");
+        assert!(matches!(
+            result,
+            Err(CompilerError::NonExhaustiveMatch { missing, span: Span { line: 6, .. } }) if missing == ["Empty"]
+        ));
+        let result = compile("let a = match s {\nCircle(r) => r,\nEmpty => 1,\n};");
+        assert!(matches!(
+            result,
+            Err(CompilerError::MatchArmType { span: Span { line: 8, .. }, .. })
+        ));
+        let result = compile("let a = match s {\n_ => 1,\nEmpty => 2,\n};");
+        assert!(matches!(result, Err(CompilerError::UnreachableArm(Span { line: 8, .. }))));
+        let result = compile("let a = match s {\nCircle => 1.0,\n_ => 2.0,\n};");
+        assert!(matches!(
+            result,
+            Err(CompilerError::PayloadCount { expected: 1, actual: 0, .. })
+        ));
+        let result = compile("let a = Shape.Square;");
+        assert!(matches!(result, Err(CompilerError::UnknownVariant { .. })));
+        assert_eq!(result.unwrap_err().message(), "No variant 'Square' in enum 'Shape'");
+        let result = compile("let a = Shape.Circle(1);");
+        assert!(matches!(result, Err(CompilerError::Type { .. })));
+        let result = compile("let a = match 1 {\n_ => 1,\n};");
+        assert!(matches!(result, Err(CompilerError::NotMatchable { span: Span { line: 6, .. }, .. })));
+    }
+
+    #[test]
+    fn closures() {
+        let source = r#"
+            fun double(x: int) int {
+                return x * 2;
+            }
+            fun apply(f: fun(int) -> int, x: int) int {
+                return f(x);
+            }
+            fun make_adder(n: int) fun(int) -> int {
+                return fun(x: int) int {
+                    return x + n;
+                };
+            }
+            print apply(double, 4);
+            let add3 = make_adder(3);
+            print add3(1);
+            print make_adder(10)(5);
+            fun counter() fun() -> str {
+                mut count = 1;
+                let show = fun() str {
+                    return "count " + count;
+                };
+                count = 2;
+                return show;
+            }
+            print counter()();
+            let fs = [double, add3];
+            print fs[1](fs[0](1));
+            print double == double;
+            print make_adder(1) == make_adder(1);
+            print make_adder(1) == make_adder(2);
+            print double;
+            print counter();
+        "#;
+        test_output(
+            source,
+            "8\n4\n15\ncount 1\n5\ntrue\ntrue\nfalse\n<fun double>\n<fun>\n",
+        );
+    }
+
+    #[test]
+    fn higher_order_array_methods() {
+        let source = r##"
+            class Button {
+                str label;
+                fun(str) on_click;
+            }
+            mut numbers = [5, 3, 8, 1];
+            let limit = 2;
+            print numbers.map(fun(x: int) str { return "#" + x; });
+            print numbers.filter(fun(x: int) bool { return x > limit; });
+            numbers.sort(fun(a: int, b: int) bool { return a > b; });
+            print numbers;
+            let b = new Button("ok", fun(label: str) { print "clicked " + label; });
+            b.on_click(b.label);
+        "##;
+        test_output(
+            source,
+            "[\"#5\", \"#3\", \"#8\", \"#1\"]\n[5, 3, 8]\n[8, 5, 3, 1]\nclicked ok\n",
+        );
+    }
+
+    #[test]
+    fn closure_errors() {
+        let source = "fun f() {\nmut a = 1;\nlet g = fun() {\na = 2;\n};\n}";
+        let result = compile(source.to_string());
+        assert!(matches!(
+            result,
+            Err(CompilerError::AssignCapture { name, span: Span { line: 4, .. } }) if name == "a"
+        ));
+        let result = compile("let a = 1;\na(2);".to_string());
+        assert!(matches!(result, Err(CompilerError::NotCallable { span: Span { line: 2, .. }, .. })));
+        let result = compile(
+            "let f = fun(x: int) int { return x; };\nprint f(\"a\");".to_string(),
+        );
+        assert!(matches!(result, Err(CompilerError::ParamType { span: Span { line: 2, .. }, .. })));
+        let result = compile(
+            "let f: fun(int) -> str = fun(x: int) int { return x; };".to_string(),
+        );
+        assert!(matches!(result, Err(CompilerError::DeclarationType { .. })));
+        let result =
+            compile("print [1].map(fun(x: str) str { return x; });".to_string());
+        assert!(matches!(result, Err(CompilerError::ParamType { .. })));
+        // a function value without a return type has no value to use
+        for source in ["let e = fun() {};\nprint e();", "let e = fun() {};\nlet x = e();", "let e = fun() {};\nprint \"a\" + e();"] {
+            let result = compile(source.to_string());
+            assert!(matches!(result, Err(CompilerError::NoneValue { span: Span { line: 2, .. } })), "{}", source);
+        }
+        test_output("let e = fun() {};\nmut i = 0;\nwhile i < 3 {\ne();\ni = i + 1;\n}\nprint i;", "3\n");
+    }
+
+    #[test]
+    fn modules() {
+        let main = r#"
+            import "lib/geo.bofink";
+            import strings;
+            import counter;
+            let p = new geo.Point(1, 2);
+            print geo.dist2(p);
+            let shape: geo.Shape = geo.Shape.Circle(1.0);
+            print shape;
+            strings.shout("hi");
+            let exclaim = strings.exclaim;
+            print exclaim("x");
+        "#;
+        let geo = r#"
+            import "../counter.bofink";
+            pub class Point {
+                int x;
+                int y;
+            }
+            pub enum Shape {
+                Circle(float),
+            }
+            fun square(x: int) int {
+                return x * x;
+            }
+            pub fun dist2(p: Point) int {
+                return square(p.x) + square(p.y);
+            }
+        "#;
+        let strings = r#"
+            pub fun exclaim(s: str) str {
+                return s + "!";
+            }
+            pub fun shout(s: str) {
+                print exclaim(s);
+            }
+        "#;
+        // the top level code of a module runs once, where it is first imported
+        let counter = "print \"counter loaded\";\n";
+        let files = [
+            ("main.bofink", main),
+            ("lib/geo.bofink", geo),
+            ("counter.bofink", counter),
+            ("path/strings.bofink", strings),
+        ];
+        let output = run_files("modules", &files, &["path"]);
+        assert_eq!(
+            output,
+            "counter loaded\n5\nShape.Circle(1.0)\nhi!\nx!\n"
+        );
+    }
+
+    #[test]
+    fn module_errors() {
+        let broken = "pub fun broken() {\nlet x: int = \"a\";\n}\n";
+        let compile = |test: &str, main: &str, files: &[(&str, &str)]| {
+            let mut files = files.to_vec();
+            files.push(("main.bofink", main));
+            compile_files(test, &files, &[])
+        };
+
+        let hidden = "fun hidden() {}";
+        let result = compile("private_fn", "import util;\nutil.hidden();", &[("util.bofink", hidden)]);
+        assert!(matches!(
+            result,
+            Err(CompilerError::NotExported { module, name, span: Span { line: 2, .. } }) if module == "util" && name == "hidden"
+        ));
+        let secret = "class Secret {\nint x;\n}";
+        let result = compile(
+            "private_class",
+            "import util;\nlet s: util.Secret = new util.Secret(1);",
+            &[("util.bofink", secret)],
+        );
+        assert!(matches!(result, Err(CompilerError::NotExported { span: Span { line: 2, .. }, .. })));
+        let result = compile("missing", "import nothing;", &[]);
+        assert!(matches!(result, Err(CompilerError::ImportNotFound { span: Span { line: 1, .. }, .. })));
+        let result = compile("nested", "fun f() {\nimport util;\n}", &[("util.bofink", "")]);
+        assert!(matches!(result, Err(CompilerError::NotTopLevel(Span { line: 2, .. }))));
+        let result = compile("error_in_module", "import util;", &[("util.bofink", broken)]);
+        assert!(matches!(
+            result,
+            Err(CompilerError::InFile { file, error }) if file.ends_with("util.bofink")
+                && matches!(*error, CompilerError::DeclarationType { span: Span { line: 2, .. }, .. })
+        ));
+        let cycle = [("a.bofink", "import b;"), ("b.bofink", "import a;")];
+        let result = compile("circular", "import a;", &cycle);
+        assert!(matches!(
+            result,
+            Err(CompilerError::InFile { file, error }) if file.ends_with("b.bofink")
+                && matches!(*error, CompilerError::CircularImport { span: Span { line: 1, .. }, .. })
+        ));
+    }
+
+    #[test]
+    fn globals() {
+        let source = r#"
+            let greeting = "hello";
+            mut count = 0;
+            mut names: [str] = [];
+            fun greet(name: str) {
+                count = count + 1;
+                names.push(name);
+                print greeting + " " + name;
+            }
+            greet("a");
+            let twice = fun(name: str) {
+                greet(name);
+                greet(name);
+            };
+            twice("b");
+            print count;
+            print names;
+        "#;
+        test_output(source, "hello a\nhello b\nhello b\n3\n[\"a\", \"b\", \"b\"]\n");
+
+        let main = "import counter;\nprint counter.next();\nprint counter.next();\n";
+        let counter = "mut count = 0;\npub fun next() int {\ncount = count + 1;\nreturn count;\n}\n";
+        let files = [("main.bofink", main), ("counter.bofink", counter)];
+        assert_eq!(run_files("globals", &files, &[]), "1\n2\n");
+    }
+
+    #[test]
+    fn global_errors() {
+        let result = compile("let a = 1;\nfun f() {\na = 2;\n}".to_string());
+        assert!(matches!(result, Err(CompilerError::CantMut { .. })));
+        let result = compile("let a = [1];\nfun f() {\na.push(2);\n}".to_string());
+        assert!(matches!(result, Err(CompilerError::CantMut { .. })));
+
+        // a function may set a mut global back to nil
+        let source = r#"
+            mut a: int? = 1;
+            fun reset() {
+                a = nil;
+            }
+            if a != nil {
+                print a + 1;
+                reset();
+                print a + 1;
+            }
+        "#;
+        let result = compile(source.to_string());
+        assert!(matches!(
+            result,
+            Err(CompilerError::UncheckedOptional { span: Span { line: 9, .. }, .. })
+        ));
+        let source = r#"
+            mut a: int? = 1;
+            if a != nil {
+                while true {
+                    print a + 1;
+                }
+            }
+        "#;
+        let result = compile(source.to_string());
+        assert!(matches!(
+            result,
+            Err(CompilerError::UncheckedOptional { span: Span { line: 5, .. }, .. })
+        ));
+    }
+
+    #[test]
+    fn forward_references() {
+        let source = r#"
+            print is_even(10);
+            print is_odd(7);
+            fun is_even(n: int) bool {
+                if n == 0 {
+                    return true;
+                }
+                return is_odd(n - 1);
+            }
+            fun is_odd(n: int) bool {
+                if n == 0 {
+                    return false;
+                }
+                return is_even(n - 1);
+            }
+
+            let line = new Line(new Point(1, 2));
+            print line.length();
+            print describe(Shape.Dot);
+            class Line {
+                Point start;
+                Point end = origin();
+                fun length() int {
+                    return distance(self.start, self.end);
+                }
+            }
+            fun origin() Point {
+                return new Point(0, 0);
+            }
+            fun distance(a: Point, b: Point) int {
+                return a.x - b.x + a.y - b.y;
+            }
+            fun describe(shape: Shape) str {
+                return match shape {
+                    Dot => "dot",
+                };
+            }
+            class Point {
+                int x;
+                int y;
+            }
+            enum Shape {
+                Dot,
+            }
+        "#;
+        let expected = "true\ntrue\n3\ndot\n";
+        test_output(source, expected);
+    }
+
+    #[test]
+    fn forward_reference_errors() {
+        let result = compile("print 1;\nmissing(2);".to_string());
+        assert!(matches!(
+            result,
+            Err(CompilerError::UnknownFunction { span: Span { line: 2, .. }, .. })
+        ));
+        let result = compile("fun f() {}\nprint 1;\nfun f() {}".to_string());
+        assert!(matches!(result, Err(CompilerError::Redeclaration { span: Span { line: 3, .. }, .. })));
+
+        // a function called before a global is declared can not read it yet
+        let source = r#"
+            show();
+            let greeting = "hello";
+            fun show() {
+                print greeting;
+            }
+        "#;
+        let error = test_runtime_error(source);
+        assert_eq!(error.kind, RuntimeErrorKind::UninitializedGlobal(0));
+        assert_eq!(error.line, 5);
+    }
+
+    #[test]
+    fn syntax_tree_passes() {
+        let source = "let a = 1 + 2;\nprint a;";
+        let (file, errors) = parser::parse(source);
+        assert!(errors.is_empty());
+        assert_eq!(file.stmts.len(), 2);
+        let mut modules = vec![ast::Module {
+            name: "<script>".to_string(),
+            path: None,
+            source: source.to_string(),
+            file,
+            init: 0,
+        }];
+        let program = checker::check(&mut modules, &[], &[], &[]).unwrap();
+        let ast::StmtNode::Let(declaration) = &modules[0].file.stmts[0].node else {
+            panic!("expected a let statement");
+        };
+        assert_eq!(declaration.value.kind, ExpressionKind::Int);
+
+        let chunk = codegen::generate(&modules, program).unwrap();
+        let mut buf = Vec::new();
+        vm::start_with_config(chunk, &mut buf, STRESS_GC, &[]).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "3\n");
+
+        // the checker reports what the parser accepts
+        let mut modules = vec![ast::Module {
+            name: "<script>".to_string(),
+            path: None,
+            source: String::new(),
+            file: parser::parse("let a = 1;\nlet b = a + 1.5;").0,
+            init: 0,
+        }];
+        let errors = checker::check(&mut modules, &[], &[], &[]).unwrap_err();
+        assert!(matches!(errors[..], [CompilerError::MixedNumbers(Span { line: 2, .. })]));
+    }
+
+    #[test]
+    fn multiple_errors() {
+        let lines = |source: &str| -> Vec<Option<usize>> {
+            let diagnostics = compiler::compile(source.to_string()).unwrap_err();
+            diagnostics.iter().map(|d| d.error.line()).collect()
+        };
+        // syntax errors skip to the next statement
+        assert_eq!(lines("let a = ;\nprint 1;\nlet b = 2 +;\nprint 2"), [Some(1), Some(3), Some(4)]);
+        assert_eq!(lines("class A {\nint a\n}\nfun f( {\n}\nprint 1;"), [Some(3), Some(4)]);
+        // the statements that parse are still checked, but the names declared by the
+        // others are not reported again
+        let diagnostics = compiler::compile("let b = \"x\" + ;\nlet c: int = \"s\";\nprint b;\nf();\nfun f( {}".to_string()).unwrap_err();
+        let errors: Vec<&CompilerError> = diagnostics.iter().map(|d| &d.error).collect();
+        assert!(matches!(
+            errors[..],
+            [
+                CompilerError::InvalidToken { span: Span { line: 1, .. }, .. },
+                CompilerError::DeclarationType { span: Span { line: 2, .. }, .. },
+                CompilerError::UnexpectedToken { span: Span { line: 5, .. }, .. },
+            ]
+        ));
+
+        let source = r#"let a = 1 + 1.5;
+            print a;
+            fun f(x: int) int {
+                let y: str = x;
+                return x;
+            }
+            if 1 {
+                print missing;
+            }
+            f(true);"#;
+        let diagnostics = compiler::compile(source.to_string()).unwrap_err();
+        let errors: Vec<&CompilerError> = diagnostics.iter().map(|d| &d.error).collect();
+        // the uses of `a`, whose declaration failed, are not reported
+        assert!(matches!(
+            errors[..],
+            [
+                CompilerError::MixedNumbers(Span { line: 1, .. }),
+                CompilerError::DeclarationType { span: Span { line: 4, .. }, .. },
+                CompilerError::Type { span: Span { line: 7, .. }, .. },
+                CompilerError::MissingLocal { span: Span { line: 8, .. }, .. },
+                CompilerError::ParamType { span: Span { line: 10, .. }, .. },
+            ]
+        ));
+        assert_eq!(diagnostics[3].label.as_ref().unwrap().text.trim(), "print missing;");
+
+        // the errors of imported files come after the compiled source
+        let files = [
+            ("main.bofink", "import util;\nprint 1 + true;\nutil.f();"),
+            ("util.bofink", "pub fun f() {\nprint -true;\n}\nlet x: int = \"s\";"),
+        ];
+        let diagnostics = compile_files_diagnostics("multiple_errors", &files, &[]).unwrap_err();
+        let lines: Vec<(bool, Option<usize>)> = diagnostics
+            .iter()
+            .map(|d| (d.file.is_some(), d.error.line()))
+            .collect();
+        assert_eq!(lines, [(false, Some(2)), (true, Some(2)), (true, Some(4))]);
+    }
+
+    #[test]
+    fn multi_line_strings() {
+        test_output("print \"ab\n\";\nprint \"c\nd\" + 1;", "ab\n\nc\nd1\n");
+
+        let tokens = crate::scanner::Scanner::get_tokens("print \"ab\ncd\";\nprint 1;".to_string());
+        let string = &tokens[1];
+        assert_eq!((string.value.as_str(), string.span, string.line), ("ab\ncd", Span { start: 6, end: 13, line: 1 }, 2));
+        // columns start over on every line, also inside strings
+        assert_eq!((tokens[2].kind, tokens[2].line, tokens[2].column), (crate::TokenKind::Semicolon, 2, 4));
+        assert_eq!((tokens[3].kind, tokens[3].line, tokens[3].column), (crate::TokenKind::Print, 3, 5));
+
+        // a string left open at a newline is an error rather than a crash
+        let diagnostics = compiler::compile("print \"ab\n".to_string()).unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+        let diagnostics = compiler::compile("print \"ab\n\n".to_string()).unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn declaration_using_its_own_name() {
+        // the error is reported although the failed declaration makes its name unknown
+        let sources = [
+            "let a = a;",
+            "fun f() {\nmut next = next;\n}",
+            "fun test(a: int, b: int) int {\nreturn a;\n}\nlet s2 = test(1, s2);",
+        ];
+        for source in sources {
+            let diagnostics = compiler::compile(source.to_string()).unwrap_err();
+            assert!(
+                matches!(&diagnostics[..], [Diagnostic { error: CompilerError::MissingLocal { .. }, .. }]),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn rendered_diagnostics() {
+        let diagnostic = |source: &str| compiler::compile(source.to_string()).unwrap_err().remove(0);
+
+        let cant_mut = diagnostic("let count = 1;\ncount = 2;");
+        assert!(matches!(
+            &cant_mut.error,
+            CompilerError::CantMut { name, span: Span { start: 15, end: 25, line: 2 }, declaration: Some(_) } if name == "count"
+        ));
+        assert_eq!(
+            cant_mut.render("main.bofink"),
+            "error[E0001]: Cannot mutate 'count', it is not declared 'mut'
+ --> main.bofink:2:1
+  |
+1 | let count = 1;
+  | -------------- declare 'count' with 'mut' to change it
+2 | count = 2;
+  | ^^^^^^^^^^
+"
+        );
+
+        // the caret covers the whole expression, with columns counted in characters
+        let mixed = diagnostic("let s = \"é\"; let b = 2 + 1.5;");
+        assert_eq!(mixed.error.code(), "E0020");
+        let label = mixed.label.as_ref().unwrap();
+        assert_eq!((label.line, label.column, label.width), (1, 22, 7));
+
+        let redeclaration = diagnostic("class A {}\nenum B { X }\n\nclass B {}");
+        assert_eq!(redeclaration.error.code(), "E0006");
+        assert_eq!(
+            redeclaration.render("main.bofink"),
+            "error[E0006]: Cannot redeclare 'B'
+ --> main.bofink:4:7
+  |
+2 | enum B { X }
+  |      - 'B' is first declared here
+4 | class B {}
+  |       ^
+"
+        );
+
+        // types are written the way the source writes them
+        let message = |source: &str| diagnostic(source).error.message();
+        assert_eq!(message("let a: int? = nil;\nprint a + 1;"), "Value of type 'int?' may be nil | Check it with '!= nil' first");
+        assert_eq!(
+            message("class P {}\nlet a: {str: [P]} = {};\nlet b: fun(int) -> str = a;"),
+            "Expression does not match declaration type | Expected 'fun(int) -> str' but got '{str: [P]}'"
+        );
+        assert_eq!(message("let f = fun(a: float) {};\nlet g: bool = f;"), "Expression does not match declaration type | Expected 'bool' but got 'fun(float)'");
+    }
+
+    #[test]
+    fn cant_mut_hints() {
+        let label = |source: &str| {
+            let diagnostic = compiler::compile(source.to_string()).unwrap_err().remove(0);
+            assert_eq!(diagnostic.error.code(), "E0001");
+            let (span, message) = diagnostic.error.labels().remove(0);
+            (source[span.start..span.end].to_string(), message)
+        };
+
+        assert_eq!(
+            label("let xs = [1];\nfor c in xs {\n    c = 2;\n}"),
+            ("c".to_string(), "'c' is declared by the loop and cannot be changed".to_string())
+        );
+        assert_eq!(
+            label("class A {\n    int x;\n    fun set() {\n        self.x = 1;\n    }\n}"),
+            ("set".to_string(), "declare the method with 'mut fun' to change 'self'".to_string())
+        );
+        assert_eq!(
+            label("enum E { A(int) }\nmatch E.A(1) {\n    A(n) => { n = 2; }\n}"),
+            ("A".to_string(), "'n' is bound by the match arm and cannot be changed".to_string())
+        );
+        assert_eq!(
+            label("mut xs = [1];\nlet ys = xs;\nys.push(2);"),
+            ("let ys = xs;".to_string(), "declare 'ys' with 'mut' to change it".to_string())
+        );
+    }
+
+    #[test]
+    fn scanner_errors() {
+        let diagnostic = |source: &str| compiler::compile(source.to_string()).unwrap_err().remove(0);
+
+        let unterminated = diagnostic("print 1;\nprint \"abc;");
+        assert_eq!(unterminated.error.code(), "E0056");
+        assert_eq!(unterminated.error.message(), "Unterminated string");
+        assert_eq!(unterminated.error.line(), Some(2));
+
+        let unexpected = diagnostic("let a = 1 @ 2;");
+        assert!(matches!(&unexpected.error, CompilerError::Lexical { span: Span { start: 10, end: 11, .. }, .. }));
+        assert_eq!(unexpected.error.message(), "Unexpected character");
+    }
+
+    #[test]
+    fn json_diagnostics() {
+        let diagnostics = compiler::compile("fun f() {}\nfun f() {}\nprint missing;".to_string()).unwrap_err();
+        let json: Vec<String> = diagnostics.iter().map(|d| d.to_json("dir\\\"main\".bofink")).collect();
+        assert_eq!(
+            json,
+            [
+                r#"{"file":"dir\\\"main\".bofink","line":2,"column":5,"span":{"start":15,"end":16},"severity":"error","code":"E0006","message":"Cannot redeclare 'f'","labels":[{"line":1,"column":5,"span":{"start":4,"end":5},"message":"'f' is first declared here"}]}"#,
+                r#"{"file":"dir\\\"main\".bofink","line":3,"column":7,"span":{"start":28,"end":35},"severity":"error","code":"E0031","message":"Could not find local with name 'missing'","labels":[]}"#,
+            ]
+        );
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+
+        // errors about the program as a whole have no location
+        let diagnostic = Diagnostic::new(CompilerError::MaxFunctions, "");
+        assert!(diagnostic.to_json("main.bofink").contains(r#""line":null,"column":null,"span":null"#));
+    }
+
+    #[test]
+    fn engine() {
+        let mut engine = crate::Engine::with_output(Vec::new());
+        engine.gc = STRESS_GC;
+        engine.eval("let a = [1, 2];\nprint a;").unwrap();
+        let chunk = engine.compile("print \"again\";").unwrap();
+        engine.run(chunk).unwrap();
+        assert_eq!(String::from_utf8(engine.into_output()).unwrap(), "[1, 2]\nagain\n");
+
+        let mut engine = crate::Engine::with_output(Vec::new());
+        assert!(matches!(
+            engine.eval("print 1 + true;"),
+            Err(crate::Error::Compile(diagnostics)) if diagnostics.len() == 1
+        ));
+        assert!(matches!(
+            engine.eval("print 1 / 0;"),
+            Err(crate::Error::Runtime(RuntimeError { kind: RuntimeErrorKind::DivisionByZero, .. }))
+        ));
+        assert!(matches!(engine.compile_file("missing.bofink"), Err(crate::Error::Io(_))));
+
+        // imports of sources that are not files are looked up in the search path
+        let files = [("main.bofink", ""), ("lib/util.bofink", "pub fun one() int { return 1; }")];
+        compile_files_diagnostics("engine", &files, &[]).unwrap();
+        let dir = std::env::temp_dir().join(format!("bofink-engine-{}", std::process::id()));
+        engine.search_path = vec![dir.join("lib")];
+        engine.eval("import util;\nprint util.one();").unwrap();
+        assert_eq!(engine.output(), b"1\n");
+    }
+
+    #[test]
+    fn imports_of_sources_ignore_the_current_directory() {
+        let name = format!("cwd_probe_{}", std::process::id());
+        let path = std::env::current_dir().unwrap().join(format!("{}.bofink", name));
+        fs::write(&path, "pub fun one() int { return 1; }").unwrap();
+        let engine = crate::Engine::with_output(Vec::new());
+        let result = engine.compile(&format!("import {};\nprint {}.one();", name, name));
+        fs::remove_file(&path).unwrap();
+        let diagnostics = result.unwrap_err();
+        assert!(matches!(diagnostics[0].error, CompilerError::ImportNotFound { .. }));
+    }
+
+    #[test]
+    fn import_redeclarations() {
+        let util = ("util.bofink", "pub fun one() int { return 1; }");
+        // the line of the redeclaration of util and of its first declaration
+        let redeclared = |test: &str, main: &str| {
+            let files = [("main.bofink", main), util];
+            let mut diagnostics = compile_files_diagnostics(test, &files, &[]).unwrap_err();
+            assert_eq!(diagnostics.len(), 1);
+            match diagnostics.remove(0).error {
+                CompilerError::Redeclaration {
+                    name,
+                    span,
+                    previous,
+                } if name == "util" => (span.line, previous.map(|previous| previous.line)),
+                error => panic!("expected a redeclaration of 'util', got {:?}", error),
+            }
+        };
+
+        // the later of the two is reported, pointing at the earlier
+        let source = "let util = 3;\nimport util;";
+        assert_eq!(
+            redeclared("import_redeclarations_let", source),
+            (2, Some(1))
+        );
+        let source = "import util;\nlet util = 3;\nprint util.one();";
+        assert_eq!(
+            redeclared("import_redeclarations_import", source),
+            (2, Some(1))
+        );
+        let source = "fun util() {}\nimport util;";
+        assert_eq!(
+            redeclared("import_redeclarations_fun", source),
+            (2, Some(1))
+        );
+        let source = "import util;\nclass util {}";
+        assert_eq!(
+            redeclared("import_redeclarations_class", source),
+            (2, Some(1))
+        );
+
+        // locals shadow imports
+        let source = "import util;\nfun f() {\n    let util = 2;\n    print util;\n}\nf();";
+        let files = [("main.bofink", source), util];
+        assert_eq!(run_files("import_redeclarations_local", &files, &[]), "2\n");
+    }
+
+    /// An engine with natives that check a prefix, fail on an empty string and return
+    /// the wrong type.
+    fn native_engine() -> crate::Engine<Vec<u8>> {
+        let mut engine = crate::Engine::with_output(Vec::new());
+        engine.gc = STRESS_GC;
+        let params = [ExpressionKind::Int, ExpressionKind::String];
+        engine.register(
+            "starts_with",
+            &params,
+            ExpressionKind::Bool,
+            |args| match args {
+                [crate::HostValue::Int(i), crate::HostValue::Str(s)] => {
+                    Ok(crate::HostValue::Bool(s.starts_with(&i.to_string())))
+                }
+                _ => unreachable!(),
+            },
+        );
+        engine.register(
+            "shout",
+            &[ExpressionKind::String],
+            ExpressionKind::None,
+            |args| match args {
+                [crate::HostValue::Str(s)] if s.is_empty() => Err("nothing to shout".to_string()),
+                _ => Ok(crate::HostValue::Nil),
+            },
+        );
+        engine.register(
+            "half",
+            &[ExpressionKind::Float],
+            ExpressionKind::Float,
+            |_| Ok(crate::HostValue::Int(1)),
+        );
+        engine
+    }
+
+    fn native_runtime_error(engine: &mut crate::Engine<Vec<u8>>, source: &str) -> RuntimeErrorKind {
+        match engine.eval(source) {
+            Err(crate::Error::Runtime(error)) => error.kind,
+            result => panic!("expected a runtime error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn natives() {
+        let mut engine = native_engine();
+        let source = r#"
+            let s = "12" + "3";
+            print starts_with(12, s);
+            print starts_with(2, s);
+            shout("a");
+        "#;
+        engine.eval(source).unwrap();
+        assert_eq!(engine.output(), b"true\nfalse\n");
+    }
+
+    #[test]
+    fn native_calls_are_checked() {
+        let engine = native_engine();
+        let error = |source: &str| engine.compile(source).unwrap_err().remove(0).error;
+        assert!(matches!(
+            error("print starts_with(\"1\", \"1\");"),
+            CompilerError::ParamType {
+                expected: ExpressionKind::Int,
+                actual: ExpressionKind::String,
+                span: Span {
+                    start: 18,
+                    end: 21,
+                    ..
+                }
+            }
+        ));
+        assert!(matches!(
+            error("print starts_with(1);"),
+            CompilerError::ParamType {
+                expected: ExpressionKind::String,
+                actual: ExpressionKind::None,
+                ..
+            }
+        ));
+        assert!(matches!(
+            error("let a: int = shout(\"a\");"),
+            CompilerError::NoneValue { .. }
+        ));
+    }
+
+    #[test]
+    fn script_functions_shadow_natives() {
+        let mut engine = native_engine();
+        engine
+            .eval("fun shout(n: int) { print n; }\nshout(5);")
+            .unwrap();
+        assert_eq!(engine.output(), b"5\n");
+    }
+
+    #[test]
+    fn native_errors() {
+        let mut engine = native_engine();
+        assert_eq!(
+            native_runtime_error(&mut engine, "shout(\"\");"),
+            RuntimeErrorKind::Native {
+                function: "shout".to_string(),
+                message: "nothing to shout".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn native_return_values_are_checked() {
+        let mut engine = native_engine();
+        assert_eq!(
+            native_runtime_error(&mut engine, "print half(1.0);"),
+            RuntimeErrorKind::Native {
+                function: "half".to_string(),
+                message: "Expected a return value of type 'float' but got 'int'".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn chunks_need_the_natives_of_their_engine() {
+        let chunk = native_engine().compile("shout(\"a\");").unwrap();
+        let mut other = crate::Engine::with_output(Vec::new());
+        assert!(matches!(
+            other.run(chunk),
+            Err(RuntimeError {
+                kind: RuntimeErrorKind::UnknownNative(1),
+                ..
+            })
+        ));
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct User {
+        name: String,
+        age: i64,
+        manager: Option<Box<User>>,
+    }
+
+    impl crate::IntoHost for User {
+        fn into_host(self) -> crate::HostValue {
+            crate::HostInstance::new("User")
+                .with("name", self.name)
+                .with("age", self.age)
+                .with("manager", self.manager.map(|m| m.into_host()))
+                .into_host()
+        }
+    }
+
+    impl crate::FromHost for User {
+        fn from_host(value: crate::HostValue) -> Result<Self, crate::ConversionError> {
+            let instance = crate::HostInstance::from_host(value)?.expect_class("User")?;
+            Ok(User {
+                name: instance.get("name")?,
+                age: instance.get("age")?,
+                manager: instance.get::<Option<User>>("manager")?.map(Box::new),
+            })
+        }
+    }
+
+    /// An engine with a `user` managed by ada and a `limit` of 2 as inputs.
+    fn input_engine() -> crate::Engine<Vec<u8>> {
+        let mut engine = crate::Engine::with_output(Vec::new());
+        engine.gc = STRESS_GC;
+        let ada = User {
+            name: "ada".to_string(),
+            age: 36,
+            manager: None,
+        };
+        let bob = User {
+            name: "bob".to_string(),
+            age: 30,
+            manager: Some(Box::new(ada)),
+        };
+        engine.set_input("user", bob);
+        engine.set_input("limit", 2);
+        engine
+    }
+
+    fn input_error(engine: &crate::Engine<Vec<u8>>, source: &str) -> CompilerError {
+        engine.compile(source).unwrap_err().remove(0).error
+    }
+
+    #[test]
+    fn host_values() {
+        let mut engine = input_engine();
+        let source = r#"
+            class User {
+                str name;
+                int age;
+                User? manager;
+            }
+            let names = [user.name];
+            let older = user.age > limit;
+            mut promoted = new User("eve", limit, user);
+            print user.manager;
+            return promoted;
+        "#;
+        let outcome = engine.eval(source).unwrap();
+        assert_eq!(
+            engine.output(),
+            b"User(name: \"ada\", age: 36, manager: nil)\n"
+        );
+        let promoted = outcome.value::<User>().unwrap();
+        assert_eq!((promoted.name.as_str(), promoted.age), ("eve", 2));
+        assert_eq!(promoted.manager.unwrap().manager.unwrap().name, "ada");
+        assert_eq!(outcome.global::<bool>("older"), Ok(true));
+        assert!(matches!(
+            outcome
+                .global::<User>("promoted")
+                .unwrap()
+                .manager
+                .map(|m| m.age),
+            Some(30)
+        ));
+
+        // values are never reinterpreted
+        let mismatch = crate::ConversionError::Type {
+            expected: "int".to_string(),
+            actual: "bool".to_string(),
+        };
+        assert_eq!(outcome.global::<i64>("older"), Err(mismatch));
+        let unsupported = crate::ConversionError::Unsupported("array".to_string());
+        assert_eq!(outcome.global::<bool>("names"), Err(unsupported));
+        let unknown = crate::ConversionError::UnknownGlobal("user".to_string());
+        assert_eq!(outcome.global::<bool>("user"), Err(unknown));
+    }
+
+    #[test]
+    fn inputs_are_checked_when_run() {
+        let mut engine = input_engine();
+        let class = "class User {\nstr name;\nint age;\nUser? manager;\n}\n";
+        let chunk = engine
+            .compile(&format!("{}print limit + 1;", class))
+            .unwrap();
+        engine.set_input("limit", "two");
+        assert_eq!(
+            engine.run(chunk).unwrap_err().kind,
+            RuntimeErrorKind::Input {
+                name: "limit".to_string(),
+                error: crate::ConversionError::Type {
+                    expected: "int".to_string(),
+                    actual: "str".to_string()
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn input_instances_are_checked_against_their_class() {
+        let mut engine = input_engine();
+        engine.set_input("user", crate::HostInstance::new("User").with("name", "bob"));
+        let chunk = engine
+            .compile("class User {\nstr name;\nint age;\n}\nprint user.name;")
+            .unwrap();
+        assert!(matches!(
+            engine.run(chunk).unwrap_err().kind,
+            RuntimeErrorKind::Input {
+                error: crate::ConversionError::MissingField { .. },
+                ..
+            }
+        ));
+
+        let user = crate::HostInstance::new("User")
+            .with("name", "bob")
+            .with("email", "b@example.com");
+        engine.set_input("user", user);
+        let chunk = engine
+            .compile("class User {\nstr name;\nUser? manager;\n}\nprint user;")
+            .unwrap();
+        assert!(matches!(
+            engine.run(chunk).unwrap_err().kind,
+            RuntimeErrorKind::Input {
+                error: crate::ConversionError::UnknownField { .. },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn input_compile_errors() {
+        let mut engine = input_engine();
+        engine.set_input("limit", "two");
+        assert!(matches!(
+            input_error(&engine, "limit = \"three\";"),
+            CompilerError::CantMut {
+                declaration: None,
+                ..
+            }
+        ));
+        assert!(matches!(
+            input_error(&engine, "let a: int = limit;"),
+            CompilerError::DeclarationType { .. }
+        ));
+        assert!(matches!(
+            input_error(&engine, "print user;"),
+            CompilerError::UnknownClass { .. }
+        ));
+        assert!(matches!(
+            input_error(&engine, "return 1;\nreturn \"a\";"),
+            CompilerError::Type { .. }
+        ));
+    }
+
+    #[test]
+    fn typed_inputs() {
+        // optionals are passed in with a declared type, also without a value
+        let mut engine = input_engine();
+        engine.set_input_typed("o", "int?", None::<i64>);
+        engine.set_input_typed("boss", "User?", None::<crate::HostInstance>);
+        let source = r#"
+            class User {
+                str name;
+            }
+            let b = boss;
+            if b != nil {
+                print b.name;
+            }
+            return o;
+        "#;
+        let chunk = engine.compile(source).unwrap();
+        assert_eq!(engine.run(chunk).unwrap().value::<Option<i64>>(), Ok(None));
+
+        engine.set_input("o", 5);
+        engine.set_input("boss", crate::HostInstance::new("User").with("name", "ada"));
+        let outcome = engine.eval(source).unwrap();
+        assert_eq!(outcome.value::<Option<i64>>(), Ok(Some(5)));
+        assert_eq!(engine.output(), b"ada\n");
+    }
+
+    #[test]
+    fn typed_input_errors() {
+        let mut engine = input_engine();
+        engine.set_input_typed("o", "int?", None::<i64>);
+        engine.set_input_typed("boss", "User?", None::<crate::HostInstance>);
+        assert!(matches!(
+            input_error(&engine, "let a: int = o;"),
+            CompilerError::DeclarationType {
+                expected: ExpressionKind::Int,
+                actual: ExpressionKind::Optional(_),
+                ..
+            }
+        ));
+        assert!(matches!(
+            input_error(&engine, "print boss;"),
+            CompilerError::NotAType {
+                span: Span {
+                    start: 6,
+                    end: 10,
+                    ..
+                },
+                ..
+            }
+        ));
+
+        engine.set_input_typed("o", "[int]", None::<i64>);
+        assert!(matches!(
+            input_error(&engine, "print o;"),
+            CompilerError::InputType { .. }
+        ));
+
+        engine.set_input_typed("o", "int?", "five");
+        let chunk = engine.compile("print o;").unwrap();
+        assert!(matches!(
+            engine.run(chunk).unwrap_err().kind,
+            RuntimeErrorKind::Input {
+                error: crate::ConversionError::Type { .. },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn inputs_can_not_be_redeclared() {
+        let mut engine = input_engine();
+        let diagnostic = engine
+            .compile("print limit;\nlet limit = 4;")
+            .unwrap_err()
+            .remove(0);
+        assert!(matches!(
+            &diagnostic.error,
+            CompilerError::Redeclaration { name, previous: None, .. } if name == "limit"
+        ));
+        assert_eq!(
+            diagnostic.render("main.bofink"),
+            "error[E0006]: Cannot redeclare 'limit'
+ --> main.bofink:2:1
+  |
+2 | let limit = 4;
+  | ^^^^^^^^^^^^^^ 'limit' is an input passed in by the host
+"
+        );
+        assert!(matches!(
+            input_error(&engine, "if true {\nmut limit = 2;\n}"),
+            CompilerError::Redeclaration { previous: None, .. }
+        ));
+        assert!(matches!(
+            input_error(&engine, "fun limit() {}"),
+            CompilerError::Redeclaration { previous: None, .. }
+        ));
+
+        // functions can shadow them with their own locals
+        let source =
+            "fun f(limit: int) int {\nlet user = limit * 2;\nreturn user;\n}\nreturn f(4);";
+        let outcome = engine.eval(source).unwrap();
+        assert_eq!(outcome.value::<i64>(), Ok(8));
+    }
+
+    #[test]
+    fn outcome_globals() {
+        // the globals declared before an early return can be read back
+        let mut engine = crate::Engine::with_output(Vec::new());
+        let outcome = engine
+            .eval("let a = \"x\";\nif true {\nreturn nil;\n}\nlet b = 1;")
+            .unwrap();
+        assert_eq!(outcome.global::<String>("a"), Ok("x".to_string()));
+        let unknown = crate::ConversionError::UnknownGlobal("b".to_string());
+        assert_eq!(outcome.global::<i64>("b"), Err(unknown));
+        assert_eq!(outcome.value::<Option<i64>>(), Ok(None));
+
+        let outcome = engine
+            .eval("class Node {\nNode? next;\n}\nmut n = new Node();\nn.next = n;")
+            .unwrap();
+        let cycle = crate::ConversionError::Cycle("Node".to_string());
+        assert_eq!(outcome.global::<crate::HostValue>("n"), Err(cycle));
+    }
+
+
+}
@@ -0,0 +1,75 @@
+//! Functions of the host program that scripts can call like their own.
+
+use std::fmt;
+
+use crate::enums::ExpressionKind;
+use crate::host::HostValue;
+
+/// The name of a kind a native function takes or returns, as
+/// `HostValue::kind_name` names its values.
+pub(crate) fn kind_name(kind: &ExpressionKind) -> &'static str {
+    match kind {
+        ExpressionKind::Int => "int",
+        ExpressionKind::Float => "float",
+        ExpressionKind::Bool => "bool",
+        ExpressionKind::String => "str",
+        _ => "nil",
+    }
+}
+
+/// The parameters and the return kind of a native function, which the checker
+/// checks its calls against.
+#[derive(Debug, Clone)]
+pub struct NativeSignature {
+    pub name: String,
+    pub params: Vec<ExpressionKind>,
+    /// `None` for functions without a return value.
+    pub return_kind: ExpressionKind,
+}
+
+type Function = Box<dyn Fn(&[HostValue]) -> Result<HostValue, String>>;
+
+/// A native function together with the Rust code it runs.
+pub struct NativeFunction {
+    pub signature: NativeSignature,
+    function: Function,
+}
+
+impl NativeFunction {
+    /// Panics if a parameter is not an int, a float, a bool or a str, or if the
+    /// return kind is none of those or `None`.
+    pub fn new(signature: NativeSignature, function: Function) -> Self {
+        let value_kind = |kind: &ExpressionKind| {
+            matches!(
+                kind,
+                ExpressionKind::Int
+                    | ExpressionKind::Float
+                    | ExpressionKind::Bool
+                    | ExpressionKind::String
+            )
+        };
+        assert!(
+            signature.params.iter().all(value_kind)
+                && (value_kind(&signature.return_kind)
+                    || signature.return_kind == ExpressionKind::None),
+            "native function '{}' can only take and return int, float, bool and str",
+            signature.name
+        );
+        NativeFunction {
+            signature,
+            function,
+        }
+    }
+
+    /// Runs the function with arguments of the kinds of its parameters. An error
+    /// message fails the script.
+    pub(crate) fn call(&self, args: &[HostValue]) -> Result<HostValue, String> {
+        (self.function)(args)
+    }
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NativeFunction({:?})", self.signature)
+    }
+}
@@ -1,6 +1,27 @@
-#[repr(u8)]
-#[derive(Debug, Copy, Clone)]
-pub enum OpCode {
+/// Declares the `OpCode` enum together with a table of every opcode, so a byte can
+/// be decoded without transmuting it.
+macro_rules! opcodes {
+    ($($(#[$attr:meta])* $name:ident,)*) => {
+        #[repr(u8)]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+        pub enum OpCode {
+            $($(#[$attr])* $name,)*
+        }
+
+        impl OpCode {
+            const ALL: &'static [OpCode] = &[$(OpCode::$name,)*];
+
+            /// The name of the opcode, for runtime errors.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(OpCode::$name => stringify!($name),)*
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
     _Constant,
     Return,
     Negate,
@@ -8,7 +29,7 @@ pub enum OpCode {
     Subtract,
     Multiply,
     Divide,
-    _Nil,
+    Nil,
     True,
     False,
     Print,
@@ -22,9 +43,8 @@ pub enum OpCode {
     GetLocal,
     SetLocal,
     JumpIfFalse,
-    SetJump,
     JumpBack,
-    _JumpForward,
+    JumpForward,
     CompareString,
     CompareBool,
     CompareInt,
@@ -40,13 +60,90 @@ pub enum OpCode {
     SetOffset,
     PopOffset,
     Modulo,
-    And,
-    Or,
+    _And,
+    _Or,
     ReturnValue,
     Not,
     // for classes
     CreateInstance,
     SetField,
     GetField,
+    Duplicate,
+    CompareValue,
+    CompareValueNot,
+    CompareIdentity,
+    StringValueConcat,
+    ValueStringConcat,
+    // for enums
+    InstanceOf,
+    // for function values
+    Closure,
+    CallValue,
+    ArrayMap,
+    ArrayFilter,
+    ArraySort,
+    // for globals
+    GetGlobal,
+    SetGlobal,
+    ReserveGlobals,
+    // for arrays
+    CreateArray,
+    GetIndex,
+    SetIndex,
+    ArrayLen,
+    ArrayPush,
+    ArrayPop,
+    // for maps
+    CreateMap,
+    MapGet,
+    MapSet,
+    MapHas,
+    MapRemove,
+    MapLen,
+    MapKeys,
+    MapValues,
+    // for floats
+    Float,
+    AddFloat,
+    SubtractFloat,
+    MultiplyFloat,
+    DivideFloat,
+    ModuloFloat,
+    NegateFloat,
+    CompareFloat,
+    CompareFloatNot,
+    LessFloat,
+    LessEqualFloat,
+    GreaterFloat,
+    GreaterEqualFloat,
+    FloatStringConcat,
+    StringFloatConcat,
+    IntToFloat,
+    FloatToInt,
+    // for the host
+    CallNative,
+    GetInput,
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        OpCode::ALL.get(byte as usize).copied().ok_or(byte)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OpCode;
+
+    #[test]
+    fn decode() {
+        for (i, opcode) in OpCode::ALL.iter().enumerate() {
+            assert_eq!(*opcode as u8 as usize, i);
+            assert_eq!(OpCode::try_from(i as u8), Ok(*opcode));
+        }
+        assert_eq!(OpCode::try_from(u8::MAX), Err(u8::MAX));
+    }
 }
 
@@ -0,0 +1,1012 @@
+//! Turns the tokens of a file into a syntax tree. Only the syntax is checked here,
+//! names and types are left to the checker.
+
+use std::path::Path;
+
+use crate::ast::*;
+use crate::enums::{CompilerError, ExpressionKind, TokenKind};
+use crate::scanner::{Scanner, Span, Token};
+
+type Result<T> = std::result::Result<T, CompilerError>;
+
+/// Parses the source of a file. A statement that fails to parse is skipped, so every
+/// independent syntax error is reported, and the statements that did parse are
+/// returned with them.
+pub fn parse(source: &str) -> (File, Vec<CompilerError>) {
+    let mut parser = Parser {
+        tokens: Scanner::get_tokens(source.to_string()),
+        p: 0,
+        errors: vec![],
+        unknown: vec![],
+    };
+    let mut stmts = vec![];
+    while parser.current_kind() != TokenKind::Eof {
+        if let Some(stmt) = parser.recovering_declaration() {
+            stmts.push(stmt);
+        }
+    }
+    let file = File {
+        stmts,
+        unknown: parser.unknown,
+    };
+    (file, parser.errors)
+}
+
+/// Parses a type written the way the source writes it, like `int?`. `None` if the
+/// whole source is not a type.
+pub fn parse_type(source: &str) -> Option<Type> {
+    let mut parser = Parser {
+        tokens: Scanner::get_tokens(source.to_string()),
+        p: 0,
+        errors: vec![],
+        unknown: vec![],
+    };
+    let ty = parser.parse_type().ok()?;
+    (parser.current_kind() == TokenKind::Eof).then_some(ty)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    p: usize,
+    /// The errors of the statements skipped so far.
+    errors: Vec<CompilerError>,
+    /// The names declared by the statements skipped so far.
+    unknown: Vec<String>,
+}
+
+impl Parser {
+    /// Parses a declaration or a statement.
+    fn declaration(&mut self) -> Result<Stmt> {
+        let start = self.current_span();
+        let node = match self.current_kind() {
+            TokenKind::Mut | TokenKind::Let => StmtNode::Let(self.let_declaration()?),
+            TokenKind::Class => StmtNode::Class(self.class_declaration(false)?),
+            TokenKind::Enum => StmtNode::Enum(self.enum_declaration(false)?),
+            TokenKind::Fun if self.peek_kind(1) == TokenKind::Identifier => {
+                StmtNode::Function(self.function_declaration(false)?)
+            }
+            TokenKind::Pub => {
+                self.p += 1;
+                match self.current_kind() {
+                    TokenKind::Fun => StmtNode::Function(self.function_declaration(true)?),
+                    TokenKind::Class => StmtNode::Class(self.class_declaration(true)?),
+                    TokenKind::Enum => StmtNode::Enum(self.enum_declaration(true)?),
+                    kind => {
+                        return Err(CompilerError::UnexpectedToken {
+                            expected: TokenKind::Fun,
+                            actual: kind,
+                            span: self.current_span(),
+                        })
+                    }
+                }
+            }
+            TokenKind::Import => StmtNode::Import(self.import()?),
+            _ => return self.statement(),
+        };
+        Ok(Stmt {
+            node,
+            span: self.span_from(start),
+        })
+    }
+
+    /// Parses the declarations up to the `}` closing a block, from its opening brace.
+    fn block(&mut self) -> Result<Block> {
+        let start = self.consume_token(TokenKind::LeftBrace)?.span;
+        let mut stmts = vec![];
+        while self.current_kind() != TokenKind::RightBrace {
+            if self.current_kind() == TokenKind::Eof {
+                return Err(CompilerError::UnexpectedToken {
+                    expected: TokenKind::RightBrace,
+                    actual: TokenKind::Eof,
+                    span: self.current_span(),
+                });
+            }
+            if let Some(stmt) = self.recovering_declaration() {
+                stmts.push(stmt);
+            }
+        }
+        self.p += 1;
+        Ok(Block {
+            stmts,
+            span: self.span_from(start),
+        })
+    }
+
+    /// Parses a declaration, or records its error and skips to the next statement.
+    fn recovering_declaration(&mut self) -> Option<Stmt> {
+        let start = self.p;
+        match self.declaration() {
+            Ok(stmt) => Some(stmt),
+            Err(error) => {
+                let error = self.scanner_error(error);
+                self.errors.push(error);
+                if let Some(name) = self.declared_name(start) {
+                    self.unknown.push(name);
+                }
+                self.synchronize(start);
+                None
+            }
+        }
+    }
+
+    /// Replaces the error for an error token of the scanner with the message of the
+    /// scanner, like an unterminated string.
+    fn scanner_error(&self, error: CompilerError) -> CompilerError {
+        let span = match &error {
+            CompilerError::UnexpectedToken {
+                actual: TokenKind::Error,
+                span,
+                ..
+            }
+            | CompilerError::InvalidToken {
+                actual: TokenKind::Error,
+                span,
+            }
+            | CompilerError::NotAType {
+                kind: TokenKind::Error,
+                span,
+            } => *span,
+            _ => return error,
+        };
+        match self
+            .tokens
+            .iter()
+            .find(|token| token.kind == TokenKind::Error && token.span == span)
+        {
+            Some(token) => CompilerError::Lexical {
+                message: token.value.to_string(),
+                span,
+            },
+            None => error,
+        }
+    }
+
+    /// The name declared by the statement at `start`, if it gets as far as one.
+    fn declared_name(&self, start: usize) -> Option<String> {
+        let mut tokens = self.tokens[start..self.p.max(start + 1)]
+            .iter()
+            .skip_while(|token| token.kind == TokenKind::Pub);
+        match (tokens.next()?.kind, tokens.next()?) {
+            (
+                TokenKind::Let
+                | TokenKind::Mut
+                | TokenKind::Fun
+                | TokenKind::Class
+                | TokenKind::Enum,
+                name,
+            ) if name.kind == TokenKind::Identifier => Some(name.value.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Skips the rest of a statement that failed to parse, which began at the token
+    /// at `start`. Parsing resumes after its `;` or the brace closing its body, or
+    /// at a keyword that begins a statement.
+    fn synchronize(&mut self, start: usize) {
+        // braces opened by the statement before the error
+        let mut depth = self.tokens[start..self.p]
+            .iter()
+            .fold(0usize, |depth, token| match token.kind {
+                TokenKind::LeftBrace => depth + 1,
+                TokenKind::RightBrace => depth.saturating_sub(1),
+                _ => depth,
+            });
+        if self.p == start {
+            self.advance();
+        }
+        loop {
+            match self.current_kind() {
+                TokenKind::Eof => return,
+                TokenKind::LeftBrace => depth += 1,
+                // closes the enclosing block
+                TokenKind::RightBrace if depth == 0 => return,
+                TokenKind::RightBrace => {
+                    depth -= 1;
+                    if depth == 0 && self.peek_kind(1) != TokenKind::Else {
+                        self.p += 1;
+                        return;
+                    }
+                }
+                TokenKind::Semicolon if depth == 0 => {
+                    self.p += 1;
+                    return;
+                }
+                TokenKind::Let
+                | TokenKind::Mut
+                | TokenKind::Class
+                | TokenKind::Enum
+                | TokenKind::Pub
+                | TokenKind::Import
+                | TokenKind::If
+                | TokenKind::While
+                | TokenKind::For
+                | TokenKind::Print
+                | TokenKind::Return
+                | TokenKind::Break
+                | TokenKind::Continue
+                    if depth == 0 =>
+                {
+                    return
+                }
+                TokenKind::Fun if depth == 0 && self.peek_kind(1) == TokenKind::Identifier => {
+                    return
+                }
+                _ => {}
+            }
+            self.p += 1;
+        }
+    }
+
+    fn let_declaration(&mut self) -> Result<Let> {
+        let is_mut = self.current_kind() == TokenKind::Mut;
+        self.p += 1;
+        let name = self.consume_token(TokenKind::Identifier)?.value;
+        let ty = match self.consume_if_match(TokenKind::Colon) {
+            Some(_) => Some(self.parse_type()?),
+            None => None,
+        };
+        self.consume_token(TokenKind::Equal)?;
+        let value = self.expression()?;
+        self.consume_token(TokenKind::Semicolon)?;
+        Ok(Let {
+            name,
+            is_mut,
+            ty,
+            value,
+        })
+    }
+
+    /// Parses `import "path/util.bofink";` or `import util;`.
+    fn import(&mut self) -> Result<Import> {
+        self.consume_token(TokenKind::Import)?;
+        let token = self.advance();
+        let path = match token.kind {
+            TokenKind::String => token.value.to_string(),
+            TokenKind::Identifier => format!("{}.bofink", token.value),
+            actual => {
+                return Err(CompilerError::UnexpectedToken {
+                    expected: TokenKind::Identifier,
+                    actual,
+                    span: token.span,
+                })
+            }
+        };
+        let name = Path::new(&path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.consume_token(TokenKind::Semicolon)?;
+        Ok(Import {
+            path,
+            name,
+            span: token.span,
+            module: None,
+        })
+    }
+
+    fn function_declaration(&mut self, is_pub: bool) -> Result<Function> {
+        self.consume_token(TokenKind::Fun)?;
+        let token = self.consume_token(TokenKind::Identifier)?;
+        let mut function = self.function(token.span)?;
+        function.name = token.value;
+        function.is_pub = is_pub;
+        Ok(function)
+    }
+
+    /// Parses the parameters, the return type and the body of a function.
+    fn function(&mut self, span: Span) -> Result<Function> {
+        self.consume_token(TokenKind::LeftParen)?;
+        let mut params = vec![];
+        while self.current_kind() != TokenKind::RightParen {
+            let token = self.consume_token(TokenKind::Identifier)?;
+            self.consume_token(TokenKind::Colon)?;
+            params.push(Param {
+                name: token.value,
+                ty: self.parse_type()?,
+                span: token.span,
+            });
+            if self.consume_if_match(TokenKind::Comma).is_none() {
+                break;
+            }
+        }
+        self.consume_token(TokenKind::RightParen)?;
+        // A map return type is told apart from the body by its key type
+        let return_type = match (self.current_kind(), self.peek_kind(1)) {
+            (TokenKind::LeftBrace, TokenKind::Int | TokenKind::Str | TokenKind::Bool)
+                if self.peek_kind(2) == TokenKind::Colon =>
+            {
+                Some(self.parse_type()?)
+            }
+            (TokenKind::LeftBrace, _) => None,
+            _ => Some(self.parse_type()?),
+        };
+        Ok(Function {
+            name: String::new(),
+            is_pub: false,
+            params,
+            return_type,
+            body: self.block()?,
+            span,
+            index: 0,
+            captures: vec![],
+        })
+    }
+
+    fn class_declaration(&mut self, is_pub: bool) -> Result<Class> {
+        self.consume_token(TokenKind::Class)?;
+        let token = self.consume_token(TokenKind::Identifier)?;
+        self.consume_token(TokenKind::LeftBrace)?;
+        let mut members = vec![];
+        while self.current_kind() != TokenKind::RightBrace {
+            if self.at_method() {
+                let is_mut = self.consume_if_match(TokenKind::Mut).is_some();
+                members.push(Member::Method(Method {
+                    is_mut,
+                    function: self.function_declaration(false)?,
+                }));
+                continue;
+            }
+            let ty = self.parse_type()?;
+            let name = self.consume_token(TokenKind::Identifier)?;
+            let default = match self.consume_if_match(TokenKind::Equal) {
+                Some(_) => Some(self.expression()?),
+                None => None,
+            };
+            self.consume_token(TokenKind::Semicolon)?;
+            members.push(Member::Field(Field {
+                name: name.value,
+                ty,
+                default,
+                span: name.span,
+                default_index: 0,
+            }));
+        }
+        self.consume_token(TokenKind::RightBrace)?;
+        Ok(Class {
+            name: token.value,
+            is_pub,
+            members,
+            span: token.span,
+        })
+    }
+
+    /// Whether the current token starts a method rather than a field.
+    fn at_method(&self) -> bool {
+        match self.current_kind() {
+            // a field can have a function type like `fun(int) -> int`
+            TokenKind::Fun => self.peek_kind(1) == TokenKind::Identifier,
+            TokenKind::Mut => self.peek_kind(1) == TokenKind::Fun,
+            _ => false,
+        }
+    }
+
+    fn enum_declaration(&mut self, is_pub: bool) -> Result<Enum> {
+        self.consume_token(TokenKind::Enum)?;
+        let token = self.consume_token(TokenKind::Identifier)?;
+        self.consume_token(TokenKind::LeftBrace)?;
+        let mut variants = vec![];
+        while self.current_kind() != TokenKind::RightBrace {
+            let variant = self.consume_token(TokenKind::Identifier)?;
+            let mut payload = vec![];
+            if self.consume_if_match(TokenKind::LeftParen).is_some() {
+                while self.current_kind() != TokenKind::RightParen {
+                    payload.push(self.parse_type()?);
+                    if self.consume_if_match(TokenKind::Comma).is_none() {
+                        break;
+                    }
+                }
+                self.consume_token(TokenKind::RightParen)?;
+            }
+            variants.push(Variant {
+                name: variant.value,
+                payload,
+                span: variant.span,
+            });
+            if self.consume_if_match(TokenKind::Comma).is_none() {
+                break;
+            }
+        }
+        self.consume_token(TokenKind::RightBrace)?;
+        Ok(Enum {
+            name: token.value,
+            is_pub,
+            variants,
+            span: token.span,
+        })
+    }
+
+    /// Parses a type annotation like `int`, `Foo`, `[str]`, `{str: int}`, `int?` or
+    /// `fun(int) -> str`.
+    fn parse_type(&mut self) -> Result<Type> {
+        let ty = self.parse_base_type()?;
+        match self.consume_if_match(TokenKind::Question) {
+            Some(_) => Ok(Type {
+                span: ty.span.to(self.previous_span()),
+                node: TypeNode::Optional(Box::new(ty)),
+            }),
+            None => Ok(ty),
+        }
+    }
+
+    fn parse_base_type(&mut self) -> Result<Type> {
+        let start = self.current_span();
+        let node = match self.current_kind() {
+            TokenKind::Int => TypeNode::Int,
+            TokenKind::Float => TypeNode::Float,
+            TokenKind::Bool => TypeNode::Bool,
+            TokenKind::Str => TypeNode::Str,
+            TokenKind::Identifier => {
+                let name = self.advance().value;
+                let node = match self.peek_kind(1) {
+                    // `util.Point`
+                    TokenKind::Identifier if self.current_kind() == TokenKind::Dot => {
+                        self.p += 1;
+                        TypeNode::Named {
+                            module: Some(name),
+                            name: self.advance().value,
+                        }
+                    }
+                    _ => TypeNode::Named { module: None, name },
+                };
+                return Ok(Type {
+                    node,
+                    span: self.span_from(start),
+                });
+            }
+            TokenKind::LeftBracket => {
+                self.p += 1;
+                let element = self.parse_type()?;
+                self.consume_token(TokenKind::RightBracket)?;
+                return Ok(Type {
+                    node: TypeNode::Array(Box::new(element)),
+                    span: self.span_from(start),
+                });
+            }
+            TokenKind::LeftBrace => {
+                self.p += 1;
+                let key = self.parse_type()?;
+                self.consume_token(TokenKind::Colon)?;
+                let value = self.parse_type()?;
+                self.consume_token(TokenKind::RightBrace)?;
+                return Ok(Type {
+                    node: TypeNode::Map(Box::new(key), Box::new(value)),
+                    span: self.span_from(start),
+                });
+            }
+            TokenKind::Fun => {
+                self.p += 1;
+                self.consume_token(TokenKind::LeftParen)?;
+                let mut params = vec![];
+                while self.current_kind() != TokenKind::RightParen {
+                    params.push(self.parse_type()?);
+                    if self.consume_if_match(TokenKind::Comma).is_none() {
+                        break;
+                    }
+                }
+                self.consume_token(TokenKind::RightParen)?;
+                let return_type = match self.consume_if_match(TokenKind::Arrow) {
+                    Some(_) => Some(Box::new(self.parse_type()?)),
+                    None => None,
+                };
+                return Ok(Type {
+                    node: TypeNode::Function(params, return_type),
+                    span: self.span_from(start),
+                });
+            }
+            kind => return Err(CompilerError::NotAType { kind, span: start }),
+        };
+        self.p += 1;
+        Ok(Type { node, span: start })
+    }
+
+    /// Parses a statement, which is anything but a declaration.
+    fn statement(&mut self) -> Result<Stmt> {
+        let start = self.current_span();
+        let node = match self.current_kind() {
+            TokenKind::While => StmtNode::While(self.while_stmt(None)?),
+            TokenKind::For => self.for_stmt(None)?,
+            TokenKind::If => StmtNode::If(self.if_stmt()?),
+            TokenKind::Identifier if self.peek_kind(1) == TokenKind::Colon => {
+                let label = self.advance().value;
+                self.p += 1;
+                match self.current_kind() {
+                    TokenKind::While => StmtNode::While(self.while_stmt(Some(label))?),
+                    TokenKind::For => self.for_stmt(Some(label))?,
+                    actual => {
+                        return Err(CompilerError::InvalidToken {
+                            actual,
+                            span: self.current_span(),
+                        })
+                    }
+                }
+            }
+            TokenKind::Print => {
+                self.p += 1;
+                let value = self.expression()?;
+                self.consume_token(TokenKind::Semicolon)?;
+                StmtNode::Print(value)
+            }
+            TokenKind::Return => {
+                self.p += 1;
+                let value = match self.current_kind() {
+                    TokenKind::Semicolon => None,
+                    _ => Some(self.expression()?),
+                };
+                self.consume_token(TokenKind::Semicolon)?;
+                StmtNode::Return(value)
+            }
+            TokenKind::Break | TokenKind::Continue => {
+                let keyword = self.advance().kind;
+                let label = self
+                    .consume_if_match(TokenKind::Identifier)
+                    .map(|t| t.value);
+                self.consume_token(TokenKind::Semicolon)?;
+                match keyword {
+                    TokenKind::Break => StmtNode::Break(label),
+                    _ => StmtNode::Continue(label),
+                }
+            }
+            // a match used as a statement needs no semicolon
+            TokenKind::Match => {
+                self.p += 1;
+                let node = ExprNode::Match(Box::new(self.match_expr()?));
+                let value = Expr::new(node, self.span_from(start));
+                self.consume_if_match(TokenKind::Semicolon);
+                StmtNode::Expr(value)
+            }
+            _ => {
+                let target = self.expression()?;
+                if self.consume_if_match(TokenKind::Equal).is_none() {
+                    self.consume_token(TokenKind::Semicolon)?;
+                    return Ok(Stmt {
+                        node: StmtNode::Expr(target),
+                        span: self.span_from(start),
+                    });
+                }
+                if !matches!(
+                    target.node,
+                    ExprNode::Variable { .. } | ExprNode::Field { .. } | ExprNode::Index { .. }
+                ) {
+                    return Err(CompilerError::InvalidToken {
+                        actual: TokenKind::Equal,
+                        span: self.previous_span(),
+                    });
+                }
+                let value = self.expression()?;
+                self.consume_token(TokenKind::Semicolon)?;
+                StmtNode::Assign { target, value }
+            }
+        };
+        Ok(Stmt {
+            node,
+            span: self.span_from(start),
+        })
+    }
+
+    fn while_stmt(&mut self, label: Option<String>) -> Result<While> {
+        self.consume_token(TokenKind::While)?;
+        Ok(While {
+            label,
+            condition: self.expression()?,
+            body: self.block()?,
+        })
+    }
+
+    fn if_stmt(&mut self) -> Result<If> {
+        self.consume_token(TokenKind::If)?;
+        let condition = self.expression()?;
+        let then_branch = self.block()?;
+        let else_branch = match self.consume_if_match(TokenKind::Else) {
+            Some(_) if self.current_kind() == TokenKind::If => {
+                Some(Else::If(Box::new(self.if_stmt()?)))
+            }
+            Some(_) => Some(Else::Block(self.block()?)),
+            None => None,
+        };
+        Ok(If {
+            condition,
+            then_branch,
+            else_branch,
+        })
+    }
+
+    /// Parses `for i in 0:10:2 { ... }` or `for x in values { ... }`.
+    fn for_stmt(&mut self, label: Option<String>) -> Result<StmtNode> {
+        self.consume_token(TokenKind::For)?;
+        let name = self.consume_token(TokenKind::Identifier)?;
+        self.consume_token(TokenKind::In)?;
+        if self.current_kind() != TokenKind::Number {
+            return Ok(StmtNode::ForIn(ForIn {
+                label,
+                name: name.value,
+                name_span: name.span,
+                iterable: self.expression()?,
+                body: self.block()?,
+            }));
+        }
+        let start = self.number()?;
+        self.consume_token(TokenKind::Colon)?;
+        let end = self.number()?;
+        let step = match self.consume_if_match(TokenKind::Colon) {
+            Some(_) if self.consume_if_match(TokenKind::Minus).is_some() => Some(-self.number()?),
+            Some(_) => Some(self.number()?),
+            None => None,
+        };
+        Ok(StmtNode::ForRange(ForRange {
+            label,
+            name: name.value,
+            name_span: name.span,
+            start,
+            end,
+            step,
+            body: self.block()?,
+        }))
+    }
+
+    /// Parses an int literal.
+    fn number(&mut self) -> Result<i64> {
+        let token = self.consume_token(TokenKind::Number)?;
+        token
+            .value
+            .parse()
+            .map_err(|_| CompilerError::InvalidNumber(token.span))
+    }
+
+    //
+    // EXPRESSIONS
+    //
+
+    fn expression(&mut self) -> Result<Expr> {
+        self.or()
+    }
+
+    /// Parses the left associative operators of one precedence level, with operands
+    /// parsed by `operand`.
+    fn binary(
+        &mut self,
+        operand: fn(&mut Self) -> Result<Expr>,
+        operator: fn(TokenKind) -> Option<BinaryOperator>,
+    ) -> Result<Expr> {
+        let mut left = operand(self)?;
+        while let Some(op) = operator(self.current_kind()) {
+            self.p += 1;
+            let right = operand(self)?;
+            let span = left.span.to(right.span);
+            left = Expr::new(
+                ExprNode::Binary {
+                    operator: op,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                },
+                span,
+            );
+        }
+        Ok(left)
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        self.binary(Self::and, |kind| match kind {
+            TokenKind::Or => Some(BinaryOperator::Or),
+            _ => None,
+        })
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        self.binary(Self::equality, |kind| match kind {
+            TokenKind::And => Some(BinaryOperator::And),
+            _ => None,
+        })
+    }
+
+    fn equality(&mut self) -> Result<Expr> {
+        self.binary(Self::comparison, |kind| match kind {
+            TokenKind::EqualEqual => Some(BinaryOperator::Equal),
+            TokenKind::BangEqual => Some(BinaryOperator::NotEqual),
+            TokenKind::Is => Some(BinaryOperator::Is),
+            _ => None,
+        })
+    }
+
+    fn comparison(&mut self) -> Result<Expr> {
+        self.binary(Self::term, |kind| match kind {
+            TokenKind::Less => Some(BinaryOperator::Less),
+            TokenKind::LessEqual => Some(BinaryOperator::LessEqual),
+            TokenKind::Greater => Some(BinaryOperator::Greater),
+            TokenKind::GreaterEqual => Some(BinaryOperator::GreaterEqual),
+            _ => None,
+        })
+    }
+
+    fn term(&mut self) -> Result<Expr> {
+        self.binary(Self::factor, |kind| match kind {
+            TokenKind::Plus => Some(BinaryOperator::Add),
+            TokenKind::Minus => Some(BinaryOperator::Subtract),
+            _ => None,
+        })
+    }
+
+    fn factor(&mut self) -> Result<Expr> {
+        self.binary(Self::unary, |kind| match kind {
+            TokenKind::Star => Some(BinaryOperator::Multiply),
+            TokenKind::Slash => Some(BinaryOperator::Divide),
+            TokenKind::Percent => Some(BinaryOperator::Modulo),
+            _ => None,
+        })
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        let operator = match self.current_kind() {
+            TokenKind::Bang => UnaryOperator::Not,
+            TokenKind::Minus => UnaryOperator::Negate,
+            _ => return self.postfix(),
+        };
+        let start = self.advance().span;
+        let operand = self.unary()?;
+        let span = start.to(operand.span);
+        Ok(Expr::new(
+            ExprNode::Unary {
+                operator,
+                operand: Box::new(operand),
+            },
+            span,
+        ))
+    }
+
+    /// Parses a `primary` followed by any field accesses, indexing and calls.
+    fn postfix(&mut self) -> Result<Expr> {
+        let mut expr = self.primary()?;
+        loop {
+            let start = expr.span;
+            let node = match self.current_kind() {
+                TokenKind::Dot => {
+                    self.p += 1;
+                    ExprNode::Field {
+                        object: Box::new(expr),
+                        name: self.consume_token(TokenKind::Identifier)?.value,
+                        target: FieldTarget::Unresolved,
+                    }
+                }
+                TokenKind::LeftBracket => {
+                    self.p += 1;
+                    let index = self.expression()?;
+                    self.consume_token(TokenKind::RightBracket)?;
+                    ExprNode::Index {
+                        object: Box::new(expr),
+                        index: Box::new(index),
+                    }
+                }
+                TokenKind::LeftParen => ExprNode::Call {
+                    callee: Box::new(expr),
+                    args: self.arguments()?,
+                    target: CallTarget::Unresolved,
+                },
+                _ => return Ok(expr),
+            };
+            expr = Expr::new(node, self.span_from(start));
+        }
+    }
+
+    /// Parses the arguments of a call, from the opening parenthesis.
+    fn arguments(&mut self) -> Result<Vec<Expr>> {
+        self.consume_token(TokenKind::LeftParen)?;
+        let mut args = vec![];
+        while self.current_kind() != TokenKind::RightParen {
+            args.push(self.expression()?);
+            if self.consume_if_match(TokenKind::Comma).is_none() {
+                break;
+            }
+        }
+        self.consume_token(TokenKind::RightParen)?;
+        Ok(args)
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let token = self.advance();
+        let span = token.span;
+        let node = match token.kind {
+            TokenKind::False => ExprNode::Bool(false),
+            TokenKind::True => ExprNode::Bool(true),
+            TokenKind::Nil => ExprNode::Nil,
+            TokenKind::Number => ExprNode::Int(
+                token
+                    .value
+                    .parse()
+                    .map_err(|_| CompilerError::InvalidNumber(span))?,
+            ),
+            TokenKind::FloatNumber => ExprNode::Float(
+                token
+                    .value
+                    .parse()
+                    .map_err(|_| CompilerError::InvalidNumber(span))?,
+            ),
+            TokenKind::String => ExprNode::String(token.value),
+            TokenKind::Identifier => ExprNode::Variable {
+                name: token.value,
+                target: VariableTarget::Unresolved,
+            },
+            TokenKind::Int | TokenKind::Float => {
+                let to = match token.kind {
+                    TokenKind::Int => ExpressionKind::Int,
+                    _ => ExpressionKind::Float,
+                };
+                self.consume_token(TokenKind::LeftParen)?;
+                let value = self.expression()?;
+                self.consume_token(TokenKind::RightParen)?;
+                ExprNode::Conversion {
+                    to,
+                    value: Box::new(value),
+                }
+            }
+            TokenKind::LeftParen => {
+                let mut expr = self.expression()?;
+                self.consume_token(TokenKind::RightParen)?;
+                expr.span = self.span_from(span);
+                return Ok(expr);
+            }
+            TokenKind::LeftBracket => {
+                let mut elements = vec![];
+                while self.current_kind() != TokenKind::RightBracket {
+                    elements.push(self.expression()?);
+                    if self.consume_if_match(TokenKind::Comma).is_none() {
+                        break;
+                    }
+                }
+                self.consume_token(TokenKind::RightBracket)?;
+                ExprNode::Array(elements)
+            }
+            TokenKind::LeftBrace => {
+                let mut entries = vec![];
+                while self.current_kind() != TokenKind::RightBrace {
+                    let key = self.expression()?;
+                    self.consume_token(TokenKind::Colon)?;
+                    entries.push((key, self.expression()?));
+                    if self.consume_if_match(TokenKind::Comma).is_none() {
+                        break;
+                    }
+                }
+                self.consume_token(TokenKind::RightBrace)?;
+                ExprNode::Map(entries)
+            }
+            TokenKind::New => self.new_expr()?,
+            TokenKind::Match => ExprNode::Match(Box::new(self.match_expr()?)),
+            TokenKind::Fun => ExprNode::Function(Box::new(self.function(span)?)),
+            actual => return Err(CompilerError::InvalidToken { actual, span }),
+        };
+        Ok(Expr::new(node, self.span_from(span)))
+    }
+
+    /// Parses a `new` expression after the keyword. The arguments are either
+    /// positional or all named, like `new Foo(a: 1, b: 2)`.
+    fn new_expr(&mut self) -> Result<ExprNode> {
+        let mut class = self.consume_token(TokenKind::Identifier)?.value;
+        let mut module = None;
+        if self.consume_if_match(TokenKind::Dot).is_some() {
+            module = Some(class);
+            class = self.consume_token(TokenKind::Identifier)?.value;
+        }
+        self.consume_token(TokenKind::LeftParen)?;
+        let named =
+            self.current_kind() == TokenKind::Identifier && self.peek_kind(1) == TokenKind::Colon;
+        let mut args = vec![];
+        while self.current_kind() != TokenKind::RightParen {
+            let start = self.current_span();
+            let name = match named {
+                true => {
+                    let name = self.consume_token(TokenKind::Identifier)?.value;
+                    self.consume_token(TokenKind::Colon)?;
+                    Some(name)
+                }
+                false => None,
+            };
+            args.push(Arg {
+                name,
+                value: self.expression()?,
+                span: self.span_from(start),
+            });
+            if self.consume_if_match(TokenKind::Comma).is_none() {
+                break;
+            }
+        }
+        self.consume_token(TokenKind::RightParen)?;
+        Ok(ExprNode::New {
+            module,
+            class,
+            args,
+            target: None,
+        })
+    }
+
+    /// Parses the value and the arms of a `match`, after the keyword.
+    fn match_expr(&mut self) -> Result<Match> {
+        let value = self.expression()?;
+        self.consume_token(TokenKind::LeftBrace)?;
+        let mut arms = vec![];
+        while self.current_kind() != TokenKind::RightBrace {
+            let token = self.consume_token(TokenKind::Identifier)?;
+            let mut bindings = vec![];
+            if self.consume_if_match(TokenKind::LeftParen).is_some() {
+                while self.current_kind() != TokenKind::RightParen {
+                    bindings.push(self.consume_token(TokenKind::Identifier)?.value);
+                    if self.consume_if_match(TokenKind::Comma).is_none() {
+                        break;
+                    }
+                }
+                self.consume_token(TokenKind::RightParen)?;
+            }
+            self.consume_token(TokenKind::FatArrow)?;
+            let body = match self.current_kind() {
+                TokenKind::LeftBrace => ArmBody::Block(self.block()?),
+                _ => ArmBody::Expr(self.expression()?),
+            };
+            self.consume_if_match(TokenKind::Comma);
+            arms.push(Arm {
+                variant: token.value,
+                bindings,
+                body,
+                span: token.span,
+                info: None,
+            });
+        }
+        self.consume_token(TokenKind::RightBrace)?;
+        Ok(Match { value, arms })
+    }
+
+    fn consume_token(&mut self, kind: TokenKind) -> Result<Token> {
+        let token = &self.tokens[self.p];
+        if token.kind != kind {
+            return Err(CompilerError::UnexpectedToken {
+                expected: kind,
+                actual: token.kind,
+                span: token.span,
+            });
+        }
+        Ok(self.advance())
+    }
+
+    fn consume_if_match(&mut self, kind: TokenKind) -> Option<Token> {
+        match self.current_kind() == kind {
+            true => Some(self.advance()),
+            false => None,
+        }
+    }
+
+    /// Returns the current token and moves past it. The last token is always `Eof`,
+    /// which is never moved past.
+    fn advance(&mut self) -> Token {
+        let token = &self.tokens[self.p];
+        if token.kind != TokenKind::Eof {
+            self.p += 1;
+        }
+        Token {
+            kind: token.kind,
+            line: token.line,
+            column: token.column,
+            value: token.value.to_string(),
+            span: token.span,
+        }
+    }
+
+    fn current_span(&self) -> Span {
+        self.tokens[self.p].span
+    }
+
+    /// The span of the last token moved past.
+    fn previous_span(&self) -> Span {
+        self.tokens[self.p.saturating_sub(1)].span
+    }
+
+    /// The span from `start` to the end of the last token moved past.
+    fn span_from(&self, start: Span) -> Span {
+        start.to(self.previous_span())
+    }
+
+    fn current_kind(&self) -> TokenKind {
+        self.tokens[self.p].kind
+    }
+
+    fn peek_kind(&self, offset: usize) -> TokenKind {
+        self.tokens
+            .get(self.p + offset)
+            .map_or(TokenKind::Eof, |t| t.kind)
+    }
+}
@@ -22,10 +22,7 @@ impl Scanner {
             let token = scanner.next_token();
             let kind = token.kind;
             res.push(token);
-            match kind {
-                TokenKind::Eof => break,
-                _ => {}
-            }
+            if kind == TokenKind::Eof { break }
         }
         res
     }
@@ -38,10 +35,10 @@ impl Scanner {
         }
 
         let c = self.advance();
-        if c.is_ascii_alphabetic() {
+        if c.is_ascii_alphabetic() || c == '_' {
             return self.identifier();
         }
-        if c.is_digit(10) {
+        if c.is_ascii_digit() {
             return self.number();
         }
         match c {
@@ -49,15 +46,25 @@ impl Scanner {
             ')' => return self.make_token(TokenKind::RightParen),
             '{' => return self.make_token(TokenKind::LeftBrace),
             '}' => return self.make_token(TokenKind::RightBrace),
+            '[' => return self.make_token(TokenKind::LeftBracket),
+            ']' => return self.make_token(TokenKind::RightBracket),
             ';' => return self.make_token(TokenKind::Semicolon),
             ',' => return self.make_token(TokenKind::Comma),
             '.' => return self.make_token(TokenKind::Dot),
-            '-' => return self.make_token(TokenKind::Minus),
+            '-' => {
+                let token = if self.check_next('>') {
+                    TokenKind::Arrow
+                } else {
+                    TokenKind::Minus
+                };
+                return self.make_token(token);
+            }
             '+' => return self.make_token(TokenKind::Plus),
             '/' => return self.make_token(TokenKind::Slash),
             '*' => return self.make_token(TokenKind::Star),
             ':' => return self.make_token(TokenKind::Colon),
             '%' => return self.make_token(TokenKind::Percent),
+            '?' => return self.make_token(TokenKind::Question),
             '!' => {
                 let token = if self.check_next('=') {
                     TokenKind::BangEqual
@@ -69,6 +76,8 @@ impl Scanner {
             '=' => {
                 let token = if self.check_next('=') {
                     TokenKind::EqualEqual
+                } else if self.check_next('>') {
+                    TokenKind::FatArrow
                 } else {
                     TokenKind::Equal
                 };
@@ -94,17 +103,17 @@ impl Scanner {
             _ => {}
         }
 
-        return self.error_token("Unexpected character");
+        self.error_token("Unexpected character")
     }
 
     fn identifier(&mut self) -> Token {
         while self
             .peek()
-            .is_some_and(|c| c.is_ascii_alphabetic() || c.is_digit(10))
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
         {
             self.advance();
         }
-        return self.make_token(self.identifier_kind());
+        self.make_token(self.identifier_kind())
     }
 
     fn identifier_list(&self) -> Vec<(String, TokenKind)> {
@@ -121,13 +130,21 @@ impl Scanner {
             ("print".to_string(), TokenKind::Print),
             ("return".to_string(), TokenKind::Return),
             ("int".to_string(), TokenKind::Int),
+            ("float".to_string(), TokenKind::Float),
             ("str".to_string(), TokenKind::Str),
             ("bool".to_string(), TokenKind::Bool),
             ("while".to_string(), TokenKind::While),
+            ("break".to_string(), TokenKind::Break),
+            ("continue".to_string(), TokenKind::Continue),
             ("false".to_string(), TokenKind::False),
             ("for".to_string(), TokenKind::For),
             ("fun".to_string(), TokenKind::Fun),
             ("in".to_string(), TokenKind::In),
+            ("is".to_string(), TokenKind::Is),
+            ("enum".to_string(), TokenKind::Enum),
+            ("match".to_string(), TokenKind::Match),
+            ("import".to_string(), TokenKind::Import),
+            ("pub".to_string(), TokenKind::Pub),
             ("new".to_string(), TokenKind::New),
         ]
     }
@@ -135,7 +152,7 @@ impl Scanner {
     fn identifier_kind(&self) -> TokenKind {
         for (s, kind) in self.identifier_list() {
             if self.start + s.len() < self.source.len()
-                && &self.source[self.start..self.current] == &s
+                && self.source[self.start..self.current] == s
             {
                 return kind;
             }
@@ -144,25 +161,54 @@ impl Scanner {
     }
 
     fn number(&mut self) -> Token {
-        while self.peek().is_some() && self.peek().unwrap().is_digit(10) {
+        self.digits();
+        let mut kind = TokenKind::Number;
+        if self.peek() == Some('.') && self.peek_next().is_some_and(|c| c.is_ascii_digit()) {
+            kind = TokenKind::FloatNumber;
+            self.advance();
+            self.digits();
+        }
+        if let Some('e' | 'E') = self.peek() {
+            let sign = matches!(self.peek_next(), Some('+' | '-'));
+            let exponent_start = if sign { 2 } else { 1 };
+            if self
+                .source[self.current..]
+                .chars()
+                .nth(exponent_start)
+                .is_some_and(|c| c.is_ascii_digit())
+            {
+                kind = TokenKind::FloatNumber;
+                for _ in 0..exponent_start {
+                    self.advance();
+                }
+                self.digits();
+            }
+        }
+        self.make_token(kind)
+    }
+
+    fn digits(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
             self.advance();
         }
-        return self.make_token(TokenKind::Number);
     }
 
     fn string(&mut self) -> Token {
-        while self.peek().is_some() && self.peek().unwrap() != '"' && !self.is_at_end() {
-            if self.peek().unwrap() == '\n' {
-                self.line += 1;
-                self.advance();
+        while let Some(c) = self.peek() {
+            if c == '"' {
+                break;
             }
             self.advance();
+            if c == '\n' {
+                self.line += 1;
+                self.column = 0;
+            }
         }
         if self.is_at_end() {
-            return self.error_token("unterminated string");
+            return self.error_token("Unterminated string");
         }
         self.advance();
-        return self.make_token(TokenKind::String);
+        self.make_token(TokenKind::String)
     }
 
     fn skip_whitespace(&mut self) {
@@ -172,9 +218,9 @@ impl Scanner {
                 match c {
                     ' ' | '\r' | '\t' => _ = self.advance(),
                     '\n' => {
+                        self.advance();
                         self.line += 1;
                         self.column = 0;
-                        self.advance();
                     }
                     '/' => {
                         if self.peek_next().is_some() && self.peek_next().unwrap() == '/' {
@@ -200,28 +246,27 @@ impl Scanner {
         if self.is_at_end() {
             return None;
         }
-        return self.source.chars().nth(self.current + 1);
+        self.source[self.current..].chars().nth(1)
     }
 
     fn peek(&self) -> Option<char> {
-        return self.source.chars().nth(self.current);
+        self.source[self.current..].chars().next()
     }
 
     fn check_next(&mut self, c: char) -> bool {
-        if self.is_at_end() {
-            return false;
-        }
-        if self.source.chars().nth(self.current).unwrap() != c {
+        if self.peek() != Some(c) {
             return false;
         }
-        self.current += 1;
+        self.current += c.len_utf8();
         true
     }
 
+    /// Moves past the next character. Positions are byte offsets into the source.
     fn advance(&mut self) -> char {
+        let c = self.peek().unwrap();
         self.column += 1;
-        self.current += 1;
-        self.source.chars().nth(self.current - 1).unwrap()
+        self.current += c.len_utf8();
+        c
     }
 
     fn make_token(&self, kind: TokenKind) -> Token {
@@ -231,23 +276,36 @@ impl Scanner {
                 line: self.line,
                 column: self.column,
                 value: self.source[(self.start + 1)..(self.current - 1)].to_string(),
+                span: self.span(),
             },
             _ => Token {
                 kind,
                 line: self.line,
                 column: self.column,
                 value: self.source[self.start..self.current].to_string(),
+                span: self.span(),
             },
         }
     }
 
-    // TODO: remove?
     fn error_token(&self, message: &str) -> Token {
         Token {
             kind: TokenKind::Error,
             line: self.line,
             column: self.column,
             value: message.to_string(),
+            span: self.span(),
+        }
+    }
+
+    /// The span of the token being scanned. A string spanning several lines starts on
+    /// the line before the current one.
+    fn span(&self) -> Span {
+        let newlines = self.source[self.start..self.current].matches('\n').count();
+        Span {
+            start: self.start,
+            end: self.current,
+            line: self.line - newlines,
         }
     }
 
@@ -262,6 +320,28 @@ pub struct Token {
     pub line: usize,
     pub column: usize,
     pub value: String,
+    pub span: Span,
+}
+
+/// A range of a source file, in bytes from the start of the file, together with the
+/// line where it starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    /// One past the last byte.
+    pub end: usize,
+    pub line: usize,
+}
+
+impl Span {
+    /// The span from the start of `self` to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end.max(self.end),
+            line: self.line,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -295,10 +375,34 @@ mod tests {
             TokenKind::Semicolon,
             TokenKind::Eof,
         ];
-        let mut i = 0;
-        for token in &tokens {
+        for (i, token) in tokens.iter().enumerate() {
             assert_eq!(token.kind, temp[i]);
-            i += 1;
         }
     }
+
+    #[test]
+    fn numbers() {
+        let source = "1 1.5 2e10 3E-2 4.0e+3 5.len 6e";
+        let tokens = Scanner::get_tokens(source.to_string());
+        let kinds: Vec<(TokenKind, &str)> = tokens
+            .iter()
+            .map(|token| (token.kind, token.value.as_str()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (TokenKind::Number, "1"),
+                (TokenKind::FloatNumber, "1.5"),
+                (TokenKind::FloatNumber, "2e10"),
+                (TokenKind::FloatNumber, "3E-2"),
+                (TokenKind::FloatNumber, "4.0e+3"),
+                (TokenKind::Number, "5"),
+                (TokenKind::Dot, "."),
+                (TokenKind::Identifier, "len"),
+                (TokenKind::Number, "6"),
+                (TokenKind::Identifier, "e"),
+                (TokenKind::Eof, ""),
+            ]
+        );
+    }
 }
@@ -2,400 +2,1571 @@ use std::fmt::Display;
 use std::io::Write;
 
 use crate::compiler::Chunk;
+use crate::enums::{RuntimeError, RuntimeErrorKind, StackFrame};
+use crate::heap::{GcConfig, Heap, MapKey, Object, RuntimeClosure, RuntimeInstance, RuntimeMap};
+use crate::host::{ConversionError, HostInstance, HostKind, HostValue, Outcome};
+use crate::native::{self, NativeFunction};
 use crate::opcode::OpCode;
 
-// rust analyzer - cant dervie debug from unions
-union StackValue {
-    b: bool,
-    i: i64,
-    u: u8,
+type Result<T> = std::result::Result<T, RuntimeError>;
+
+/// A tagged runtime value. Strings, instances, arrays, maps and functions are handles
+/// into the garbage collected heap of the vm.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    String(usize),
+    Instance(usize),
+    Array(usize),
+    Map(usize),
+    Function(usize),
+    Nil,
+    /// A global whose declaration has not run yet.
+    Uninitialized,
+}
+
+impl Value {
+    fn kind_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Bool(_) => "bool",
+            Value::String(_) => "str",
+            Value::Instance(_) => "instance",
+            Value::Array(_) => "array",
+            Value::Map(_) => "map",
+            Value::Function(_) => "function",
+            Value::Nil => "nil",
+            Value::Uninitialized => "uninitialized",
+        }
+    }
 }
 
-impl Display for StackValue {
+impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "i: {}, u: {}, bool: {}",
-            unsafe { self.i },
-            unsafe { self.u },
-            unsafe { self.b }
-        )
+        match self {
+            Value::Int(i) => write!(f, "int: {}", i),
+            Value::Float(x) => write!(f, "float: {}", float_to_string(*x)),
+            Value::Bool(b) => write!(f, "bool: {}", b),
+            Value::String(s) => write!(f, "str: #{}", s),
+            Value::Instance(i) => write!(f, "instance: #{}", i),
+            Value::Array(a) => write!(f, "array: #{}", a),
+            Value::Map(m) => write!(f, "map: #{}", m),
+            Value::Function(c) => write!(f, "function: #{}", c),
+            Value::Nil => write!(f, "nil"),
+            Value::Uninitialized => write!(f, "uninitialized"),
+        }
     }
 }
 
-struct Vm {
-    stack: Vec<StackValue>,
-    // TODO: (usize, usize) ? with the second value being the row
+struct Vm<'a> {
+    chunk: Chunk,
+    /// The functions registered by the host, by the index `CallNative` uses.
+    natives: &'a [NativeFunction],
+    stack: Vec<Value>,
+    // (return ip, return function)
     call_stack: Vec<(usize, usize)>,
     stack_offset: usize,
     offsets: Vec<usize>,
-    instances: Vec<RuntimeInstance>,
+    heap: Heap,
+    /// Heap handles of the string constants in the chunk. These are always live.
+    constants: Vec<Value>,
+    /// Index of the function currently executing.
+    function: usize,
+    ip: usize,
+    /// Position and opcode of the instruction currently executing, used for error reporting.
+    op_ip: usize,
+    opcode: OpCode,
+    /// Instances, arrays and maps that are being rendered, innermost last. A value
+    /// that contains itself renders as `...` the second time around.
+    rendering: Vec<usize>,
+    /// The values passed in by the host, by the index `GetInput` uses. These are
+    /// always live.
+    inputs: Vec<Value>,
+    /// The value returned by the top level code of the compiled source.
+    result: Option<Value>,
 }
 
-struct RuntimeInstance {
-    // class: u8,
-    values: Vec<StackValue>,
+/// Floats always print with a fractional part or an exponent, so `2.0` is not
+/// mistaken for an int.
+fn float_to_string(f: f64) -> String {
+    format!("{:?}", f)
 }
 
-impl Display for RuntimeInstance {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Struct RuntimeInstance - values")?;
-        for v in &self.values {
-            write!(
-                f,
-                "i: {}, u: {}, bool: {}",
-                unsafe { v.i },
-                unsafe { v.u },
-                unsafe { v.b }
-            )?;
-        }
-        Ok(())
-    }
-}
-
-fn print_stack(stack: &Vec<StackValue>) {
+fn print_stack(stack: &[Value]) {
     println!("Stack:");
     for v in stack {
-        println!(
-            "i: {}, u: {}, bool: {}",
-            unsafe { v.i },
-            unsafe { v.u },
-            unsafe { v.b }
-        );
+        println!("{}", v);
     }
 }
 
-pub fn start(chunk: Chunk, out: &mut impl Write) {
+#[cfg(test)]
+pub fn start(chunk: Chunk, out: &mut impl Write) -> Result<()> {
+    start_with_config(chunk, out, GcConfig::default(), &[])?;
+    Ok(())
+}
+
+/// Runs `chunk` with a custom garbage collector configuration and the native
+/// functions it was compiled against, and returns the collection statistics of the
+/// run.
+#[cfg(test)]
+pub fn start_with_config(
+    chunk: Chunk,
+    out: &mut impl Write,
+    config: GcConfig,
+    natives: &[NativeFunction],
+) -> Result<crate::heap::GcStats> {
+    Ok(execute(chunk, out, config, natives, &[])?.gc_stats)
+}
+
+/// Runs `chunk` with the values of the inputs it was compiled against, and reads
+/// back its return value and globals.
+pub fn execute(
+    chunk: Chunk,
+    out: &mut impl Write,
+    config: GcConfig,
+    natives: &[NativeFunction],
+    inputs: &[(String, HostValue)],
+) -> Result<Outcome> {
+    let mut heap = Heap::new(config);
+    let constants = chunk
+        .strings
+        .iter()
+        .map(|s| Value::String(heap.alloc(Object::String(s.to_string()))))
+        .collect();
     let mut vm = Vm {
+        chunk,
+        natives,
         stack: vec![],
         call_stack: vec![],
         stack_offset: 0,
         offsets: vec![0],
-        instances: vec![],
+        heap,
+        constants,
+        function: 0,
+        ip: 0,
+        op_ip: 0,
+        opcode: OpCode::Return,
+        rendering: vec![],
+        inputs: vec![],
+        result: None,
     };
-    vm.interpret(chunk, out);
+    vm.pass_inputs(inputs)?;
+    vm.interpret(out)?;
+    Ok(vm.outcome())
 }
 
-impl Vm {
-    pub fn interpret(&mut self, mut chunk: Chunk, out: &mut impl Write) {
-        let debug_loggin = false;
+/// Type verification of stores. A compiler bug that changes the type stored in a
+/// local or a field is reported as an error instead of being silently accepted.
+const VERIFY_STORES: bool = cfg!(debug_assertions);
 
-        let mut ip: usize = 0;
-        let mut curr_code = &chunk.code[0];
+impl Vm<'_> {
+    pub fn interpret(&mut self, out: &mut impl Write) -> Result<()> {
+        self.run(out, 0)
+    }
 
-        while ip < curr_code.len() {
-            let curr_instruction: OpCode = unsafe { std::mem::transmute(curr_code[ip]) };
+    /// Executes instructions until the program ends, or until a function returns to
+    /// a call stack shallower than `return_depth`.
+    fn run(&mut self, out: &mut impl Write, return_depth: usize) -> Result<()> {
+        let debug_loggin = false;
+
+        while self.ip < self.chunk.code[self.function].len() {
+            self.op_ip = self.ip;
+            let byte = self.read_byte()?;
+            self.opcode = match OpCode::try_from(byte) {
+                Ok(opcode) => opcode,
+                Err(byte) => return Err(self.error(RuntimeErrorKind::UnknownOpcode(byte))),
+            };
 
             if debug_loggin {
                 println!("===============================");
-                println!("curr: '{:?}'", curr_instruction);
-                for b in curr_code {
+                println!("curr: '{:?}'", self.opcode);
+                for b in &self.chunk.code[self.function] {
                     print!("{:02x?} ", b);
                 }
                 println!();
-                println!("{:indent$}{}", "", "|", indent = ip * 3);
-                println!("{:indent$}{}", "", "|", indent = ip * 3);
-                println!(
-                    "{:indent$}{} {:?}",
-                    "",
-                    "|",
-                    curr_instruction,
-                    indent = ip * 3
-                );
+                println!("{:indent$}|", "", indent = self.op_ip * 3);
+                println!("{:indent$}|", "", indent = self.op_ip * 3);
+                println!("{:indent$}| {:?}", "", self.opcode, indent = self.op_ip * 3);
                 print_stack(&self.stack);
                 println!("===============================");
                 println!();
             }
 
-            match curr_instruction {
+            match self.opcode {
                 OpCode::Print => {
-                    let val = self.stack.pop().unwrap();
-                    writeln!(out, "{}", chunk.strings[unsafe { val.u } as usize])
-                        .expect("Unable to write to output");
+                    // the value stays on the stack while it is rendered
+                    let val = self.peek(0)?;
+                    let s = self.render(val, false, out)?;
+                    self.pop()?;
+                    if let Err(e) = writeln!(out, "{}", s) {
+                        return Err(self.error(RuntimeErrorKind::Output(e.kind())));
+                    }
                 }
                 OpCode::String => {
-                    ip += 1;
-                    self.stack.push(StackValue { u: curr_code[ip] });
+                    let idx = self.read_u16()?;
+                    match self.constants.get(idx) {
+                        Some(val) => self.stack.push(*val),
+                        None => return Err(self.error(RuntimeErrorKind::InvalidConstant(idx))),
+                    }
                 }
                 OpCode::Modulo => {
-                    let num1 = unsafe { self.stack.pop().unwrap().i };
-                    let num2 = unsafe { self.stack.pop().unwrap().i };
-                    self.stack.push(StackValue { i: num2 % num1 });
+                    let num1 = self.pop_int()?;
+                    let num2 = self.pop_int()?;
+                    if num1 == 0 {
+                        return Err(self.error(RuntimeErrorKind::DivisionByZero));
+                    }
+                    self.push_int(num2.checked_rem(num1))?;
                 }
                 OpCode::Add => {
-                    let num1 = unsafe { self.stack.pop().unwrap().i };
-                    let num2 = unsafe { self.stack.pop().unwrap().i };
-                    self.stack.push(StackValue { i: num2 + num1 });
+                    let num1 = self.pop_int()?;
+                    let num2 = self.pop_int()?;
+                    self.push_int(num2.checked_add(num1))?;
                 }
                 OpCode::Subtract => {
-                    let num1 = unsafe { self.stack.pop().unwrap().i };
-                    let num2 = unsafe { self.stack.pop().unwrap().i };
-                    self.stack.push(StackValue { i: num2 - num1 });
+                    let num1 = self.pop_int()?;
+                    let num2 = self.pop_int()?;
+                    self.push_int(num2.checked_sub(num1))?;
                 }
                 OpCode::Divide => {
-                    let num1 = unsafe { self.stack.pop().unwrap().i };
-                    let num2 = unsafe { self.stack.pop().unwrap().i };
-                    self.stack.push(StackValue { i: num2 / num1 });
+                    let num1 = self.pop_int()?;
+                    let num2 = self.pop_int()?;
+                    if num1 == 0 {
+                        return Err(self.error(RuntimeErrorKind::DivisionByZero));
+                    }
+                    self.push_int(num2.checked_div(num1))?;
                 }
                 OpCode::Multiply => {
-                    let num1 = unsafe { self.stack.pop().unwrap().i };
-                    let num2 = unsafe { self.stack.pop().unwrap().i };
-                    self.stack.push(StackValue { i: num2 * num1 });
+                    let num1 = self.pop_int()?;
+                    let num2 = self.pop_int()?;
+                    self.push_int(num2.checked_mul(num1))?;
                 }
                 OpCode::Negate => {
-                    let num = unsafe { self.stack.pop().unwrap().i };
-                    self.stack.push(StackValue { i: -num });
+                    let num = self.pop_int()?;
+                    self.push_int(num.checked_neg())?;
                 }
                 OpCode::Not => {
-                    let bool = unsafe { self.stack.pop().unwrap().b };
-                    self.stack.push(StackValue { b: !bool });
+                    let bool = self.pop_bool()?;
+                    self.stack.push(Value::Bool(!bool));
                 }
                 OpCode::StringStringConcat => {
-                    let s1 = &chunk.strings[unsafe { self.stack.pop().unwrap().u } as usize];
-                    let s2 = &chunk.strings[unsafe { self.stack.pop().unwrap().u } as usize];
-                    let ptr = chunk.strings.len() as u8;
-                    chunk.strings.push(s2.to_string() + s1);
-                    self.stack.push(StackValue { u: ptr });
+                    let s1 = self.pop_string()?;
+                    let s2 = self.pop_string()?;
+                    let s = self.string(s2)?.to_string() + self.string(s1)?;
+                    self.push_string(s);
                 }
                 OpCode::BoolStringConcat => {
-                    let s1 = &chunk.strings[unsafe { self.stack.pop().unwrap().u } as usize];
-                    let s2 = match unsafe { self.stack.pop().unwrap().b } == true {
-                        true => "true",
-                        false => "false",
-                    };
-
-                    let ptr = chunk.strings.len() as u8;
-                    chunk.strings.push(s2.to_string() + &s1);
-                    self.stack.push(StackValue { u: ptr });
+                    let s1 = self.pop_string()?;
+                    let s2 = self.pop_bool()?;
+                    let s = s2.to_string() + self.string(s1)?;
+                    self.push_string(s);
                 }
                 OpCode::StringBoolConcat => {
-                    // TODO: use match
-                    let s1 = if unsafe { self.stack.pop().unwrap().b } == true {
-                        "true"
-                    } else {
-                        "false"
-                    };
-                    let s2 = &chunk.strings[unsafe { self.stack.pop().unwrap().u } as usize];
-
-                    let ptr = chunk.strings.len() as u8;
-                    chunk.strings.push(s2.to_string() + &s1);
-                    self.stack.push(StackValue { u: ptr });
+                    let s1 = self.pop_bool()?;
+                    let s2 = self.pop_string()?;
+                    let s = self.string(s2)?.to_string() + &s1.to_string();
+                    self.push_string(s);
                 }
                 OpCode::IntStringConcat => {
-                    let s1 = &chunk.strings[unsafe { self.stack.pop().unwrap().u } as usize];
-                    let s2 = unsafe { self.stack.pop().unwrap().i };
-
-                    let ptr = chunk.strings.len() as u8;
-                    chunk.strings.push(s2.to_string() + &s1);
-                    self.stack.push(StackValue { u: ptr });
+                    let s1 = self.pop_string()?;
+                    let s2 = self.pop_int()?;
+                    let s = s2.to_string() + self.string(s1)?;
+                    self.push_string(s);
                 }
                 OpCode::StringIntConcat => {
-                    let s1 = unsafe { self.stack.pop().unwrap().i.to_string() };
-                    let s2 = &chunk.strings[unsafe { self.stack.pop().unwrap().u } as usize];
-
-                    let ptr = chunk.strings.len() as u8;
-                    chunk.strings.push(s2.to_string() + &s1);
-                    self.stack.push(StackValue { u: ptr });
+                    let s1 = self.pop_int()?;
+                    let s2 = self.pop_string()?;
+                    let s = self.string(s2)?.to_string() + &s1.to_string();
+                    self.push_string(s);
                 }
                 OpCode::Int => {
-                    ip += 1;
-                    self.stack.push(StackValue {
-                        i: chunk.ints[curr_code[ip] as usize],
-                    });
+                    let idx = self.read_u16()?;
+                    match self.chunk.ints.get(idx) {
+                        Some(&int) => self.stack.push(Value::Int(int)),
+                        None => return Err(self.error(RuntimeErrorKind::InvalidConstant(idx))),
+                    }
                 }
                 OpCode::GetLocal => {
-                    ip += 1;
-                    self.stack.push(StackValue {
-                        i: unsafe { self.stack[(curr_code[ip] as usize) + self.stack_offset].i },
-                    })
+                    let slot = self.read_u16()? + self.stack_offset;
+                    let val = self.local(slot)?;
+                    self.stack.push(val);
                 }
                 OpCode::SetLocal => {
-                    ip += 1;
-                    let slot = curr_code[ip] as usize;
-                    unsafe { self.stack[slot].i = self.stack.pop().unwrap().i };
+                    let slot = self.read_u16()? + self.stack_offset;
+                    let val = self.pop()?;
+                    self.verify_store(self.local(slot)?, val)?;
+                    self.stack[slot] = val;
+                }
+                OpCode::GetGlobal => {
+                    let slot = self.read_u16()?;
+                    let val = self.global(slot)?;
+                    self.stack.push(val);
+                }
+                OpCode::SetGlobal => {
+                    let slot = self.read_u16()?;
+                    let val = self.pop()?;
+                    self.verify_store(self.global(slot)?, val)?;
+                    self.stack[slot] = val;
+                }
+                OpCode::ReserveGlobals => {
+                    let count = self.read_u16()?;
+                    self.stack
+                        .resize(self.stack.len() + count, Value::Uninitialized);
                 }
                 OpCode::True => {
-                    self.stack.push(StackValue { b: true });
+                    self.stack.push(Value::Bool(true));
                 }
                 OpCode::False => {
-                    self.stack.push(StackValue { b: false });
+                    self.stack.push(Value::Bool(false));
                 }
-                OpCode::And => {
-                    let v1 = unsafe { self.stack.pop().unwrap().b };
-                    let v2 = unsafe { self.stack.pop().unwrap().b };
-                    self.stack.push(StackValue { b: v1 && v2 })
-                }
-                OpCode::Or => {
-                    let v1 = unsafe { self.stack.pop().unwrap().b };
-                    let v2 = unsafe { self.stack.pop().unwrap().b };
-                    self.stack.push(StackValue { b: v1 || v2 })
+                OpCode::Nil => {
+                    self.stack.push(Value::Nil);
                 }
                 OpCode::CompareInt => {
-                    let v1 = unsafe { self.stack.pop().unwrap().i };
-                    let v2 = unsafe { self.stack.pop().unwrap().i };
-                    self.stack.push(StackValue { b: v1 == v2 });
+                    let v1 = self.pop_int()?;
+                    let v2 = self.pop_int()?;
+                    self.stack.push(Value::Bool(v1 == v2));
                 }
                 OpCode::CompareIntNot => {
-                    let v1 = unsafe { self.stack.pop().unwrap().i };
-                    let v2 = unsafe { self.stack.pop().unwrap().i };
-                    self.stack.push(StackValue { b: v1 != v2 })
+                    let v1 = self.pop_int()?;
+                    let v2 = self.pop_int()?;
+                    self.stack.push(Value::Bool(v1 != v2))
                 }
                 OpCode::CompareString => {
-                    let v1 = unsafe { self.stack.pop().unwrap().u };
-                    let v2 = unsafe { self.stack.pop().unwrap().u };
-                    self.stack.push(StackValue {
-                        b: &chunk.strings[v1 as usize] == &chunk.strings[v2 as usize],
-                    })
+                    let v1 = self.pop_string()?;
+                    let v2 = self.pop_string()?;
+                    let equal = self.string(v1)? == self.string(v2)?;
+                    self.stack.push(Value::Bool(equal))
                 }
                 OpCode::CompareStringNot => {
-                    let v1 = unsafe { self.stack.pop().unwrap().u };
-                    let v2 = unsafe { self.stack.pop().unwrap().u };
-                    self.stack.push(StackValue {
-                        b: &chunk.strings[v1 as usize] != &chunk.strings[v2 as usize],
-                    })
+                    let v1 = self.pop_string()?;
+                    let v2 = self.pop_string()?;
+                    let equal = self.string(v1)? == self.string(v2)?;
+                    self.stack.push(Value::Bool(!equal))
                 }
                 OpCode::CompareBool => {
-                    let v1 = unsafe { self.stack.pop().unwrap().b };
-                    let v2 = unsafe { self.stack.pop().unwrap().b };
-                    self.stack.push(StackValue { b: v1 == v2 })
+                    let v1 = self.pop_bool()?;
+                    let v2 = self.pop_bool()?;
+                    self.stack.push(Value::Bool(v1 == v2))
                 }
                 OpCode::CompareBoolNot => {
-                    let v1 = unsafe { self.stack.pop().unwrap().b };
-                    let v2 = unsafe { self.stack.pop().unwrap().b };
-                    self.stack.push(StackValue { b: v1 != v2 })
+                    let v1 = self.pop_bool()?;
+                    let v2 = self.pop_bool()?;
+                    self.stack.push(Value::Bool(v1 != v2))
                 }
                 OpCode::Less => {
-                    let v1 = unsafe { self.stack.pop().unwrap().i };
-                    let v2 = unsafe { self.stack.pop().unwrap().i };
-                    self.stack.push(StackValue { b: v2 < v1 })
+                    let v1 = self.pop_int()?;
+                    let v2 = self.pop_int()?;
+                    self.stack.push(Value::Bool(v2 < v1))
                 }
                 OpCode::LessEqual => {
-                    let v1 = unsafe { self.stack.pop().unwrap().i };
-                    let v2 = unsafe { self.stack.pop().unwrap().i };
-                    self.stack.push(StackValue { b: v2 <= v1 })
+                    let v1 = self.pop_int()?;
+                    let v2 = self.pop_int()?;
+                    self.stack.push(Value::Bool(v2 <= v1))
                 }
                 OpCode::Greater => {
-                    let v1 = unsafe { self.stack.pop().unwrap().i };
-                    let v2 = unsafe { self.stack.pop().unwrap().i };
-                    self.stack.push(StackValue { b: v2 > v1 })
+                    let v1 = self.pop_int()?;
+                    let v2 = self.pop_int()?;
+                    self.stack.push(Value::Bool(v2 > v1))
                 }
                 OpCode::GreaterEqual => {
-                    let v1 = unsafe { self.stack.pop().unwrap().i };
-                    let v2 = unsafe { self.stack.pop().unwrap().i };
-                    self.stack.push(StackValue { b: v2 >= v1 })
-                }
-                OpCode::SetJump => {
-                    ip += 1;
-                    self.stack.push(StackValue { u: curr_code[ip] });
+                    let v1 = self.pop_int()?;
+                    let v2 = self.pop_int()?;
+                    self.stack.push(Value::Bool(v2 >= v1))
                 }
                 OpCode::JumpIfFalse => {
-                    let jump_distance = unsafe { self.stack.pop().unwrap().u };
-
-                    let bool = unsafe { self.stack.pop().unwrap().b };
+                    let jump_distance = self.read_u16()?;
+                    let bool = self.pop_bool()?;
                     if !bool {
-                        ip += jump_distance as usize;
+                        self.ip += jump_distance;
                     }
                 }
+                OpCode::JumpForward => {
+                    let jump_distance = self.read_u16()?;
+                    self.ip += jump_distance;
+                }
                 OpCode::JumpBack => {
-                    let jump_distance = unsafe { self.stack.pop().unwrap().u };
-                    ip -= jump_distance as usize;
+                    let jump_distance = self.read_u16()?;
+                    self.ip = match self.ip.checked_sub(jump_distance) {
+                        Some(ip) => ip,
+                        None => return Err(self.error(RuntimeErrorKind::InvalidJump)),
+                    };
                 }
                 OpCode::FunctionCall => {
-                    ip += 1;
-                    let func_idx = curr_code[ip] as usize;
-                    let return_func = match self.call_stack.last() {
-                        Some(_) => func_idx,
-                        None => 0,
+                    let func_idx = self.read_u16()?;
+                    if func_idx >= self.chunk.code.len() {
+                        return Err(self.error(RuntimeErrorKind::UnknownFunction(func_idx)));
+                    }
+                    self.call_stack.push((self.ip, self.function));
+                    self.function = func_idx;
+                    self.ip = 0;
+                }
+                OpCode::Closure => {
+                    let function = self.read_u16()?;
+                    let capture_count = self.read_u16()?;
+                    let Some(start) = self.stack.len().checked_sub(capture_count) else {
+                        return Err(self.error(RuntimeErrorKind::StackUnderflow));
                     };
-                    self.call_stack.push((ip + 1, return_func));
-                    curr_code = &chunk.code[func_idx];
-                    ip = 0;
-                    continue;
+                    let captures = self.stack[start..].to_vec();
+                    let idx = self.alloc(Object::Closure(RuntimeClosure { function, captures }));
+                    self.stack.truncate(start);
+                    self.stack.push(Value::Function(idx));
+                }
+                OpCode::CallValue => {
+                    // the function value is below the arguments, and is replaced by its
+                    // captures after them
+                    let arg_count = self.read_u16()?;
+                    let Some(slot) = self.stack.len().checked_sub(arg_count + 1) else {
+                        return Err(self.error(RuntimeErrorKind::StackUnderflow));
+                    };
+                    let closure_idx = match self.stack[slot] {
+                        Value::Function(idx) => idx,
+                        val => return Err(self.type_mismatch("function", val)),
+                    };
+                    let (function, captures) = self.closure(closure_idx)?;
+                    if function >= self.chunk.code.len() {
+                        return Err(self.error(RuntimeErrorKind::UnknownFunction(function)));
+                    }
+                    self.stack.remove(slot);
+                    self.stack.extend(captures);
+                    self.stack_offset = slot;
+                    self.offsets.push(self.stack_offset);
+                    self.call_stack.push((self.ip, self.function));
+                    self.function = function;
+                    self.ip = 0;
+                }
+                OpCode::Duplicate => {
+                    let val = self.pop()?;
+                    self.stack.push(val);
+                    self.stack.push(val);
                 }
                 OpCode::PopStack => {
-                    self.stack.pop();
+                    self.pop()?;
                 }
                 OpCode::SetOffset => {
-                    ip += 1;
-                    let vars_in_current_scope = curr_code[ip];
-                    self.stack_offset = self.stack.len() - vars_in_current_scope as usize;
+                    let vars_in_current_scope = self.read_u16()?;
+                    self.stack_offset = match self.stack.len().checked_sub(vars_in_current_scope) {
+                        Some(offset) => offset,
+                        None => return Err(self.error(RuntimeErrorKind::StackUnderflow)),
+                    };
                     self.offsets.push(self.stack_offset);
                 }
                 OpCode::PopOffset => {
                     self.offsets.pop();
-                    self.stack_offset = self.offsets.last().unwrap().clone();
+                    self.stack_offset = match self.offsets.last() {
+                        Some(offset) => *offset,
+                        None => return Err(self.error(RuntimeErrorKind::StackUnderflow)),
+                    };
                 }
                 OpCode::Return => {
-                    let call_frame = self.call_stack.pop().unwrap();
-                    for _ in 0..curr_code[ip + 1] {
-                        self.stack.pop();
+                    let local_count = self.read_u16()?;
+                    // returning from the top level ends the program, with the globals
+                    // left to read back
+                    if self.call_stack.is_empty() {
+                        return Ok(());
+                    }
+                    for _ in 0..local_count {
+                        self.pop()?;
+                    }
+                    let Some(call_frame) = self.call_stack.pop() else {
+                        return Ok(());
+                    };
+                    self.ip = call_frame.0;
+                    self.function = call_frame.1;
+                    if self.call_stack.len() < return_depth {
+                        return Ok(());
                     }
-                    curr_code = &chunk.code[call_frame.1];
-                    ip = call_frame.0;
-                    continue;
                 }
                 OpCode::ReturnValue => {
-                    let return_position = self.call_stack.pop().unwrap();
-                    let return_value = self.stack.pop().unwrap();
-                    for _ in 0..curr_code[ip + 1] {
-                        self.stack.pop();
+                    let local_count = self.read_u16()?;
+                    let return_value = self.pop()?;
+                    if self.call_stack.is_empty() {
+                        self.result = Some(return_value);
+                        return Ok(());
                     }
-                    curr_code = &chunk.code[return_position.1];
-                    ip = return_position.0;
+                    for _ in 0..local_count {
+                        self.pop()?;
+                    }
+                    let Some(call_frame) = self.call_stack.pop() else {
+                        return Ok(());
+                    };
+                    self.ip = call_frame.0;
+                    self.function = call_frame.1;
                     self.stack.push(return_value);
-
-                    continue;
+                    if self.call_stack.len() < return_depth {
+                        return Ok(());
+                    }
                 }
                 OpCode::CreateInstance => {
-                    let mut instance = RuntimeInstance { values: vec![] };
-                    ip += 1;
-                    let field_count = curr_code[ip];
-                    let mut temp: Vec<StackValue> = vec![];
-                    for _ in 0..field_count {
-                        let val = self.stack.pop().unwrap();
-                        temp.push(val);
-                    }
-                    while temp.len() > 0 {
-                        instance.values.push(temp.pop().unwrap());
+                    let class = self.read_u16()?;
+                    let field_count = match self.chunk.classes.get(class) {
+                        Some(info) => info.fields.len(),
+                        None => return Err(self.error(RuntimeErrorKind::InvalidClass(class))),
+                    };
+                    if field_count > self.stack.len() {
+                        return Err(self.error(RuntimeErrorKind::StackUnderflow));
                     }
-                    self.stack.push(StackValue {
-                        u: self.instances.len() as u8,
-                    });
-                    self.instances.push(instance);
+                    let values = self.stack[self.stack.len() - field_count..].to_vec();
+                    let idx = self.alloc(Object::Instance(RuntimeInstance { class, values }));
+                    self.stack.truncate(self.stack.len() - field_count);
+                    self.stack.push(Value::Instance(idx));
                 }
                 OpCode::GetField => {
-                    ip += 1;
-                    let instance_idx = unsafe { self.stack.pop().unwrap().u as usize };
-                    let field_idx = curr_code[ip] as usize;
-
-                    self.stack.push(StackValue {
-                        i: unsafe { self.instances[instance_idx].values[field_idx].i },
-                    });
+                    let field_idx = self.read_u16()?;
+                    let instance_idx = self.pop_instance()?;
+                    let val = *self.field(instance_idx, field_idx)?;
+                    self.stack.push(val);
                 }
                 OpCode::SetField => {
-                    let new_value = unsafe { self.stack.pop().unwrap().i };
-                    let mut instance_idx = unsafe { self.stack.pop().unwrap().u as usize };
-                    ip += 1;
-
-                    let field_levels = curr_code[ip] as usize;
-                    for i in 0..field_levels {
-                        ip += 1;
-                        let temp = curr_code[ip] as usize;
-                        if i == (field_levels - 1) {
-                            self.instances[instance_idx].values[temp] = StackValue { i: new_value };
-                        } else {
-                            // TODO: works with u - why?
-                            instance_idx =
-                                unsafe { self.instances[instance_idx].values[temp].u as usize };
+                    let field_idx = self.read_u16()?;
+                    let new_value = self.pop()?;
+                    let instance_idx = self.pop_instance()?;
+                    let field = *self.field(instance_idx, field_idx)?;
+                    self.verify_store(field, new_value)?;
+                    *self.field(instance_idx, field_idx)? = new_value;
+                }
+                OpCode::CompareValue | OpCode::CompareValueNot => {
+                    let v1 = self.pop()?;
+                    let v2 = self.pop()?;
+                    let equal = self.values_equal(v1, v2, &mut vec![])?;
+                    let not = self.opcode == OpCode::CompareValueNot;
+                    self.stack.push(Value::Bool(equal != not));
+                }
+                OpCode::CompareIdentity => {
+                    let v1 = self.pop()?;
+                    let v2 = self.pop()?;
+                    self.stack.push(Value::Bool(v1 == v2));
+                }
+                OpCode::InstanceOf => {
+                    let class = self.read_u16()?;
+                    let instance_idx = self.pop_instance()?;
+                    let is_class = self.class_of(instance_idx)? == class;
+                    self.stack.push(Value::Bool(is_class));
+                }
+                OpCode::StringValueConcat => {
+                    let val = self.peek(0)?;
+                    let s1 = self.render(val, false, out)?;
+                    self.pop()?;
+                    let s2 = self.pop_string()?;
+                    let s = self.string(s2)?.to_string() + &s1;
+                    self.push_string(s);
+                }
+                OpCode::ValueStringConcat => {
+                    let val = self.peek(1)?;
+                    let s2 = self.render(val, false, out)?;
+                    let s1 = self.pop_string()?;
+                    self.pop()?;
+                    let s = s2 + self.string(s1)?;
+                    self.push_string(s);
+                }
+                OpCode::CreateArray => {
+                    let element_count = self.read_u16()?;
+                    if element_count > self.stack.len() {
+                        return Err(self.error(RuntimeErrorKind::StackUnderflow));
+                    }
+                    let values = self.stack[self.stack.len() - element_count..].to_vec();
+                    let idx = self.alloc(Object::Array(values));
+                    self.stack.truncate(self.stack.len() - element_count);
+                    self.stack.push(Value::Array(idx));
+                }
+                OpCode::GetIndex => {
+                    let index = self.pop_int()?;
+                    let array_idx = self.pop_array()?;
+                    let val = *self.element(array_idx, index)?;
+                    self.stack.push(val);
+                }
+                OpCode::SetIndex => {
+                    let new_value = self.pop()?;
+                    let index = self.pop_int()?;
+                    let array_idx = self.pop_array()?;
+                    let element = *self.element(array_idx, index)?;
+                    self.verify_store(element, new_value)?;
+                    *self.element(array_idx, index)? = new_value;
+                }
+                OpCode::ArrayLen => {
+                    let array_idx = self.pop_array()?;
+                    let len = self.array(array_idx)?.len();
+                    self.stack.push(Value::Int(len as i64));
+                }
+                OpCode::ArrayPush => {
+                    let val = self.pop()?;
+                    let array_idx = self.pop_array()?;
+                    self.array(array_idx)?.push(val);
+                    self.heap.grow(array_idx);
+                }
+                OpCode::ArrayMap | OpCode::ArrayFilter => {
+                    let Value::Function(closure_idx) = self.peek(0)? else {
+                        return Err(self.type_mismatch("function", self.peek(0)?));
+                    };
+                    let array_idx = match self.peek(1)? {
+                        Value::Array(idx) => idx,
+                        val => return Err(self.type_mismatch("array", val)),
+                    };
+                    // the new elements are kept on the stack until the array is allocated
+                    let start = self.stack.len();
+                    let mut i = 0;
+                    while let Some(element) = self.array(array_idx)?.get(i).copied() {
+                        let result = self.call_closure(closure_idx, &[element], out)?;
+                        match (self.opcode, result) {
+                            (OpCode::ArrayMap, _) => self.stack.push(result),
+                            (_, Value::Bool(true)) => self.stack.push(element),
+                            (_, Value::Bool(false)) => {}
+                            (_, val) => return Err(self.type_mismatch("bool", val)),
+                        }
+                        i += 1;
+                    }
+                    let values = self.stack.split_off(start);
+                    self.stack.truncate(start - 2);
+                    let idx = self.alloc(Object::Array(values));
+                    self.stack.push(Value::Array(idx));
+                }
+                OpCode::ArraySort => {
+                    let Value::Function(closure_idx) = self.peek(0)? else {
+                        return Err(self.type_mismatch("function", self.peek(0)?));
+                    };
+                    let array_idx = match self.peek(1)? {
+                        Value::Array(idx) => idx,
+                        val => return Err(self.type_mismatch("array", val)),
+                    };
+                    // the comparison may change the array, so the elements are sorted
+                    // in a copy that is kept on the stack
+                    let values = self.array(array_idx)?.clone();
+                    let start = self.stack.len();
+                    self.stack.extend_from_slice(&values);
+                    let sorted = self.sort(values, closure_idx, out)?;
+                    *self.array(array_idx)? = sorted;
+                    self.stack.truncate(start - 2);
+                }
+                OpCode::ArrayPop => {
+                    let array_idx = self.pop_array()?;
+                    match self.array(array_idx)?.pop() {
+                        Some(val) => self.stack.push(val),
+                        None => return Err(self.error(RuntimeErrorKind::EmptyArray)),
+                    }
+                }
+                OpCode::CreateMap => {
+                    let entry_count = self.read_u16()?;
+                    if entry_count * 2 > self.stack.len() {
+                        return Err(self.error(RuntimeErrorKind::StackUnderflow));
+                    }
+                    let start = self.stack.len() - entry_count * 2;
+                    let mut map = RuntimeMap::default();
+                    for i in 0..entry_count {
+                        let (key, value) =
+                            (self.stack[start + i * 2], self.stack[start + i * 2 + 1]);
+                        map.insert(self.map_key(key)?, key, value);
+                    }
+                    let idx = self.alloc(Object::Map(map));
+                    self.stack.truncate(start);
+                    self.stack.push(Value::Map(idx));
+                }
+                OpCode::MapGet => {
+                    let key = self.pop()?;
+                    let map_idx = self.pop_map()?;
+                    let map_key = self.map_key(key)?;
+                    match self.map(map_idx)?.get(&map_key) {
+                        Some(val) => self.stack.push(val),
+                        None => {
+                            return Err(
+                                self.error(RuntimeErrorKind::MissingKey(map_key.to_string()))
+                            )
+                        }
+                    }
+                }
+                OpCode::MapSet => {
+                    let value = self.pop()?;
+                    let key = self.pop()?;
+                    let map_idx = self.pop_map()?;
+                    let map_key = self.map_key(key)?;
+                    if let Some(old) = self.map(map_idx)?.get(&map_key) {
+                        self.verify_store(old, value)?;
+                    }
+                    self.map(map_idx)?.insert(map_key, key, value);
+                    self.heap.grow(map_idx);
+                }
+                OpCode::MapHas => {
+                    let key = self.pop()?;
+                    let map_idx = self.pop_map()?;
+                    let map_key = self.map_key(key)?;
+                    let has = self.map(map_idx)?.has(&map_key);
+                    self.stack.push(Value::Bool(has));
+                }
+                OpCode::MapRemove => {
+                    let key = self.pop()?;
+                    let map_idx = self.pop_map()?;
+                    let map_key = self.map_key(key)?;
+                    match self.map(map_idx)?.remove(&map_key) {
+                        Some(val) => self.stack.push(val),
+                        None => {
+                            return Err(
+                                self.error(RuntimeErrorKind::MissingKey(map_key.to_string()))
+                            )
                         }
                     }
                 }
-                _ => panic!(
-                    "No implementation for instruction '{:#?}'",
-                    curr_instruction
+                OpCode::MapLen => {
+                    let map_idx = self.pop_map()?;
+                    let len = self.map(map_idx)?.len();
+                    self.stack.push(Value::Int(len as i64));
+                }
+                OpCode::MapKeys | OpCode::MapValues => {
+                    let map_idx = self.pop_map()?;
+                    let keys = self.opcode == OpCode::MapKeys;
+                    let values = self
+                        .map(map_idx)?
+                        .entries()
+                        .map(|(key, value)| if keys { key } else { value })
+                        .collect();
+                    // the map is no longer on the stack, but its entries are all in the new array
+                    let idx = self.alloc(Object::Array(values));
+                    self.stack.push(Value::Array(idx));
+                }
+                OpCode::Float => {
+                    let idx = self.read_u16()?;
+                    match self.chunk.floats.get(idx) {
+                        Some(&float) => self.stack.push(Value::Float(float)),
+                        None => return Err(self.error(RuntimeErrorKind::InvalidConstant(idx))),
+                    }
+                }
+                OpCode::AddFloat => {
+                    let num1 = self.pop_float()?;
+                    let num2 = self.pop_float()?;
+                    self.stack.push(Value::Float(num2 + num1));
+                }
+                OpCode::SubtractFloat => {
+                    let num1 = self.pop_float()?;
+                    let num2 = self.pop_float()?;
+                    self.stack.push(Value::Float(num2 - num1));
+                }
+                OpCode::MultiplyFloat => {
+                    let num1 = self.pop_float()?;
+                    let num2 = self.pop_float()?;
+                    self.stack.push(Value::Float(num2 * num1));
+                }
+                OpCode::DivideFloat => {
+                    let num1 = self.pop_float()?;
+                    let num2 = self.pop_float()?;
+                    self.stack.push(Value::Float(num2 / num1));
+                }
+                OpCode::ModuloFloat => {
+                    let num1 = self.pop_float()?;
+                    let num2 = self.pop_float()?;
+                    self.stack.push(Value::Float(num2 % num1));
+                }
+                OpCode::NegateFloat => {
+                    let num = self.pop_float()?;
+                    self.stack.push(Value::Float(-num));
+                }
+                OpCode::CompareFloat => {
+                    let v1 = self.pop_float()?;
+                    let v2 = self.pop_float()?;
+                    self.stack.push(Value::Bool(v1 == v2));
+                }
+                OpCode::CompareFloatNot => {
+                    let v1 = self.pop_float()?;
+                    let v2 = self.pop_float()?;
+                    self.stack.push(Value::Bool(v1 != v2));
+                }
+                OpCode::LessFloat => {
+                    let v1 = self.pop_float()?;
+                    let v2 = self.pop_float()?;
+                    self.stack.push(Value::Bool(v2 < v1))
+                }
+                OpCode::LessEqualFloat => {
+                    let v1 = self.pop_float()?;
+                    let v2 = self.pop_float()?;
+                    self.stack.push(Value::Bool(v2 <= v1))
+                }
+                OpCode::GreaterFloat => {
+                    let v1 = self.pop_float()?;
+                    let v2 = self.pop_float()?;
+                    self.stack.push(Value::Bool(v2 > v1))
+                }
+                OpCode::GreaterEqualFloat => {
+                    let v1 = self.pop_float()?;
+                    let v2 = self.pop_float()?;
+                    self.stack.push(Value::Bool(v2 >= v1))
+                }
+                OpCode::FloatStringConcat => {
+                    let s1 = self.pop_string()?;
+                    let s2 = self.pop_float()?;
+                    let s = float_to_string(s2) + self.string(s1)?;
+                    self.push_string(s);
+                }
+                OpCode::StringFloatConcat => {
+                    let s1 = self.pop_float()?;
+                    let s2 = self.pop_string()?;
+                    let s = self.string(s2)?.to_string() + &float_to_string(s1);
+                    self.push_string(s);
+                }
+                OpCode::IntToFloat => {
+                    let num = self.pop_int()?;
+                    self.stack.push(Value::Float(num as f64));
+                }
+                OpCode::FloatToInt => {
+                    // truncates towards zero, values that do not fit in an int are an error
+                    let num = self.pop_float()?.trunc();
+                    if !(num >= i64::MIN as f64 && num < i64::MAX as f64) {
+                        return Err(self.error(RuntimeErrorKind::IntegerOverflow));
+                    }
+                    self.stack.push(Value::Int(num as i64));
+                }
+                OpCode::GetInput => {
+                    let index = self.read_u16()?;
+                    match self.inputs.get(index) {
+                        Some(val) => self.stack.push(*val),
+                        None => return Err(self.error(RuntimeErrorKind::UnknownInput(index))),
+                    }
+                }
+                OpCode::CallNative => {
+                    let index = self.read_u16()?;
+                    let arg_count = self.read_u16()?;
+                    self.call_native(index, arg_count)?;
+                }
+                _ => return Err(self.error(RuntimeErrorKind::UnknownOpcode(byte))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Pops the arguments of a native function, runs it and pushes its result, if it
+    /// has one. Values cross over by copy, so the function never sees the heap.
+    fn call_native(&mut self, index: usize, arg_count: usize) -> Result<()> {
+        let natives = self.natives;
+        let Some(native) = natives.get(index) else {
+            return Err(self.error(RuntimeErrorKind::UnknownNative(index)));
+        };
+        if self.stack.len() < self.stack_offset + arg_count {
+            return Err(self.error(RuntimeErrorKind::StackUnderflow));
+        }
+        let values = self.stack.split_off(self.stack.len() - arg_count);
+        let mut args = Vec::with_capacity(arg_count);
+        for value in values {
+            args.push(match value {
+                Value::Int(i) => HostValue::Int(i),
+                Value::Float(x) => HostValue::Float(x),
+                Value::Bool(b) => HostValue::Bool(b),
+                Value::String(idx) => HostValue::Str(self.string(idx)?.to_string()),
+                value => return Err(self.type_mismatch("int, float, bool or str", value)),
+            });
+        }
+        let signature = &native.signature;
+        let result = native.call(&args).map_err(|message| {
+            self.error(RuntimeErrorKind::Native {
+                function: signature.name.to_string(),
+                message,
+            })
+        })?;
+        if !result.is_kind(&signature.return_kind) {
+            return Err(self.error(RuntimeErrorKind::Native {
+                function: signature.name.to_string(),
+                message: format!(
+                    "Expected a return value of type '{}' but got '{}'",
+                    native::kind_name(&signature.return_kind),
+                    result.kind_name()
                 ),
+            }));
+        }
+        match result {
+            HostValue::Nil => {}
+            HostValue::Int(i) => self.stack.push(Value::Int(i)),
+            HostValue::Float(x) => self.stack.push(Value::Float(x)),
+            HostValue::Bool(b) => self.stack.push(Value::Bool(b)),
+            HostValue::Str(s) => self.push_string(s),
+            HostValue::Instance(_) => unreachable!("natives never return instances"),
+        }
+        Ok(())
+    }
+
+    /// Converts the values of the inputs the chunk uses, checking them against the
+    /// types the chunk was compiled against.
+    fn pass_inputs(&mut self, inputs: &[(String, HostValue)]) -> Result<()> {
+        for index in 0..self.chunk.inputs.len() {
+            let (name, kind) = &self.chunk.inputs[index];
+            let Some((_, value)) = inputs.iter().find(|(input, _)| input == name) else {
+                return Err(self.error(RuntimeErrorKind::MissingInput(name.to_string())));
+            };
+            let kind = kind.clone();
+            match self.input_value(value, &kind) {
+                Ok(val) => self.inputs.push(val),
+                Err(error) => {
+                    let name = self.chunk.inputs[index].0.to_string();
+                    return Err(self.error(RuntimeErrorKind::Input { name, error }));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Converts a value of the host, which must be of `kind`. The values of the fields
+    /// of an instance are kept on the stack until the instance is allocated.
+    fn input_value(
+        &mut self,
+        value: &HostValue,
+        kind: &HostKind,
+    ) -> std::result::Result<Value, ConversionError> {
+        match (value, kind) {
+            (HostValue::Int(i), HostKind::Int) => Ok(Value::Int(*i)),
+            (HostValue::Float(x), HostKind::Float) => Ok(Value::Float(*x)),
+            (HostValue::Bool(b), HostKind::Bool) => Ok(Value::Bool(*b)),
+            (HostValue::Str(s), HostKind::Str) => {
+                Ok(Value::String(self.alloc(Object::String(s.to_string()))))
+            }
+            (HostValue::Nil, HostKind::Optional(_)) => Ok(Value::Nil),
+            (value, HostKind::Optional(inner)) => self.input_value(value, inner),
+            (HostValue::Instance(instance), HostKind::Instance(class))
+                if instance.class == self.chunk.classes[*class].name =>
+            {
+                self.input_instance(instance, *class)
+            }
+            (value, kind) => Err(ConversionError::Type {
+                expected: self.host_kind_name(kind),
+                actual: match value {
+                    HostValue::Instance(instance) => instance.class.to_string(),
+                    value => value.kind_name().to_string(),
+                },
+            }),
+        }
+    }
+
+    fn input_instance(
+        &mut self,
+        instance: &HostInstance,
+        class: usize,
+    ) -> std::result::Result<Value, ConversionError> {
+        let info = &self.chunk.classes[class];
+        if let Some((field, _)) = instance
+            .fields
+            .iter()
+            .find(|(f, _)| !info.fields.contains(f))
+        {
+            return Err(ConversionError::UnknownField {
+                class: instance.class.to_string(),
+                field: field.to_string(),
+            });
+        }
+        let start = self.stack.len();
+        for field_idx in 0..self.chunk.classes[class].fields.len() {
+            let info = &self.chunk.classes[class];
+            let field = &info.fields[field_idx];
+            let Some(kind) = info.field_kinds[field_idx].clone() else {
+                self.stack.truncate(start);
+                return Err(ConversionError::Unsupported(info.name.to_string()));
+            };
+            let val = match (instance.field(field), &kind) {
+                (Some(value), _) => self.input_value(value, &kind),
+                // optional fields left out are nil
+                (None, HostKind::Optional(_)) => Ok(Value::Nil),
+                (None, _) => Err(ConversionError::MissingField {
+                    class: instance.class.to_string(),
+                    field: field.to_string(),
+                }),
+            };
+            match val {
+                Ok(val) => self.stack.push(val),
+                Err(error) => {
+                    self.stack.truncate(start);
+                    return Err(error);
+                }
+            }
+        }
+        let values = self.stack[start..].to_vec();
+        let idx = self.alloc(Object::Instance(RuntimeInstance { class, values }));
+        self.stack.truncate(start);
+        Ok(Value::Instance(idx))
+    }
+
+    fn host_kind_name(&self, kind: &HostKind) -> String {
+        match kind {
+            HostKind::Int => "int".to_string(),
+            HostKind::Float => "float".to_string(),
+            HostKind::Bool => "bool".to_string(),
+            HostKind::Str => "str".to_string(),
+            HostKind::Optional(inner) => format!("{}?", self.host_kind_name(inner)),
+            HostKind::Instance(class) => self.chunk.classes[*class].name.to_string(),
+        }
+    }
+
+    /// Reads back the return value and the globals of the compiled source after the
+    /// run.
+    fn outcome(&self) -> Outcome {
+        let globals = self
+            .chunk
+            .globals
+            .iter()
+            .filter_map(|(name, slot)| match self.stack.get(*slot) {
+                // globals whose declaration did not run are not there to read
+                Some(Value::Uninitialized) | None => None,
+                Some(val) => Some((name.to_string(), self.to_host(*val, &mut vec![]))),
+            })
+            .collect();
+        Outcome {
+            value: self.to_host(self.result.unwrap_or(Value::Nil), &mut vec![]),
+            globals,
+            gc_stats: self.heap.stats(),
+        }
+    }
+
+    /// Converts a value for the host. `seen` holds the instances being converted, so
+    /// an instance that contains itself is an error.
+    fn to_host(
+        &self,
+        val: Value,
+        seen: &mut Vec<usize>,
+    ) -> std::result::Result<HostValue, ConversionError> {
+        match val {
+            Value::Int(i) => Ok(HostValue::Int(i)),
+            Value::Float(x) => Ok(HostValue::Float(x)),
+            Value::Bool(b) => Ok(HostValue::Bool(b)),
+            Value::Nil => Ok(HostValue::Nil),
+            Value::String(idx) => match self.heap.get(idx) {
+                Some(Object::String(s)) => Ok(HostValue::Str(s.to_string())),
+                _ => Err(ConversionError::Unsupported(val.kind_name().to_string())),
+            },
+            Value::Instance(idx) => {
+                let Some(Object::Instance(instance)) = self.heap.get(idx) else {
+                    return Err(ConversionError::Unsupported(val.kind_name().to_string()));
+                };
+                let info = &self.chunk.classes[instance.class];
+                if info.variant {
+                    return Err(ConversionError::Unsupported(info.name.to_string()));
+                }
+                if seen.contains(&idx) {
+                    return Err(ConversionError::Cycle(info.name.to_string()));
+                }
+                seen.push(idx);
+                let mut fields = Vec::with_capacity(info.fields.len());
+                for (name, val) in info.fields.iter().zip(&instance.values) {
+                    fields.push((name.to_string(), self.to_host(*val, seen)?));
+                }
+                seen.pop();
+                Ok(HostValue::Instance(HostInstance {
+                    class: info.name.to_string(),
+                    fields,
+                }))
             }
-            ip += 1;
+            _ => Err(ConversionError::Unsupported(val.kind_name().to_string())),
         }
     }
+
+    /// Calls `function` with `args` from native code and runs it to completion.
+    fn call(&mut self, function: usize, args: &[Value], out: &mut impl Write) -> Result<Value> {
+        let (op_ip, opcode) = (self.op_ip, self.opcode);
+        self.stack.extend_from_slice(args);
+        self.stack_offset = self.stack.len() - args.len();
+        self.offsets.push(self.stack_offset);
+        self.call_stack.push((self.ip, self.function));
+        self.function = function;
+        self.ip = 0;
+        self.run(out, self.call_stack.len())?;
+        self.offsets.pop();
+        self.stack_offset = match self.offsets.last() {
+            Some(offset) => *offset,
+            None => return Err(self.error(RuntimeErrorKind::StackUnderflow)),
+        };
+        self.op_ip = op_ip;
+        self.opcode = opcode;
+        self.pop()
+    }
+
+    /// Calls the function value `closure_idx` with `args` followed by its captures.
+    fn call_closure(
+        &mut self,
+        closure_idx: usize,
+        args: &[Value],
+        out: &mut impl Write,
+    ) -> Result<Value> {
+        let (function, captures) = self.closure(closure_idx)?;
+        if function >= self.chunk.code.len() {
+            return Err(self.error(RuntimeErrorKind::UnknownFunction(function)));
+        }
+        let args: Vec<Value> = args.iter().copied().chain(captures).collect();
+        self.call(function, &args, out)
+    }
+
+    /// A stable merge sort of `values`, where `less` is a function value telling if
+    /// its first argument goes before its second. The values have to be reachable
+    /// from the stack.
+    fn sort(
+        &mut self,
+        mut values: Vec<Value>,
+        less: usize,
+        out: &mut impl Write,
+    ) -> Result<Vec<Value>> {
+        let len = values.len();
+        let mut buffer = values.clone();
+        let mut width = 1;
+        while width < len {
+            let mut start = 0;
+            while start < len {
+                let mid = (start + width).min(len);
+                let end = (start + 2 * width).min(len);
+                let (mut left, mut right) = (start, mid);
+                for slot in buffer.iter_mut().take(end).skip(start) {
+                    // the left element goes first unless the right one is less
+                    let take_right = right < end
+                        && (left >= mid
+                            || match self.call_closure(less, &[values[right], values[left]], out)? {
+                                Value::Bool(b) => b,
+                                val => return Err(self.type_mismatch("bool", val)),
+                            });
+                    if take_right {
+                        *slot = values[right];
+                        right += 1;
+                    } else {
+                        *slot = values[left];
+                        left += 1;
+                    }
+                }
+                start = end;
+            }
+            std::mem::swap(&mut values, &mut buffer);
+            width *= 2;
+        }
+        Ok(values)
+    }
+
+    /// Renders `value` the way `print` shows it. Strings inside collections and
+    /// instances are quoted. `value` has to be reachable from the stack, since a
+    /// `to_string` method may allocate and collect garbage.
+    fn render(&mut self, value: Value, nested: bool, out: &mut impl Write) -> Result<String> {
+        match value {
+            Value::Int(i) => Ok(i.to_string()),
+            Value::Float(f) => Ok(float_to_string(f)),
+            Value::Bool(b) => Ok(b.to_string()),
+            Value::Nil => Ok("nil".to_string()),
+            Value::Uninitialized => Ok("uninitialized".to_string()),
+            Value::String(s) if nested => Ok(format!("{:?}", self.string(s)?)),
+            Value::String(s) => Ok(self.string(s)?.to_string()),
+            Value::Function(idx) => {
+                let (function, _) = self.closure(idx)?;
+                match self.chunk.function_names.get(function) {
+                    Some(name) if name != "<anonymous>" => Ok(format!("<fun {}>", name)),
+                    _ => Ok("<fun>".to_string()),
+                }
+            }
+            Value::Instance(idx) | Value::Array(idx) | Value::Map(idx)
+                if self.rendering.contains(&idx) =>
+            {
+                Ok("...".to_string())
+            }
+            Value::Instance(idx) => {
+                let class = self.class_of(idx)?;
+                self.rendering.push(idx);
+                let info = &self.chunk.classes[class];
+                let s = match info.to_string {
+                    Some(function) => match self.call(function, &[value], out)? {
+                        Value::String(s) => self.string(s)?.to_string(),
+                        val => return Err(self.type_mismatch("str", val)),
+                    },
+                    // enum variants show their payload without field names
+                    None if info.variant && info.fields.is_empty() => info.name.to_string(),
+                    None if info.variant => {
+                        let mut values = vec![];
+                        for i in 0..self.chunk.classes[class].fields.len() {
+                            let value = *self.field(idx, i)?;
+                            values.push(self.render_child(value, out)?);
+                        }
+                        format!("{}({})", self.chunk.classes[class].name, values.join(", "))
+                    }
+                    None => {
+                        let mut fields = vec![];
+                        for i in 0..self.chunk.classes[class].fields.len() {
+                            let field = *self.field(idx, i)?;
+                            let field = self.render_child(field, out)?;
+                            fields.push(format!(
+                                "{}: {}",
+                                self.chunk.classes[class].fields[i], field
+                            ));
+                        }
+                        format!("{}({})", self.chunk.classes[class].name, fields.join(", "))
+                    }
+                };
+                self.rendering.pop();
+                Ok(s)
+            }
+            Value::Array(idx) => {
+                self.rendering.push(idx);
+                let mut elements = vec![];
+                // a to_string method could change the array, so it is indexed every time
+                let mut i = 0;
+                while let Some(element) = self.array(idx)?.get(i).copied() {
+                    elements.push(self.render_child(element, out)?);
+                    i += 1;
+                }
+                self.rendering.pop();
+                Ok(format!("[{}]", elements.join(", ")))
+            }
+            Value::Map(idx) => {
+                self.rendering.push(idx);
+                let mut entries = vec![];
+                // a to_string method of a value can change the map
+                let pairs: Vec<_> = self.map(idx)?.entries().collect();
+                for (key, value) in pairs {
+                    let key = self.render_child(key, out)?;
+                    entries.push(format!("{}: {}", key, self.render_child(value, out)?));
+                }
+                self.rendering.pop();
+                Ok(format!("{{{}}}", entries.join(", ")))
+            }
+        }
+    }
+
+    /// Renders a value held by another object, keeping it on the stack meanwhile.
+    fn render_child(&mut self, value: Value, out: &mut impl Write) -> Result<String> {
+        self.stack.push(value);
+        let s = self.render(value, true, out)?;
+        self.pop()?;
+        Ok(s)
+    }
+
+    /// Structural equality. Instances are equal when they are of the same class and
+    /// their fields are equal, maps when they have the same entries in any order and
+    /// functions when they are the same function with equal captures.
+    /// `seen` holds the pairs already being compared, which are assumed equal so
+    /// cyclic values terminate.
+    fn values_equal(&self, a: Value, b: Value, seen: &mut Vec<(Value, Value)>) -> Result<bool> {
+        if seen.contains(&(a, b)) {
+            return Ok(true);
+        }
+        match (a, b) {
+            (Value::String(a), Value::String(b)) => Ok(self.string(a)? == self.string(b)?),
+            (Value::Instance(a_idx), Value::Instance(b_idx)) => {
+                let (Some(Object::Instance(a_inst)), Some(Object::Instance(b_inst))) =
+                    (self.heap.get(a_idx), self.heap.get(b_idx))
+                else {
+                    return Err(self.error(RuntimeErrorKind::InvalidInstance(a_idx)));
+                };
+                if a_inst.class != b_inst.class {
+                    return Ok(false);
+                }
+                seen.push((a, b));
+                let pairs: Vec<(Value, Value)> = a_inst
+                    .values
+                    .iter()
+                    .copied()
+                    .zip(b_inst.values.iter().copied())
+                    .collect();
+                self.all_equal(pairs, seen)
+            }
+            (Value::Array(a_idx), Value::Array(b_idx)) => {
+                let (Some(Object::Array(a_values)), Some(Object::Array(b_values))) =
+                    (self.heap.get(a_idx), self.heap.get(b_idx))
+                else {
+                    return Err(self.error(RuntimeErrorKind::InvalidArray(a_idx)));
+                };
+                if a_values.len() != b_values.len() {
+                    return Ok(false);
+                }
+                seen.push((a, b));
+                let pairs = a_values
+                    .iter()
+                    .copied()
+                    .zip(b_values.iter().copied())
+                    .collect();
+                self.all_equal(pairs, seen)
+            }
+            (Value::Function(a_idx), Value::Function(b_idx)) => {
+                let ((a_function, a_captures), (b_function, b_captures)) =
+                    (self.closure(a_idx)?, self.closure(b_idx)?);
+                if a_function != b_function {
+                    return Ok(false);
+                }
+                seen.push((a, b));
+                self.all_equal(a_captures.into_iter().zip(b_captures).collect(), seen)
+            }
+            (Value::Map(a_idx), Value::Map(b_idx)) => {
+                let (Some(Object::Map(a_map)), Some(Object::Map(b_map))) =
+                    (self.heap.get(a_idx), self.heap.get(b_idx))
+                else {
+                    return Err(self.error(RuntimeErrorKind::InvalidMap(a_idx)));
+                };
+                if a_map.len() != b_map.len() {
+                    return Ok(false);
+                }
+                let mut pairs = vec![];
+                for (key, value) in a_map.entries() {
+                    match b_map.get(&self.map_key(key)?) {
+                        Some(other) => pairs.push((value, other)),
+                        None => return Ok(false),
+                    }
+                }
+                seen.push((a, b));
+                self.all_equal(pairs, seen)
+            }
+            // ints, floats, bools and nil compare by value
+            (a, b) => Ok(a == b),
+        }
+    }
+
+    fn all_equal(
+        &self,
+        pairs: Vec<(Value, Value)>,
+        seen: &mut Vec<(Value, Value)>,
+    ) -> Result<bool> {
+        for (a, b) in pairs {
+            if !self.values_equal(a, b, seen)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn read_byte(&mut self) -> Result<u8> {
+        match self.chunk.code[self.function].get(self.ip) {
+            Some(b) => {
+                self.ip += 1;
+                Ok(*b)
+            }
+            None => Err(self.error(RuntimeErrorKind::InvalidJump)),
+        }
+    }
+
+    /// Reads a two byte operand.
+    fn read_u16(&mut self) -> Result<usize> {
+        let high = self.read_byte()?;
+        let low = self.read_byte()?;
+        Ok(u16::from_be_bytes([high, low]) as usize)
+    }
+
+    fn string(&self, idx: usize) -> Result<&str> {
+        match self.heap.get(idx) {
+            Some(Object::String(s)) => Ok(s),
+            _ => Err(self.error(RuntimeErrorKind::InvalidString(idx))),
+        }
+    }
+
+    fn field(&mut self, instance_idx: usize, field_idx: usize) -> Result<&mut Value> {
+        let error = match self.heap.get(instance_idx) {
+            Some(Object::Instance(instance)) if field_idx < instance.values.len() => None,
+            Some(Object::Instance(_)) => Some(RuntimeErrorKind::InvalidField(field_idx)),
+            _ => Some(RuntimeErrorKind::InvalidInstance(instance_idx)),
+        };
+        if let Some(kind) = error {
+            return Err(self.error(kind));
+        }
+        match self.heap.get_mut(instance_idx) {
+            Some(Object::Instance(instance)) => Ok(&mut instance.values[field_idx]),
+            _ => unreachable!(),
+        }
+    }
+
+    fn class_of(&self, instance_idx: usize) -> Result<usize> {
+        match self.heap.get(instance_idx) {
+            Some(Object::Instance(instance)) => Ok(instance.class),
+            _ => Err(self.error(RuntimeErrorKind::InvalidInstance(instance_idx))),
+        }
+    }
+
+    fn array(&mut self, array_idx: usize) -> Result<&mut Vec<Value>> {
+        if !matches!(self.heap.get(array_idx), Some(Object::Array(_))) {
+            return Err(self.error(RuntimeErrorKind::InvalidArray(array_idx)));
+        }
+        match self.heap.get_mut(array_idx) {
+            Some(Object::Array(values)) => Ok(values),
+            _ => unreachable!(),
+        }
+    }
+
+    /// The function of a closure and a copy of its captures.
+    fn closure(&self, closure_idx: usize) -> Result<(usize, Vec<Value>)> {
+        match self.heap.get(closure_idx) {
+            Some(Object::Closure(closure)) => Ok((closure.function, closure.captures.clone())),
+            _ => Err(self.error(RuntimeErrorKind::InvalidFunction(closure_idx))),
+        }
+    }
+
+    fn map(&mut self, map_idx: usize) -> Result<&mut RuntimeMap> {
+        if !matches!(self.heap.get(map_idx), Some(Object::Map(_))) {
+            return Err(self.error(RuntimeErrorKind::InvalidMap(map_idx)));
+        }
+        match self.heap.get_mut(map_idx) {
+            Some(Object::Map(map)) => Ok(map),
+            _ => unreachable!(),
+        }
+    }
+
+    fn map_key(&self, key: Value) -> Result<MapKey> {
+        match key {
+            Value::Int(i) => Ok(MapKey::Int(i)),
+            Value::Bool(b) => Ok(MapKey::Bool(b)),
+            Value::String(s) => Ok(MapKey::String(self.string(s)?.to_string())),
+            val => Err(self.type_mismatch("int, bool or str", val)),
+        }
+    }
+
+    fn element(&mut self, array_idx: usize, index: i64) -> Result<&mut Value> {
+        let len = self.array(array_idx)?.len();
+        if index < 0 || index as usize >= len {
+            return Err(self.error(RuntimeErrorKind::IndexOutOfBounds { index, len }));
+        }
+        Ok(&mut self.array(array_idx)?[index as usize])
+    }
+
+    /// Allocates `object` on the heap, collecting garbage first if the heap has
+    /// grown past its threshold. Everything reachable from the value stack, the
+    /// constants and the object itself survives the collection.
+    fn alloc(&mut self, object: Object) -> usize {
+        if self.heap.should_collect(&object) {
+            let children = match &object {
+                Object::Instance(instance) => &instance.values[..],
+                Object::Array(values) => &values[..],
+                Object::Closure(closure) => &closure.captures[..],
+                Object::Map(_) | Object::String(_) => &[],
+            };
+            self.heap.collect(
+                self.stack
+                    .iter()
+                    .chain(&self.constants)
+                    .chain(&self.inputs)
+                    .chain(children),
+            );
+        }
+        self.heap.alloc(object)
+    }
+
+    fn local(&self, slot: usize) -> Result<Value> {
+        match self.stack.get(slot) {
+            Some(val) => Ok(*val),
+            None => Err(self.error(RuntimeErrorKind::StackUnderflow)),
+        }
+    }
+
+    /// Reads a global, which is a local of a top level frame at an absolute slot.
+    fn global(&self, slot: usize) -> Result<Value> {
+        match self.local(slot)? {
+            Value::Uninitialized => Err(self.error(RuntimeErrorKind::UninitializedGlobal(slot))),
+            val => Ok(val),
+        }
+    }
+
+    fn push_string(&mut self, s: String) {
+        let idx = self.alloc(Object::String(s));
+        self.stack.push(Value::String(idx));
+    }
+
+    fn push_int(&mut self, i: Option<i64>) -> Result<()> {
+        match i {
+            Some(i) => {
+                self.stack.push(Value::Int(i));
+                Ok(())
+            }
+            None => Err(self.error(RuntimeErrorKind::IntegerOverflow)),
+        }
+    }
+
+    /// Returns the value `distance` slots below the top of the stack.
+    fn peek(&self, distance: usize) -> Result<Value> {
+        match self.stack.len().checked_sub(distance + 1) {
+            Some(slot) => self.local(slot),
+            None => Err(self.error(RuntimeErrorKind::StackUnderflow)),
+        }
+    }
+
+    fn pop(&mut self) -> Result<Value> {
+        match self.stack.pop() {
+            Some(val) => Ok(val),
+            None => Err(self.error(RuntimeErrorKind::StackUnderflow)),
+        }
+    }
+
+    fn pop_int(&mut self) -> Result<i64> {
+        match self.pop()? {
+            Value::Int(i) => Ok(i),
+            val => Err(self.type_mismatch("int", val)),
+        }
+    }
+
+    fn pop_float(&mut self) -> Result<f64> {
+        match self.pop()? {
+            Value::Float(f) => Ok(f),
+            val => Err(self.type_mismatch("float", val)),
+        }
+    }
+
+    fn pop_bool(&mut self) -> Result<bool> {
+        match self.pop()? {
+            Value::Bool(b) => Ok(b),
+            val => Err(self.type_mismatch("bool", val)),
+        }
+    }
+
+    fn pop_string(&mut self) -> Result<usize> {
+        match self.pop()? {
+            Value::String(s) => Ok(s),
+            val => Err(self.type_mismatch("str", val)),
+        }
+    }
+
+    fn pop_instance(&mut self) -> Result<usize> {
+        match self.pop()? {
+            Value::Instance(i) => Ok(i),
+            val => Err(self.type_mismatch("instance", val)),
+        }
+    }
+
+    fn pop_array(&mut self) -> Result<usize> {
+        match self.pop()? {
+            Value::Array(a) => Ok(a),
+            val => Err(self.type_mismatch("array", val)),
+        }
+    }
+
+    fn pop_map(&mut self) -> Result<usize> {
+        match self.pop()? {
+            Value::Map(m) => Ok(m),
+            val => Err(self.type_mismatch("map", val)),
+        }
+    }
+
+    fn verify_store(&self, old: Value, new: Value) -> Result<()> {
+        // optionals may switch between nil and a value, and globals get their first
+        if old == Value::Nil || new == Value::Nil || old == Value::Uninitialized {
+            return Ok(());
+        }
+        if VERIFY_STORES && std::mem::discriminant(&old) != std::mem::discriminant(&new) {
+            return Err(self.type_mismatch(old.kind_name(), new));
+        }
+        Ok(())
+    }
+
+    fn type_mismatch(&self, expected: &'static str, actual: Value) -> RuntimeError {
+        self.error(RuntimeErrorKind::TypeMismatch {
+            instruction: self.opcode.name(),
+            expected,
+            actual: actual.kind_name(),
+        })
+    }
+
+    /// Builds an error for the instruction currently executing, with the call stack
+    /// unwound from the innermost function outwards.
+    fn error(&self, kind: RuntimeErrorKind) -> RuntimeError {
+        let line = self.line_at(self.function, self.op_ip);
+        let mut call_stack = vec![StackFrame {
+            function: self.function_name(self.function),
+            line,
+        }];
+        for (return_ip, function) in self.call_stack.iter().rev() {
+            call_stack.push(StackFrame {
+                function: self.function_name(*function),
+                // the operands of the call instruction are right before the return ip
+                line: self.line_at(*function, return_ip.saturating_sub(1)),
+            });
+        }
+        RuntimeError {
+            kind,
+            line,
+            call_stack,
+        }
+    }
+
+    fn line_at(&self, function: usize, ip: usize) -> usize {
+        self.chunk.line[function].get(ip).copied().unwrap_or(0)
+    }
+
+    fn function_name(&self, function: usize) -> String {
+        self.chunk.function_names[function].to_string()
+    }
 }